@@ -18,8 +18,8 @@ use tracing_subscriber::util::SubscriberInitExt as _;
 use uuid::Uuid;
 
 use bismuth_common::{
-    init_sentry, init_tracer, pack_backends, unpack_backends, ApiError, Backend, ContainerState,
-    FunctionDefinition, BACKEND_PORT,
+    backends_path, init_sentry, init_tracer, pack_backends, prepare_backends, read_backends,
+    ApiError, Backend, ContainerState, FunctionDefinition, BACKEND_PORT,
 };
 
 pub struct ControlPlaneState {
@@ -94,12 +94,7 @@ async fn function_status(
             .0,
     )?;
 
-    let function_backends_key = format!("/function/{}/backends", &function_id);
-    let (function_backends_raw, _) = zk
-        .get_data(&function_backends_key)
-        .await
-        .context("Error getting function backends")?;
-    let backends = unpack_backends(&function_backends_raw)?;
+    let (backends, _) = read_backends(&zk, &function_id).await?;
 
     let mut reader = zk.new_multi_reader();
     for backend in &backends {
@@ -146,12 +141,7 @@ async fn function_logs(
 ) -> Result<axum::response::Response<hyper::Body>, ApiError> {
     tracing::Span::current().record("follow", params.follow.unwrap_or_default());
     let zk = state.zk().await?;
-    let function_backends_key = format!("/function/{}/backends", &function_id);
-    let (function_backends_raw, _) = zk
-        .get_data(&function_backends_key)
-        .await
-        .context("Error getting function backends")?;
-    let backends = unpack_backends(&function_backends_raw)?;
+    let (backends, _) = read_backends(&zk, &function_id).await?;
     if backends.is_empty() {
         return Err(ApiError::Status(StatusCode::SERVICE_UNAVAILABLE));
     }
@@ -233,13 +223,10 @@ async fn function_update(
 
     {
         // Get current backends
-        let function_backends_key = format!("/function/{}/backends", &function_id);
-        let (function_backends_raw, functions_backends_stat) = zk
-            .get_data(&function_backends_key)
-            .await
-            .context("Error getting function backends")?;
+        let (backends, version) = read_backends(&zk, &function_id).await?;
 
         let new_backend = pick_backend(&zk).await?;
+        let prepared = prepare_backends(&zk, &function_id, &[new_backend.clone()]).await?;
 
         let mut multi = zk.new_multi_writer();
 
@@ -254,13 +241,13 @@ async fn function_update(
 
         // Clear the function's backend list
         multi.add_set_data(
-            &format!("/function/{}/backends", &function_id),
-            &pack_backends(&[new_backend.clone()]),
-            Some(functions_backends_stat.version),
+            &backends_path(&function_id),
+            &prepared.data,
+            Some(version.version),
         )?;
 
         // And remove each container/backend
-        for backend in unpack_backends(&function_backends_raw)? {
+        for backend in &backends {
             multi.add_delete(
                 &format!(
                     "/node/{}/container/{}/status",
@@ -294,7 +281,16 @@ async fn function_update(
                 .with_acls(zookeeper_client::Acls::anyone_all()),
         )?;
 
-        multi.commit().await.context("Error updating function")?;
+        match multi.commit().await {
+            // Deletes the chunks of a list that was too long for one znode.
+            Ok(_) => prepared.finish(&zk, version).await,
+            Err(e) => {
+                prepared.abandon(&zk).await;
+                return Err(anyhow::Error::from(e)
+                    .context("Error updating function")
+                    .into());
+            }
+        }
     }
 
     function_status(State(state), Path(function_id)).await
@@ -309,24 +305,37 @@ async fn function_delete(
     let zk = state.zk().await?;
 
     // Get current backends
-    let function_backends_key = format!("/function/{}/backends", &function_id);
-    let (function_backends_raw, functions_backends_stat) = zk
-        .get_data(&function_backends_key)
-        .await
-        .context("Error getting function backends")?;
+    let (backends, version) = read_backends(&zk, &function_id).await?;
 
     let mut multi = zk.new_multi_writer();
 
-    // Delete the function's backend list
-    multi.add_delete(
-        &format!("/function/{}/backends", &function_id),
-        Some(functions_backends_stat.version),
-    )?;
+    // Delete the function's backend list, after the chunks of one too long for one znode (or left
+    // behind by failed writes)
+    let backends_key = backends_path(&function_id);
+    for chunk in zk
+        .list_children(&backends_key)
+        .await
+        .context("Error listing function backends chunks")?
+    {
+        multi.add_delete(&format!("{}/{}", backends_key, chunk), None)?;
+    }
+    multi.add_delete(&backends_key, Some(version.version))?;
+
+    // Any optional per-function znodes (metadata, replicas, ...)
+    for child in zk
+        .list_children(&format!("/function/{}", &function_id))
+        .await
+        .context("Error listing function znodes")?
+    {
+        if child != "backends" {
+            multi.add_delete(&format!("/function/{}/{}", &function_id, child), None)?;
+        }
+    }
 
     multi.add_delete(&format!("/function/{}", &function_id), None)?;
 
     // And remove each container/backend
-    for backend in unpack_backends(&function_backends_raw)? {
+    for backend in &backends {
         multi.add_delete(
             &format!(
                 "/node/{}/container/{}/status",
@@ -376,6 +385,7 @@ struct Cli {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    bismuth_common::init_scrubber()?;
     let _sentry_guard = init_sentry();
     let tracer = init_tracer("faas-api")?;
 
@@ -384,7 +394,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     tracing_subscriber::registry()
         .with(tracing_opentelemetry::layer().with_tracer(tracer))
         .with(tracing_subscriber::EnvFilter::from_default_env())
-        .with(tracing_subscriber::fmt::layer())
+        .with(
+            tracing_subscriber::fmt::layer().with_writer(bismuth_common::Scrubbed(std::io::stdout)),
+        )
+        .with(bismuth_common::file_log_layer()?)
+        .with(bismuth_common::system_log_layers("faas-api")?)
         .init();
 
     let http_client = hyper::Client::new();
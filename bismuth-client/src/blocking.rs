@@ -0,0 +1,43 @@
+//! Synchronous wrapper around `Client`, for callers without a tokio runtime.
+
+use hyper::body::Bytes;
+use hyper::http::{Request, Response};
+use uuid::Uuid;
+
+use crate::{Error, RetryPolicy};
+
+pub struct Client {
+    inner: crate::Client,
+    runtime: tokio::runtime::Runtime,
+}
+
+impl Client {
+    pub fn new(frontend: &str) -> Result<Self, Error> {
+        Ok(Self {
+            inner: crate::Client::new(frontend)?,
+            runtime: tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?,
+        })
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.inner = self.inner.with_retry_policy(retry);
+        self
+    }
+
+    /// See `crate::Client::invoke`.
+    pub fn invoke(&self, function_id: Uuid, req: Request<Bytes>) -> Result<Response<Bytes>, Error> {
+        self.runtime.block_on(self.inner.invoke(function_id, req))
+    }
+
+    /// See `crate::Client::invoke_batch`.
+    pub fn invoke_batch(
+        &self,
+        invocations: impl IntoIterator<Item = (Uuid, Request<Bytes>)>,
+        concurrency: usize,
+    ) -> Vec<Result<Response<Bytes>, Error>> {
+        self.runtime
+            .block_on(self.inner.invoke_batch(invocations, concurrency))
+    }
+}
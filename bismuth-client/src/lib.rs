@@ -0,0 +1,393 @@
+//! Client for invoking functions through a Bismuth frontend (`bismuthfe`).
+//!
+//! ```no_run
+//! # async fn example(function_id: uuid::Uuid) -> Result<(), bismuth_client::Error> {
+//! let client = bismuth_client::Client::new("http://localhost:8000")?;
+//! let resp = client
+//!     .invoke(function_id, hyper::Request::post("/hello").body("world".into())?)
+//!     .await?;
+//! println!("{:?}", resp.body());
+//! # Ok(())
+//! # }
+//! ```
+
+use futures::StreamExt as _;
+use hyper::body::{Body, Bytes};
+use hyper::client::HttpConnector;
+use hyper::http::{HeaderMap, Request, Response, StatusCode};
+use rand::Rng as _;
+use std::time::Duration;
+use tracing::{event, instrument, Level};
+use tracing_opentelemetry::OpenTelemetrySpanExt as _;
+use uuid::Uuid;
+
+pub mod blocking;
+
+/// Mirrors the platform's `ApiError`/`GenericError` status codes.
+/// The frontend and the function itself share one status space, so a function returning
+/// e.g. 404 surfaces as `NotFound` just like an unknown function id does.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Not found")]
+    NotFound,
+    #[error("Unavailable")]
+    Unavailable,
+    #[error("Invalid request: {0}")]
+    Invalid(String),
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    #[error("Unexpected status {status}")]
+    Status { status: StatusCode, body: Bytes },
+    #[error("Invalid URI: {0}")]
+    Uri(#[from] hyper::http::uri::InvalidUri),
+    #[error("Error building request: {0}")]
+    Http(#[from] hyper::http::Error),
+    #[error("Transport error: {0}")]
+    Transport(#[from] hyper::Error),
+    #[error("Error starting runtime: {0}")]
+    Runtime(#[from] std::io::Error),
+}
+
+impl Error {
+    fn from_response(status: StatusCode, body: Bytes) -> Self {
+        match status {
+            StatusCode::NOT_FOUND => Error::NotFound,
+            StatusCode::SERVICE_UNAVAILABLE => Error::Unavailable,
+            StatusCode::BAD_REQUEST => Error::Invalid(String::from_utf8_lossy(&body).into_owned()),
+            StatusCode::CONFLICT => Error::Conflict(String::from_utf8_lossy(&body).into_owned()),
+            status => Error::Status { status, body },
+        }
+    }
+
+    /// Whether the request was never sent, so it can be retried whatever it does.
+    fn is_unsent(&self) -> bool {
+        matches!(self, Error::Transport(e) if e.is_connect())
+    }
+
+    /// Whether the request can be sent again, if it's idempotent (see `is_idempotent`).
+    /// 503s usually come from the frontend before the request reaches a backend (no backends,
+    /// load shedding, concurrency limits), but can also come from the function itself.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Unavailable => true,
+            Error::Status { status, .. } => {
+                *status == StatusCode::BAD_GATEWAY || *status == StatusCode::GATEWAY_TIMEOUT
+            }
+            Error::Transport(e) => e.is_connect(),
+            _ => false,
+        }
+    }
+}
+
+/// Marks retries of the same operation, so the frontend can collapse concurrent duplicates.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Whether sending `req` more than once is harmless: it uses a safe method, or carries an
+/// `Idempotency-Key` for the function to deduplicate with.
+pub fn is_idempotent<B>(req: &Request<B>) -> bool {
+    req.method().is_safe() || req.headers().contains_key(IDEMPOTENCY_KEY_HEADER)
+}
+
+/// Exponential backoff with full jitter between attempts.
+/// Only idempotent requests (see `is_idempotent`) are retried, apart from connection failures.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Total attempts, including the first. 1 disables retries.
+    pub max_attempts: u32,
+    pub base_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retry number `attempt` (starting at 1). A server-provided `Retry-After`
+    /// takes precedence, capped at `max_backoff`.
+    fn backoff(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        if let Some(retry_after) = retry_after {
+            return retry_after.min(self.max_backoff);
+        }
+        let ceiling = self
+            .base_backoff
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.max_backoff);
+        ceiling.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Client {
+    /// Frontend base URL, without a trailing slash.
+    frontend: String,
+    http_client: hyper::Client<HttpConnector, Body>,
+    retry: RetryPolicy,
+}
+
+impl Client {
+    /// `frontend` is the base URL of a bismuthfe, e.g. `http://localhost:8000`.
+    pub fn new(frontend: &str) -> Result<Self, Error> {
+        frontend.parse::<hyper::Uri>()?;
+        Ok(Self {
+            frontend: frontend.trim_end_matches('/').to_string(),
+            http_client: hyper::Client::new(),
+            retry: RetryPolicy::default(),
+        })
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Invoke `function_id`. The request's URI is the path (and query) within the function,
+    /// e.g. `/` or `/users?id=1`.
+    /// Trace context from the current span is propagated, and retryable errors are retried
+    /// according to the client's `RetryPolicy` if the request is idempotent. Give unsafe
+    /// requests an `Idempotency-Key` to have them retried.
+    #[instrument(skip(self, req), fields(method = %req.method(), path = %req.uri()))]
+    pub async fn invoke(
+        &self,
+        function_id: Uuid,
+        req: Request<Bytes>,
+    ) -> Result<Response<Bytes>, Error> {
+        let path = req
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/");
+        let uri: hyper::Uri = format!(
+            "{}/invoke/{}/{}",
+            self.frontend,
+            function_id,
+            path.trim_start_matches('/')
+        )
+        .parse()?;
+
+        let idempotent = is_idempotent(&req);
+        let mut attempt = 1;
+        loop {
+            let (err, retry_after) = match self.send(&uri, &req).await {
+                Ok(resp) => return Ok(resp),
+                Err(e) => e,
+            };
+            let retryable = err.is_unsent() || (idempotent && err.is_retryable());
+            if attempt >= self.retry.max_attempts || !retryable {
+                return Err(err);
+            }
+            let backoff = self.retry.backoff(attempt, retry_after);
+            event!(Level::DEBUG, function = %function_id, attempt, error = %err, ?backoff, "Retrying invocation");
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+
+    /// One attempt. On error, also returns the response's `Retry-After`, if any.
+    async fn send(
+        &self,
+        uri: &hyper::Uri,
+        req: &Request<Bytes>,
+    ) -> Result<Response<Bytes>, (Error, Option<Duration>)> {
+        let mut builder = Request::builder()
+            .method(req.method())
+            .uri(uri)
+            .version(req.version());
+        if let Some(headers) = builder.headers_mut() {
+            *headers = req.headers().clone();
+            inject_trace_context(headers);
+        }
+        let attempt = builder
+            .body(Body::from(req.body().clone()))
+            .map_err(|e| (e.into(), None))?;
+
+        let resp = self
+            .http_client
+            .request(attempt)
+            .await
+            .map_err(|e| (e.into(), None))?;
+        let (parts, body) = resp.into_parts();
+        let body = hyper::body::to_bytes(body)
+            .await
+            .map_err(|e| (e.into(), None))?;
+        if parts.status.is_success() || parts.status.is_redirection() {
+            return Ok(Response::from_parts(parts, body));
+        }
+
+        let retry_after = parts
+            .headers
+            .get(hyper::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs);
+        Err((Error::from_response(parts.status, body), retry_after))
+    }
+
+    /// Invoke several functions, at most `concurrency` at a time.
+    /// Results are in the same order as `invocations`.
+    pub async fn invoke_batch(
+        &self,
+        invocations: impl IntoIterator<Item = (Uuid, Request<Bytes>)>,
+        concurrency: usize,
+    ) -> Vec<Result<Response<Bytes>, Error>> {
+        futures::stream::iter(invocations)
+            .map(|(function_id, req)| self.invoke(function_id, req))
+            .buffered(concurrency.max(1))
+            .collect()
+            .await
+    }
+}
+
+/// Propagate the current span to the frontend.
+fn inject_trace_context(headers: &mut HeaderMap) {
+    let cx = tracing::Span::current().context();
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&cx, &mut opentelemetry_http::HeaderInjector(headers))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Serve `handler` on an ephemeral port, returning its base URL.
+    fn serve<F>(handler: F) -> String
+    where
+        F: Fn(usize) -> (StatusCode, &'static str) + Clone + Send + Sync + 'static,
+    {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = axum::Router::new().route(
+            "/invoke/:function_id/*path",
+            axum::routing::any(move || {
+                let handler = handler.clone();
+                let calls = calls.clone();
+                async move { handler(calls.fetch_add(1, Ordering::SeqCst)) }
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        format!("http://{}", addr)
+    }
+
+    fn fast_retries() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(10),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retries_unavailable() {
+        let frontend = serve(|call| match call {
+            0 | 1 => (StatusCode::SERVICE_UNAVAILABLE, ""),
+            _ => (StatusCode::OK, "hello"),
+        });
+        let client = Client::new(&frontend)
+            .unwrap()
+            .with_retry_policy(fast_retries());
+        let resp = client
+            .invoke(
+                Uuid::new_v4(),
+                Request::get("/foo").body(Bytes::new()).unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.body(), "hello");
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_attempts() {
+        let frontend = serve(|_| (StatusCode::SERVICE_UNAVAILABLE, ""));
+        let client = Client::new(&frontend)
+            .unwrap()
+            .with_retry_policy(fast_retries());
+        let err = client
+            .invoke(
+                Uuid::new_v4(),
+                Request::get("/").body(Bytes::new()).unwrap(),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Unavailable));
+    }
+
+    #[tokio::test]
+    async fn test_does_not_retry_client_errors() {
+        let frontend = serve(|call| match call {
+            0 => (StatusCode::CONFLICT, "taken"),
+            _ => (StatusCode::OK, ""),
+        });
+        let client = Client::new(&frontend)
+            .unwrap()
+            .with_retry_policy(fast_retries());
+        let err = client
+            .invoke(
+                Uuid::new_v4(),
+                Request::post("/").body(Bytes::new()).unwrap(),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Conflict(body) if body == "taken"));
+    }
+
+    #[tokio::test]
+    async fn test_retries_unsafe_only_with_idempotency_key() {
+        let frontend = serve(|call| match call {
+            0 | 1 => (StatusCode::SERVICE_UNAVAILABLE, ""),
+            _ => (StatusCode::OK, "created"),
+        });
+        let client = Client::new(&frontend)
+            .unwrap()
+            .with_retry_policy(fast_retries());
+        let err = client
+            .invoke(
+                Uuid::new_v4(),
+                Request::post("/").body(Bytes::new()).unwrap(),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Unavailable));
+
+        let resp = client
+            .invoke(
+                Uuid::new_v4(),
+                Request::post("/")
+                    .header(IDEMPOTENCY_KEY_HEADER, "abc")
+                    .body(Bytes::new())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.body(), "created");
+    }
+
+    #[test]
+    fn test_backoff_bounds() {
+        let retry = RetryPolicy::default();
+        for attempt in 1..10 {
+            assert!(retry.backoff(attempt, None) <= retry.max_backoff);
+        }
+        assert_eq!(
+            retry.backoff(1, Some(Duration::from_secs(1))),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            retry.backoff(1, Some(Duration::from_secs(60))),
+            retry.max_backoff
+        );
+    }
+}
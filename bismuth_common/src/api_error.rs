@@ -10,6 +10,10 @@ pub enum GenericError {
     NotFound,
     #[error("Unavailable")]
     Unavailable,
+    #[error("Invalid request: {0}")]
+    Invalid(String),
+    #[error("Conflict: {0}")]
+    Conflict(String),
 }
 
 // axum error type which wraps `anyhow::Error`.
@@ -33,6 +37,12 @@ impl IntoResponse for ApiError {
                         GenericError::Unavailable => {
                             StatusCode::SERVICE_UNAVAILABLE.into_response()
                         }
+                        GenericError::Invalid(msg) => {
+                            (StatusCode::BAD_REQUEST, msg.clone()).into_response()
+                        }
+                        GenericError::Conflict(msg) => {
+                            (StatusCode::CONFLICT, msg.clone()).into_response()
+                        }
                     }
                 } else {
                     capture_anyhow(&err);
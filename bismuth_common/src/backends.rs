@@ -0,0 +1,309 @@
+//! Function backend lists in ZooKeeper (`/function/{id}/backends`).
+//!
+//! Lists are stored packed (see `pack_backends`) in the znode itself, unless they're too long for
+//! one znode: then they're split into chunks stored as its children, `{index:04}-{generation}`,
+//! and the znode holds a manifest naming the generation and how many chunks it has. A new list's
+//! chunks are all written under a new generation before the manifest is switched to them (with
+//! the usual version check), and the old generation's are only deleted after, so readers always
+//! get one whole list, never parts of two.
+//!
+//! Backends being drained are marked in `/function/{id}/draining` (see `DrainingBackend`), next to
+//! the list, rather than in it, so the packed format stays as it is.
+
+use anyhow::{anyhow, Context, Result};
+use uuid::Uuid;
+
+use crate::{pack_backends, unpack_backends, Backend, DrainingBackend};
+
+/// Most backends stored in one znode: 500 KB packed, well within ZooKeeper's default 1 MB limit.
+pub const BACKENDS_PER_CHUNK: usize = 25_000;
+/// Starts a manifest, followed by the generation and chunk count. Manifests are 16 bytes, which
+/// packed lists (a multiple of 20) never are.
+const MANIFEST_MAGIC: &[u8; 4] = b"BKC1";
+const MANIFEST_LEN: usize = 16;
+/// Times to read a chunked list again when it's replaced while being read.
+const READ_ATTEMPTS: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Manifest {
+    generation: u64,
+    chunks: u32,
+}
+
+impl Manifest {
+    fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(MANIFEST_LEN);
+        data.extend_from_slice(MANIFEST_MAGIC);
+        data.extend_from_slice(&self.generation.to_be_bytes());
+        data.extend_from_slice(&self.chunks.to_be_bytes());
+        data
+    }
+
+    /// `None` if `data` is a packed list rather than a manifest.
+    fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() != MANIFEST_LEN || &data[..4] != MANIFEST_MAGIC {
+            return None;
+        }
+        Some(Self {
+            generation: u64::from_be_bytes(data[4..12].try_into().ok()?),
+            chunks: u32::from_be_bytes(data[12..16].try_into().ok()?),
+        })
+    }
+}
+
+fn chunk_name(index: u32, generation: u64) -> String {
+    format!("{:04}-{:016x}", index, generation)
+}
+
+fn chunk_generation(name: &str) -> Option<u64> {
+    let (_, generation) = name.split_once('-')?;
+    u64::from_str_radix(generation, 16).ok()
+}
+
+pub fn backends_path(function_id: &Uuid) -> String {
+    format!("/function/{}/backends", function_id)
+}
+
+pub fn draining_path(function_id: &Uuid) -> String {
+    format!("/function/{}/draining", function_id)
+}
+
+/// A function's draining backends, and the version of their znode, if it exists.
+pub async fn read_draining(
+    zk: &zookeeper_client::Client,
+    function_id: &Uuid,
+) -> Result<(Vec<DrainingBackend>, Option<i32>)> {
+    match zk.get_data(&draining_path(function_id)).await {
+        Ok((data, stat)) => Ok((
+            serde_json::from_slice(&data).with_context(|| {
+                format!("Invalid draining backends for function {}", function_id)
+            })?,
+            Some(stat.version),
+        )),
+        Err(zookeeper_client::Error::NoNode) => Ok((Vec::new(), None)),
+        Err(e) => Err(e).context("Error getting function draining backends"),
+    }
+}
+
+/// Replace a function's draining backends in `multi`, unless they've changed since `version`
+/// was read (creating the znode if there was none).
+pub fn add_write_draining(
+    multi: &mut zookeeper_client::MultiWriter<'_>,
+    function_id: &Uuid,
+    draining: &[DrainingBackend],
+    version: Option<i32>,
+) -> Result<()> {
+    let path = draining_path(function_id);
+    let data = serde_json::to_vec(draining)?;
+    match version {
+        Some(version) => multi.add_set_data(&path, &data, Some(version))?,
+        None => multi.add_create(
+            &path,
+            &data,
+            &zookeeper_client::CreateMode::Persistent
+                .with_acls(zookeeper_client::Acls::anyone_all()),
+        )?,
+    }
+    Ok(())
+}
+
+/// Whether a backends znode's data is a manifest of chunks, rather than the list itself.
+pub fn is_chunked(data: &[u8]) -> bool {
+    Manifest::decode(data).is_some()
+}
+
+/// The version of a backend list as read, to replace it with.
+#[derive(Clone, Copy, Debug)]
+pub struct BackendsVersion {
+    /// Of the backends znode.
+    pub version: i32,
+    generation: Option<u64>,
+}
+
+/// A function's backends, however they're stored.
+pub async fn read_backends(
+    zk: &zookeeper_client::Client,
+    function_id: &Uuid,
+) -> Result<(Vec<Backend>, BackendsVersion)> {
+    let path = backends_path(function_id);
+    for _ in 0..READ_ATTEMPTS {
+        let (data, stat) = zk
+            .get_data(&path)
+            .await
+            .context("Error getting function backends")?;
+        let Some(manifest) = Manifest::decode(&data) else {
+            let version = BackendsVersion {
+                version: stat.version,
+                generation: None,
+            };
+            return Ok((unpack_backends(&data)?, version));
+        };
+        if let Some(backends) = read_chunks(zk, &path, manifest).await? {
+            let version = BackendsVersion {
+                version: stat.version,
+                generation: Some(manifest.generation),
+            };
+            return Ok((backends, version));
+        }
+    }
+    Err(anyhow!(
+        "Backends of function {} kept being replaced while being read",
+        function_id
+    ))
+}
+
+/// `None` if the list was replaced, and its chunks deleted, while being read.
+async fn read_chunks(
+    zk: &zookeeper_client::Client,
+    path: &str,
+    manifest: Manifest,
+) -> Result<Option<Vec<Backend>>> {
+    let mut backends = Vec::new();
+    for index in 0..manifest.chunks {
+        let chunk = format!("{}/{}", path, chunk_name(index, manifest.generation));
+        match zk.get_data(&chunk).await {
+            Ok((data, _)) => backends.extend(unpack_backends(&data)?),
+            Err(zookeeper_client::Error::NoNode) => return Ok(None),
+            Err(e) => return Err(e).context("Error getting function backends chunk"),
+        }
+    }
+    Ok(Some(backends))
+}
+
+/// A backend list ready to be stored: set the backends znode's data to `data` (checking the
+/// version read, maybe in a multi with other changes), then `finish` if that succeeded or
+/// `abandon` if not.
+pub struct PreparedBackends {
+    pub data: Vec<u8>,
+    path: String,
+    generation: Option<u64>,
+}
+
+/// Get `backends` ready to store, writing their chunks first if they're too many for one znode.
+pub async fn prepare_backends(
+    zk: &zookeeper_client::Client,
+    function_id: &Uuid,
+    backends: &[Backend],
+) -> Result<PreparedBackends> {
+    let path = backends_path(function_id);
+    if backends.len() <= BACKENDS_PER_CHUNK {
+        return Ok(PreparedBackends {
+            data: pack_backends(backends),
+            path,
+            generation: None,
+        });
+    }
+
+    let generation = Uuid::new_v4().as_u128() as u64;
+    let prepared = PreparedBackends {
+        data: Manifest {
+            generation,
+            chunks: backends.chunks(BACKENDS_PER_CHUNK).len() as u32,
+        }
+        .encode(),
+        path,
+        generation: Some(generation),
+    };
+    for (index, chunk) in backends.chunks(BACKENDS_PER_CHUNK).enumerate() {
+        let created = zk
+            .create(
+                &format!("{}/{}", prepared.path, chunk_name(index as u32, generation)),
+                &pack_backends(chunk),
+                &zookeeper_client::CreateMode::Persistent
+                    .with_acls(zookeeper_client::Acls::anyone_all()),
+            )
+            .await;
+        if let Err(e) = created {
+            prepared.abandon(zk).await;
+            return Err(e).context("Error creating function backends chunk");
+        }
+    }
+    Ok(prepared)
+}
+
+impl PreparedBackends {
+    /// Delete the chunks of the list this one replaced.
+    pub async fn finish(self, zk: &zookeeper_client::Client, replaced: BackendsVersion) {
+        if let Some(generation) = replaced.generation {
+            delete_chunks(zk, &self.path, generation).await;
+        }
+    }
+
+    /// Delete this list's chunks, after failing to store it.
+    pub async fn abandon(self, zk: &zookeeper_client::Client) {
+        if let Some(generation) = self.generation {
+            delete_chunks(zk, &self.path, generation).await;
+        }
+    }
+}
+
+/// Best effort: leftover chunks are never read, they only take up space.
+async fn delete_chunks(zk: &zookeeper_client::Client, path: &str, generation: u64) {
+    let Ok(children) = zk.list_children(path).await else {
+        return;
+    };
+    for child in children {
+        if chunk_generation(&child) == Some(generation) {
+            let _ = zk.delete(&format!("{}/{}", path, child), None).await;
+        }
+    }
+}
+
+/// Replace a function's backends, unless they've changed since `replaced` was read: then this
+/// fails with `zookeeper_client::Error::BadVersion`, as `set_data` does.
+pub async fn write_backends(
+    zk: &zookeeper_client::Client,
+    function_id: &Uuid,
+    backends: &[Backend],
+    replaced: BackendsVersion,
+) -> Result<()> {
+    let prepared = prepare_backends(zk, function_id, backends).await?;
+    match zk
+        .set_data(&prepared.path, &prepared.data, Some(replaced.version))
+        .await
+    {
+        Ok(_) => {
+            prepared.finish(zk, replaced).await;
+            Ok(())
+        }
+        Err(e) => {
+            prepared.abandon(zk).await;
+            Err(e).context("Error updating function backends")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest() {
+        let manifest = Manifest {
+            generation: 0x0123_4567_89ab_cdef,
+            chunks: 3,
+        };
+        let data = manifest.encode();
+        assert_eq!(data.len(), MANIFEST_LEN);
+        assert!(is_chunked(&data));
+        assert_eq!(Manifest::decode(&data), Some(manifest));
+
+        // Packed lists are never taken for manifests.
+        let backends: Vec<Backend> = (0..4)
+            .map(|n| Backend {
+                ip: [10, 0, 0, n].into(),
+                container_id: Uuid::new_v4(),
+            })
+            .collect();
+        assert!(!is_chunked(&pack_backends(&backends)));
+        assert!(!is_chunked(&[]));
+    }
+
+    #[test]
+    fn test_chunk_names() {
+        let name = chunk_name(7, 0xabc);
+        assert_eq!(name, "0007-0000000000000abc");
+        assert_eq!(chunk_generation(&name), Some(0xabc));
+        assert_eq!(chunk_generation("0007"), None);
+    }
+}
@@ -0,0 +1,174 @@
+//! Deregistration on shutdown, for backend daemons to call on SIGTERM before they stop serving:
+//! take themselves out of their function's backends (or delete the node registering them), then
+//! wait for frontends to have seen that, so a rolling restart doesn't send them invocations after
+//! they've gone and clients don't get connection-refused errors.
+//!
+//! Frontends reload a function's backends when their watch on its list fires, which is usually
+//! well within a second, but ZooKeeper can't tell whether every frontend has: hence the fixed
+//! settling time, long enough for the slowest of them to have reloaded (frontends riding out a
+//! quorum loss won't have, however long it is).
+
+use anyhow::{anyhow, Context, Result};
+use std::time::Duration;
+use tracing::{event, Level};
+use uuid::Uuid;
+
+use crate::{
+    add_write_draining, backends_path, prepare_backends, read_backends, read_draining, Backend,
+};
+
+/// Settling time for frontends to reload a function's backends after a change.
+pub const DEFAULT_SETTLE: Duration = Duration::from_secs(5);
+/// Times to try again when the list changes between reading and writing it.
+const WRITE_ATTEMPTS: usize = 10;
+
+/// Remove `container_id` from `function_id`'s backends, and from its draining ones, in one
+/// transaction, trying again if either changes meanwhile. Returns the backend removed, if it was
+/// listed.
+pub async fn remove_backend(
+    zk: &zookeeper_client::Client,
+    function_id: &Uuid,
+    container_id: &Uuid,
+) -> Result<Option<Backend>> {
+    for _ in 0..WRITE_ATTEMPTS {
+        let (mut backends, version) = read_backends(zk, function_id).await?;
+        let (mut draining, draining_version) = read_draining(zk, function_id).await?;
+        let removed = backends
+            .iter()
+            .position(|b| b.container_id == *container_id)
+            .map(|index| backends.remove(index));
+        let was_draining = draining.iter().any(|d| d.container_id == *container_id);
+        if removed.is_none() && !was_draining {
+            return Ok(None);
+        }
+        draining.retain(|d| d.container_id != *container_id);
+
+        let prepared = prepare_backends(zk, function_id, &backends).await?;
+        let mut multi = zk.new_multi_writer();
+        multi.add_set_data(
+            &backends_path(function_id),
+            &prepared.data,
+            Some(version.version),
+        )?;
+        if was_draining {
+            add_write_draining(&mut multi, function_id, &draining, draining_version)?;
+        }
+        match multi.commit().await {
+            Ok(_) => {
+                prepared.finish(zk, version).await;
+                return Ok(removed);
+            }
+            Err(zookeeper_client::MultiWriteError::OperationFailed {
+                source: zookeeper_client::Error::BadVersion,
+                ..
+            }) => prepared.abandon(zk).await,
+            Err(e) => {
+                prepared.abandon(zk).await;
+                return Err(anyhow::Error::from(e).context("Error removing function backend"));
+            }
+        }
+    }
+    Err(anyhow!(
+        "Backends of function {} kept changing while removing {}",
+        function_id,
+        container_id
+    ))
+}
+
+/// Remove `container_id` from `function_id`'s backends, then wait `settle` (see `DEFAULT_SETTLE`)
+/// for frontends to stop routing to it. Returns whether it was listed; if not, it doesn't wait.
+pub async fn deregister_backend(
+    zk: &zookeeper_client::Client,
+    function_id: &Uuid,
+    container_id: &Uuid,
+    settle: Duration,
+) -> Result<bool> {
+    let removed = remove_backend(zk, function_id, container_id).await?;
+    if let Some(backend) = &removed {
+        event!(Level::INFO, function = %function_id, container_id = %container_id, ip = %backend.ip, "Deregistered backend, waiting for frontends");
+        tokio::time::sleep(settle).await;
+    }
+    Ok(removed.is_some())
+}
+
+/// Delete the node at `path` registering a backend (e.g. an ephemeral one, which would otherwise
+/// only go once the session expires), then wait `settle` for frontends to stop routing to it.
+/// Returns whether it existed; if not, it doesn't wait.
+pub async fn deregister_node(
+    zk: &zookeeper_client::Client,
+    path: &str,
+    settle: Duration,
+) -> Result<bool> {
+    match zk.delete(path, None).await {
+        Ok(()) => {
+            event!(
+                Level::INFO,
+                path,
+                "Deregistered backend node, waiting for frontends"
+            );
+            tokio::time::sleep(settle).await;
+            Ok(true)
+        }
+        Err(zookeeper_client::Error::NoNode) => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("Error deleting {}", path)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{pack_backends, DrainingBackend};
+
+    #[tokio::test]
+    async fn test_remove_backend() {
+        let zookeeper_cluster =
+            std::env::var("ZOOKEEPER_CLUSTER").unwrap_or("zookeeper1:2181".to_string());
+        let zk = crate::test::zk_bootstrap(&zookeeper_cluster, "test_remove_backend").await;
+
+        let function_id = Uuid::new_v4();
+        let backends: Vec<_> = (1..=3)
+            .map(|n| Backend {
+                ip: [10, 0, 0, n].into(),
+                container_id: Uuid::new_v4(),
+            })
+            .collect();
+        let persistent = zookeeper_client::CreateMode::Persistent
+            .with_acls(zookeeper_client::Acls::anyone_all());
+        zk.create(&format!("/function/{}", function_id), &b""[..], &persistent)
+            .await
+            .unwrap();
+        zk.create(
+            &backends_path(&function_id),
+            &pack_backends(&backends),
+            &persistent,
+        )
+        .await
+        .unwrap();
+        let mut multi = zk.new_multi_writer();
+        let draining = [DrainingBackend {
+            container_id: backends[1].container_id,
+            since: 0,
+        }];
+        add_write_draining(&mut multi, &function_id, &draining, None).unwrap();
+        multi.commit().await.unwrap();
+
+        let removed = remove_backend(&zk, &function_id, &backends[1].container_id)
+            .await
+            .unwrap();
+        assert_eq!(removed, Some(backends[1].clone()));
+        let (listed, _) = read_backends(&zk, &function_id).await.unwrap();
+        assert_eq!(listed, vec![backends[0].clone(), backends[2].clone()]);
+        let (draining, _) = read_draining(&zk, &function_id).await.unwrap();
+        assert!(draining.is_empty());
+
+        // Already gone, so nothing to wait for.
+        assert!(!deregister_backend(
+            &zk,
+            &function_id,
+            &backends[1].container_id,
+            Duration::from_secs(60)
+        )
+        .await
+        .unwrap());
+    }
+}
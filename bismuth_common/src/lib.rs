@@ -1,14 +1,27 @@
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::net::Ipv4Addr;
 use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 use url::Url;
+use utoipa::ToSchema;
 use uuid::Uuid;
 
 mod api_error;
 pub use api_error::*;
+mod backends;
+pub use backends::*;
+mod deregister;
+pub use deregister::*;
+mod logging;
+pub use logging::*;
 mod metrics;
 pub use metrics::*;
+mod scrub;
+pub use scrub::*;
+mod syslog;
+pub use syslog::*;
 mod tracing;
 pub use tracing::*;
 
@@ -49,12 +62,13 @@ impl FromStr for InvokeMode {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct FunctionDefinition {
     /// Image to use for the container.
     pub image: String,
 
     /// URL of the git repository and branch to clone into the container.
+    #[schema(value_type = Option<Vec<String>>)]
     pub repo: Option<(Url, String)>,
 
     /// CPU limit, as a fraction of a core.
@@ -63,24 +77,632 @@ pub struct FunctionDefinition {
     /// Memory limit, in bytes.
     pub memory: u64,
 
-    /// How to communicate with the container.
+    /// How to communicate with the container: `{"Executable": [args...]}` or
+    /// `{"Server": [[args...], port]}`.
+    #[schema(value_type = Object)]
     pub invoke_mode: InvokeMode,
 
     /// Maximum number of instances of this function to run.
     pub max_instances: u32,
+
+    /// How bismuthd tells a `Server` container has started and can be routed to.
+    #[serde(default)]
+    pub health_check: HealthCheck,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthCheck {
+    /// The container's port accepts connections.
+    #[default]
+    Tcp,
+    /// A GET of `path` gets a 2xx.
+    Http { path: String },
+    /// `grpc.health.v1.Health/Check` (over h2c) says `service` is serving. The empty service is
+    /// the server as a whole.
+    Grpc {
+        #[serde(default)]
+        service: String,
+    },
+}
+
+impl FromStr for HealthCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "tcp" => Ok(HealthCheck::Tcp),
+            None if s == "grpc" => Ok(HealthCheck::Grpc {
+                service: String::new(),
+            }),
+            Some(("http", path)) => Ok(HealthCheck::Http {
+                path: path.to_string(),
+            }),
+            Some(("grpc", service)) => Ok(HealthCheck::Grpc {
+                service: service.to_string(),
+            }),
+            _ => Err(
+                "Health check must be one of 'tcp', 'http:/path' or 'grpc[:service]'".to_string(),
+            ),
+        }
+    }
+}
+
+impl FunctionDefinition {
+    /// Sanity check a definition received from a client before it's written anywhere.
+    pub fn validate(&self) -> Result<(), GenericError> {
+        if self.image.is_empty() {
+            return Err(GenericError::Invalid("image must not be empty".to_string()));
+        }
+        if self.cpu.is_nan() || self.cpu <= 0.0 {
+            return Err(GenericError::Invalid("cpu must be positive".to_string()));
+        }
+        if self.memory == 0 {
+            return Err(GenericError::Invalid("memory must be positive".to_string()));
+        }
+        if self.max_instances == 0 {
+            return Err(GenericError::Invalid(
+                "max_instances must be at least 1".to_string(),
+            ));
+        }
+        if let HealthCheck::Http { path } = &self.health_check {
+            if !path.starts_with('/') {
+                return Err(GenericError::Invalid(
+                    "health check path must start with '/'".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Descriptive information about a function (for catalogs and humans), stored as JSON in
+/// `/function/{id}/metadata`. Optional, and not used for routing or scheduling.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(default)]
+pub struct FunctionMetadata {
+    pub name: String,
+    pub owner: String,
+    /// Language/runtime the function is written for, e.g. "python3.11".
+    pub runtime: String,
+    pub description: String,
+    /// Unix timestamp (seconds) the metadata was first written. Set by the server.
+    pub created_at: u64,
+}
+
+/// Per-function frontend settings, stored as JSON in `/function/{id}/config`.
+/// Every field is optional, so functions without the znode get the frontend's defaults.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(default)]
+pub struct FunctionConfig {
+    /// Name of the frontend's histogram bucket profile to record this function's latency with,
+    /// instead of the default buckets.
+    pub latency_buckets: Option<String>,
+    /// While set, invocations get this 503 straight from the frontend instead of being proxied.
+    /// The backends are left in place, so planned migrations don't look like routing failures.
+    pub maintenance: Option<Maintenance>,
+    /// WASM filters (as named with the frontend's `--wasm-filter`) to run on every invocation, in
+    /// order.
+    pub filters: Vec<String>,
+    /// How long an invocation may take in total, including streaming the response body, in
+    /// milliseconds, overriding the frontend's `--total-timeout-ms`. Passed on to the backend as a
+    /// deadline, along with any earlier deadline the client sent.
+    pub timeout_ms: Option<u64>,
+    /// How long to wait for the backend's response headers, in milliseconds, overriding the
+    /// frontend's `--header-timeout-ms`.
+    pub header_timeout_ms: Option<u64>,
+    /// Largest request body the function accepts, in bytes, overriding the frontend's
+    /// `--max-upload-bytes`.
+    pub max_upload_bytes: Option<u64>,
+    /// Largest response body the frontend passes on for the function, in bytes, overriding its
+    /// `--max-response-bytes`.
+    pub max_response_bytes: Option<u64>,
+    /// While set, gzip, deflate and brotli request bodies are decompressed before they're passed
+    /// on, for backends that can't handle a `Content-Encoding`.
+    pub decompress_requests: bool,
+    /// While set, frontends with a `--capture-dir` record a sample of invocations there.
+    pub capture: Option<Capture>,
+    /// How the frontend passes on response bodies. By default they're proxied as they arrive,
+    /// without changing their headers.
+    pub response_mode: Option<ResponseMode>,
+    /// While set, backends added to the function only get invocations once they've answered
+    /// these warm-up requests.
+    pub warmup: Option<Warmup>,
+    /// Sends a share of the function's invocations to another function, e.g. a new version.
+    pub split: Option<TrafficSplit>,
+    /// Invocations each client may make per window, as counted by each frontend.
+    pub rate_limit: Option<RateLimit>,
+    /// Countries the function may be invoked from, as located by frontends' GeoIP database.
+    pub geo: Option<GeoRestriction>,
+    /// Which invocations frontends emit access log events for. By default, all of them.
+    pub access_log: Option<AccessLog>,
+    /// Priority of the function's invocations, unless their tenant token gives one. By default,
+    /// normal.
+    pub priority: Option<Priority>,
+    /// While set, concurrent identical GETs share one upstream request and its response, unless
+    /// they send credentials or the response is private or large.
+    pub coalesce_gets: bool,
+    /// Objectives each frontend tracks the function's invocations against, reporting their
+    /// compliance and error budget burn rates.
+    pub slo: Option<Slo>,
+    /// JSON Schemas request bodies must satisfy, by method and path; an invocation is checked
+    /// against the first that applies to it, and rejected with a 422 if it doesn't.
+    pub request_schemas: Vec<RequestSchema>,
+}
+
+/// A JSON Schema for the bodies of some of a function's requests.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct RequestSchema {
+    /// Methods it applies to, e.g. `["POST", "PUT"]`; all of them if empty.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Request path under the function it applies to, where `*` matches one segment and a
+    /// trailing `**` any number, e.g. `/orders/*`; all of them if not set.
+    #[serde(default)]
+    pub path: Option<String>,
+    #[schema(value_type = Object)]
+    pub schema: serde_json::Value,
+}
+
+fn default_slo_window_secs() -> u64 {
+    86400
+}
+
+/// A function's service level objectives, each a fraction of invocations over a rolling window.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct Slo {
+    /// Fraction of invocations that should get a response other than a 5xx, e.g. 0.999.
+    #[serde(default)]
+    pub availability: Option<f64>,
+    #[serde(default)]
+    pub latency: Option<LatencyObjective>,
+    /// Window compliance is measured over, in seconds, at most a day.
+    #[serde(default = "default_slo_window_secs")]
+    pub window_secs: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct LatencyObjective {
+    /// Fraction of invocations whose response headers should be ready within `threshold_ms`.
+    pub target: f64,
+    pub threshold_ms: u64,
+}
+
+fn default_rate_limit_window_secs() -> u64 {
+    60
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct RateLimit {
+    /// Invocations allowed per key and window; further ones get a 429.
+    pub requests: u64,
+    #[serde(default = "default_rate_limit_window_secs")]
+    pub window_secs: u64,
+    /// Request header to count invocations by (e.g. an API key), instead of the client's IP.
+    /// Requests without it are counted by IP.
+    #[serde(default)]
+    pub key_header: Option<String>,
+}
+
+/// Countries (ISO 3166-1 codes) clients may invoke a function from. Refused invocations get
+/// `status` straight from the frontend.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct GeoRestriction {
+    /// Only clients located in one of these, if any are listed. Clients that can't be located
+    /// are refused.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Never clients located in one of these.
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// 451 (the default) or 403.
+    #[serde(default = "default_geo_status")]
+    pub status: u16,
+}
+
+fn default_geo_status() -> u16 {
+    451
+}
+
+/// Invocations of a function that are served by another (the canary) instead. Only the backends
+/// differ: the invocation is otherwise handled with the original function's config.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct TrafficSplit {
+    pub canary: Uuid,
+    /// Share of invocations routed to `canary`, from 0 to 100.
+    pub percent: f64,
+    /// While set, frontends compare the canary with the stable function and set `percent` to 0
+    /// if it regresses.
+    #[serde(default)]
+    pub analysis: Option<CanaryAnalysis>,
+    /// While set, the share routed to `canary` follows this schedule instead of `percent`.
+    #[serde(default)]
+    pub ramp: Option<Ramp>,
+}
+
+impl TrafficSplit {
+    /// Share of invocations routed to `canary` at `now`, from 0 to 100.
+    pub fn current_percent(&self, now: SystemTime) -> f64 {
+        match &self.ramp {
+            Some(ramp) => {
+                ramp.percent_at(now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs())
+            }
+            None => self.percent,
+        }
+    }
+}
+
+fn default_ramp_steps() -> u32 {
+    10
+}
+
+fn default_ramp_from_percent() -> f64 {
+    1.0
+}
+
+/// A schedule shifting a split's invocations to the canary in equal steps, from `from_percent`
+/// to 100 over `duration_secs`. Frontends each work out where it's at from the clock, so nothing
+/// is written as it goes.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct Ramp {
+    /// Unix timestamp (seconds) it started at, moved on by however long it was paused for when
+    /// it's resumed.
+    pub started: u64,
+    pub duration_secs: u64,
+    #[serde(default = "default_ramp_steps")]
+    pub steps: u32,
+    #[serde(default = "default_ramp_from_percent")]
+    pub from_percent: f64,
+    /// What frontends do if canary analysis finds the canary regressed.
+    #[serde(default)]
+    pub on_regression: RampAction,
+    /// Unix timestamp (seconds) it was paused at, holding the percentage it had then.
+    #[serde(default)]
+    pub paused_at: Option<u64>,
+}
+
+impl Ramp {
+    /// Percentage at Unix timestamp `now` (seconds).
+    pub fn percent_at(&self, now: u64) -> f64 {
+        if self.duration_secs == 0 || self.steps == 0 {
+            return 100.0;
+        }
+        let elapsed = self.paused_at.unwrap_or(now).saturating_sub(self.started);
+        let step = (elapsed as u128 * self.steps as u128 / self.duration_secs as u128)
+            .min(self.steps as u128) as f64;
+        self.from_percent + (100.0 - self.from_percent) * step / self.steps as f64
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RampAction {
+    /// Set the split to 0 and drop the ramp, as without one.
+    #[default]
+    Revert,
+    /// Hold the ramp where it is until it's resumed.
+    Pause,
+}
+
+impl FromStr for RampAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "revert" => Ok(RampAction::Revert),
+            "pause" => Ok(RampAction::Pause),
+            _ => Err("Ramp action must be one of 'revert' or 'pause'".to_string()),
+        }
+    }
+}
+
+fn default_analysis_window_secs() -> u64 {
+    300
+}
+
+fn default_min_requests() -> u64 {
+    100
+}
+
+fn default_max_error_rate_increase() -> f64 {
+    0.01
+}
+
+fn default_max_latency_ratio() -> f64 {
+    1.5
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct CanaryAnalysis {
+    /// Seconds of invocations compared at a time.
+    #[serde(default = "default_analysis_window_secs")]
+    pub window_secs: u64,
+    /// Invocations each side needs in a window for it to be compared.
+    #[serde(default = "default_min_requests")]
+    pub min_requests: u64,
+    /// Rolled back if the canary's rate of 5xxs and failed invocations is more than this much
+    /// higher than the stable function's, as a fraction (0.01 is one percentage point).
+    #[serde(default = "default_max_error_rate_increase")]
+    pub max_error_rate_increase: f64,
+    /// Rolled back if the canary's p99 latency is more than this many times the stable
+    /// function's.
+    #[serde(default = "default_max_latency_ratio")]
+    pub max_latency_ratio: f64,
+}
+
+fn default_warmup_path() -> String {
+    "/".to_string()
+}
+
+fn default_warmup_requests() -> u32 {
+    3
+}
+
+fn default_warmup_timeout_ms() -> u64 {
+    5000
+}
+
+/// Requests a frontend sends a newly listed backend before routing to it, so the container's
+/// cold start (loading code, filling caches, JIT warm-up) isn't paid by a real invocation.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct Warmup {
+    /// Path within the function each warm-up request GETs.
+    #[serde(default = "default_warmup_path")]
+    pub path: String,
+    /// Successive requests that must all get a 2xx.
+    #[serde(default = "default_warmup_requests")]
+    pub requests: u32,
+    /// How long each request may take, in milliseconds.
+    #[serde(default = "default_warmup_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseMode {
+    /// Every chunk is passed on as soon as the backend sends it, with intermediaries asked not
+    /// to buffer or compress it, for event streams and long polls.
+    Stream,
+    /// The whole body is read before responding, so it can be sent with a `Content-Length`,
+    /// gzipped for clients that accept it, and given an `ETag` for conditional requests.
+    Buffer,
+}
+
+impl FromStr for ResponseMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stream" => Ok(ResponseMode::Stream),
+            "buffer" => Ok(ResponseMode::Buffer),
+            _ => Err("Response mode must be one of 'stream' or 'buffer'".to_string()),
+        }
+    }
+}
+
+/// How an invocation fares under load: frontends shed low priority invocations first, and give
+/// queued high priority ones free slots before the others.
+#[derive(
+    Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, ToSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err("Priority must be one of 'low', 'normal' or 'high'".to_string()),
+        }
+    }
+}
+
+/// Which of a function's invocations to record, for replaying them against another function
+/// (e.g. a new version) with `bismuthctl replay`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(default)]
+pub struct Capture {
+    /// Fraction of invocations recorded, from 0 to 1.
+    pub sample_rate: f64,
+    /// Bodies are recorded up to this many bytes, and marked truncated past it.
+    pub max_body_bytes: u64,
+}
+
+/// Access logging for a function, e.g. sampled so a high-volume one doesn't drown the logging
+/// pipeline. Only the log events are affected: sizes and latencies are always recorded.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(default)]
+pub struct AccessLog {
+    pub enabled: bool,
+    /// Fraction of invocations logged, from 0 to 1.
+    pub sample_rate: f64,
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            sample_rate: 1.0,
+        }
+    }
+}
+
+/// An invocation recorded by a frontend, stored one JSON object per line in
+/// `{capture dir}/{function id}.jsonl`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct CapturedRequest {
+    /// Unix milliseconds.
+    pub time: u64,
+    pub function_id: Uuid,
+    pub method: String,
+    /// Path within the function and query, without the `/invoke/{id}/` prefix.
+    pub path: String,
+    /// Sensitive headers (see `Scrubber`) are left out.
+    pub headers: Vec<(String, String)>,
+    /// Base64 encoded.
+    #[serde(with = "base64_bytes")]
+    pub body: Vec<u8>,
+    /// Whether the body was cut off at `Capture::max_body_bytes`.
+    pub body_truncated: bool,
+}
+
+mod base64_bytes {
+    use base64::Engine as _;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::engine::general_purpose::STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        base64::engine::general_purpose::STANDARD
+            .decode(String::deserialize(deserializer)?)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// The response a function in maintenance gets.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(default)]
+pub struct Maintenance {
+    /// Response body, defaults to a short plain text notice.
+    pub body: Option<String>,
+    /// Defaults to `text/plain; charset=utf-8`.
+    pub content_type: Option<String>,
+    /// Seconds, sent as `Retry-After`.
+    pub retry_after: Option<u64>,
+}
+
+/// Resources a node offers to functions, stored as JSON in `/node/{ip}/capacity`.
+/// Nodes without it are treated as unbounded by the scheduler.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub struct NodeCapacity {
+    /// In cores, comparable to `FunctionDefinition::cpu`.
+    pub cpu: f32,
+    /// In bytes.
+    pub memory: u64,
+}
+
+/// Switches for risky frontend behaviors, stored as JSON in `/config/featureflags` and picked up
+/// by frontends without a restart, so features can be rolled out (or killed) per environment and
+/// per function.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(default)]
+pub struct FeatureFlags {
+    /// By flag name. Flags that aren't listed keep their built-in default.
+    pub flags: HashMap<String, FeatureFlag>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(default)]
+pub struct FeatureFlag {
+    /// For every function that isn't listed in `functions`.
+    pub enabled: bool,
+    /// Per-function overrides.
+    pub functions: HashMap<Uuid, bool>,
+}
+
+impl FeatureFlags {
+    /// Whether `flag` is on for `function_id`, or `default` if it isn't set at all.
+    pub fn enabled(&self, flag: &str, function_id: &Uuid, default: bool) -> bool {
+        match self.flags.get(flag) {
+            Some(flag) => flag
+                .functions
+                .get(function_id)
+                .copied()
+                .unwrap_or(flag.enabled),
+            None => default,
+        }
+    }
+}
+
+/// An API token of a tenant, stored as JSON in `/token/{id}`. Only a hash of the secret is
+/// kept, so reading ZooKeeper doesn't reveal working tokens.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct TenantToken {
+    pub tenant: String,
+    /// URL-safe base64 (unpadded) of the SHA-256 of the secret.
+    pub secret_hash: String,
+    pub scope: TokenScope,
+    /// Unix timestamps (seconds).
+    pub created_at: u64,
+    #[serde(default)]
+    pub rotated_at: Option<u64>,
+    /// Priority of the tenant's invocations with this token, over the function's own.
+    #[serde(default)]
+    pub priority: Option<Priority>,
+}
+
+/// Which functions a token may invoke.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    All,
+    Functions(Vec<Uuid>),
+}
+
+impl TokenScope {
+    pub fn allows(&self, function_id: &Uuid) -> bool {
+        match self {
+            TokenScope::All => true,
+            TokenScope::Functions(functions) => functions.contains(function_id),
+        }
+    }
 }
 
 pub const BACKEND_PORT: u16 = 8001;
+/// Set by bismuthd on invocation responses to the number of invocations the container has in
+/// flight, so frontends can route around overloaded backends.
+pub const IN_FLIGHT_HEADER: &str = "x-bismuth-in-flight";
 pub const SVCPROVIDER_PORT: u16 = 9000;
 pub const UUID_PACKED_LEN: usize = 16;
 pub const UUID_STR_LEN: usize = 36;
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash, ToSchema)]
 pub struct Backend {
+    #[schema(value_type = String)]
     pub ip: Ipv4Addr,
     pub container_id: Uuid,
 }
 
+/// A backend addressed by hostname, for nodes known by DNS name (e.g. behind per-node names or
+/// headless services). Listed as JSON in `/function/{id}/backend_hosts`, next to the packed
+/// `backends`, and resolved by the frontend.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct HostBackend {
+    pub host: String,
+    pub container_id: Uuid,
+}
+
+/// A backend frontends stop sending new invocations to, letting those in flight finish, e.g.
+/// ahead of its node's maintenance. Listed as JSON in `/function/{id}/draining`, while the backend
+/// is still in the function's `backends`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct DrainingBackend {
+    pub container_id: Uuid,
+    /// Unix timestamp (seconds) it was marked draining.
+    pub since: u64,
+}
+
 impl conhash::Node for Backend {
     fn name(&self) -> String {
         format!("{}:{}", self.ip, self.container_id)
@@ -131,6 +753,9 @@ pub fn init_sentry() -> Option<sentry::ClientInitGuard> {
             dsn,
             sentry::ClientOptions {
                 release: sentry::release_name!(),
+                before_send: Some(std::sync::Arc::new(|event| {
+                    Some(scrubber().scrub_sentry_event(event))
+                })),
                 ..Default::default()
             },
         )))
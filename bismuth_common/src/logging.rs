@@ -0,0 +1,230 @@
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::Subscriber;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Where and when to rotate a log file.
+#[derive(Clone, Debug)]
+pub struct FileLogConfig {
+    pub path: PathBuf,
+    /// Rotate once the file would grow past this many bytes.
+    pub max_size: Option<u64>,
+    /// Rotate at the start of every period (e.g. every day, counted from the Unix epoch).
+    pub rotation: Option<Duration>,
+    /// Number of rotated files to keep, besides the current one.
+    pub retention: usize,
+}
+
+impl FileLogConfig {
+    /// Read from `LOG_FILE` (unset disables file logging), `LOG_FILE_MAX_SIZE` (bytes, default
+    /// 100MiB, 0 for no limit), `LOG_FILE_ROTATION` (`hourly`, `daily` (default) or `never`) and
+    /// `LOG_FILE_RETENTION` (default 7).
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(path) = std::env::var("LOG_FILE") else {
+            return Ok(None);
+        };
+        let max_size = match std::env::var("LOG_FILE_MAX_SIZE") {
+            Ok(size) => size.parse().context("Invalid LOG_FILE_MAX_SIZE")?,
+            Err(_) => 100 << 20,
+        };
+        let rotation = match std::env::var("LOG_FILE_ROTATION").as_deref() {
+            Ok("hourly") => Some(Duration::from_secs(3600)),
+            Ok("daily") | Err(_) => Some(Duration::from_secs(86400)),
+            Ok("never") => None,
+            Ok(other) => anyhow::bail!("Invalid LOG_FILE_ROTATION {}", other),
+        };
+        let retention = match std::env::var("LOG_FILE_RETENTION") {
+            Ok(retention) => retention.parse().context("Invalid LOG_FILE_RETENTION")?,
+            Err(_) => 7,
+        };
+        Ok(Some(Self {
+            path: PathBuf::from(path),
+            max_size: Some(max_size).filter(|&s| s > 0),
+            rotation,
+            retention,
+        }))
+    }
+}
+
+struct State {
+    file: File,
+    size: u64,
+    /// Rotation period the file was started in.
+    period: u64,
+}
+
+/// A log file that's renamed to `<path>.<unix millis>` when it gets too big or too old, keeping
+/// only the newest `retention` rotated files.
+/// Writes go straight to the file, so each formatted event is a single write and never split
+/// across files.
+pub struct RotatingFile {
+    config: FileLogConfig,
+    state: Mutex<State>,
+}
+
+fn period(time: SystemTime, rotation: Option<Duration>) -> u64 {
+    match rotation {
+        Some(rotation) => {
+            time.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+                / rotation.as_secs().max(1)
+        }
+        None => 0,
+    }
+}
+
+fn open(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl RotatingFile {
+    pub fn new(config: FileLogConfig) -> Result<Self> {
+        if let Some(parent) = config.path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Error creating log directory {}", parent.display()))?;
+        }
+        let file = open(&config.path)
+            .with_context(|| format!("Error opening log file {}", config.path.display()))?;
+        let metadata = file.metadata()?;
+        // An existing file continues in the period it was last written in, so a restart after
+        // the period ends still rotates it.
+        let period = period(
+            metadata.modified().unwrap_or_else(|_| SystemTime::now()),
+            config.rotation,
+        );
+        Ok(Self {
+            state: Mutex::new(State {
+                file,
+                size: metadata.len(),
+                period,
+            }),
+            config,
+        })
+    }
+
+    fn rotate(&self, state: &mut State) -> std::io::Result<()> {
+        let mut millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let rotated = loop {
+            let rotated = PathBuf::from(format!("{}.{}", self.config.path.display(), millis));
+            if !rotated.exists() {
+                break rotated;
+            }
+            // Rotated more than once in the same millisecond.
+            millis += 1;
+        };
+        std::fs::rename(&self.config.path, &rotated)?;
+        state.file = open(&self.config.path)?;
+        state.size = 0;
+        state.period = period(SystemTime::now(), self.config.rotation);
+        self.prune()
+    }
+
+    /// Delete all but the newest `retention` rotated files.
+    fn prune(&self) -> std::io::Result<()> {
+        let (Some(dir), Some(name)) = (self.config.path.parent(), self.config.path.file_name())
+        else {
+            return Ok(());
+        };
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        let prefix = format!("{}.", name.to_string_lossy());
+        let mut rotated: Vec<(u128, PathBuf)> = std::fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name();
+                let suffix = name.to_str()?.strip_prefix(&prefix)?;
+                Some((suffix.parse().ok()?, entry.path()))
+            })
+            .collect();
+        rotated.sort_by(|a, b| b.0.cmp(&a.0));
+        for (_, path) in rotated.into_iter().skip(self.config.retention) {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for &RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let too_big = self
+            .config
+            .max_size
+            .is_some_and(|max| state.size > 0 && state.size + buf.len() as u64 > max);
+        let too_old = period(SystemTime::now(), self.config.rotation) != state.period;
+        if too_big || too_old {
+            self.rotate(&mut state)?;
+        }
+        let n = state.file.write(buf)?;
+        state.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.state.lock().unwrap().file.flush()
+    }
+}
+
+/// JSON logs to a rotating file, if configured with `LOG_FILE` (see `FileLogConfig::from_env`).
+/// Meant to be added next to the stdout layer, for deployments without a log-shipping sidecar.
+pub fn file_log_layer<S>() -> Result<Option<impl Layer<S>>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let Some(config) = FileLogConfig::from_env()? else {
+        return Ok(None);
+    };
+    let file = Arc::new(RotatingFile::new(config)?);
+    Ok(Some(
+        tracing_subscriber::fmt::layer()
+            .json()
+            .with_ansi(false)
+            .with_writer(crate::Scrubbed(file)),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotates_by_size_and_prunes() {
+        let dir = std::env::temp_dir().join(format!("bismuth-log-test-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("test.log");
+        let file = RotatingFile::new(FileLogConfig {
+            path: path.clone(),
+            max_size: Some(10),
+            rotation: None,
+            retention: 2,
+        })
+        .unwrap();
+
+        for _ in 0..5 {
+            (&file).write_all(b"0123456789").unwrap();
+        }
+
+        let mut names: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        // The current file plus the 2 newest of the 4 rotated ones.
+        assert_eq!(names.len(), 3);
+        assert!(names.contains(&"test.log".to_string()));
+        assert_eq!(std::fs::read(&path).unwrap(), b"0123456789");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -13,6 +13,11 @@ struct Metrics {
     requests_total: Counter<u64>,
 }
 
+/// Extra attributes for the request metrics, inserted as a request extension by middleware
+/// that runs before `OtelAxumMetricsLayer`.
+#[derive(Clone, Debug, Default)]
+pub struct MetricAttributes(pub Vec<KeyValue>);
+
 #[derive(Clone)]
 pub struct OtelAxumMetricsLayer {
     metrics: Metrics,
@@ -55,6 +60,7 @@ pin_project! {
         metrics: Metrics,
         method: String,
         path: String,
+        extra_attrs: Vec<KeyValue>,
     }
 }
 
@@ -77,12 +83,18 @@ where
         } else {
             "".to_owned()
         };
+        let extra_attrs = req
+            .extensions()
+            .get::<MetricAttributes>()
+            .map(|a| a.0.clone())
+            .unwrap_or_default();
 
         ResponseFuture {
             inner: self.service.call(req),
             metrics: self.metrics.clone(),
             method,
             path,
+            extra_attrs,
         }
     }
 }
@@ -96,7 +108,7 @@ where
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let this = self.project();
         let response = futures_util::ready!(this.inner.poll(cx))?;
-        let attrs = [
+        let mut attrs = vec![
             KeyValue::new("http.request.method", this.method.clone()),
             KeyValue::new("http.route", this.path.clone()),
             KeyValue::new(
@@ -104,6 +116,7 @@ where
                 response.status().as_u16().to_string(),
             ),
         ];
+        attrs.extend(this.extra_attrs.iter().cloned());
         this.metrics.requests_total.add(1, &attrs);
         Poll::Ready(Ok(response))
     }
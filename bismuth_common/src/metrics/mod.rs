@@ -1,16 +1,72 @@
 use opentelemetry_otlp::WithExportConfig as _;
 use opentelemetry_sdk::metrics::{
     reader::{DefaultAggregationSelector, DefaultTemporalitySelector},
-    MeterProvider, PeriodicReader,
+    Aggregation, Instrument, InstrumentKind, MeterProvider, PeriodicReader, Stream,
 };
 use opentelemetry_sdk::resource::SdkProvidedResourceDetector;
 use opentelemetry_sdk::Resource;
+use std::collections::BTreeMap;
 use std::time::Duration;
 
 mod axum_metrics;
 pub use axum_metrics::*;
+mod statsd;
+pub use statsd::StatsdExporter;
+
+/// Histogram bucket boundaries: a default for every histogram, and named profiles for
+/// histograms recorded through `bucket_profile_meter`.
+#[derive(Clone, Debug, Default)]
+pub struct HistogramBuckets {
+    /// Falls back to the SDK's defaults if not set.
+    pub default: Option<Vec<f64>>,
+    pub profiles: BTreeMap<String, Vec<f64>>,
+}
+
+impl HistogramBuckets {
+    fn boundaries(&self, scope: &str) -> Option<&Vec<f64>> {
+        self.profiles
+            .iter()
+            .find(|(profile, _)| profile_scope_matches(scope, profile))
+            .map(|(_, boundaries)| boundaries)
+            .or(self.default.as_ref())
+    }
+}
+
+fn profile_scope_matches(scope: &str, profile: &str) -> bool {
+    scope
+        .strip_suffix(profile)
+        .and_then(|s| s.strip_suffix(".buckets."))
+        .is_some()
+}
+
+/// A meter whose histograms use the bucket boundaries of `profile` (set up by `init_metrics_with_buckets`).
+/// Meant for recording the same instrument at different resolutions, e.g. for functions with
+/// very different latencies.
+pub fn bucket_profile_meter(name: &str, profile: &str) -> opentelemetry::metrics::Meter {
+    opentelemetry::global::meter(format!("{}.buckets.{}", name, profile))
+}
+
+/// Parse comma-separated, increasing bucket boundaries, e.g. `5,10,25,50`.
+pub fn parse_buckets(s: &str) -> Result<Vec<f64>, String> {
+    let boundaries = s
+        .split(',')
+        .map(|b| b.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid bucket boundary: {}", e))?;
+    if boundaries.windows(2).any(|w| w[0] >= w[1]) {
+        return Err("Bucket boundaries must be increasing".to_string());
+    }
+    Ok(boundaries)
+}
 
 pub fn init_metrics(static_attrs: &[opentelemetry::KeyValue]) {
+    init_metrics_with_buckets(static_attrs, HistogramBuckets::default())
+}
+
+pub fn init_metrics_with_buckets(
+    static_attrs: &[opentelemetry::KeyValue],
+    buckets: HistogramBuckets,
+) {
     let reader = PeriodicReader::builder(
         opentelemetry_otlp::new_exporter()
             .http()
@@ -26,8 +82,40 @@ pub fn init_metrics(static_attrs: &[opentelemetry::KeyValue]) {
     .with_interval(Duration::from_secs(30))
     .build();
 
-    let provider = MeterProvider::builder()
-        .with_reader(reader)
+    let histogram_view = move |inst: &Instrument| -> Option<Stream> {
+        if inst.kind != Some(InstrumentKind::Histogram) {
+            return None;
+        }
+        let boundaries = buckets.boundaries(&inst.scope.name)?;
+        Some(
+            Stream::new()
+                .name(inst.name.clone())
+                .description(inst.description.clone())
+                .unit(inst.unit.clone())
+                .aggregation(Aggregation::ExplicitBucketHistogram {
+                    boundaries: boundaries.clone(),
+                    record_min_max: true,
+                }),
+        )
+    };
+
+    let mut provider = MeterProvider::builder().with_reader(reader);
+    // Also push to a StatsD/DogStatsD agent, for environments standardized on Datadog.
+    if let Ok(statsd_url) = std::env::var("STATSD_URL") {
+        match StatsdExporter::new(&statsd_url, static_attrs) {
+            Ok(exporter) => {
+                provider = provider.with_reader(
+                    PeriodicReader::builder(exporter, opentelemetry_sdk::runtime::Tokio)
+                        .with_interval(Duration::from_secs(10))
+                        .build(),
+                );
+            }
+            Err(e) => eprintln!("Error setting up StatsD exporter for {}: {}", statsd_url, e),
+        }
+    }
+
+    let provider = provider
+        .with_view(histogram_view)
         .with_resource(
             Resource::from_detectors(
                 Duration::from_secs(5),
@@ -39,3 +127,32 @@ pub fn init_metrics(static_attrs: &[opentelemetry::KeyValue]) {
 
     opentelemetry::global::set_meter_provider(provider.clone());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_buckets() {
+        assert_eq!(parse_buckets("1, 5,10").unwrap(), vec![1.0, 5.0, 10.0]);
+        assert!(parse_buckets("5,1").is_err());
+        assert!(parse_buckets("1,x").is_err());
+    }
+
+    #[test]
+    fn test_profile_boundaries() {
+        let buckets = HistogramBuckets {
+            default: Some(vec![1.0]),
+            profiles: [("batch".to_string(), vec![1000.0])].into(),
+        };
+        assert_eq!(buckets.boundaries("bismuthfe"), Some(&vec![1.0]));
+        assert_eq!(
+            buckets.boundaries("bismuthfe.buckets.batch"),
+            Some(&vec![1000.0])
+        );
+        assert_eq!(
+            buckets.boundaries("bismuthfe.buckets.other"),
+            Some(&vec![1.0])
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use async_trait::async_trait;
+use opentelemetry::metrics::{MetricsError, Result};
+use opentelemetry::{Key, KeyValue, Value};
+use opentelemetry_sdk::metrics::{
+    data::{self, ResourceMetrics, Temporality},
+    exporter::PushMetricsExporter,
+    reader::{AggregationSelector, DefaultAggregationSelector, TemporalitySelector},
+    Aggregation, InstrumentKind,
+};
+use std::fmt::Display;
+use std::net::UdpSocket;
+use std::os::unix::net::UnixDatagram;
+
+/// Conservative UDP payload size that avoids fragmentation on most networks.
+const UDP_MAX_PACKET: usize = 1432;
+/// Unix sockets have no MTU, DogStatsD's own default buffer is 8KB.
+const UDS_MAX_PACKET: usize = 8192;
+
+enum Socket {
+    Udp(UdpSocket),
+    Unix(UnixDatagram),
+}
+
+/// Pushes metrics to a StatsD agent, with attributes as DogStatsD tags (`|#key:value,...`).
+/// Counters and histograms are sent as deltas (histograms as `.count`/`.sum` counters and
+/// `.min`/`.max` gauges), up-down counters and gauges as absolute gauges.
+pub struct StatsdExporter {
+    socket: Socket,
+    max_packet: usize,
+    /// Tags added to every metric, already formatted.
+    static_tags: Vec<String>,
+}
+
+impl StatsdExporter {
+    /// `url` is `udp://HOST[:PORT]` (default port 8125) or `unix:///path/to/dsd.socket`.
+    pub fn new(url: &str, static_attrs: &[KeyValue]) -> anyhow::Result<Self> {
+        let url = url::Url::parse(url)?;
+        let (socket, max_packet) = match url.scheme() {
+            "udp" => {
+                let host = url
+                    .host_str()
+                    .ok_or_else(|| anyhow::anyhow!("StatsD URL has no host"))?;
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.connect((host, url.port().unwrap_or(8125)))?;
+                socket.set_nonblocking(true)?;
+                (Socket::Udp(socket), UDP_MAX_PACKET)
+            }
+            "unix" => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(url.path())?;
+                socket.set_nonblocking(true)?;
+                (Socket::Unix(socket), UDS_MAX_PACKET)
+            }
+            scheme => anyhow::bail!("Unsupported StatsD URL scheme {}", scheme),
+        };
+        Ok(Self {
+            socket,
+            max_packet,
+            static_tags: static_attrs
+                .iter()
+                .map(|kv| format_tag(&kv.key, &kv.value))
+                .collect(),
+        })
+    }
+
+    fn tags<'a>(&self, attrs: impl Iterator<Item = (&'a Key, &'a Value)>) -> String {
+        self.static_tags
+            .iter()
+            .cloned()
+            .chain(attrs.map(|(k, v)| format_tag(k, v)))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    fn sum<T: Display>(&self, name: &str, sum: &data::Sum<T>, lines: &mut Vec<String>) {
+        let kind = if sum.is_monotonic { "c" } else { "g" };
+        for point in &sum.data_points {
+            let tags = self.tags(point.attributes.iter());
+            lines.push(format_line(name, &point.value, kind, &tags));
+        }
+    }
+
+    fn gauge<T: Display>(&self, name: &str, gauge: &data::Gauge<T>, lines: &mut Vec<String>) {
+        for point in &gauge.data_points {
+            let tags = self.tags(point.attributes.iter());
+            lines.push(format_line(name, &point.value, "g", &tags));
+        }
+    }
+
+    fn histogram<T: Display>(
+        &self,
+        name: &str,
+        histogram: &data::Histogram<T>,
+        lines: &mut Vec<String>,
+    ) {
+        for point in &histogram.data_points {
+            let tags = self.tags(point.attributes.iter());
+            lines.push(format_line(
+                &format!("{}.count", name),
+                &point.count,
+                "c",
+                &tags,
+            ));
+            lines.push(format_line(
+                &format!("{}.sum", name),
+                &point.sum,
+                "c",
+                &tags,
+            ));
+            if let Some(min) = &point.min {
+                lines.push(format_line(&format!("{}.min", name), min, "g", &tags));
+            }
+            if let Some(max) = &point.max {
+                lines.push(format_line(&format!("{}.max", name), max, "g", &tags));
+            }
+        }
+    }
+
+    fn format_metric(&self, metric: &data::Metric, lines: &mut Vec<String>) {
+        let name = sanitize(&metric.name);
+        let data = metric.data.as_any();
+        if let Some(sum) = data.downcast_ref::<data::Sum<u64>>() {
+            self.sum(&name, sum, lines);
+        } else if let Some(sum) = data.downcast_ref::<data::Sum<i64>>() {
+            self.sum(&name, sum, lines);
+        } else if let Some(sum) = data.downcast_ref::<data::Sum<f64>>() {
+            self.sum(&name, sum, lines);
+        } else if let Some(gauge) = data.downcast_ref::<data::Gauge<u64>>() {
+            self.gauge(&name, gauge, lines);
+        } else if let Some(gauge) = data.downcast_ref::<data::Gauge<i64>>() {
+            self.gauge(&name, gauge, lines);
+        } else if let Some(gauge) = data.downcast_ref::<data::Gauge<f64>>() {
+            self.gauge(&name, gauge, lines);
+        } else if let Some(histogram) = data.downcast_ref::<data::Histogram<u64>>() {
+            self.histogram(&name, histogram, lines);
+        } else if let Some(histogram) = data.downcast_ref::<data::Histogram<f64>>() {
+            self.histogram(&name, histogram, lines);
+        }
+    }
+
+    fn send(&self, packet: &[u8]) -> Result<()> {
+        let result = match &self.socket {
+            Socket::Udp(socket) => socket.send(packet),
+            Socket::Unix(socket) => socket.send(packet),
+        };
+        result
+            .map(|_| ())
+            .map_err(|e| MetricsError::Other(format!("Error sending to StatsD: {}", e)))
+    }
+}
+
+impl AggregationSelector for StatsdExporter {
+    fn aggregation(&self, kind: InstrumentKind) -> Aggregation {
+        DefaultAggregationSelector::new().aggregation(kind)
+    }
+}
+
+impl TemporalitySelector for StatsdExporter {
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        match kind {
+            InstrumentKind::Counter
+            | InstrumentKind::ObservableCounter
+            | InstrumentKind::Histogram => Temporality::Delta,
+            _ => Temporality::Cumulative,
+        }
+    }
+}
+
+#[async_trait]
+impl PushMetricsExporter for StatsdExporter {
+    async fn export(&self, metrics: &mut ResourceMetrics) -> Result<()> {
+        let mut lines = vec![];
+        for scope in &metrics.scope_metrics {
+            for metric in &scope.metrics {
+                self.format_metric(metric, &mut lines);
+            }
+        }
+        for packet in pack_lines(&lines, self.max_packet) {
+            self.send(packet.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    async fn force_flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn shutdown(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Replace the characters StatsD uses as separators.
+fn sanitize(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            ':' | '|' | '@' | '#' | ',' | '\n' | ' ' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+fn format_tag(key: &Key, value: &Value) -> String {
+    format!("{}:{}", sanitize(key.as_str()), sanitize(&value.as_str()))
+}
+
+fn format_line(name: &str, value: &dyn Display, kind: &str, tags: &str) -> String {
+    if tags.is_empty() {
+        format!("{}:{}|{}", name, value, kind)
+    } else {
+        format!("{}:{}|{}|#{}", name, value, kind, tags)
+    }
+}
+
+/// Join lines into newline-separated packets of at most `max_packet` bytes (a longer line gets a
+/// packet of its own).
+fn pack_lines(lines: &[String], max_packet: usize) -> Vec<String> {
+    let mut packets = vec![];
+    let mut packet = String::new();
+    for line in lines {
+        if !packet.is_empty() && packet.len() + 1 + line.len() > max_packet {
+            packets.push(std::mem::take(&mut packet));
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(line);
+    }
+    if !packet.is_empty() {
+        packets.push(packet);
+    }
+    packets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_line() {
+        let attrs = [
+            KeyValue::new("function", "f|1"),
+            KeyValue::new("http.response.status_code", 200),
+        ];
+        let tags = attrs
+            .iter()
+            .map(|kv| format_tag(&kv.key, &kv.value))
+            .collect::<Vec<_>>()
+            .join(",");
+        assert_eq!(
+            format_line("invocation_duration.count", &3, "c", &tags),
+            "invocation_duration.count:3|c|#function:f_1,http.response.status_code:200"
+        );
+        assert_eq!(
+            format_line("in_flight_requests", &7, "g", ""),
+            "in_flight_requests:7|g"
+        );
+    }
+
+    #[test]
+    fn test_pack_lines() {
+        let lines = vec![
+            "a:1|c".to_string(),
+            "b:2|c".to_string(),
+            "c:3|c".to_string(),
+        ];
+        assert_eq!(pack_lines(&lines, 11), vec!["a:1|c\nb:2|c", "c:3|c"]);
+        assert_eq!(pack_lines(&lines, 1).len(), 3);
+    }
+}
@@ -0,0 +1,289 @@
+use anyhow::Context as _;
+use opentelemetry::trace::TraceResult;
+use opentelemetry::{Context, Value};
+use opentelemetry_sdk::export::trace::SpanData;
+use opentelemetry_sdk::trace::{Span, SpanProcessor};
+use regex::Regex;
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::io::Write;
+use std::sync::OnceLock;
+use tracing_subscriber::fmt::MakeWriter;
+
+const REDACTED: &str = "[REDACTED]";
+
+/// What to scrub, read from the JSON file at `SCRUB_CONFIG`. Rules are added to the defaults.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ScrubConfig {
+    /// Header names (case insensitive) whose values are redacted.
+    pub headers: Vec<String>,
+    /// Query parameter names whose values are redacted from anything URL-like.
+    pub query_params: Vec<String>,
+    /// Regexes whose matches are redacted.
+    pub patterns: Vec<String>,
+}
+
+const DEFAULT_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+];
+const DEFAULT_QUERY_PARAMS: &[&str] = &[
+    "token",
+    "access_token",
+    "api_key",
+    "apikey",
+    "password",
+    "secret",
+    "signature",
+];
+
+/// Redacts credentials and personal data before they reach logs, spans or Sentry events.
+pub struct Scrubber {
+    headers: Vec<String>,
+    /// Matches a header name and its value as they appear when logged, e.g. `authorization: ...`
+    /// or `"cookie": "..."`. Group 1 is the part kept. Unquoted values run to the next `,`, `}` or
+    /// line end, since they may have spaces (`Bearer abc`).
+    header_values: Regex,
+    /// Group 1 is the part kept (`?token=`).
+    query_params: Regex,
+    patterns: Vec<Regex>,
+}
+
+fn alternation(names: &[String]) -> String {
+    names
+        .iter()
+        .map(|n| regex::escape(n))
+        .collect::<Vec<_>>()
+        .join("|")
+}
+
+impl Scrubber {
+    pub fn new(config: &ScrubConfig) -> Result<Self, regex::Error> {
+        let headers: Vec<String> = DEFAULT_HEADERS
+            .iter()
+            .map(|h| h.to_string())
+            .chain(config.headers.iter().map(|h| h.to_ascii_lowercase()))
+            .collect();
+        let query_params: Vec<String> = DEFAULT_QUERY_PARAMS
+            .iter()
+            .map(|p| p.to_string())
+            .chain(config.query_params.iter().cloned())
+            .collect();
+        Ok(Self {
+            header_values: Regex::new(&format!(
+                r#"(?i)("?\b(?:{})"?\s*[:=]\s*)("[^"]*"|[^,}}\r\n]+)"#,
+                alternation(&headers)
+            ))?,
+            query_params: Regex::new(&format!(
+                r"(?i)([?&](?:{})=)[^&#\s]*",
+                alternation(&query_params)
+            ))?,
+            headers,
+            patterns: config
+                .patterns
+                .iter()
+                .map(|p| Regex::new(p))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Whether a header's value should be redacted.
+    pub fn is_sensitive_header(&self, name: &str) -> bool {
+        self.headers.iter().any(|h| h.eq_ignore_ascii_case(name))
+    }
+
+    /// Redact sensitive query parameters, header values and pattern matches in free text
+    /// (URLs, log lines, error messages).
+    pub fn scrub<'a>(&self, s: &'a str) -> Cow<'a, str> {
+        let mut s = Cow::Borrowed(s);
+        for (regex, replacement) in [
+            (&self.query_params, format!("${{1}}{}", REDACTED)),
+            (&self.header_values, format!("${{1}}{}", REDACTED)),
+        ] {
+            if regex.is_match(&s) {
+                s = Cow::Owned(regex.replace_all(&s, replacement.as_str()).into_owned());
+            }
+        }
+        for pattern in &self.patterns {
+            if pattern.is_match(&s) {
+                s = Cow::Owned(pattern.replace_all(&s, REDACTED).into_owned());
+            }
+        }
+        s
+    }
+
+    /// Scrub a Sentry event's request data, messages and breadcrumbs.
+    pub fn scrub_sentry_event(
+        &self,
+        mut event: sentry::protocol::Event<'static>,
+    ) -> sentry::protocol::Event<'static> {
+        if let Some(request) = &mut event.request {
+            if let Some(url) = &request.url {
+                if let Ok(scrubbed) = self.scrub(url.as_str()).parse() {
+                    request.url = Some(scrubbed);
+                }
+            }
+            if let Some(query) = &request.query_string {
+                request.query_string = Some(self.scrub(&format!("?{}", query))[1..].to_string());
+            }
+            request.cookies = None;
+            for (name, value) in request.headers.iter_mut() {
+                if self.is_sensitive_header(name) {
+                    *value = REDACTED.to_string();
+                } else {
+                    *value = self.scrub(value).into_owned();
+                }
+            }
+            if let Some(data) = &request.data {
+                request.data = Some(self.scrub(data).into_owned());
+            }
+        }
+        if let Some(message) = &event.message {
+            event.message = Some(self.scrub(message).into_owned());
+        }
+        if let Some(transaction) = &event.transaction {
+            event.transaction = Some(self.scrub(transaction).into_owned());
+        }
+        for exception in event.exception.values.iter_mut() {
+            if let Some(value) = &exception.value {
+                exception.value = Some(self.scrub(value).into_owned());
+            }
+        }
+        for breadcrumb in event.breadcrumbs.values.iter_mut() {
+            if let Some(message) = &breadcrumb.message {
+                breadcrumb.message = Some(self.scrub(message).into_owned());
+            }
+        }
+        event
+    }
+}
+
+static SCRUBBER: OnceLock<Scrubber> = OnceLock::new();
+
+/// Install the process-wide scrubber, configured from `SCRUB_CONFIG` (a JSON `ScrubConfig`) if
+/// set. Called first thing in `main`, so a config that can't be loaded stops the process instead
+/// of its rules being left out.
+pub fn init_scrubber() -> anyhow::Result<()> {
+    let config = match std::env::var("SCRUB_CONFIG") {
+        Ok(path) => {
+            let data = std::fs::read(&path)
+                .with_context(|| format!("Error reading scrub config {}", path))?;
+            serde_json::from_slice(&data)
+                .with_context(|| format!("Invalid scrub config {}", path))?
+        }
+        Err(_) => ScrubConfig::default(),
+    };
+    let scrubber = Scrubber::new(&config).context("Invalid scrub pattern")?;
+    SCRUBBER
+        .set(scrubber)
+        .map_err(|_| anyhow::anyhow!("Scrubber used before it was installed"))
+}
+
+/// The scrubber `init_scrubber` installed, or until then (e.g. in tests), one with just the
+/// default rules.
+pub fn scrubber() -> &'static Scrubber {
+    SCRUBBER.get_or_init(|| Scrubber::new(&ScrubConfig::default()).unwrap())
+}
+
+/// Wraps a `MakeWriter` so every log line is scrubbed before it's written.
+pub struct Scrubbed<M>(pub M);
+
+pub struct ScrubbedWriter<W>(W);
+
+impl<W: Write> Write for ScrubbedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // The fmt layer writes each formatted event in one call, so lines aren't split.
+        match std::str::from_utf8(buf) {
+            Ok(s) => self.0.write_all(scrubber().scrub(s).as_bytes())?,
+            Err(_) => self.0.write_all(buf)?,
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for Scrubbed<M> {
+    type Writer = ScrubbedWriter<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        ScrubbedWriter(self.0.make_writer())
+    }
+}
+
+/// Scrubs span attributes before handing spans to the exporting processor.
+#[derive(Debug)]
+pub struct ScrubbingSpanProcessor<P>(pub P);
+
+impl<P: SpanProcessor> SpanProcessor for ScrubbingSpanProcessor<P> {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        self.0.on_start(span, cx)
+    }
+
+    fn on_end(&self, mut span: SpanData) {
+        let scrubber = scrubber();
+        for kv in span.attributes.iter_mut() {
+            let key = kv.key.as_str();
+            if key
+                .strip_prefix("http.request.header.")
+                .or_else(|| key.strip_prefix("http.response.header."))
+                .is_some_and(|name| scrubber.is_sensitive_header(name))
+            {
+                kv.value = Value::String(REDACTED.into());
+            } else if let Value::String(s) = &kv.value {
+                let scrubbed = match scrubber.scrub(s.as_str()) {
+                    Cow::Owned(scrubbed) => Some(scrubbed),
+                    Cow::Borrowed(_) => None,
+                };
+                if let Some(scrubbed) = scrubbed {
+                    kv.value = Value::String(scrubbed.into());
+                }
+            }
+        }
+        self.0.on_end(span)
+    }
+
+    fn force_flush(&self) -> TraceResult<()> {
+        self.0.force_flush()
+    }
+
+    fn shutdown(&mut self) -> TraceResult<()> {
+        self.0.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scrub() {
+        let scrubber = Scrubber::new(&ScrubConfig {
+            headers: vec!["X-Session".to_string()],
+            query_params: vec!["email".to_string()],
+            patterns: vec![r"\d{3}-\d{2}-\d{4}".to_string()],
+        })
+        .unwrap();
+        assert_eq!(
+            scrubber.scrub("GET /invoke/f/users?email=a@b.c&page=2&token=abc"),
+            "GET /invoke/f/users?email=[REDACTED]&page=2&token=[REDACTED]"
+        );
+        assert_eq!(
+            scrubber.scrub(r#"headers: {"authorization": "Bearer abc", "x-session": "s"}"#),
+            r#"headers: {"authorization": [REDACTED], "x-session": [REDACTED]}"#
+        );
+        assert_eq!(
+            scrubber.scrub("authorization: Bearer abc123\nx-session: s, accept: */*"),
+            "authorization: [REDACTED]\nx-session: [REDACTED], accept: */*"
+        );
+        assert_eq!(scrubber.scrub("ssn 123-45-6789"), "ssn [REDACTED]");
+        assert!(matches!(scrubber.scrub("nothing here"), Cow::Borrowed(_)));
+        assert!(scrubber.is_sensitive_header("Cookie"));
+    }
+}
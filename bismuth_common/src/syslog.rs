@@ -0,0 +1,270 @@
+use anyhow::{Context as _, Result};
+use std::fmt::Write as _;
+use std::net::UdpSocket;
+use std::os::unix::net::UnixDatagram;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+/// `daemon` facility.
+const FACILITY: u8 = 3;
+/// Private enterprise number reserved for examples by RFC 5612, so the SD-ID is well formed.
+const SD_ID: &str = "fields@32473";
+
+enum Socket {
+    Udp(UdpSocket),
+    Unix(UnixDatagram),
+}
+
+/// Sends each event to a syslog daemon as an RFC 5424 message, with the event's fields as
+/// structured data. Timestamp and hostname are left for the daemon to fill in.
+pub struct SyslogLayer {
+    socket: Socket,
+    app_name: String,
+}
+
+impl SyslogLayer {
+    /// `url` is `unix:///dev/log` or `udp://HOST[:PORT]` (default port 514).
+    pub fn new(url: &str, app_name: &str) -> Result<Self> {
+        let url = url::Url::parse(url).context("Invalid syslog URL")?;
+        let socket = match url.scheme() {
+            "udp" => {
+                let host = url
+                    .host_str()
+                    .ok_or_else(|| anyhow::anyhow!("Syslog URL has no host"))?;
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.connect((host, url.port().unwrap_or(514)))?;
+                Socket::Udp(socket)
+            }
+            "unix" => {
+                let socket = UnixDatagram::unbound()?;
+                socket
+                    .connect(url.path())
+                    .with_context(|| format!("Error connecting to syslog at {}", url.path()))?;
+                Socket::Unix(socket)
+            }
+            scheme => anyhow::bail!("Unsupported syslog URL scheme {}", scheme),
+        };
+        Ok(Self {
+            socket,
+            app_name: app_name.to_string(),
+        })
+    }
+}
+
+fn severity(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+/// Escape a structured data parameter value (RFC 5424 section 6.3.3).
+fn escape_param(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Param names are printable ASCII without `=`, space, `]` or `"`, and at most 32 characters.
+fn param_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_ascii_graphic() && !matches!(c, '=' | ']' | '"'))
+        .take(32)
+        .collect()
+}
+
+#[derive(Default)]
+struct Fields {
+    message: String,
+    params: String,
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            let _ = write!(
+                self.params,
+                " {}=\"{}\"",
+                param_name(field.name()),
+                escape_param(value)
+            );
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+}
+
+fn format_message(app_name: &str, level: &Level, target: &str, fields: &Fields) -> String {
+    format!(
+        "<{}>1 - - {} {} - [{} target=\"{}\"{}] {}",
+        FACILITY * 8 + severity(level),
+        app_name,
+        std::process::id(),
+        SD_ID,
+        escape_param(target),
+        fields.params,
+        fields.message,
+    )
+}
+
+impl<S: Subscriber> Layer<S> for SyslogLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let metadata = event.metadata();
+        let message = format_message(&self.app_name, metadata.level(), metadata.target(), &fields);
+        let message = crate::scrubber().scrub(&message);
+        // Nowhere to report a failure to log.
+        let _ = match &self.socket {
+            Socket::Udp(socket) => socket.send(message.as_bytes()),
+            Socket::Unix(socket) => socket.send(message.as_bytes()),
+        };
+    }
+}
+
+/// journald's native protocol socket.
+const JOURNALD_PATH: &str = "/run/systemd/journal/socket";
+
+/// Sends each event to the local journal over its native protocol, with the event's fields as
+/// `F_`-prefixed journal fields, and every value scrubbed.
+pub struct JournaldLayer {
+    socket: UnixDatagram,
+    identifier: String,
+}
+
+impl JournaldLayer {
+    pub fn new(identifier: &str) -> Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket
+            .connect(JOURNALD_PATH)
+            .context("Error connecting to journald")?;
+        Ok(Self {
+            socket,
+            identifier: identifier.to_string(),
+        })
+    }
+}
+
+/// Journal field names are uppercase letters, digits and underscores.
+fn journal_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' => c.to_ascii_uppercase(),
+            'A'..='Z' | '0'..='9' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Append a field in the native protocol's serialization: `NAME=value`, or with a length
+/// prefix if the value has a newline.
+fn put_journal_field(buf: &mut Vec<u8>, name: &str, value: &str) {
+    let value = crate::scrubber().scrub(value);
+    buf.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(b'\n');
+}
+
+#[derive(Default)]
+struct JournalFields(Vec<u8>);
+
+impl Visit for JournalFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let name = match field.name() {
+            "message" => "MESSAGE".to_string(),
+            name => format!("F_{}", journal_name(name)),
+        };
+        put_journal_field(&mut self.0, &name, value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+}
+
+impl<S: Subscriber> Layer<S> for JournaldLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut fields = JournalFields::default();
+        put_journal_field(
+            &mut fields.0,
+            "PRIORITY",
+            &severity(metadata.level()).to_string(),
+        );
+        put_journal_field(&mut fields.0, "SYSLOG_IDENTIFIER", &self.identifier);
+        put_journal_field(&mut fields.0, "TARGET", metadata.target());
+        event.record(&mut fields);
+        // Nowhere to report a failure to log. Events too big for one datagram are dropped,
+        // rather than passed in a memfd as journald also allows.
+        let _ = self.socket.send(&fields.0);
+    }
+}
+
+/// journald and/or syslog output, for hosts with no stdout collector:
+/// `LOG_JOURNALD=1` logs to the local journal (fields become journal fields, levels map to
+/// priorities), and `LOG_SYSLOG=unix:///dev/log` (or `udp://HOST:PORT`) to a syslog daemon.
+pub fn system_log_layers<S>(service: &str) -> Result<impl Layer<S>>
+where
+    S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    let journald = match std::env::var("LOG_JOURNALD").as_deref() {
+        Ok("1") | Ok("true") => Some(JournaldLayer::new(service)?),
+        _ => None,
+    };
+    let syslog = match std::env::var("LOG_SYSLOG") {
+        Ok(url) => Some(SyslogLayer::new(&url, service)?),
+        Err(_) => None,
+    };
+    Ok(<Option<JournaldLayer> as Layer<S>>::and_then(
+        journald, syslog,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_message() {
+        let fields = Fields {
+            message: "Error reconciling".to_string(),
+            params: format!(" error=\"{}\"", escape_param("bad \"data\"]")),
+        };
+        assert_eq!(
+            format_message("bismuthsched", &Level::ERROR, "bismuthsched", &fields),
+            format!(
+                "<27>1 - - bismuthsched {} - [fields@32473 target=\"bismuthsched\" error=\"bad \\\"data\\\"\\]\"] Error reconciling",
+                std::process::id()
+            )
+        );
+    }
+
+    #[test]
+    fn test_journal_fields() {
+        assert_eq!(journal_name("http.status-code"), "HTTP_STATUS_CODE");
+        let mut buf = Vec::new();
+        put_journal_field(&mut buf, "F_ERROR", "authorization: Bearer abc");
+        assert_eq!(buf, b"F_ERROR=authorization: [REDACTED]\n");
+        buf.clear();
+        put_journal_field(&mut buf, "MESSAGE", "a\nb");
+        assert_eq!(buf, b"MESSAGE\n\x03\0\0\0\0\0\0\0a\nb\n");
+    }
+}
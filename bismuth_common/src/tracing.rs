@@ -3,13 +3,23 @@ use opentelemetry::trace::TracerProvider as _;
 use opentelemetry::KeyValue;
 use opentelemetry_otlp::WithExportConfig as _;
 use opentelemetry_sdk::{
-    trace::{RandomIdGenerator, Sampler},
+    trace::{BatchSpanProcessor, RandomIdGenerator, Sampler},
     Resource,
 };
 use std::time::Duration;
 
+use crate::ScrubbingSpanProcessor;
+
 pub fn init_tracer(
     service: &str,
+) -> Result<opentelemetry_sdk::trace::Tracer, opentelemetry::trace::TraceError> {
+    init_tracer_with_attributes(service, &[])
+}
+
+/// Like `init_tracer`, with `attrs` (e.g. the build's version) added to the exported resource.
+pub fn init_tracer_with_attributes(
+    service: &str,
+    attrs: &[KeyValue],
 ) -> Result<opentelemetry_sdk::trace::Tracer, opentelemetry::trace::TraceError> {
     // Required for axum-otel to work
     std::env::set_var(
@@ -40,27 +50,34 @@ pub fn init_tracer(
             opentelemetry_endpoint,
         );
 
-        opentelemetry_otlp::new_pipeline()
-            .tracing()
-            .with_exporter(
-                opentelemetry_otlp::new_exporter()
-                    .http()
-                    .with_http_client(reqwest::Client::new())
-                    .with_timeout(Duration::from_secs(3)),
-            )
-            .with_trace_config(
+        let exporter = opentelemetry_otlp::new_exporter()
+            .http()
+            .with_http_client(reqwest::Client::new())
+            .with_timeout(Duration::from_secs(3))
+            .build_span_exporter()?;
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+            // Scrubbed before batching, so nothing sensitive is held in the export queue either.
+            .with_span_processor(ScrubbingSpanProcessor(
+                BatchSpanProcessor::builder(exporter, opentelemetry_sdk::runtime::Tokio).build(),
+            ))
+            .with_config(
                 opentelemetry_sdk::trace::config()
                     .with_sampler(Sampler::AlwaysOn)
                     .with_id_generator(RandomIdGenerator::default())
                     // .with_max_events_per_span(64)
                     // .with_max_attributes_per_span(16)
                     // .with_max_events_per_span(16)
-                    .with_resource(Resource::new(vec![KeyValue::new(
-                        "service.name",
-                        service.to_string(),
-                    )])),
+                    .with_resource(Resource::new(
+                        [KeyValue::new("service.name", service.to_string())]
+                            .into_iter()
+                            .chain(attrs.iter().cloned()),
+                    )),
             )
-            .install_batch(opentelemetry_sdk::runtime::Tokio)
+            .build();
+        let tracer = provider.tracer(service.to_string());
+        opentelemetry::global::set_tracer_provider(provider);
+
+        Ok(tracer)
     } else if cfg!(debug_assertions) {
         eprintln!("DEV OTEL_EXPORTER_OTLP_ENDPOINT not found, sinking to file");
 
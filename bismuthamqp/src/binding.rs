@@ -0,0 +1,169 @@
+//! Which queues are consumed, the function each one's messages invoke, and what happens to a
+//! message once its invocation returns.
+
+use anyhow::{anyhow, Context, Result};
+use hyper::body::Bytes;
+use hyper::http::Response;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::Path;
+use uuid::Uuid;
+
+fn default_path() -> String {
+    "/".to_string()
+}
+
+fn default_concurrency() -> u16 {
+    8
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Binding {
+    pub queue: String,
+    pub function_id: Uuid,
+    /// Path within the function each message is POSTed to.
+    #[serde(default = "default_path")]
+    pub path: String,
+    /// Most of the queue's messages being invoked at once.
+    #[serde(default = "default_concurrency")]
+    pub concurrency: u16,
+    /// Unacknowledged messages the broker sends ahead of them being invoked. Defaults to
+    /// `concurrency`, so a message is always waiting when an invocation finishes.
+    #[serde(default)]
+    pub prefetch: Option<u16>,
+}
+
+impl Binding {
+    pub fn prefetch(&self) -> u16 {
+        self.prefetch.unwrap_or(self.concurrency)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub bindings: Vec<Binding>,
+}
+
+impl Config {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let config: Config = serde_json::from_slice(data)?;
+        if config.bindings.is_empty() {
+            return Err(anyhow!("No bindings"));
+        }
+        let mut queues = HashSet::new();
+        for binding in &config.bindings {
+            if !queues.insert(binding.queue.as_str()) {
+                // Two consumers would split the queue's messages rather than each get them all.
+                return Err(anyhow!("Queue {} is bound more than once", binding.queue));
+            }
+            if binding.concurrency == 0 {
+                return Err(anyhow!("Concurrency of queue {} is 0", binding.queue));
+            }
+            if binding.prefetch() < binding.concurrency {
+                return Err(anyhow!(
+                    "Prefetch of queue {} is less than its concurrency",
+                    binding.queue
+                ));
+            }
+            if !binding.path.starts_with('/') {
+                return Err(anyhow!("Path of queue {} must start with /", binding.queue));
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path)
+            .with_context(|| format!("Error reading bindings from {}", path.display()))?;
+        Self::parse(&data).with_context(|| format!("Invalid bindings in {}", path.display()))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Ack,
+    /// Put back on the queue to be tried again.
+    Requeue,
+    /// Dropped, or dead-lettered if the queue has a dead letter exchange.
+    Reject,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Ack => "acked",
+            Outcome::Requeue => "requeued",
+            Outcome::Reject => "rejected",
+        }
+    }
+}
+
+/// Messages are acknowledged once they're handled successfully. A retryable failure is requeued
+/// once, so a function that's briefly unavailable doesn't lose messages, but one that keeps
+/// failing doesn't hold up the queue with them forever.
+pub fn outcome(
+    result: &Result<Response<Bytes>, bismuth_client::Error>,
+    redelivered: bool,
+) -> Outcome {
+    match result {
+        Ok(_) => Outcome::Ack,
+        Err(e) if e.is_retryable() && !redelivered => Outcome::Requeue,
+        Err(_) => Outcome::Reject,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            br#"{"bindings": [
+                {"queue": "orders", "function_id": "6ba7b810-9dad-11d1-80b4-00c04fd430c8"},
+                {"queue": "emails", "function_id": "6ba7b810-9dad-11d1-80b4-00c04fd430c8",
+                 "path": "/send", "concurrency": 2, "prefetch": 10}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(config.bindings[0].path, "/");
+        assert_eq!(config.bindings[0].prefetch(), 8);
+        assert_eq!(config.bindings[1].path, "/send");
+        assert_eq!(config.bindings[1].concurrency, 2);
+        assert_eq!(config.bindings[1].prefetch(), 10);
+
+        for invalid in [
+            r#"{"bindings": []}"#,
+            r#"{"bindings": [{"queue": "a", "function_id": "6ba7b810-9dad-11d1-80b4-00c04fd430c8"},
+                             {"queue": "a", "function_id": "6ba7b810-9dad-11d1-80b4-00c04fd430c8"}]}"#,
+            r#"{"bindings": [{"queue": "a", "function_id": "6ba7b810-9dad-11d1-80b4-00c04fd430c8", "concurrency": 0}]}"#,
+            r#"{"bindings": [{"queue": "a", "function_id": "6ba7b810-9dad-11d1-80b4-00c04fd430c8", "concurrency": 4, "prefetch": 2}]}"#,
+            r#"{"bindings": [{"queue": "a", "function_id": "6ba7b810-9dad-11d1-80b4-00c04fd430c8", "path": "run"}]}"#,
+            r#"{"bindings": [{"queue": "a", "function": "6ba7b810-9dad-11d1-80b4-00c04fd430c8"}]}"#,
+        ] {
+            assert!(Config::parse(invalid.as_bytes()).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_outcome() {
+        assert_eq!(
+            outcome(&Ok(Response::new(Bytes::new())), true),
+            Outcome::Ack
+        );
+        assert_eq!(
+            outcome(&Err(bismuth_client::Error::Unavailable), false),
+            Outcome::Requeue
+        );
+        assert_eq!(
+            outcome(&Err(bismuth_client::Error::Unavailable), true),
+            Outcome::Reject
+        );
+        assert_eq!(
+            outcome(&Err(bismuth_client::Error::NotFound), false),
+            Outcome::Reject
+        );
+    }
+}
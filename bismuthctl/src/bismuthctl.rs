@@ -5,7 +5,12 @@ use std::collections::HashMap;
 use std::net::Ipv4Addr;
 use uuid::Uuid;
 
-use bismuth_common::{pack_backends, unpack_backends, Backend, FunctionDefinition, InvokeMode};
+use bismuth_common::{
+    add_write_draining, read_backends, read_draining, write_backends, AccessLog, Backend,
+    CanaryAnalysis, Capture, CapturedRequest, DrainingBackend, FunctionConfig, FunctionDefinition,
+    GeoRestriction, HealthCheck, InvokeMode, LatencyObjective, Maintenance, NodeCapacity, Priority,
+    Ramp, RampAction, RateLimit, RequestSchema, ResponseMode, Slo, TrafficSplit, Warmup,
+};
 
 /// bismuthctl
 #[derive(Debug, Parser)]
@@ -25,10 +30,45 @@ enum Command {
     Bootstrap {},
     /// Perform a consistency check on the cluster
     Consistency {},
+    /// Set the ACL of a ZooKeeper tree, by default /function: --writer identities may change it,
+    /// --reader ones only read it, and no one else either, without --anyone-read. Authenticate
+    /// as a writer (--zookeeper-auth), or bismuthctl locks itself out
+    SetAcls {
+        /// Identity that may change the tree, as scheme:id, e.g. ip:10.0.1.0/24 or
+        /// digest:bismuth:<base64 SHA-1 of bismuth:password>
+        #[clap(long = "writer", required = true)]
+        writers: Vec<AclId>,
+        /// Identity that may only read the tree, e.g. the frontends'
+        #[clap(long = "reader")]
+        readers: Vec<AclId>,
+        /// Let anyone read the tree
+        #[clap(long)]
+        anyone_read: bool,
+        #[clap(long, default_value = "/function")]
+        path: String,
+    },
+    /// List the nodes of a ZooKeeper tree, by default /function, that anyone may change, failing
+    /// if there are any
+    AuditAcls {
+        #[clap(long, default_value = "/function")]
+        path: String,
+        /// List every node's ACL
+        #[clap(long)]
+        all: bool,
+    },
 
     /// Provision a server into the cluster
     Provision {
         node_ip: Ipv4Addr,
+        /// Region tag for the node, which frontends use to route clients to nearby backends
+        #[clap(long)]
+        region: Option<String>,
+        /// CPU cores the scheduler may allocate to functions on this node (default: unbounded)
+        #[clap(long, requires = "memory")]
+        cpu: Option<f32>,
+        /// Bytes of memory the scheduler may allocate to functions on this node
+        #[clap(long, requires = "cpu")]
+        memory: Option<u64>,
     },
     /// Fully deprovision a server from the cluster
     Deprovision {
@@ -42,6 +82,15 @@ enum Command {
     Undrain {
         node_ip: Ipv4Addr,
     },
+    /// Have frontends stop sending new invocations to one of a function's backends, and
+    /// bismuthsched replace it once drained
+    DrainBackend {
+        function_id: Uuid,
+        container_id: Uuid,
+        /// Send it invocations again
+        #[clap(long)]
+        off: bool,
+    },
     ListNodes {},
     ListFunctions {},
     GetFunction {
@@ -54,6 +103,9 @@ enum Command {
         repo: Option<url::Url>,
         #[clap(default_value = "main")]
         branch: String,
+        /// How to tell a server container is ready: tcp, http:/path or grpc[:service]
+        #[clap(long, default_value = "tcp")]
+        health_check: HealthCheck,
     },
     AddBackend {
         function_id: Uuid,
@@ -67,6 +119,222 @@ enum Command {
     DeleteFunction {
         id: Uuid,
     },
+    /// Set how many backends bismuthsched should keep for a function (capped at max_instances)
+    SetReplicas {
+        function_id: Uuid,
+        replicas: u32,
+    },
+    /// Put a function into (or take it out of) maintenance: frontends answer with a 503 instead
+    /// of proxying, without touching its backends
+    Maintenance {
+        function_id: Uuid,
+        /// Take the function out of maintenance
+        #[clap(long)]
+        off: bool,
+        /// Response body (default: a short plain text notice)
+        #[clap(long)]
+        body: Option<String>,
+        #[clap(long)]
+        content_type: Option<String>,
+        /// Seconds, sent as Retry-After
+        #[clap(long)]
+        retry_after: Option<u64>,
+    },
+    /// Have frontends started with --capture-dir record a sample of a function's invocations
+    Capture {
+        function_id: Uuid,
+        /// Stop capturing
+        #[clap(long)]
+        off: bool,
+        /// Fraction of invocations to record, from 0 to 1
+        #[clap(long, default_value = "0.01")]
+        sample_rate: f64,
+        /// Record bodies up to this many bytes
+        #[clap(long, default_value = "65536")]
+        max_body_bytes: u64,
+    },
+    /// Turn a function's access logging on or off, or log only a sample of its invocations
+    AccessLog {
+        function_id: Uuid,
+        /// Don't log the function's invocations
+        #[clap(long)]
+        off: bool,
+        /// Fraction of invocations to log, from 0 to 1
+        #[clap(long, default_value = "1")]
+        sample_rate: f64,
+    },
+    /// Have frontends warm up backends newly added to a function before routing to them
+    Warmup {
+        function_id: Uuid,
+        /// Route to new backends straight away
+        #[clap(long)]
+        off: bool,
+        /// Path within the function to GET
+        #[clap(long, default_value = "/")]
+        path: String,
+        /// Successive requests that must succeed
+        #[clap(long, default_value = "3")]
+        requests: u32,
+        /// Milliseconds each request may take
+        #[clap(long, default_value = "5000")]
+        timeout_ms: u64,
+    },
+    /// Send a percentage of a function's invocations to another function (e.g. a new version),
+    /// optionally rolling it back automatically if the canary regresses
+    Split {
+        function_id: Uuid,
+        /// Function to send the invocations to
+        #[clap(required_unless_present_any = ["off", "resume"])]
+        canary: Option<Uuid>,
+        /// Percentage of invocations, from 0 to 100
+        #[clap(required_unless_present_any = ["off", "resume", "ramp_secs"])]
+        percent: Option<f64>,
+        /// Remove the split
+        #[clap(long, conflicts_with_all = ["canary", "percent", "resume"])]
+        off: bool,
+        /// Ramp the canary up to 100% over this many seconds instead of setting a percentage
+        #[clap(long, conflicts_with = "percent")]
+        ramp_secs: Option<u64>,
+        /// Steps the ramp goes up in
+        #[clap(long, default_value = "10", requires = "ramp_secs")]
+        ramp_steps: u32,
+        /// Percentage the ramp starts at
+        #[clap(long, default_value = "1", requires = "ramp_secs")]
+        ramp_from: f64,
+        /// What to do when analysis finds the canary regressed while ramping: revert or pause
+        #[clap(long, default_value = "revert", requires_all = ["ramp_secs", "analyze"])]
+        on_regression: RampAction,
+        /// Carry on with a paused ramp from where it was paused
+        #[clap(long, conflicts_with_all = ["canary", "percent"])]
+        resume: bool,
+        /// Have frontends compare the canary with the function and roll the split back if it
+        /// regresses
+        #[clap(long)]
+        analyze: bool,
+        /// Seconds of invocations compared at a time
+        #[clap(long, default_value = "300", requires = "analyze")]
+        window_secs: u64,
+        /// Invocations each side needs in a window for it to be compared
+        #[clap(long, default_value = "100", requires = "analyze")]
+        min_requests: u64,
+        /// Largest acceptable increase in the canary's error rate, as a fraction
+        #[clap(long, default_value = "0.01", requires = "analyze")]
+        max_error_rate_increase: f64,
+        /// Largest acceptable ratio of the canary's p99 latency to the function's
+        #[clap(long, default_value = "1.5", requires = "analyze")]
+        max_latency_ratio: f64,
+    },
+    /// Limit how often each client may invoke a function (counted by each frontend)
+    RateLimit {
+        function_id: Uuid,
+        /// Invocations allowed per window
+        #[clap(required_unless_present = "off")]
+        requests: Option<u64>,
+        /// Remove the limit
+        #[clap(long, conflicts_with = "requests")]
+        off: bool,
+        #[clap(long, default_value = "60")]
+        window_secs: u64,
+        /// Count invocations by this request header (e.g. an API key) rather than client IP
+        #[clap(long)]
+        key_header: Option<String>,
+    },
+    /// Restrict the countries clients may invoke a function from, as located by frontends'
+    /// GeoIP database
+    Geo {
+        function_id: Uuid,
+        /// Only allow clients in these countries (ISO 3166-1 codes), e.g. US,CA
+        #[clap(long, value_delimiter = ',', required_unless_present_any = ["deny", "off"])]
+        allow: Vec<String>,
+        /// Refuse clients in these countries
+        #[clap(long, value_delimiter = ',')]
+        deny: Vec<String>,
+        /// Status refused clients get: 451 or 403
+        #[clap(long, default_value = "451")]
+        status: u16,
+        /// Remove the restriction
+        #[clap(long, conflicts_with_all = ["allow", "deny"])]
+        off: bool,
+    },
+    /// Set the priority of a function's invocations under load
+    Priority {
+        function_id: Uuid,
+        /// low, normal, high, or default (normal, unless tenant tokens give one)
+        priority: String,
+    },
+    /// Set how frontends pass on a function's responses
+    ResponseMode {
+        function_id: Uuid,
+        /// stream (every chunk passed on at once), buffer (read in full, then sent with a
+        /// length, compressed and with an ETag), or default
+        mode: String,
+    },
+    /// Have frontends send concurrent identical GETs of a function to its backends once, sharing
+    /// the response
+    Coalesce {
+        function_id: Uuid,
+        /// Send every GET on
+        #[clap(long)]
+        off: bool,
+    },
+    /// Have frontends decompress a function's gzip, deflate and brotli request bodies before
+    /// passing them on
+    Decompress {
+        function_id: Uuid,
+        /// Pass request bodies on as they're sent
+        #[clap(long)]
+        off: bool,
+    },
+    /// Set the objectives frontends track a function's invocations against, reporting compliance
+    /// and error budget burn rates
+    Slo {
+        function_id: Uuid,
+        /// Fraction of invocations that should get a response other than a 5xx, e.g. 0.999
+        #[clap(long, required_unless_present_any = ["latency_ms", "off"])]
+        availability: Option<f64>,
+        /// Latency objective: response headers within this many milliseconds
+        #[clap(long, requires = "latency_target")]
+        latency_ms: Option<u64>,
+        /// Fraction of invocations that should be within --latency-ms, e.g. 0.99
+        #[clap(long, requires = "latency_ms")]
+        latency_target: Option<f64>,
+        /// Window compliance is measured over, at most a day
+        #[clap(long, default_value = "86400")]
+        window_secs: u64,
+        /// Remove the SLO
+        #[clap(long, conflicts_with_all = ["availability", "latency_ms"])]
+        off: bool,
+    },
+    /// Add a JSON Schema that frontends check a function's request bodies against, rejecting
+    /// those that don't match with a 422
+    RequestSchema {
+        function_id: Uuid,
+        /// File holding the JSON Schema
+        #[clap(required_unless_present = "off")]
+        file: Option<std::path::PathBuf>,
+        /// Methods it applies to (default: all)
+        #[clap(long = "method")]
+        methods: Vec<String>,
+        /// Request path it applies to, where `*` matches one segment and a trailing `**` the
+        /// rest, e.g. /orders/* (default: all)
+        #[clap(long)]
+        path: Option<String>,
+        /// Remove all of the function's request schemas
+        #[clap(long, conflicts_with = "file")]
+        off: bool,
+    },
+    /// Re-issue captured invocations (from a frontend's --capture-dir) against a function,
+    /// e.g. a new version of the one they were captured from. Doesn't use ZooKeeper.
+    Replay {
+        /// A `{function id}.jsonl` capture file
+        file: std::path::PathBuf,
+        /// Frontend base URL, e.g. http://127.0.0.1:8000
+        frontend: url::Url,
+        function_id: Uuid,
+        /// Also replay requests whose bodies were cut off when captured
+        #[clap(long)]
+        include_truncated: bool,
+    },
 }
 
 #[derive(Debug, Args)]
@@ -81,6 +349,165 @@ struct GlobalOpts {
     /// ZooKeeper environment name (e.g. "dev", "test", "default")
     #[clap(long, global = true, default_value = "default")]
     zookeeper_env: String,
+
+    /// Authenticate to ZooKeeper, as scheme:credentials (e.g. digest:bismuth:password). May be
+    /// repeated
+    #[clap(long, global = true)]
+    zookeeper_auth: Vec<String>,
+}
+
+/// A ZooKeeper ACL identity, given as scheme:id.
+#[derive(Clone, Debug)]
+struct AclId {
+    scheme: String,
+    id: String,
+}
+
+impl std::str::FromStr for AclId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((scheme, id)) if !scheme.is_empty() && !id.is_empty() => Ok(AclId {
+                scheme: scheme.to_string(),
+                id: id.to_string(),
+            }),
+            _ => Err(format!("Expected scheme:id, got '{}'", s)),
+        }
+    }
+}
+
+/// `permission` as ZooKeeper's CLI shows it, e.g. `cdrwa`.
+fn permission_letters(permission: zookeeper_client::Permission) -> String {
+    use zookeeper_client::Permission;
+    [
+        (Permission::CREATE, 'c'),
+        (Permission::DELETE, 'd'),
+        (Permission::READ, 'r'),
+        (Permission::WRITE, 'w'),
+        (Permission::ADMIN, 'a'),
+    ]
+    .into_iter()
+    .filter(|(p, _)| (permission | *p) == permission)
+    .map(|(_, letter)| letter)
+    .collect()
+}
+
+/// `root` and every node under it, parents before their children.
+async fn walk(zk: &zookeeper_client::Client, root: &str) -> Result<Vec<String>> {
+    let mut paths = Vec::new();
+    let mut pending = vec![root.to_string()];
+    while let Some(path) = pending.pop() {
+        let (children, _) = zk
+            .get_children(&path)
+            .await
+            .with_context(|| format!("Error listing {}", path))?;
+        for child in children {
+            pending.push(match path.as_str() {
+                "/" => format!("/{}", child),
+                _ => format!("{}/{}", path, child),
+            });
+        }
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// Read-modify-write a function's config, creating the znode if it doesn't exist yet.
+async fn update_config(
+    zk: &zookeeper_client::Client,
+    function_id: &Uuid,
+    update: impl FnOnce(&mut FunctionConfig),
+) -> Result<()> {
+    let function_key = format!("/function/{}", function_id);
+    if zk.check_stat(&function_key).await?.is_none() {
+        return Err(anyhow!("Function {} does not exist", function_id));
+    }
+    let config_key = format!("{}/config", &function_key);
+    let (mut config, version) = match zk.get_data(&config_key).await {
+        Ok((data, stat)) => (
+            serde_json::from_slice::<FunctionConfig>(&data).context("Invalid function config")?,
+            Some(stat.version),
+        ),
+        Err(zookeeper_client::Error::NoNode) => (FunctionConfig::default(), None),
+        Err(e) => return Err(e).context("Error getting function config"),
+    };
+    update(&mut config);
+    let data = serde_json::to_vec(&config)?;
+    match version {
+        Some(version) => {
+            zk.set_data(&config_key, &data, Some(version))
+                .await
+                .context("Error setting function config")?;
+        }
+        None => {
+            zk.create(
+                &config_key,
+                &data,
+                &zookeeper_client::CreateMode::Persistent
+                    .with_acls(zookeeper_client::Acls::anyone_all()),
+            )
+            .await
+            .context("Error creating function config znode")?;
+        }
+    }
+    Ok(())
+}
+
+/// Send each captured request in `file` to `function_id` through `frontend`, in order.
+async fn replay(
+    file: &std::path::Path,
+    frontend: &url::Url,
+    function_id: &Uuid,
+    include_truncated: bool,
+) -> Result<()> {
+    let captured = std::fs::read_to_string(file)
+        .with_context(|| format!("Error reading {}", file.display()))?;
+    let client = hyper::Client::new();
+    let (mut replayed, mut failed, mut skipped) = (0, 0, 0);
+    for (i, line) in captured.lines().enumerate() {
+        let record: CapturedRequest = serde_json::from_str(line)
+            .with_context(|| format!("Invalid captured request on line {}", i + 1))?;
+        if record.body_truncated && !include_truncated {
+            skipped += 1;
+            continue;
+        }
+        let uri = frontend
+            .join(&format!("invoke/{}/{}", function_id, record.path))?
+            .to_string();
+        let mut req = hyper::Request::builder()
+            .method(record.method.as_str())
+            .uri(&uri);
+        for (name, value) in &record.headers {
+            // Set for the new request (and body, if truncated) instead.
+            if !["host", "content-length"].contains(&name.as_str()) {
+                req = req.header(name, value);
+            }
+        }
+        match client
+            .request(req.body(hyper::Body::from(record.body))?)
+            .await
+        {
+            Ok(resp) => {
+                info!("{} {} -> {}", record.method, record.path, resp.status());
+                if resp.status().is_server_error() {
+                    failed += 1;
+                }
+                // Drain the body so the connection can be reused.
+                hyper::body::to_bytes(resp.into_body()).await?;
+            }
+            Err(e) => {
+                error!("{} {} failed: {}", record.method, record.path, e);
+                failed += 1;
+            }
+        }
+        replayed += 1;
+    }
+    println!(
+        "Replayed {} requests ({} failed), skipped {} with truncated bodies",
+        replayed, failed, skipped
+    );
+    Ok(())
 }
 
 async fn drain(zk: &zookeeper_client::Client, node_ip: &Ipv4Addr) -> Result<()> {
@@ -124,20 +551,10 @@ async fn drain(zk: &zookeeper_client::Client, node_ip: &Ipv4Addr) -> Result<()>
 
     for function in &node_functions {
         // Remove this node from the function's backend list
-        let function_backends_key = format!("/function/{}/backends", &function);
-        let (function_backends_raw, stat) = zk
-            .get_data(&function_backends_key)
-            .await
-            .context("Error getting function backends")?;
-        let mut function_backends = unpack_backends(&function_backends_raw)?;
+        let function_id = Uuid::parse_str(function)?;
+        let (mut function_backends, version) = read_backends(&zk, &function_id).await?;
         function_backends.retain(|b| b.ip != *node_ip);
-        zk.set_data(
-            &function_backends_key,
-            &pack_backends(&function_backends),
-            Some(stat.version),
-        )
-        .await
-        .context("Error updating function backends")?;
+        write_backends(&zk, &function_id, &function_backends, version).await?;
 
         // And now delete the function entry in the node
         zk.delete(&format!("{}/function/{}", &node_key, &function), None)
@@ -155,9 +572,27 @@ async fn main() -> Result<()> {
         .filter_level(args.global_opts.verbose.log_level_filter())
         .init();
 
+    if let Command::Replay {
+        file,
+        frontend,
+        function_id,
+        include_truncated,
+    } = &args.command
+    {
+        return replay(file, frontend, function_id, *include_truncated).await;
+    }
+
     let zk = zookeeper_client::Client::connect(&args.global_opts.zookeeper)
         .await
         .context("Failed to connect to zookeeper")?;
+    for auth in &args.global_opts.zookeeper_auth {
+        let (scheme, credentials) = auth
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Expected --zookeeper-auth scheme:credentials"))?;
+        zk.auth(scheme.to_string(), credentials.as_bytes().to_vec())
+            .await
+            .with_context(|| format!("Error authenticating to zookeeper with {}", scheme))?;
+    }
 
     if zk
         .check_stat(&format!("/{}", &args.global_opts.zookeeper_env))
@@ -209,6 +644,74 @@ async fn main() -> Result<()> {
 
             info!("Cluster successfully bootstrapped");
         }
+        Command::SetAcls {
+            writers,
+            readers,
+            anyone_read,
+            path,
+        } => {
+            let mut acls: Vec<_> = writers
+                .iter()
+                .map(|w| {
+                    zookeeper_client::Acl::new(zookeeper_client::Permission::ALL, &w.scheme, &w.id)
+                })
+                .collect();
+            acls.extend(readers.iter().map(|r| {
+                zookeeper_client::Acl::new(zookeeper_client::Permission::READ, &r.scheme, &r.id)
+            }));
+            if *anyone_read {
+                acls.push(zookeeper_client::Acl::new(
+                    zookeeper_client::Permission::READ,
+                    "world",
+                    "anyone",
+                ));
+            }
+            // Children first, so the tree can still be listed if the new ACL doesn't let this
+            // session read it.
+            let paths = walk(&zk, path).await?;
+            for node in paths.iter().rev() {
+                zk.set_acl(node, &acls, None)
+                    .await
+                    .with_context(|| format!("Error setting ACL of {}", node))?;
+                debug!("Set ACL of {}", node);
+            }
+            info!("Set ACL of {} nodes under {}", paths.len(), path);
+        }
+        Command::AuditAcls { path, all } => {
+            let mut open = 0;
+            for node in walk(&zk, path).await? {
+                let (acls, _) = zk
+                    .get_acl(&node)
+                    .await
+                    .with_context(|| format!("Error reading ACL of {}", node))?;
+                let anyone_may_change = acls.iter().any(|acl| {
+                    acl.scheme() == "world"
+                        && acl.id() == "anyone"
+                        && permission_letters(acl.permission()).contains(['c', 'd', 'w', 'a'])
+                });
+                if anyone_may_change {
+                    open += 1;
+                }
+                if anyone_may_change || *all {
+                    let acls: Vec<_> = acls
+                        .iter()
+                        .map(|acl| {
+                            format!(
+                                "{}:{}:{}",
+                                acl.scheme(),
+                                acl.id(),
+                                permission_letters(acl.permission())
+                            )
+                        })
+                        .collect();
+                    println!("{} {}", node, acls.join(","));
+                }
+            }
+            if open > 0 {
+                return Err(anyhow!("{} nodes under {} anyone may change", open, path));
+            }
+            info!("No nodes under {} anyone may change", path);
+        }
         Command::Consistency {} => {
             let node_ips = zk
                 .get_children("/node")
@@ -259,13 +762,10 @@ async fn main() -> Result<()> {
             println!("{} functions in cluster", function_ids.len());
 
             for function_id in &function_ids {
-                let (function_backends_raw, _) = zk
-                    .get_data(&format!("/function/{}/backends", &function_id))
+                let (backends, _) = read_backends(&zk, &Uuid::parse_str(function_id)?)
                     .await
                     .context("Failed to read function backend data")?;
 
-                let backends = unpack_backends(&function_backends_raw)?;
-
                 for backend in backends {
                     match node_state.get(&backend.ip) {
                         Some(node_enabled) => {
@@ -306,7 +806,12 @@ async fn main() -> Result<()> {
                 }
             }
         }
-        Command::Provision { node_ip } => {
+        Command::Provision {
+            node_ip,
+            region,
+            cpu,
+            memory,
+        } => {
             let node_key = format!("/node/{}", node_ip);
             let exists = zk
                 .check_stat(&node_key)
@@ -332,10 +837,80 @@ async fn main() -> Result<()> {
             )
             .await
             .context("Error creating node container znode")?;
+
+            if let Some(region) = region {
+                zk.create(
+                    &format!("{}/region", &node_key),
+                    region.as_bytes(),
+                    &zookeeper_client::CreateMode::Persistent
+                        .with_acls(zookeeper_client::Acls::anyone_all()),
+                )
+                .await
+                .context("Error creating node region znode")?;
+            }
+
+            if let (Some(cpu), Some(memory)) = (cpu, memory) {
+                zk.create(
+                    &format!("{}/capacity", &node_key),
+                    &serde_json::to_vec(&NodeCapacity {
+                        cpu: *cpu,
+                        memory: *memory,
+                    })?,
+                    &zookeeper_client::CreateMode::Persistent
+                        .with_acls(zookeeper_client::Acls::anyone_all()),
+                )
+                .await
+                .context("Error creating node capacity znode")?;
+            }
         }
         Command::Drain { node_ip } => {
             drain(&zk, node_ip).await?;
         }
+        Command::DrainBackend {
+            function_id,
+            container_id,
+            off,
+        } => {
+            let (backends, _) = read_backends(&zk, function_id).await?;
+            if !*off && !backends.iter().any(|b| b.container_id == *container_id) {
+                return Err(anyhow!("Backend not listed for function"));
+            }
+            let (mut draining, version) = read_draining(&zk, function_id).await?;
+            let marked = draining.iter().any(|d| d.container_id == *container_id);
+            if marked != *off {
+                info!(
+                    "Backend {} already {}",
+                    container_id,
+                    if *off { "not draining" } else { "draining" }
+                );
+                return Ok(());
+            }
+            draining.retain(|d| d.container_id != *container_id);
+            if !*off {
+                draining.push(DrainingBackend {
+                    container_id: *container_id,
+                    since: std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)?
+                        .as_secs(),
+                });
+            }
+            let mut multi = zk.new_multi_writer();
+            add_write_draining(&mut multi, function_id, &draining, version)?;
+            multi
+                .commit()
+                .await
+                .context("Error updating draining backends")?;
+            info!(
+                "Backend {} of function {} {}",
+                container_id,
+                function_id,
+                if *off {
+                    "no longer draining"
+                } else {
+                    "draining"
+                }
+            );
+        }
         Command::Undrain { node_ip } => {
             let node_key = format!("/node/{}", node_ip);
             let exists = zk
@@ -373,6 +948,14 @@ async fn main() -> Result<()> {
             zk.delete(&format!("{}/container", &node_key), None)
                 .await
                 .context("Error deleting node container znode")?;
+            match zk.delete(&format!("{}/region", &node_key), None).await {
+                Ok(()) | Err(zookeeper_client::Error::NoNode) => {}
+                Err(e) => return Err(e).context("Error deleting node region znode"),
+            }
+            match zk.delete(&format!("{}/capacity", &node_key), None).await {
+                Ok(()) | Err(zookeeper_client::Error::NoNode) => {}
+                Err(e) => return Err(e).context("Error deleting node capacity znode"),
+            }
             zk.delete(&node_key, None)
                 .await
                 .context("Error deleting node znode")?;
@@ -408,12 +991,11 @@ async fn main() -> Result<()> {
             println!("FunctionDefinition: {:#?}", function);
             print!("Backends: ");
 
-            let (function_backends_raw, _) = zk
-                .get_data(&format!("/function/{}/backends", &function_id))
+            let (backends, _) = read_backends(&zk, &function_id)
                 .await
                 .context("Failed to read function backend data")?;
 
-            for backend in unpack_backends(&function_backends_raw)? {
+            for backend in backends {
                 print!("{}:{} ", backend.ip, backend.container_id);
             }
             print!("\n");
@@ -425,6 +1007,7 @@ async fn main() -> Result<()> {
             invoke_mode,
             repo,
             branch,
+            health_check,
         } => {
             let id = Uuid::new_v4().to_string();
             let function_key = format!("/function/{}", id);
@@ -441,6 +1024,7 @@ async fn main() -> Result<()> {
                     memory: 512 * 1024 * 1024,
                     invoke_mode: invoke_mode.clone(),
                     max_instances: 1,
+                    health_check: health_check.clone(),
                 })?,
                 &zookeeper_client::CreateMode::Persistent
                     .with_acls(zookeeper_client::Acls::anyone_all()),
@@ -483,13 +1067,7 @@ async fn main() -> Result<()> {
             .await
             .context("Error creating container status znode")?;
 
-            let backends_key = format!("/function/{}/backends", function_id);
-            let (backends_raw, stat) = zk
-                .get_data(&backends_key)
-                .await
-                .context("Error getting function backends")?;
-
-            let mut backends = unpack_backends(&backends_raw)?;
+            let (mut backends, version) = read_backends(&zk, &function_id).await?;
             for backend in &backends {
                 if backend.ip == *new_backend {
                     warn!(
@@ -503,10 +1081,7 @@ async fn main() -> Result<()> {
                 container_id,
             });
 
-            let backends_raw = pack_backends(&backends);
-            zk.set_data(&backends_key, &backends_raw, Some(stat.version))
-                .await
-                .context("Error updating function backends")?;
+            write_backends(&zk, &function_id, &backends, version).await?;
             println!("{}", container_id);
         }
         Command::RemoveBackend {
@@ -525,25 +1100,14 @@ async fn main() -> Result<()> {
                 .await
                 .context("Error deleting container znode")?;
 
-            let backends_key = format!("/function/{}/backends", function_id);
-            let (backends_raw, stat) = zk
-                .get_data(&backends_key)
-                .await
-                .context("Error getting function backends")?;
-            let mut backends = unpack_backends(&backends_raw)?;
+            let (mut backends, version) = read_backends(&zk, &function_id).await?;
             backends.retain(|b| b.ip != *remove_backend && b.container_id != *container_id);
-            zk.set_data(&backends_key, &pack_backends(&backends), Some(stat.version))
-                .await
-                .context("Error updating function backends")?;
+            write_backends(&zk, &function_id, &backends, version).await?;
         }
         Command::DeleteFunction { id } => {
             let function_key = format!("/function/{}", id);
             let backends_key = format!("{}/backends", &function_key);
-            let (backends_raw, _) = zk
-                .get_data(&backends_key)
-                .await
-                .context("Error getting function backends")?;
-            let backends = unpack_backends(&backends_raw)?;
+            let (backends, _) = read_backends(&zk, id).await?;
             if backends.len() > 0 {
                 return Err(anyhow!(
                     "Function {} still has backends ({:?})",
@@ -551,13 +1115,395 @@ async fn main() -> Result<()> {
                     backends
                 ));
             }
-            zk.delete(&format!("{}/backends", &function_key), None)
+            // Chunks left behind by failed writes of long lists.
+            for chunk in zk
+                .list_children(&backends_key)
+                .await
+                .context("Error listing function backends chunks")?
+            {
+                zk.delete(&format!("{}/{}", &backends_key, chunk), None)
+                    .await
+                    .context("Error deleting function backends chunk")?;
+            }
+            zk.delete(&backends_key, None)
                 .await
                 .context("Error deleting function backends znode")?;
+            // Optional per-function znodes (metadata, replicas, ...)
+            for child in zk
+                .list_children(&function_key)
+                .await
+                .context("Error listing function znodes")?
+            {
+                zk.delete(&format!("{}/{}", &function_key, child), None)
+                    .await
+                    .with_context(|| format!("Error deleting function {} znode", child))?;
+            }
             zk.delete(&function_key, None)
                 .await
                 .context("Error deleting function znode")?;
         }
+        Command::SetReplicas {
+            function_id,
+            replicas,
+        } => {
+            let function_key = format!("/function/{}", function_id);
+            if zk.check_stat(&function_key).await?.is_none() {
+                return Err(anyhow!("Function {} does not exist", function_id));
+            }
+            let replicas_key = format!("{}/replicas", &function_key);
+            let data = serde_json::to_vec(replicas)?;
+            match zk.set_data(&replicas_key, &data, None).await {
+                Ok(_) => {}
+                Err(zookeeper_client::Error::NoNode) => {
+                    zk.create(
+                        &replicas_key,
+                        &data,
+                        &zookeeper_client::CreateMode::Persistent
+                            .with_acls(zookeeper_client::Acls::anyone_all()),
+                    )
+                    .await
+                    .context("Error creating function replicas znode")?;
+                }
+                Err(e) => return Err(e).context("Error setting function replicas"),
+            }
+        }
+        Command::Maintenance {
+            function_id,
+            off,
+            body,
+            content_type,
+            retry_after,
+        } => {
+            update_config(&zk, function_id, |config| {
+                config.maintenance = if *off {
+                    None
+                } else {
+                    Some(Maintenance {
+                        body: body.clone(),
+                        content_type: content_type.clone(),
+                        retry_after: *retry_after,
+                    })
+                };
+            })
+            .await?;
+            info!(
+                "Function {} {} maintenance",
+                function_id,
+                if *off { "out of" } else { "in" }
+            );
+        }
+        Command::Capture {
+            function_id,
+            off,
+            sample_rate,
+            max_body_bytes,
+        } => {
+            if !(0.0..=1.0).contains(sample_rate) {
+                return Err(anyhow!("Sample rate must be between 0 and 1"));
+            }
+            update_config(&zk, function_id, |config| {
+                config.capture = (!*off).then_some(Capture {
+                    sample_rate: *sample_rate,
+                    max_body_bytes: *max_body_bytes,
+                });
+            })
+            .await?;
+            info!(
+                "Function {} capture {}",
+                function_id,
+                if *off { "stopped" } else { "started" }
+            );
+        }
+        Command::AccessLog {
+            function_id,
+            off,
+            sample_rate,
+        } => {
+            if !(0.0..=1.0).contains(sample_rate) {
+                return Err(anyhow!("Sample rate must be between 0 and 1"));
+            }
+            update_config(&zk, function_id, |config| {
+                config.access_log = Some(AccessLog {
+                    enabled: !*off,
+                    sample_rate: *sample_rate,
+                });
+            })
+            .await?;
+            info!(
+                "Function {} access logging {}",
+                function_id,
+                if *off { "disabled" } else { "enabled" }
+            );
+        }
+        Command::Warmup {
+            function_id,
+            off,
+            path,
+            requests,
+            timeout_ms,
+        } => {
+            update_config(&zk, function_id, |config| {
+                config.warmup = (!*off).then(|| Warmup {
+                    path: path.clone(),
+                    requests: *requests,
+                    timeout_ms: *timeout_ms,
+                });
+            })
+            .await?;
+            info!(
+                "Function {} warm-up {}",
+                function_id,
+                if *off { "disabled" } else { "enabled" }
+            );
+        }
+        Command::Split {
+            function_id,
+            canary,
+            percent,
+            off,
+            analyze,
+            window_secs,
+            min_requests,
+            max_error_rate_increase,
+            max_latency_ratio,
+            ramp_secs,
+            ramp_steps,
+            ramp_from,
+            on_regression,
+            resume,
+        } => {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs();
+            if *resume {
+                let mut resumed = None;
+                update_config(&zk, function_id, |config| {
+                    let ramp = config.split.as_mut().and_then(|s| s.ramp.as_mut());
+                    if let Some(ramp) = ramp.filter(|r| r.paused_at.is_some()) {
+                        let paused_at = ramp.paused_at.take().unwrap();
+                        ramp.started += now.saturating_sub(paused_at);
+                        resumed = Some(ramp.percent_at(now));
+                    }
+                })
+                .await?;
+                match resumed {
+                    Some(percent) => info!("Function {} ramp resumed at {}%", function_id, percent),
+                    None => return Err(anyhow!("Function {} has no paused ramp", function_id)),
+                }
+                return Ok(());
+            }
+            let percent = match ramp_secs {
+                Some(_) => Some(*ramp_from),
+                None => *percent,
+            };
+            let split = match (canary, percent) {
+                (Some(canary), Some(percent)) if !*off => {
+                    if !(0.0..=100.0).contains(&percent) {
+                        return Err(anyhow!("Percentage must be between 0 and 100"));
+                    }
+                    if *ramp_secs == Some(0) || *ramp_steps == 0 {
+                        return Err(anyhow!("Ramp duration and steps must be more than 0"));
+                    }
+                    Some(TrafficSplit {
+                        canary: *canary,
+                        // For frontends that don't know about ramps.
+                        percent,
+                        analysis: analyze.then(|| CanaryAnalysis {
+                            window_secs: *window_secs,
+                            min_requests: *min_requests,
+                            max_error_rate_increase: *max_error_rate_increase,
+                            max_latency_ratio: *max_latency_ratio,
+                        }),
+                        ramp: ramp_secs.map(|duration_secs| Ramp {
+                            started: now,
+                            duration_secs,
+                            steps: *ramp_steps,
+                            from_percent: *ramp_from,
+                            on_regression: *on_regression,
+                            paused_at: None,
+                        }),
+                    })
+                }
+                _ => None,
+            };
+            update_config(&zk, function_id, |config| {
+                config.split = split.clone();
+            })
+            .await?;
+            match split {
+                Some(TrafficSplit {
+                    canary,
+                    ramp: Some(ramp),
+                    ..
+                }) => info!(
+                    "Function {} ramping invocations to {} from {}% to 100% over {}s",
+                    function_id, canary, ramp.from_percent, ramp.duration_secs
+                ),
+                Some(split) => info!(
+                    "Function {} sending {}% of invocations to {}",
+                    function_id, split.percent, split.canary
+                ),
+                None => info!("Function {} split removed", function_id),
+            }
+        }
+        Command::RateLimit {
+            function_id,
+            requests,
+            off,
+            window_secs,
+            key_header,
+        } => {
+            let rate_limit = match requests {
+                Some(requests) if !*off => Some(RateLimit {
+                    requests: *requests,
+                    window_secs: *window_secs,
+                    key_header: key_header.clone(),
+                }),
+                _ => None,
+            };
+            update_config(&zk, function_id, |config| {
+                config.rate_limit = rate_limit.clone();
+            })
+            .await?;
+            match rate_limit {
+                Some(rate_limit) => info!(
+                    "Function {} limited to {} invocations per {}s",
+                    function_id, rate_limit.requests, rate_limit.window_secs
+                ),
+                None => info!("Function {} rate limit removed", function_id),
+            }
+        }
+        Command::ResponseMode { function_id, mode } => {
+            let response_mode = match mode.as_str() {
+                "default" => None,
+                mode => Some(mode.parse::<ResponseMode>().map_err(|e| anyhow!(e))?),
+            };
+            update_config(&zk, function_id, |config| {
+                config.response_mode = response_mode;
+            })
+            .await?;
+            info!("Function {} response mode set to {}", function_id, mode);
+        }
+        Command::Coalesce { function_id, off } => {
+            update_config(&zk, function_id, |config| {
+                config.coalesce_gets = !*off;
+            })
+            .await?;
+            info!(
+                "Function {} GET coalescing {}",
+                function_id,
+                if *off { "disabled" } else { "enabled" }
+            );
+        }
+        Command::Decompress { function_id, off } => {
+            update_config(&zk, function_id, |config| {
+                config.decompress_requests = !*off;
+            })
+            .await?;
+            info!(
+                "Function {} request decompression {}",
+                function_id,
+                if *off { "disabled" } else { "enabled" }
+            );
+        }
+        Command::Slo {
+            function_id,
+            availability,
+            latency_ms,
+            latency_target,
+            window_secs,
+            off,
+        } => {
+            let slo = (!*off).then(|| Slo {
+                availability: *availability,
+                latency: latency_ms
+                    .zip(*latency_target)
+                    .map(|(threshold_ms, target)| LatencyObjective {
+                        target,
+                        threshold_ms,
+                    }),
+                window_secs: *window_secs,
+            });
+            update_config(&zk, function_id, |config| {
+                config.slo = slo.clone();
+            })
+            .await?;
+            match slo {
+                Some(_) => info!("Function {} SLO set", function_id),
+                None => info!("Function {} SLO removed", function_id),
+            }
+        }
+        Command::RequestSchema {
+            function_id,
+            file,
+            methods,
+            path,
+            off,
+        } => {
+            let schema = match file {
+                Some(file) => {
+                    let schema = std::fs::read_to_string(file)
+                        .with_context(|| format!("Error reading {}", file.display()))?;
+                    Some(RequestSchema {
+                        methods: methods.clone(),
+                        path: path.clone(),
+                        schema: serde_json::from_str(&schema)
+                            .with_context(|| format!("Invalid JSON in {}", file.display()))?,
+                    })
+                }
+                None => None,
+            };
+            update_config(&zk, function_id, |config| match &schema {
+                // Checked against the first that applies, so a narrower schema added later
+                // takes precedence over broader ones.
+                Some(schema) => config.request_schemas.insert(0, schema.clone()),
+                None => config.request_schemas.clear(),
+            })
+            .await?;
+            match schema {
+                Some(_) => info!("Function {} request schema added", function_id),
+                None => info!("Function {} request schemas removed", function_id),
+            }
+        }
+        Command::Geo {
+            function_id,
+            allow,
+            deny,
+            status,
+            off,
+        } => {
+            if *status != 451 && *status != 403 {
+                return Err(anyhow!("--status must be 451 or 403"));
+            }
+            let geo = (!*off).then(|| GeoRestriction {
+                allow: allow.iter().map(|c| c.to_ascii_uppercase()).collect(),
+                deny: deny.iter().map(|c| c.to_ascii_uppercase()).collect(),
+                status: *status,
+            });
+            update_config(&zk, function_id, |config| {
+                config.geo = geo.clone();
+            })
+            .await?;
+            match geo {
+                Some(_) => info!("Function {} geo restriction set", function_id),
+                None => info!("Function {} geo restriction removed", function_id),
+            }
+        }
+        Command::Priority {
+            function_id,
+            priority,
+        } => {
+            let class = match priority.as_str() {
+                "default" => None,
+                priority => Some(priority.parse::<Priority>().map_err(|e| anyhow!(e))?),
+            };
+            update_config(&zk, function_id, |config| {
+                config.priority = class;
+            })
+            .await?;
+            info!("Function {} priority set to {}", function_id, priority);
+        }
+        Command::Replay { .. } => unreachable!("handled before connecting to ZooKeeper"),
     }
 
     Ok(())
@@ -1,6 +1,9 @@
 use std::io::Result;
 
 fn main() -> Result<()> {
-    prost_build::compile_protos(&["io.containerd.cgroups.v2.proto"], &["vendor/"])?;
+    prost_build::compile_protos(
+        &["io.containerd.cgroups.v2.proto", "grpc.health.v1.proto"],
+        &["vendor/"],
+    )?;
     Ok(())
 }
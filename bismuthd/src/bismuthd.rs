@@ -13,6 +13,7 @@ use prost_types::Any;
 use sentry::integrations::tower::{NewSentryLayer, SentryHttpLayer};
 use serde::Deserialize;
 use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::fs::File;
@@ -34,12 +35,13 @@ use containerd_client::with_namespace;
 
 use bismuth_common::{
     init_metrics, init_sentry, init_tracer, ApiError, ContainerState, InvokeMode,
-    OtelAxumMetricsLayer, BACKEND_PORT,
+    OtelAxumMetricsLayer, BACKEND_PORT, IN_FLIGHT_HEADER,
 };
 
 pub mod consts;
 pub mod container;
 pub mod container_manager;
+pub mod health;
 
 use consts::*;
 use container::SvcProviderOptions;
@@ -66,6 +68,26 @@ struct Cli {
     svcprovider_args: Vec<String>,
 }
 
+/// Counts an invocation as in flight for as long as it's held.
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl<'a> InFlight<'a> {
+    fn enter(count: &'a AtomicUsize) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        Self(count)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn in_flight(container: &container::Container) -> String {
+    container.in_flight.load(Ordering::Relaxed).to_string()
+}
+
 #[instrument(skip(container_manager, http_client, req))]
 #[axum::debug_handler]
 async fn invoke_path(
@@ -78,6 +100,7 @@ async fn invoke_path(
 ) -> Result<axum::response::Response, ApiError> {
     let container = container_manager.get_container(container_id).await?;
     let container = container.read().await;
+    let _in_flight = InFlight::enter(&container.in_flight);
 
     match &container.definition.invoke_mode {
         InvokeMode::Executable(args) => {
@@ -162,7 +185,11 @@ async fn invoke_path(
 
             let outf = File::open(outpath).await?;
             let reader_stream = tokio_util::io::ReaderStream::new(outf);
-            Ok(axum::body::boxed(axum::body::StreamBody::new(reader_stream)).into_response())
+            Ok((
+                [(IN_FLIGHT_HEADER, in_flight(&container))],
+                axum::body::boxed(axum::body::StreamBody::new(reader_stream)),
+            )
+                .into_response())
         }
         InvokeMode::Server(_, dport) => {
             event!(Level::TRACE, reqpath = %reqpath, container_id = %container.id, "Proxying request");
@@ -204,7 +231,9 @@ async fn invoke_path(
             })?;
             let mut axum_resp = axum::response::Response::builder().status(resp.status());
             *axum_resp.headers_mut().unwrap() = resp.headers().clone();
-            let axum_resp = axum_resp.header("X-Bismuth-Container-ID", container_id.to_string());
+            let axum_resp = axum_resp
+                .header("X-Bismuth-Container-ID", container_id.to_string())
+                .header(IN_FLIGHT_HEADER, in_flight(&container));
             let body = http_body::Body::map_err(resp.into_body(), axum::Error::new);
             Ok(axum_resp.body(axum::body::boxed(body))?)
         }
@@ -308,6 +337,7 @@ pub fn app() -> axum::Router<(
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    bismuth_common::init_scrubber()?;
     let mut _lockfile = fd_lock::RwLock::new(
         File::create(LOCKFILE_PATH)
             .await
@@ -329,7 +359,11 @@ async fn main() -> Result<()> {
     tracing_subscriber::registry()
         .with(tracing_opentelemetry::layer().with_tracer(tracer))
         .with(tracing_subscriber::EnvFilter::from_default_env())
-        .with(tracing_subscriber::fmt::layer())
+        .with(
+            tracing_subscriber::fmt::layer().with_writer(bismuth_common::Scrubbed(std::io::stdout)),
+        )
+        .with(bismuth_common::file_log_layer()?)
+        .with(bismuth_common::system_log_layers(env!("CARGO_PKG_NAME"))?)
         .init();
 
     let manager = ContainerManager::new(
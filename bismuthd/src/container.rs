@@ -6,9 +6,9 @@ use nix::libc::{kill, SIGKILL};
 use nix::unistd::getpid;
 use std::net::{Ipv4Addr, SocketAddrV4};
 use std::os::fd::AsRawFd;
+use std::sync::atomic::AtomicUsize;
 use std::time::Duration;
 use tokio::fs::File;
-use tokio::net::TcpStream;
 use tokio::time::sleep;
 use tracing::{event, Level};
 use uuid::Uuid;
@@ -24,6 +24,7 @@ use containerd_client::with_namespace;
 use bismuth_common::{ContainerState, FunctionDefinition, InvokeMode};
 
 use crate::consts::*;
+use crate::health;
 
 pub struct ContainerRoot {
     pub directory: std::path::PathBuf,
@@ -170,6 +171,9 @@ pub struct Container {
     pub node_data: ContainerNodeData,
 
     pub containerd: containerd_client::tonic::transport::Channel,
+
+    /// Invocations being handled, reported to the frontend with each response.
+    pub in_flight: AtomicUsize,
 }
 
 impl Drop for Container {
@@ -484,11 +488,11 @@ impl Container {
         };
         match self.definition.invoke_mode {
             InvokeMode::Executable { .. } => Ok(true),
-            InvokeMode::Server(_, dport) => {
-                Ok(TcpStream::connect(SocketAddrV4::new(runtime.ip, dport))
-                    .await
-                    .is_ok())
-            }
+            InvokeMode::Server(_, dport) => Ok(health::probe(
+                &self.definition.health_check,
+                SocketAddrV4::new(runtime.ip, dport),
+            )
+            .await),
         }
     }
 }
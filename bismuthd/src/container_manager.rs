@@ -10,6 +10,7 @@ use prost_types::Any;
 use rand::Rng;
 use std::collections::{HashMap, HashSet};
 use std::net::Ipv4Addr;
+use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 use tokio::sync::{Mutex, RwLock};
 use tokio::time::sleep;
@@ -724,6 +725,7 @@ impl ContainerManager {
                 runtime: None,
             },
             containerd: self.containerd.clone(),
+            in_flight: AtomicUsize::new(0),
         }));
         self.instance_map
             .write()
@@ -868,7 +870,7 @@ impl ContainerManager {
 
 #[cfg(test)]
 mod tests {
-    use bismuth_common::{pack_backends, Backend};
+    use bismuth_common::{pack_backends, Backend, HealthCheck};
     use std::{
         fmt::Display,
         path::{Path, PathBuf},
@@ -933,6 +935,7 @@ mod tests {
                     "master".to_string(),
                 )),
                 max_instances: 1,
+                health_check: HealthCheck::Tcp,
             },
             container_id,
         )
@@ -983,6 +986,7 @@ mod tests {
                 "master".to_string(),
             )),
             max_instances: 1,
+            health_check: HealthCheck::Tcp,
         };
 
         // Bootstrap ZK
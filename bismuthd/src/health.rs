@@ -0,0 +1,122 @@
+//! Readiness probes of server containers, as their function's `HealthCheck` says: a TCP connect,
+//! an HTTP GET, or the standard gRPC health-checking protocol for gRPC servers.
+
+use anyhow::{anyhow, Result};
+use http_body::Body as _;
+use hyper::body::Bytes;
+use hyper::Body;
+use prost::Message as _;
+use std::net::SocketAddrV4;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+use bismuth_common::HealthCheck;
+
+mod grpc_health_proto {
+    include!(concat!(env!("OUT_DIR"), "/grpc.health.v1.rs"));
+}
+
+use grpc_health_proto::{
+    health_check_response::ServingStatus, HealthCheckRequest, HealthCheckResponse,
+};
+
+/// How long a probe may take before the container counts as not ready yet.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+const GRPC_CHECK_PATH: &str = "/grpc.health.v1.Health/Check";
+
+/// Whether the server at `addr` is ready.
+pub async fn probe(check: &HealthCheck, addr: SocketAddrV4) -> bool {
+    let probe = async {
+        match check {
+            HealthCheck::Tcp => Ok(TcpStream::connect(addr).await.is_ok()),
+            HealthCheck::Http { path } => http(addr, path).await,
+            HealthCheck::Grpc { service } => grpc(addr, service).await,
+        }
+    };
+    matches!(
+        tokio::time::timeout(PROBE_TIMEOUT, probe).await,
+        Ok(Ok(true))
+    )
+}
+
+async fn http(addr: SocketAddrV4, path: &str) -> Result<bool> {
+    let req = hyper::Request::get(format!("http://{}{}", addr, path)).body(Body::empty())?;
+    let resp = hyper::Client::new().request(req).await?;
+    Ok(resp.status().is_success())
+}
+
+/// A gRPC message: a compressed flag (never set here), its length, and the message.
+fn encode_frame(message: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(5 + message.len());
+    frame.push(0);
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(message);
+    frame
+}
+
+fn decode_frame(body: &[u8]) -> Result<&[u8]> {
+    if body.len() < 5 {
+        return Err(anyhow!("Truncated gRPC message"));
+    }
+    if body[0] != 0 {
+        return Err(anyhow!("Compressed gRPC message"));
+    }
+    let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+    body[5..]
+        .get(..len)
+        .ok_or_else(|| anyhow!("Truncated gRPC message"))
+}
+
+async fn grpc(addr: SocketAddrV4, service: &str) -> Result<bool> {
+    let request = HealthCheckRequest {
+        service: service.to_string(),
+    };
+    let req = hyper::Request::post(format!("http://{}{}", addr, GRPC_CHECK_PATH))
+        .header(hyper::header::CONTENT_TYPE, "application/grpc")
+        .header(hyper::header::TE, "trailers")
+        .body(Body::from(encode_frame(&request.encode_to_vec())))?;
+    let resp = hyper::Client::builder()
+        .http2_only(true)
+        .build_http()
+        .request(req)
+        .await?;
+    if !resp.status().is_success() {
+        return Ok(false);
+    }
+    // Errors come as a trailers-only response, with the status in the headers.
+    let mut status = resp.headers().get("grpc-status").cloned();
+    let mut body = resp.into_body();
+    let mut message = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk: Bytes = chunk?;
+        message.extend_from_slice(&chunk);
+    }
+    if let Some(trailers) = body.trailers().await? {
+        status = status.or_else(|| trailers.get("grpc-status").cloned());
+    }
+    if status.as_ref().and_then(|s| s.to_str().ok()) != Some("0") {
+        return Ok(false);
+    }
+    let response = HealthCheckResponse::decode(decode_frame(&message)?)?;
+    Ok(response.status() == ServingStatus::Serving)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames() {
+        let response = HealthCheckResponse {
+            status: ServingStatus::Serving as i32,
+        };
+        let frame = encode_frame(&response.encode_to_vec());
+        assert_eq!(frame, [0, 0, 0, 0, 2, 0x08, 0x01]);
+        let decoded = HealthCheckResponse::decode(decode_frame(&frame).unwrap()).unwrap();
+        assert_eq!(decoded.status(), ServingStatus::Serving);
+
+        assert!(decode_frame(&frame[..4]).is_err());
+        assert!(decode_frame(&frame[..6]).is_err());
+        assert!(decode_frame(&[1, 0, 0, 0, 0]).is_err());
+    }
+}
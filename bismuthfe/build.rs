@@ -0,0 +1,43 @@
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Build information for `/version`. `GIT_SHA` and `SOURCE_DATE_EPOCH` override what's detected,
+/// for builds outside a git checkout and reproducible builds.
+fn main() {
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs");
+
+    let git_sha = std::env::var("GIT_SHA").ok().or_else(|| {
+        let output = Command::new("git")
+            .args(["rev-parse", "HEAD"])
+            .output()
+            .ok()?;
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    });
+    println!(
+        "cargo:rustc-env=BISMUTHFE_GIT_SHA={}",
+        git_sha.as_deref().unwrap_or("unknown")
+    );
+
+    let timestamp = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        });
+    println!("cargo:rustc-env=BISMUTHFE_BUILD_TIMESTAMP={}", timestamp);
+
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(k, _)| Some(k.strip_prefix("CARGO_FEATURE_")?.to_lowercase()))
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=BISMUTHFE_FEATURES={}", features.join(","));
+}
@@ -0,0 +1,320 @@
+//! Access logs and per-function request/response sizes, for attributing bandwidth to functions,
+//! and invocation records for `events`; invocations are counted for `alerts` too. All but the
+//! last are recorded once the response body has been sent (or
+//! the client has gone away), so streamed bodies are counted in full.
+//!
+//! Access log events have target `access` at `DEBUG`, so they're off by default and enabled with
+//! e.g. `RUST_LOG=info,access=debug`. Functions can turn them off for their invocations, or have
+//! only a sample logged, with an `AccessLog` in their config.
+
+use axum::extract::{ConnectInfo, Path, State};
+use axum::http::{Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use futures::StreamExt as _;
+use hyper::body::{Body, Buf as _, HttpBody, SizeHint};
+use opentelemetry::metrics::Histogram;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::{event, Level};
+use uuid::Uuid;
+
+use bismuth_common::{AccessLog, FunctionConfig};
+
+use crate::alerts::Alerts;
+use crate::events::{EventExporter, InvocationEvent, Upstream};
+use crate::{metric_labels, slo, BackendMonitor};
+
+pub struct InvocationSizes {
+    request: Histogram<u64>,
+    response: Histogram<u64>,
+}
+
+impl InvocationSizes {
+    pub fn new() -> Self {
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+        Self {
+            request: meter
+                .u64_histogram("invocation_request_size")
+                .with_description("Request body bytes received for an invocation")
+                .with_unit(opentelemetry::metrics::Unit::new("By"))
+                .init(),
+            response: meter
+                .u64_histogram("invocation_response_size")
+                .with_description("Response body bytes sent for an invocation")
+                .with_unit(opentelemetry::metrics::Unit::new("By"))
+                .init(),
+        }
+    }
+}
+
+impl Default for InvocationSizes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct AccessLogs {
+    sizes: Arc<InvocationSizes>,
+    /// For functions' `AccessLog`s. `None` with a dev backend, logging every invocation.
+    monitor: Option<Arc<BackendMonitor>>,
+    /// Records every invocation, whatever the function's `AccessLog`.
+    events: Option<Arc<EventExporter>>,
+    alerts: Option<Arc<Alerts>>,
+    slo: Arc<slo::SloTracker>,
+}
+
+impl AccessLogs {
+    pub fn new(
+        monitor: Option<Arc<BackendMonitor>>,
+        events: Option<EventExporter>,
+        alerts: Option<Arc<Alerts>>,
+        slo: Arc<slo::SloTracker>,
+    ) -> Self {
+        Self {
+            sizes: Arc::new(InvocationSizes::new()),
+            monitor,
+            events: events.map(Arc::new),
+            alerts,
+            slo,
+        }
+    }
+
+    /// The config of the function invoked, if it's loaded.
+    async fn config(&self, function_id: &str) -> Option<(Uuid, FunctionConfig)> {
+        let function_id = Uuid::parse_str(function_id).ok()?;
+        let config = self.monitor.as_ref()?.config(&function_id).await?;
+        Some((function_id, config))
+    }
+}
+
+/// Whether to log an invocation, given `roll` from 0 (inclusive) to 1.
+fn sampled(access_log: Option<&AccessLog>, roll: f64) -> bool {
+    match access_log {
+        Some(access_log) => access_log.enabled && roll < access_log.sample_rate,
+        None => true,
+    }
+}
+
+/// Recorded when dropped, along with the response body.
+struct Entry {
+    sizes: Arc<InvocationSizes>,
+    function_id: String,
+    method: Method,
+    path: String,
+    status: StatusCode,
+    client: SocketAddr,
+    start: Instant,
+    request_bytes: Arc<AtomicU64>,
+    response_bytes: u64,
+    /// Whether to emit the access log event, or just record the sizes.
+    log: bool,
+    /// With the invocation's record.
+    export: Option<(Arc<EventExporter>, Export)>,
+}
+
+/// What an invocation's record takes besides what it's logged with.
+struct Export {
+    arrived: SystemTime,
+    headers: std::time::Duration,
+    upstream: Option<Upstream>,
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        let request_bytes = self.request_bytes.load(Ordering::Relaxed);
+        let attrs = [metric_labels::function_str(&self.function_id)];
+        self.sizes.request.record(request_bytes, &attrs);
+        self.sizes.response.record(self.response_bytes, &attrs);
+        if let Some((events, export)) = self.export.take() {
+            events.record(InvocationEvent {
+                id: Uuid::new_v4(),
+                env: events.env().to_string(),
+                time: export
+                    .arrived
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64,
+                function_id: self.function_id.clone(),
+                version: export.upstream.as_ref().map(|u| u.version),
+                tenant: export.upstream.as_ref().and_then(|u| u.tenant.clone()),
+                method: self.method.to_string(),
+                status: self.status.as_u16(),
+                request_bytes,
+                response_bytes: self.response_bytes,
+                headers_ms: export.headers.as_millis() as u64,
+                backend_ms: export.upstream.map(|u| u.backend.as_millis() as u64),
+                total_ms: self.start.elapsed().as_millis() as u64,
+            });
+        }
+        if !self.log {
+            return;
+        }
+        event!(
+            target: "access",
+            Level::DEBUG,
+            function = %self.function_id,
+            method = %self.method,
+            path = %self.path,
+            status = self.status.as_u16(),
+            request_bytes,
+            response_bytes = self.response_bytes,
+            duration_ms = self.start.elapsed().as_millis() as u64,
+            client = %self.client,
+            "Invocation"
+        );
+    }
+}
+
+/// A response body that counts the bytes sent through it, passing trailers and size hints on.
+struct CountedBody<B> {
+    inner: B,
+    entry: Entry,
+}
+
+impl<B: HttpBody + Unpin> HttpBody for CountedBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_data(cx);
+        if let Poll::Ready(Some(Ok(data))) = &poll {
+            this.entry.response_bytes += data.remaining() as u64;
+        }
+        poll
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<axum::http::HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Route layer for the invocation routes.
+pub async fn middleware(
+    State(logs): State<Arc<AccessLogs>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Path(params): Path<HashMap<String, String>>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let (start, arrived) = (Instant::now(), SystemTime::now());
+    let request_bytes = Arc::new(AtomicU64::new(0));
+    let (parts, body) = req.into_parts();
+    let body = if body.is_end_stream() {
+        body
+    } else {
+        let counter = request_bytes.clone();
+        Body::wrap_stream(body.inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            }
+        }))
+    };
+    let method = parts.method.clone();
+    let path = parts.uri.path().to_string();
+
+    let (parts, body) = next
+        .run(Request::from_parts(parts, body))
+        .await
+        .into_parts();
+    let headers = start.elapsed();
+    let export = logs.events.clone().map(|events| {
+        let export = Export {
+            arrived,
+            headers,
+            upstream: parts.extensions.get::<Upstream>().cloned(),
+        };
+        (events, export)
+    });
+    let function_id = params.get("function_id").cloned().unwrap_or_default();
+    if let Some(alerts) = &logs.alerts {
+        let served = parts.extensions.get::<Upstream>().is_some();
+        alerts.record(&function_id, parts.status, served);
+    }
+    let config = logs.config(&function_id).await;
+    if let Some((id, Some(slo))) = config.as_ref().map(|(id, c)| (id, c.slo.as_ref())) {
+        let now = arrived.duration_since(UNIX_EPOCH).unwrap_or_default();
+        logs.slo
+            .record(*id, slo, parts.status, headers, now.as_secs());
+    }
+    let access_log = config.as_ref().and_then(|(_, c)| c.access_log.as_ref());
+    let entry = Entry {
+        sizes: logs.sizes.clone(),
+        log: sampled(access_log, rand::random()),
+        function_id,
+        method,
+        path,
+        status: parts.status,
+        client,
+        start,
+        request_bytes,
+        response_bytes: 0,
+        export,
+    };
+    Response::from_parts(parts, axum::body::boxed(CountedBody { inner: body, entry }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_counts_response_bytes() {
+        let request_bytes = Arc::new(AtomicU64::new(3));
+        let mut body = CountedBody {
+            inner: Body::from("hello"),
+            entry: Entry {
+                sizes: Arc::new(InvocationSizes::new()),
+                function_id: "f".to_string(),
+                method: Method::GET,
+                path: "/invoke/f/".to_string(),
+                status: StatusCode::OK,
+                client: "127.0.0.1:1234".parse().unwrap(),
+                start: Instant::now(),
+                request_bytes,
+                response_bytes: 0,
+                log: true,
+                export: None,
+            },
+        };
+        assert_eq!(body.size_hint().exact(), Some(5));
+        while body.data().await.is_some() {}
+        assert_eq!(body.entry.response_bytes, 5);
+    }
+
+    #[test]
+    fn test_sampled() {
+        assert!(sampled(None, 0.99));
+        let sampled_1pct = AccessLog {
+            enabled: true,
+            sample_rate: 0.01,
+        };
+        assert!(sampled(Some(&sampled_1pct), 0.005));
+        assert!(!sampled(Some(&sampled_1pct), 0.01));
+        let off = AccessLog {
+            enabled: false,
+            sample_rate: 1.0,
+        };
+        assert!(!sampled(Some(&off), 0.0));
+    }
+}
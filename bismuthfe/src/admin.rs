@@ -0,0 +1,1077 @@
+use anyhow::Context;
+use axum::extract::{Path, State};
+use axum::routing::{delete, get, post};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tracing::instrument;
+use utoipa::{OpenApi, ToSchema};
+use uuid::Uuid;
+
+use bismuth_common::{
+    add_write_draining, backends_path, pack_backends, prepare_backends, read_backends,
+    read_draining, ApiError, Backend, ContainerState, DrainingBackend, FeatureFlags,
+    FunctionConfig, FunctionDefinition, FunctionMetadata, GenericError, Priority, TenantToken,
+    TokenScope, BACKENDS_PER_CHUNK,
+};
+
+use crate::admin_auth::{self, AdminAuth};
+use crate::snapshot::{self, Snapshot};
+use crate::{breaker, drain, hash_ring, request_schema, route_check, slo};
+use crate::{tokens, FrontendState};
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub(crate) struct CreateFunction {
+    definition: FunctionDefinition,
+    #[serde(default)]
+    backends: Vec<Backend>,
+    #[serde(default)]
+    metadata: Option<FunctionMetadata>,
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn zk_error(e: zookeeper_client::Error) -> ApiError {
+    match e {
+        zookeeper_client::Error::NoNode => ApiError::NotFound,
+        zookeeper_client::Error::NodeExists => {
+            GenericError::Conflict("znode already exists".to_string()).into()
+        }
+        zookeeper_client::Error::BadVersion => {
+            GenericError::Conflict("concurrent modification".to_string()).into()
+        }
+        e => ApiError::Error(e.into()),
+    }
+}
+
+/// `zk_error`, for errors from reading or writing backend lists.
+fn backends_error(e: anyhow::Error) -> ApiError {
+    match e.downcast::<zookeeper_client::Error>() {
+        Ok(e) => zk_error(e),
+        Err(e) => ApiError::Error(e),
+    }
+}
+
+/// Check that every backend points at a provisioned, enabled node and that container IDs are unique.
+async fn validate_backends(
+    zk: &zookeeper_client::Client,
+    backends: &[Backend],
+) -> Result<(), ApiError> {
+    let mut container_ids = HashSet::new();
+    for backend in backends {
+        if !container_ids.insert(backend.container_id) {
+            return Err(GenericError::Invalid(format!(
+                "Duplicate container id {}",
+                backend.container_id
+            ))
+            .into());
+        }
+
+        match zk.get_data(&format!("/node/{}", backend.ip)).await {
+            Ok((data, _)) if data == vec![0u8] => {
+                return Err(GenericError::Invalid(format!("Node {} is drained", backend.ip)).into())
+            }
+            Ok(_) => {}
+            Err(zookeeper_client::Error::NoNode) => {
+                return Err(
+                    GenericError::Invalid(format!("Node {} not in cluster", backend.ip)).into(),
+                )
+            }
+            Err(e) => return Err(zk_error(e)),
+        }
+    }
+    Ok(())
+}
+
+fn add_container_creates(
+    multi: &mut zookeeper_client::MultiWriter<'_>,
+    function_id: &Uuid,
+    backend: &Backend,
+) -> Result<(), ApiError> {
+    multi.add_create(
+        &format!("/node/{}/container/{}", backend.ip, backend.container_id),
+        function_id.as_bytes(),
+        &zookeeper_client::CreateMode::Persistent.with_acls(zookeeper_client::Acls::anyone_all()),
+    )?;
+    multi.add_create(
+        &format!(
+            "/node/{}/container/{}/status",
+            backend.ip, backend.container_id
+        ),
+        &[ContainerState::Starting as u8],
+        &zookeeper_client::CreateMode::Persistent.with_acls(zookeeper_client::Acls::anyone_all()),
+    )?;
+    Ok(())
+}
+
+fn add_container_deletes(
+    multi: &mut zookeeper_client::MultiWriter<'_>,
+    backend: &Backend,
+) -> Result<(), ApiError> {
+    multi.add_delete(
+        &format!(
+            "/node/{}/container/{}/status",
+            backend.ip, backend.container_id
+        ),
+        None,
+    )?;
+    multi.add_delete(
+        &format!("/node/{}/container/{}", backend.ip, backend.container_id),
+        None,
+    )?;
+    Ok(())
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/function",
+    tag = "admin",
+    request_body = CreateFunction,
+    responses(
+        (status = 200, description = "The new function's ID", body = HashMap<String, String>),
+        (status = 400, description = "Invalid definition or backends"),
+    ),
+    security(("admin_token" = []))
+)]
+#[instrument(skip(state))]
+#[axum::debug_handler]
+async fn function_create(
+    State(state): State<Arc<FrontendState>>,
+    Json(create): Json<CreateFunction>,
+) -> Result<Json<HashMap<String, String>>, ApiError> {
+    create.definition.validate()?;
+    let zk = state.zk().await?;
+    validate_backends(&zk, &create.backends).await?;
+    if create.backends.len() > BACKENDS_PER_CHUNK {
+        return Err(GenericError::Invalid(format!(
+            "At most {} backends can be given on creation; set the rest afterwards",
+            BACKENDS_PER_CHUNK
+        ))
+        .into());
+    }
+
+    let function_id = Uuid::new_v4();
+    let mut multi = zk.new_multi_writer();
+    multi.add_create(
+        &format!("/function/{}", &function_id),
+        &serde_json::to_vec(&create.definition)?,
+        &zookeeper_client::CreateMode::Persistent.with_acls(zookeeper_client::Acls::anyone_all()),
+    )?;
+    multi.add_create(
+        &format!("/function/{}/backends", &function_id),
+        &pack_backends(&create.backends),
+        &zookeeper_client::CreateMode::Persistent.with_acls(zookeeper_client::Acls::anyone_all()),
+    )?;
+    if let Some(metadata) = &create.metadata {
+        multi.add_create(
+            &format!("/function/{}/metadata", &function_id),
+            &serde_json::to_vec(&FunctionMetadata {
+                created_at: unix_now(),
+                ..metadata.clone()
+            })?,
+            &zookeeper_client::CreateMode::Persistent
+                .with_acls(zookeeper_client::Acls::anyone_all()),
+        )?;
+    }
+    for backend in &create.backends {
+        add_container_creates(&mut multi, &function_id, backend)?;
+    }
+    multi
+        .commit()
+        .await
+        .context("Error creating function znodes")?;
+
+    let mut res = HashMap::new();
+    res.insert("id".to_string(), function_id.to_string());
+    Ok(Json(res))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/function/{function_id}",
+    tag = "admin",
+    params(("function_id" = Uuid, Path, description = "Function ID")),
+    responses(
+        (status = 200, body = FunctionDefinition),
+        (status = 404, description = "No such function"),
+    ),
+    security(("admin_token" = []))
+)]
+#[instrument(skip(state))]
+#[axum::debug_handler]
+async fn function_get(
+    State(state): State<Arc<FrontendState>>,
+    Path(function_id): Path<Uuid>,
+) -> Result<Json<FunctionDefinition>, ApiError> {
+    let zk = state.zk().await?;
+    let (data, _) = zk
+        .get_data(&format!("/function/{}", &function_id))
+        .await
+        .map_err(zk_error)?;
+    Ok(Json(serde_json::from_slice(&data)?))
+}
+
+#[utoipa::path(
+    put,
+    path = "/admin/function/{function_id}",
+    tag = "admin",
+    params(("function_id" = Uuid, Path, description = "Function ID")),
+    request_body = FunctionDefinition,
+    responses(
+        (status = 200, description = "Updated"),
+        (status = 400, description = "Invalid definition"),
+        (status = 404, description = "No such function"),
+    ),
+    security(("admin_token" = []))
+)]
+#[instrument(skip(state))]
+#[axum::debug_handler]
+async fn function_update(
+    State(state): State<Arc<FrontendState>>,
+    Path(function_id): Path<Uuid>,
+    Json(definition): Json<FunctionDefinition>,
+) -> Result<(), ApiError> {
+    definition.validate()?;
+    let zk = state.zk().await?;
+    zk.set_data(
+        &format!("/function/{}", &function_id),
+        &serde_json::to_vec(&definition)?,
+        None,
+    )
+    .await
+    .map_err(zk_error)?;
+    Ok(())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/function/{function_id}",
+    tag = "admin",
+    params(("function_id" = Uuid, Path, description = "Function ID")),
+    responses(
+        (status = 200, description = "Deleted"),
+        (status = 404, description = "No such function"),
+        (status = 409, description = "The function still has backends"),
+    ),
+    security(("admin_token" = []))
+)]
+#[instrument(skip(state))]
+#[axum::debug_handler]
+async fn function_delete(
+    State(state): State<Arc<FrontendState>>,
+    Path(function_id): Path<Uuid>,
+) -> Result<(), ApiError> {
+    let zk = state.zk().await?;
+    let backends_key = backends_path(&function_id);
+    let (backends, version) = read_backends(&zk, &function_id)
+        .await
+        .map_err(backends_error)?;
+    if !backends.is_empty() {
+        return Err(GenericError::Conflict(format!(
+            "Function {} still has {} backends",
+            function_id,
+            backends.len()
+        ))
+        .into());
+    }
+
+    let function_key = format!("/function/{}", &function_id);
+    let mut multi = zk.new_multi_writer();
+    // Chunks left behind by failed writes of long lists.
+    for chunk in zk.list_children(&backends_key).await.map_err(zk_error)? {
+        multi.add_delete(&format!("{}/{}", backends_key, chunk), None)?;
+    }
+    multi.add_delete(&backends_key, Some(version.version))?;
+    // Optional per-function znodes (metadata, replicas, ...).
+    for child in zk.list_children(&function_key).await.map_err(zk_error)? {
+        if child != "backends" {
+            multi.add_delete(&format!("{}/{}", function_key, child), None)?;
+        }
+    }
+    multi.add_delete(&function_key, None)?;
+    multi.commit().await.context("Error deleting function")?;
+    Ok(())
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/function/{function_id}/backends",
+    tag = "admin",
+    params(("function_id" = Uuid, Path, description = "Function ID")),
+    responses(
+        (status = 200, body = Vec<Backend>),
+        (status = 404, description = "No such function"),
+    ),
+    security(("admin_token" = []))
+)]
+#[instrument(skip(state))]
+#[axum::debug_handler]
+async fn backends_get(
+    State(state): State<Arc<FrontendState>>,
+    Path(function_id): Path<Uuid>,
+) -> Result<Json<Vec<Backend>>, ApiError> {
+    let zk = state.zk().await?;
+    let (backends, _) = read_backends(&zk, &function_id)
+        .await
+        .map_err(backends_error)?;
+    Ok(Json(backends))
+}
+
+/// Replace the function's backend list, creating/removing the corresponding container znodes
+/// so the affected bismuthd nodes start or stop containers.
+#[utoipa::path(
+    put,
+    path = "/admin/function/{function_id}/backends",
+    tag = "admin",
+    params(("function_id" = Uuid, Path, description = "Function ID")),
+    request_body = Vec<Backend>,
+    responses(
+        (status = 200, description = "Updated"),
+        (status = 400, description = "Invalid backends"),
+        (status = 404, description = "No such function"),
+        (status = 409, description = "Concurrent modification"),
+    ),
+    security(("admin_token" = []))
+)]
+#[instrument(skip(state))]
+#[axum::debug_handler]
+async fn backends_set(
+    State(state): State<Arc<FrontendState>>,
+    Path(function_id): Path<Uuid>,
+    Json(backends): Json<Vec<Backend>>,
+) -> Result<(), ApiError> {
+    let zk = state.zk().await?;
+    validate_backends(&zk, &backends).await?;
+
+    let (old, version) = read_backends(&zk, &function_id)
+        .await
+        .map_err(backends_error)?;
+
+    let prepared = prepare_backends(&zk, &function_id, &backends).await?;
+    let mut multi = zk.new_multi_writer();
+    multi.add_set_data(
+        &backends_path(&function_id),
+        &prepared.data,
+        Some(version.version),
+    )?;
+    for backend in old.iter().filter(|b| !backends.contains(b)) {
+        add_container_deletes(&mut multi, backend)?;
+    }
+    for backend in backends.iter().filter(|b| !old.contains(b)) {
+        add_container_creates(&mut multi, &function_id, backend)?;
+    }
+    match multi.commit().await {
+        Ok(_) => prepared.finish(&zk, version).await,
+        Err(e) => {
+            prepared.abandon(&zk).await;
+            return Err(anyhow::Error::from(e)
+                .context("Error updating function backends")
+                .into());
+        }
+    }
+    Ok(())
+}
+
+/// Mark a backend draining, so frontends stop sending it new invocations; with `--drain-secs`,
+/// the scheduler removes it once it's been draining that long.
+#[utoipa::path(
+    post,
+    path = "/admin/function/{function_id}/draining/{container_id}",
+    tag = "admin",
+    params(
+        ("function_id" = Uuid, Path, description = "Function ID"),
+        ("container_id" = Uuid, Path, description = "Backend container ID"),
+    ),
+    responses(
+        (status = 200, description = "Draining"),
+        (status = 404, description = "No such function or backend"),
+    ),
+    security(("admin_token" = []))
+)]
+#[instrument(skip(state))]
+async fn backend_drain(
+    State(state): State<Arc<FrontendState>>,
+    Path((function_id, container_id)): Path<(Uuid, Uuid)>,
+) -> Result<(), ApiError> {
+    set_draining(&state, &function_id, &container_id, true).await
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/function/{function_id}/draining/{container_id}",
+    tag = "admin",
+    params(
+        ("function_id" = Uuid, Path, description = "Function ID"),
+        ("container_id" = Uuid, Path, description = "Backend container ID"),
+    ),
+    responses(
+        (status = 200, description = "No longer draining"),
+        (status = 404, description = "No such function"),
+    ),
+    security(("admin_token" = []))
+)]
+#[instrument(skip(state))]
+async fn backend_undrain(
+    State(state): State<Arc<FrontendState>>,
+    Path((function_id, container_id)): Path<(Uuid, Uuid)>,
+) -> Result<(), ApiError> {
+    set_draining(&state, &function_id, &container_id, false).await
+}
+
+async fn set_draining(
+    state: &FrontendState,
+    function_id: &Uuid,
+    container_id: &Uuid,
+    draining: bool,
+) -> Result<(), ApiError> {
+    let zk = state.zk().await?;
+    let (backends, _) = read_backends(&zk, function_id)
+        .await
+        .map_err(backends_error)?;
+    if draining && !backends.iter().any(|b| b.container_id == *container_id) {
+        return Err(ApiError::NotFound);
+    }
+    let (mut marked, version) = read_draining(&zk, function_id)
+        .await
+        .map_err(backends_error)?;
+    if marked.iter().any(|d| d.container_id == *container_id) == draining {
+        return Ok(());
+    }
+    marked.retain(|d| d.container_id != *container_id);
+    if draining {
+        marked.push(DrainingBackend {
+            container_id: *container_id,
+            since: unix_now(),
+        });
+    }
+    let mut multi = zk.new_multi_writer();
+    add_write_draining(&mut multi, function_id, &marked, version)?;
+    multi
+        .commit()
+        .await
+        .context("Error updating draining backends")?;
+    Ok(())
+}
+
+/// Functions created without metadata have the default (empty) metadata.
+#[utoipa::path(
+    get,
+    path = "/admin/function/{function_id}/metadata",
+    tag = "admin",
+    params(("function_id" = Uuid, Path, description = "Function ID")),
+    responses(
+        (status = 200, body = FunctionMetadata),
+        (status = 404, description = "No such function"),
+    ),
+    security(("admin_token" = []))
+)]
+#[instrument(skip(state))]
+#[axum::debug_handler]
+async fn metadata_get(
+    State(state): State<Arc<FrontendState>>,
+    Path(function_id): Path<Uuid>,
+) -> Result<Json<FunctionMetadata>, ApiError> {
+    let zk = state.zk().await?;
+    match zk
+        .get_data(&format!("/function/{}/metadata", &function_id))
+        .await
+    {
+        Ok((data, _)) => Ok(Json(serde_json::from_slice(&data)?)),
+        Err(zookeeper_client::Error::NoNode) => {
+            zk.check_stat(&format!("/function/{}", &function_id))
+                .await
+                .map_err(zk_error)?
+                .ok_or(ApiError::NotFound)?;
+            Ok(Json(FunctionMetadata::default()))
+        }
+        Err(e) => Err(zk_error(e)),
+    }
+}
+
+/// Replace the function's metadata. `created_at` is kept from the existing metadata (or set now),
+/// whatever the client sends.
+#[utoipa::path(
+    put,
+    path = "/admin/function/{function_id}/metadata",
+    tag = "admin",
+    params(("function_id" = Uuid, Path, description = "Function ID")),
+    request_body = FunctionMetadata,
+    responses(
+        (status = 200, description = "Updated"),
+        (status = 404, description = "No such function"),
+    ),
+    security(("admin_token" = []))
+)]
+#[instrument(skip(state))]
+#[axum::debug_handler]
+async fn metadata_set(
+    State(state): State<Arc<FrontendState>>,
+    Path(function_id): Path<Uuid>,
+    Json(metadata): Json<FunctionMetadata>,
+) -> Result<(), ApiError> {
+    let zk = state.zk().await?;
+    let metadata_key = format!("/function/{}/metadata", &function_id);
+    match zk.get_data(&metadata_key).await {
+        Ok((data, stat)) => {
+            let old: FunctionMetadata = serde_json::from_slice(&data)?;
+            let metadata = FunctionMetadata {
+                created_at: old.created_at,
+                ..metadata
+            };
+            zk.set_data(
+                &metadata_key,
+                &serde_json::to_vec(&metadata)?,
+                Some(stat.version),
+            )
+            .await
+            .map_err(zk_error)?;
+        }
+        Err(zookeeper_client::Error::NoNode) => {
+            let metadata = FunctionMetadata {
+                created_at: unix_now(),
+                ..metadata
+            };
+            // Fails with NoNode (404) if the function itself doesn't exist.
+            zk.create(
+                &metadata_key,
+                &serde_json::to_vec(&metadata)?,
+                &zookeeper_client::CreateMode::Persistent
+                    .with_acls(zookeeper_client::Acls::anyone_all()),
+            )
+            .await
+            .map_err(zk_error)?;
+        }
+        Err(e) => return Err(zk_error(e)),
+    }
+    Ok(())
+}
+
+/// Functions without a config znode have the default config.
+#[utoipa::path(
+    get,
+    path = "/admin/function/{function_id}/config",
+    tag = "admin",
+    params(("function_id" = Uuid, Path, description = "Function ID")),
+    responses(
+        (status = 200, body = FunctionConfig),
+        (status = 404, description = "No such function"),
+    ),
+    security(("admin_token" = []))
+)]
+#[instrument(skip(state))]
+#[axum::debug_handler]
+async fn config_get(
+    State(state): State<Arc<FrontendState>>,
+    Path(function_id): Path<Uuid>,
+) -> Result<Json<FunctionConfig>, ApiError> {
+    let zk = state.zk().await?;
+    match zk
+        .get_data(&format!("/function/{}/config", &function_id))
+        .await
+    {
+        Ok((data, _)) => Ok(Json(serde_json::from_slice(&data)?)),
+        Err(zookeeper_client::Error::NoNode) => {
+            zk.check_stat(&format!("/function/{}", &function_id))
+                .await
+                .map_err(zk_error)?
+                .ok_or(ApiError::NotFound)?;
+            Ok(Json(FunctionConfig::default()))
+        }
+        Err(e) => Err(zk_error(e)),
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/admin/function/{function_id}/config",
+    tag = "admin",
+    params(("function_id" = Uuid, Path, description = "Function ID")),
+    request_body = FunctionConfig,
+    responses(
+        (status = 200, description = "Updated"),
+        (status = 400, description = "A request schema isn't a valid JSON Schema, or the geo status isn't 403 or 451"),
+        (status = 404, description = "No such function"),
+    ),
+    security(("admin_token" = []))
+)]
+#[instrument(skip(state))]
+#[axum::debug_handler]
+async fn config_set(
+    State(state): State<Arc<FrontendState>>,
+    Path(function_id): Path<Uuid>,
+    Json(config): Json<FunctionConfig>,
+) -> Result<(), ApiError> {
+    if let Some(geo) = &config.geo {
+        if geo.status != 403 && geo.status != 451 {
+            return Err(GenericError::Invalid("geo status must be 403 or 451".to_string()).into());
+        }
+    }
+    for (i, schema) in config.request_schemas.iter().enumerate() {
+        request_schema::compile(&schema.schema).map_err(|e| {
+            GenericError::Invalid(format!("Request schema {} is invalid: {}", i, e))
+        })?;
+    }
+    let zk = state.zk().await?;
+    let config_key = format!("/function/{}/config", &function_id);
+    let config = serde_json::to_vec(&config)?;
+    match zk.set_data(&config_key, &config, None).await {
+        Ok(_) => {}
+        Err(zookeeper_client::Error::NoNode) => {
+            // Fails with NoNode (404) if the function itself doesn't exist.
+            zk.create(
+                &config_key,
+                &config,
+                &zookeeper_client::CreateMode::Persistent
+                    .with_acls(zookeeper_client::Acls::anyone_all()),
+            )
+            .await
+            .map_err(zk_error)?;
+        }
+        Err(e) => return Err(zk_error(e)),
+    }
+    Ok(())
+}
+
+/// No flags are set until the znode is written.
+#[utoipa::path(
+    get,
+    path = "/admin/featureflags",
+    tag = "admin",
+    responses(
+        (status = 200, body = FeatureFlags),
+    ),
+    security(("admin_token" = []))
+)]
+#[instrument(skip(state))]
+#[axum::debug_handler]
+async fn flags_get(
+    State(state): State<Arc<FrontendState>>,
+) -> Result<Json<FeatureFlags>, ApiError> {
+    let zk = state.zk().await?;
+    match zk.get_data("/config/featureflags").await {
+        Ok((data, _)) => Ok(Json(serde_json::from_slice(&data)?)),
+        Err(zookeeper_client::Error::NoNode) => Ok(Json(FeatureFlags::default())),
+        Err(e) => Err(zk_error(e)),
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/admin/featureflags",
+    tag = "admin",
+    request_body = FeatureFlags,
+    responses(
+        (status = 200, description = "Updated"),
+    ),
+    security(("admin_token" = []))
+)]
+#[instrument(skip(state))]
+#[axum::debug_handler]
+async fn flags_set(
+    State(state): State<Arc<FrontendState>>,
+    Json(flags): Json<FeatureFlags>,
+) -> Result<(), ApiError> {
+    let zk = state.zk().await?;
+    let flags = serde_json::to_vec(&flags)?;
+    match zk.set_data("/config/featureflags", &flags, None).await {
+        Ok(_) => {}
+        Err(zookeeper_client::Error::NoNode) => {
+            let mode = zookeeper_client::CreateMode::Persistent
+                .with_acls(zookeeper_client::Acls::anyone_all());
+            match zk.create("/config", &b""[..], &mode).await {
+                Ok(_) | Err(zookeeper_client::Error::NodeExists) => {}
+                Err(e) => return Err(zk_error(e)),
+            }
+            zk.create("/config/featureflags", &flags, &mode)
+                .await
+                .map_err(zk_error)?;
+        }
+        Err(e) => return Err(zk_error(e)),
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/snapshot",
+    tag = "admin",
+    responses(
+        (status = 200, body = Snapshot),
+    ),
+    security(("admin_token" = []))
+)]
+#[instrument(skip(state))]
+#[axum::debug_handler]
+async fn snapshot_export(
+    State(state): State<Arc<FrontendState>>,
+) -> Result<Json<Snapshot>, ApiError> {
+    let zk = state.zk().await?;
+    Ok(Json(snapshot::export(&zk).await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/snapshot",
+    tag = "admin",
+    request_body = Snapshot,
+    responses(
+        (status = 200, description = "Imported"),
+        (status = 400, description = "Invalid definition"),
+    ),
+    security(("admin_token" = []))
+)]
+#[instrument(skip(state, snapshot))]
+#[axum::debug_handler]
+async fn snapshot_import(
+    State(state): State<Arc<FrontendState>>,
+    Json(snapshot): Json<Snapshot>,
+) -> Result<(), ApiError> {
+    for function in snapshot.functions.values() {
+        function.definition.validate()?;
+    }
+    let zk = state.zk().await?;
+    snapshot::import(&zk, &snapshot).await?;
+    Ok(())
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub(crate) struct MintToken {
+    scope: TokenScope,
+    /// Priority of invocations with the token, over their functions' own.
+    #[serde(default)]
+    priority: Option<Priority>,
+}
+
+/// A token as listed: everything but its secret hash.
+#[derive(Serialize, Debug, ToSchema)]
+pub(crate) struct TokenInfo {
+    id: Uuid,
+    tenant: String,
+    scope: TokenScope,
+    created_at: u64,
+    rotated_at: Option<u64>,
+    priority: Option<Priority>,
+}
+
+impl TokenInfo {
+    fn new(id: Uuid, token: TenantToken) -> Self {
+        Self {
+            id,
+            tenant: token.tenant,
+            scope: token.scope,
+            created_at: token.created_at,
+            rotated_at: token.rotated_at,
+            priority: token.priority,
+        }
+    }
+}
+
+/// Only returned when minting or rotating: the secret isn't stored.
+#[derive(Serialize, Debug, ToSchema)]
+pub(crate) struct TokenSecret {
+    id: Uuid,
+    /// Sent by the tenant as `X-Bismuth-Token`.
+    token: String,
+}
+
+fn token_key(token_id: &Uuid) -> String {
+    format!("{}/{}", tokens::TOKENS_PATH, token_id)
+}
+
+fn new_secret() -> Result<(String, String), ApiError> {
+    let secret = tokens::generate_secret()
+        .map_err(|_| ApiError::Error(anyhow::anyhow!("Error generating token")))?;
+    let hash = tokens::hash_secret(&secret);
+    Ok((secret, hash))
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/tenant/{tenant}/token",
+    tag = "admin",
+    params(("tenant" = String, Path, description = "Tenant name")),
+    request_body = MintToken,
+    responses(
+        (status = 200, description = "The new token, which can't be retrieved again", body = TokenSecret),
+        (status = 400, description = "Invalid tenant name"),
+    ),
+    security(("admin_token" = []))
+)]
+#[instrument(skip(state))]
+#[axum::debug_handler]
+async fn token_mint(
+    State(state): State<Arc<FrontendState>>,
+    Path(tenant): Path<String>,
+    Json(mint): Json<MintToken>,
+) -> Result<Json<TokenSecret>, ApiError> {
+    if tenant.is_empty() || tenant.len() > 128 {
+        return Err(GenericError::Invalid("Tenant name must be 1 to 128 bytes".to_string()).into());
+    }
+    let zk = state.zk().await?;
+    let (secret, secret_hash) = new_secret()?;
+    let token_id = Uuid::new_v4();
+    let token = TenantToken {
+        tenant,
+        secret_hash,
+        scope: mint.scope,
+        created_at: unix_now(),
+        rotated_at: None,
+        priority: mint.priority,
+    };
+    let mode =
+        zookeeper_client::CreateMode::Persistent.with_acls(zookeeper_client::Acls::anyone_all());
+    match zk.create(tokens::TOKENS_PATH, &b""[..], &mode).await {
+        Ok(_) | Err(zookeeper_client::Error::NodeExists) => {}
+        Err(e) => return Err(zk_error(e)),
+    }
+    zk.create(&token_key(&token_id), &serde_json::to_vec(&token)?, &mode)
+        .await
+        .map_err(zk_error)?;
+    Ok(Json(TokenSecret {
+        id: token_id,
+        token: secret,
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/tenant/{tenant}/token",
+    tag = "admin",
+    params(("tenant" = String, Path, description = "Tenant name")),
+    responses(
+        (status = 200, body = Vec<TokenInfo>),
+    ),
+    security(("admin_token" = []))
+)]
+#[instrument(skip(state))]
+#[axum::debug_handler]
+async fn token_list(
+    State(state): State<Arc<FrontendState>>,
+    Path(tenant): Path<String>,
+) -> Result<Json<Vec<TokenInfo>>, ApiError> {
+    let zk = state.zk().await?;
+    let ids = match zk.list_children(tokens::TOKENS_PATH).await {
+        Ok(ids) => ids,
+        Err(zookeeper_client::Error::NoNode) => Vec::new(),
+        Err(e) => return Err(zk_error(e)),
+    };
+    let mut listed = Vec::new();
+    for id in ids {
+        let Ok(token_id) = Uuid::parse_str(&id) else {
+            continue;
+        };
+        let data = match zk.get_data(&token_key(&token_id)).await {
+            Ok((data, _)) => data,
+            Err(zookeeper_client::Error::NoNode) => continue,
+            Err(e) => return Err(zk_error(e)),
+        };
+        let token: TenantToken = serde_json::from_slice(&data)
+            .with_context(|| format!("Invalid tenant token {}", token_id))?;
+        if token.tenant == tenant {
+            listed.push(TokenInfo::new(token_id, token));
+        }
+    }
+    listed.sort_by_key(|t| t.created_at);
+    Ok(Json(listed))
+}
+
+/// Replaces the token's secret, keeping its ID, tenant and scope. The old secret stops working
+/// as soon as frontends have seen the change.
+#[utoipa::path(
+    post,
+    path = "/admin/token/{token_id}/rotate",
+    tag = "admin",
+    params(("token_id" = Uuid, Path, description = "Token ID")),
+    responses(
+        (status = 200, description = "The new secret", body = TokenSecret),
+        (status = 404, description = "No such token"),
+        (status = 409, description = "Changed concurrently"),
+    ),
+    security(("admin_token" = []))
+)]
+#[instrument(skip(state))]
+#[axum::debug_handler]
+async fn token_rotate(
+    State(state): State<Arc<FrontendState>>,
+    Path(token_id): Path<Uuid>,
+) -> Result<Json<TokenSecret>, ApiError> {
+    let zk = state.zk().await?;
+    let key = token_key(&token_id);
+    let (data, stat) = zk.get_data(&key).await.map_err(zk_error)?;
+    let mut token: TenantToken = serde_json::from_slice(&data)
+        .with_context(|| format!("Invalid tenant token {}", token_id))?;
+    let (secret, secret_hash) = new_secret()?;
+    token.secret_hash = secret_hash;
+    token.rotated_at = Some(unix_now());
+    zk.set_data(&key, &serde_json::to_vec(&token)?, Some(stat.version))
+        .await
+        .map_err(zk_error)?;
+    Ok(Json(TokenSecret {
+        id: token_id,
+        token: secret,
+    }))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/token/{token_id}",
+    tag = "admin",
+    params(("token_id" = Uuid, Path, description = "Token ID")),
+    responses(
+        (status = 200, description = "Revoked"),
+        (status = 404, description = "No such token"),
+    ),
+    security(("admin_token" = []))
+)]
+#[instrument(skip(state))]
+#[axum::debug_handler]
+async fn token_revoke(
+    State(state): State<Arc<FrontendState>>,
+    Path(token_id): Path<Uuid>,
+) -> Result<(), ApiError> {
+    let zk = state.zk().await?;
+    zk.delete(&token_key(&token_id), None)
+        .await
+        .map_err(zk_error)?;
+    Ok(())
+}
+
+/// The admin API's part of the OpenAPI document.
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        function_create,
+        function_get,
+        function_update,
+        function_delete,
+        backends_get,
+        backends_set,
+        metadata_get,
+        metadata_set,
+        config_get,
+        config_set,
+        flags_get,
+        flags_set,
+        snapshot_export,
+        snapshot_import,
+        token_mint,
+        token_list,
+        token_rotate,
+        token_revoke,
+        hash_ring::ring_handler,
+        route_check::route_check_handler,
+        drain::draining_handler,
+        backend_drain,
+        backend_undrain,
+        breaker::breakers_handler,
+        breaker::breaker_trip,
+        breaker::breaker_reset,
+        slo::slo_handler,
+    ),
+    components(schemas(
+        CreateFunction,
+        FunctionDefinition,
+        bismuth_common::HealthCheck,
+        FunctionConfig,
+        FunctionMetadata,
+        bismuth_common::Maintenance,
+        bismuth_common::Capture,
+        bismuth_common::AccessLog,
+        Priority,
+        bismuth_common::ResponseMode,
+        bismuth_common::Warmup,
+        bismuth_common::TrafficSplit,
+        bismuth_common::CanaryAnalysis,
+        bismuth_common::Ramp,
+        bismuth_common::RampAction,
+        bismuth_common::RateLimit,
+        bismuth_common::GeoRestriction,
+        bismuth_common::Slo,
+        bismuth_common::LatencyObjective,
+        bismuth_common::RequestSchema,
+        FeatureFlags,
+        bismuth_common::FeatureFlag,
+        Backend,
+        Snapshot,
+        snapshot::FunctionSnapshot,
+        MintToken,
+        TokenInfo,
+        TokenSecret,
+        TokenScope,
+        hash_ring::RingPlacement,
+        hash_ring::BackendShare,
+        route_check::RouteCheck,
+        drain::DrainStatus,
+        breaker::BreakerStatus,
+        slo::SloStatus,
+        slo::ObjectiveStatus,
+        slo::BurnRate,
+    ))
+)]
+pub(crate) struct ApiDoc;
+
+/// Function provisioning endpoints, so tooling doesn't need to write to ZooKeeper directly, and
+/// debugging ones. Every route requires `Authorization: Bearer <token>` with a role allowed to
+/// use it (see `admin_auth`).
+pub fn app(auth: Arc<AdminAuth>) -> axum::Router<Arc<FrontendState>> {
+    axum::Router::new()
+        .route("/admin/function", post(function_create))
+        .route(
+            "/admin/function/:function_id",
+            get(function_get)
+                .put(function_update)
+                .delete(function_delete),
+        )
+        .route(
+            "/admin/function/:function_id/backends",
+            get(backends_get).put(backends_set),
+        )
+        .route(
+            "/admin/function/:function_id/draining",
+            get(drain::draining_handler),
+        )
+        .route(
+            "/admin/function/:function_id/draining/:container_id",
+            post(backend_drain).delete(backend_undrain),
+        )
+        .route(
+            "/admin/function/:function_id/breakers",
+            get(breaker::breakers_handler),
+        )
+        .route(
+            "/admin/function/:function_id/breakers/:container_id",
+            post(breaker::breaker_trip).delete(breaker::breaker_reset),
+        )
+        .route(
+            "/admin/function/:function_id/metadata",
+            get(metadata_get).put(metadata_set),
+        )
+        .route(
+            "/admin/function/:function_id/config",
+            get(config_get).put(config_set),
+        )
+        .route("/admin/function/:function_id/slo", get(slo::slo_handler))
+        .route("/admin/featureflags", get(flags_get).put(flags_set))
+        .route("/admin/route-check", get(route_check::route_check_handler))
+        .route(
+            "/admin/snapshot",
+            get(snapshot_export).post(snapshot_import),
+        )
+        .route(
+            "/admin/tenant/:tenant/token",
+            get(token_list).post(token_mint),
+        )
+        .route("/admin/token/:token_id", delete(token_revoke))
+        .route("/admin/token/:token_id/rotate", post(token_rotate))
+        .route("/debug/ring/:function_id", get(hash_ring::ring_handler))
+        .route_layer(axum::middleware::from_fn_with_state(
+            auth,
+            admin_auth::middleware,
+        ))
+}
@@ -0,0 +1,635 @@
+//! Authentication and role-based authorization for the admin API, separate from invocation auth.
+//! Every request carries `Authorization: Bearer <token>`, where the token is either static (the
+//! `--admin-token`, an admin, or an `--admin-role-token`) or an OIDC token: a JWT from
+//! `--admin-oidc-issuer` for `--admin-oidc-audience`, signed (RS256 or ES256) with one of the keys
+//! in the `--admin-oidc-jwks` file, whose `--admin-oidc-role-claim` values are mapped to roles by
+//! `--admin-oidc-role`. The JWKS file is read again every minute, so keys can be rotated by
+//! replacing it.
+//!
+//! Roles, each allowed what the ones before it are:
+//! * `read-only`: every `GET`
+//! * `operator`: operational changes: function configs (e.g. maintenance, splits, rate limits),
+//!   feature flags, draining backends and their circuit breakers
+//! * `admin`: provisioning: functions, their backends and metadata, snapshots and tenant tokens
+//!
+//! Unauthenticated requests get a 401 and ones needing a higher role a 403. Changes are logged
+//! with who made them.
+
+use axum::extract::{MatchedPath, State};
+use axum::http::{header, Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse as _, Response};
+use base64::Engine as _;
+use hyper::Body;
+use opentelemetry::metrics::Counter;
+use opentelemetry::KeyValue;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{event, Level};
+
+use crate::tokens::hash_secret;
+
+const JWKS_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+/// Clock skew allowed checking tokens' `exp` and `nbf`, in seconds.
+const LEEWAY_SECS: u64 = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    ReadOnly,
+    Operator,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::ReadOnly => "read-only",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read-only" => Ok(Role::ReadOnly),
+            "operator" => Ok(Role::Operator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!(
+                "Unknown role {}, expected read-only, operator or admin",
+                s
+            )),
+        }
+    }
+}
+
+/// Parse a `--admin-role-token` or `--admin-oidc-role` of the form `ROLE=VALUE`.
+pub fn parse_role_value(s: &str) -> Result<(Role, String), String> {
+    let (role, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected ROLE=VALUE, got '{}'", s))?;
+    if value.is_empty() {
+        return Err(format!("Empty value for role {}", role));
+    }
+    Ok((role.parse()?, value.to_string()))
+}
+
+/// The role needed for `method` on the admin route `path` (as routed, e.g.
+/// `/admin/function/:function_id`, or under an extra environment's `/env/{name}`).
+pub fn required_role(method: &Method, path: &str) -> Role {
+    if method == Method::GET || method == Method::HEAD {
+        return Role::ReadOnly;
+    }
+    match unnested(path) {
+        "/admin/function/:function_id/config"
+        | "/admin/function/:function_id/draining/:container_id"
+        | "/admin/function/:function_id/breakers/:container_id"
+        | "/admin/featureflags" => Role::Operator,
+        _ => Role::Admin,
+    }
+}
+
+/// `path` without the `/env/{name}` prefix extra environments' routers are nested under.
+fn unnested(path: &str) -> &str {
+    path.strip_prefix("/env/")
+        .and_then(|rest| rest.find('/').map(|i| &rest[i..]))
+        .unwrap_or(path)
+}
+
+#[derive(Clone, Debug)]
+pub struct OidcConfig {
+    pub issuer: String,
+    pub audience: String,
+    pub jwks: PathBuf,
+    /// Dotted path of the claim listing the caller's groups or roles, e.g. `realm_access.roles`.
+    pub role_claim: String,
+    /// Roles granted by values of the claim.
+    pub roles: Vec<(Role, String)>,
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    crv: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum PublicKey {
+    /// Modulus and exponent.
+    Rsa(Vec<u8>, Vec<u8>),
+    /// An uncompressed P-256 point.
+    P256(Vec<u8>),
+}
+
+fn decode(s: &str) -> Option<Vec<u8>> {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(s.trim_end_matches('='))
+        .ok()
+}
+
+/// The usable keys in a JWKS document, by key ID. Keys of other types are skipped.
+fn parse_jwks(data: &[u8]) -> serde_json::Result<Vec<(Option<String>, PublicKey)>> {
+    let jwks: Jwks = serde_json::from_slice(data)?;
+    Ok(jwks
+        .keys
+        .into_iter()
+        .filter_map(|jwk| {
+            let key = match (jwk.kty.as_str(), jwk.crv.as_deref()) {
+                ("RSA", _) => PublicKey::Rsa(decode(jwk.n.as_ref()?)?, decode(jwk.e.as_ref()?)?),
+                ("EC", Some("P-256")) => {
+                    let mut point = vec![0x04];
+                    point.extend(decode(jwk.x.as_ref()?)?);
+                    point.extend(decode(jwk.y.as_ref()?)?);
+                    PublicKey::P256(point)
+                }
+                _ => return None,
+            };
+            Some((jwk.kid, key))
+        })
+        .collect())
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Invalid {
+    Malformed,
+    Algorithm,
+    Signature,
+    Expired,
+    Issuer,
+    Audience,
+}
+
+impl Invalid {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Invalid::Malformed => "malformed",
+            Invalid::Algorithm => "algorithm",
+            Invalid::Signature => "signature",
+            Invalid::Expired => "expired",
+            Invalid::Issuer => "issuer",
+            Invalid::Audience => "audience",
+        }
+    }
+}
+
+/// The claims of `token` if it's signed by one of `keys` and valid at `now` (Unix seconds).
+fn verify_jwt(
+    token: &str,
+    keys: &[(Option<String>, PublicKey)],
+    config: &OidcConfig,
+    now: u64,
+) -> Result<serde_json::Value, Invalid> {
+    let mut parts = token.split('.');
+    let (Some(header), Some(payload), Some(signature), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(Invalid::Malformed);
+    };
+    let jwt_header: JwtHeader = decode(header)
+        .and_then(|h| serde_json::from_slice(&h).ok())
+        .ok_or(Invalid::Malformed)?;
+    let signature = decode(signature).ok_or(Invalid::Malformed)?;
+    let message = &token.as_bytes()[..header.len() + 1 + payload.len()];
+
+    let verified = keys
+        .iter()
+        .filter(|(kid, _)| jwt_header.kid.is_none() || *kid == jwt_header.kid)
+        .any(|(_, key)| match (jwt_header.alg.as_str(), key) {
+            ("RS256", PublicKey::Rsa(n, e)) => ring::signature::RsaPublicKeyComponents { n, e }
+                .verify(
+                    &ring::signature::RSA_PKCS1_2048_8192_SHA256,
+                    message,
+                    &signature,
+                )
+                .is_ok(),
+            ("ES256", PublicKey::P256(point)) => ring::signature::UnparsedPublicKey::new(
+                &ring::signature::ECDSA_P256_SHA256_FIXED,
+                point,
+            )
+            .verify(message, &signature)
+            .is_ok(),
+            _ => false,
+        });
+    if !verified {
+        // Never "none", nor HMAC with a public key as the secret.
+        return Err(if ["RS256", "ES256"].contains(&jwt_header.alg.as_str()) {
+            Invalid::Signature
+        } else {
+            Invalid::Algorithm
+        });
+    }
+
+    let claims: serde_json::Value = decode(payload)
+        .and_then(|p| serde_json::from_slice(&p).ok())
+        .ok_or(Invalid::Malformed)?;
+    let exp = claims["exp"].as_u64().ok_or(Invalid::Malformed)?;
+    let nbf = claims["nbf"].as_u64().unwrap_or(0);
+    if now > exp.saturating_add(LEEWAY_SECS) || now + LEEWAY_SECS < nbf {
+        return Err(Invalid::Expired);
+    }
+    if claims["iss"].as_str() != Some(config.issuer.as_str()) {
+        return Err(Invalid::Issuer);
+    }
+    let audience = match &claims["aud"] {
+        serde_json::Value::String(aud) => *aud == config.audience,
+        serde_json::Value::Array(auds) => auds
+            .iter()
+            .any(|aud| aud.as_str() == Some(config.audience.as_str())),
+        _ => false,
+    };
+    if !audience {
+        return Err(Invalid::Audience);
+    }
+    Ok(claims)
+}
+
+/// The highest role `claims` are granted, if any.
+fn oidc_role(claims: &serde_json::Value, config: &OidcConfig) -> Option<Role> {
+    let claim = config
+        .role_claim
+        .split('.')
+        .try_fold(claims, |value, name| value.get(name))?;
+    let values: Vec<&str> = match claim {
+        serde_json::Value::String(value) => vec![value.as_str()],
+        serde_json::Value::Array(values) => values.iter().filter_map(|v| v.as_str()).collect(),
+        _ => return None,
+    };
+    config
+        .roles
+        .iter()
+        .filter(|(_, value)| values.contains(&value.as_str()))
+        .map(|(role, _)| *role)
+        .max()
+}
+
+struct Oidc {
+    config: OidcConfig,
+    keys: RwLock<Vec<(Option<String>, PublicKey)>>,
+}
+
+impl Oidc {
+    fn load_keys(&self) -> anyhow::Result<()> {
+        let keys = parse_jwks(&std::fs::read(&self.config.jwks)?)?;
+        *self.keys.write().unwrap() = keys;
+        Ok(())
+    }
+}
+
+/// Who a request is from, for logging changes.
+struct Caller {
+    principal: String,
+    role: Role,
+}
+
+pub struct AdminAuth {
+    /// Static tokens' roles, by `hash_secret` of the token.
+    tokens: HashMap<String, Role>,
+    oidc: Option<Arc<Oidc>>,
+    rejected: Counter<u64>,
+}
+
+impl AdminAuth {
+    /// `None` if no way of authenticating is given, so the admin API isn't served. Fails if the
+    /// JWKS file can't be read.
+    pub fn new(
+        admin_token: Option<&str>,
+        role_tokens: &[(Role, String)],
+        oidc: Option<OidcConfig>,
+    ) -> anyhow::Result<Option<Arc<Self>>> {
+        let mut tokens: HashMap<String, Role> = role_tokens
+            .iter()
+            .map(|(role, token)| (hash_secret(token), *role))
+            .collect();
+        if let Some(token) = admin_token {
+            tokens.insert(hash_secret(token), Role::Admin);
+        }
+        if tokens.is_empty() && oidc.is_none() {
+            return Ok(None);
+        }
+        let oidc = match oidc {
+            Some(config) => {
+                let oidc = Arc::new(Oidc {
+                    config,
+                    keys: RwLock::new(Vec::new()),
+                });
+                oidc.load_keys()?;
+                let weak = Arc::downgrade(&oidc);
+                tokio::spawn(async move {
+                    loop {
+                        tokio::time::sleep(JWKS_RELOAD_INTERVAL).await;
+                        let Some(oidc) = weak.upgrade() else {
+                            return;
+                        };
+                        if let Err(e) = oidc.load_keys() {
+                            event!(Level::WARN, error = %e, "Error reloading admin OIDC keys, keeping the previous ones");
+                        }
+                    }
+                });
+                Some(oidc)
+            }
+            None => None,
+        };
+        Ok(Some(Arc::new(Self {
+            tokens,
+            oidc,
+            rejected: opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+                .u64_counter("admin_requests_rejected")
+                .with_description("Admin API requests rejected, by reason")
+                .init(),
+        })))
+    }
+
+    fn authenticate(&self, token: &str, now: u64) -> Result<Caller, &'static str> {
+        if let Some(role) = self.tokens.get(&hash_secret(token)) {
+            return Ok(Caller {
+                principal: "static token".to_string(),
+                role: *role,
+            });
+        }
+        let Some(oidc) = &self.oidc else {
+            return Err("unknown_token");
+        };
+        let claims = verify_jwt(token, &oidc.keys.read().unwrap(), &oidc.config, now)
+            .map_err(|e| e.as_str())?;
+        let role = oidc_role(&claims, &oidc.config).ok_or("no_role")?;
+        Ok(Caller {
+            principal: claims["sub"].as_str().unwrap_or_default().to_string(),
+            role,
+        })
+    }
+}
+
+/// Route layer for the admin routes.
+pub async fn middleware(
+    State(auth): State<Arc<AdminAuth>>,
+    path: MatchedPath,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let caller = match token.map(|token| auth.authenticate(token, now)) {
+        Some(Ok(caller)) => caller,
+        rejected => {
+            let reason = match rejected {
+                Some(Err(reason)) => reason,
+                _ => "missing",
+            };
+            auth.rejected.add(1, &[KeyValue::new("reason", reason)]);
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    };
+    let required = required_role(req.method(), path.as_str());
+    if caller.role < required {
+        auth.rejected.add(1, &[KeyValue::new("reason", "role")]);
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    if required > Role::ReadOnly {
+        event!(
+            Level::INFO,
+            principal = %caller.principal,
+            role = caller.role.as_str(),
+            method = %req.method(),
+            path = %req.uri().path(),
+            "Admin change"
+        );
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::KeyPair as _;
+
+    fn config() -> OidcConfig {
+        OidcConfig {
+            issuer: "https://id.example.com".to_string(),
+            audience: "bismuth-admin".to_string(),
+            jwks: PathBuf::new(),
+            role_claim: "realm_access.roles".to_string(),
+            roles: vec![
+                (Role::Operator, "sre".to_string()),
+                (Role::Admin, "platform".to_string()),
+            ],
+        }
+    }
+
+    fn encode(data: &[u8]) -> String {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(data)
+    }
+
+    #[test]
+    fn test_required_role() {
+        assert_eq!(
+            required_role(&Method::GET, "/admin/function/:function_id"),
+            Role::ReadOnly
+        );
+        assert_eq!(
+            required_role(&Method::PUT, "/admin/function/:function_id/config"),
+            Role::Operator
+        );
+        assert_eq!(
+            required_role(
+                &Method::POST,
+                "/admin/function/:function_id/draining/:container_id"
+            ),
+            Role::Operator
+        );
+        assert_eq!(
+            required_role(&Method::PUT, "/admin/function/:function_id/backends"),
+            Role::Admin
+        );
+        assert_eq!(
+            required_role(&Method::POST, "/admin/tenant/:tenant/token"),
+            Role::Admin
+        );
+        assert_eq!(
+            required_role(
+                &Method::PUT,
+                "/env/staging/admin/function/:function_id/config"
+            ),
+            Role::Operator
+        );
+        assert_eq!(
+            required_role(
+                &Method::PUT,
+                "/env/staging/admin/function/:function_id/backends"
+            ),
+            Role::Admin
+        );
+        assert!(Role::Admin > Role::Operator && Role::Operator > Role::ReadOnly);
+        assert_eq!(
+            parse_role_value("operator=sre"),
+            Ok((Role::Operator, "sre".to_string()))
+        );
+        assert!(parse_role_value("root=sre").is_err());
+    }
+
+    #[test]
+    fn test_verify_jwt() {
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = ring::signature::EcdsaKeyPair::generate_pkcs8(
+            &ring::signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+            &rng,
+        )
+        .unwrap();
+        let pair = ring::signature::EcdsaKeyPair::from_pkcs8(
+            &ring::signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+            pkcs8.as_ref(),
+            &rng,
+        )
+        .unwrap();
+        let point = pair.public_key().as_ref();
+        let jwks = serde_json::json!({"keys": [
+            {"kty": "EC", "crv": "P-256", "kid": "k1", "x": encode(&point[1..33]), "y": encode(&point[33..])},
+            {"kty": "oct", "k": "c2VjcmV0"},
+        ]});
+        let keys = parse_jwks(jwks.to_string().as_bytes()).unwrap();
+        assert_eq!(
+            keys,
+            [(Some("k1".to_string()), PublicKey::P256(point.to_vec()))]
+        );
+
+        let sign = |header: serde_json::Value, claims: serde_json::Value| {
+            let message = format!(
+                "{}.{}",
+                encode(header.to_string().as_bytes()),
+                encode(claims.to_string().as_bytes())
+            );
+            let signature = pair.sign(&rng, message.as_bytes()).unwrap();
+            format!("{}.{}", message, encode(signature.as_ref()))
+        };
+        let header = serde_json::json!({"alg": "ES256", "kid": "k1"});
+        let claims = serde_json::json!({
+            "iss": "https://id.example.com",
+            "aud": ["other", "bismuth-admin"],
+            "sub": "alice",
+            "exp": 2000,
+            "realm_access": {"roles": ["sre", "viewer"]},
+        });
+        let config = config();
+        let token = sign(header.clone(), claims.clone());
+        let verified = verify_jwt(&token, &keys, &config, 1000).unwrap();
+        assert_eq!(oidc_role(&verified, &config), Some(Role::Operator));
+
+        assert_eq!(
+            verify_jwt(&token, &keys, &config, 2100),
+            Err(Invalid::Expired)
+        );
+        let mut tampered = token.clone();
+        tampered.insert(token.find('.').unwrap() + 2, 'x');
+        assert!(verify_jwt(&tampered, &keys, &config, 1000).is_err());
+        let none = format!(
+            "{}.{}.",
+            encode(br#"{"alg":"none"}"#),
+            encode(claims.to_string().as_bytes())
+        );
+        assert_eq!(
+            verify_jwt(&none, &keys, &config, 1000),
+            Err(Invalid::Algorithm)
+        );
+        let mut wrong_issuer = claims.clone();
+        wrong_issuer["iss"] = "https://evil.example.com".into();
+        assert_eq!(
+            verify_jwt(&sign(header.clone(), wrong_issuer), &keys, &config, 1000),
+            Err(Invalid::Issuer)
+        );
+        let mut wrong_audience = claims;
+        wrong_audience["aud"] = "other".into();
+        assert_eq!(
+            verify_jwt(&sign(header, wrong_audience), &keys, &config, 1000),
+            Err(Invalid::Audience)
+        );
+    }
+
+    #[test]
+    fn test_static_tokens() {
+        let auth = AdminAuth {
+            tokens: HashMap::from([
+                (hash_secret("admin-secret"), Role::Admin),
+                (hash_secret("sre-secret"), Role::Operator),
+            ]),
+            oidc: None,
+            rejected: opentelemetry::global::meter("test")
+                .u64_counter("test")
+                .init(),
+        };
+        assert_eq!(
+            auth.authenticate("sre-secret", 0).unwrap().role,
+            Role::Operator
+        );
+        assert_eq!(
+            auth.authenticate("admin-secret", 0).unwrap().role,
+            Role::Admin
+        );
+        assert!(auth.authenticate("guess", 0).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_nested_environment() {
+        use tower::ServiceExt as _;
+
+        let auth = AdminAuth::new(None, &[(Role::Operator, "sre-secret".to_string())], None)
+            .unwrap()
+            .unwrap();
+        let admin = axum::Router::new()
+            .route(
+                "/admin/function/:function_id/config",
+                axum::routing::put(|| async { "ok" }),
+            )
+            .route(
+                "/admin/function/:function_id/backends",
+                axum::routing::put(|| async { "ok" }),
+            )
+            .route_layer(axum::middleware::from_fn_with_state(auth, middleware));
+        // As server.rs nests an `--extra-zookeeper-env`'s routes.
+        let app = axum::Router::new().nest("/env/staging", admin);
+        let put = |path: &str| {
+            Request::put(path)
+                .header(header::AUTHORIZATION, "Bearer sre-secret")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let resp = app
+            .clone()
+            .oneshot(put("/env/staging/admin/function/f/config"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = app
+            .oneshot(put("/env/staging/admin/function/f/backends"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,469 @@
+//! Webhook alerts, for installations without a monitoring stack to alert on the metrics: with
+//! `--alert-webhook`, every `--alert-window-secs` the frontend checks each function's invocations
+//! over the window against `--alert-error-rate` (5xx responses, from backends or not) and
+//! `--alert-unavailable-rate` (503s without a backend to send them to), and routing data's
+//! staleness against `--alert-staleness-secs`. When one crosses its threshold it POSTs an alert
+//! to the webhook, and when it's back under, a resolution. Functions with fewer than
+//! `--alert-min-requests` invocations in a window aren't judged, and count as back under.
+//!
+//! Alerts are JSON with a `text` summary, which Slack incoming webhooks (and most chat tools'
+//! equivalents) show as is, and the details as fields; with `--alert-pagerduty-routing-key` they
+//! are PagerDuty Events API v2 events instead, triggered and resolved by the same `dedup_key`.
+//! Like event export, webhooks are `http://`, so use a local proxy originating TLS for hosted
+//! services.
+
+use anyhow::{anyhow, Result};
+use axum::http::{header, Method, Request, StatusCode, Uri};
+use hyper::Body;
+use opentelemetry::metrics::Counter;
+use opentelemetry::KeyValue;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{event, Level};
+use uuid::Uuid;
+
+use crate::BackendMonitor;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+pub struct AlertConfig {
+    pub webhook: Uri,
+    /// Send PagerDuty events with this routing key, rather than plain JSON.
+    pub pagerduty_routing_key: Option<String>,
+    /// Fractions of invocations, from 0 to 1.
+    pub error_rate: Option<f64>,
+    pub unavailable_rate: Option<f64>,
+    pub staleness: Option<Duration>,
+    pub window: Duration,
+    pub min_requests: u64,
+}
+
+/// Parse `--alert-webhook`.
+pub fn parse_webhook(s: &str) -> Result<Uri, String> {
+    let url: Uri = s
+        .parse()
+        .map_err(|e| format!("Invalid URL '{}': {}", s, e))?;
+    if url.scheme_str() != Some("http") || url.host().is_none() {
+        return Err(format!("Expected http://HOST[:PORT]/PATH, got '{}'", s));
+    }
+    Ok(url)
+}
+
+/// Parse a rate threshold, from 0 to 1.
+pub fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        _ => Err(format!("Expected a fraction from 0 to 1, got '{}'", s)),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum AlertKind {
+    ErrorRate,
+    UnavailableRate,
+    Staleness,
+}
+
+impl AlertKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AlertKind::ErrorRate => "error_rate",
+            AlertKind::UnavailableRate => "unavailable_rate",
+            AlertKind::Staleness => "routing_data_staleness",
+        }
+    }
+}
+
+/// What an alert is about: staleness is the whole environment's.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct AlertKey {
+    kind: AlertKind,
+    function_id: Option<Uuid>,
+}
+
+/// A function's invocations in the current window.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Counts {
+    total: u64,
+    errors: u64,
+    unavailable: u64,
+}
+
+/// The alerts over their thresholds, with their values and thresholds.
+fn evaluate(
+    counts: &HashMap<Uuid, Counts>,
+    staleness: Option<Duration>,
+    config: &AlertConfig,
+) -> HashMap<AlertKey, (f64, f64)> {
+    let mut firing = HashMap::new();
+    for (function_id, counts) in counts {
+        if counts.total == 0 || counts.total < config.min_requests {
+            continue;
+        }
+        for (kind, count, threshold) in [
+            (AlertKind::ErrorRate, counts.errors, config.error_rate),
+            (
+                AlertKind::UnavailableRate,
+                counts.unavailable,
+                config.unavailable_rate,
+            ),
+        ] {
+            let rate = count as f64 / counts.total as f64;
+            if let Some(threshold) = threshold.filter(|threshold| rate > *threshold) {
+                let key = AlertKey {
+                    kind,
+                    function_id: Some(*function_id),
+                };
+                firing.insert(key, (rate, threshold));
+            }
+        }
+    }
+    if let (Some(staleness), Some(threshold)) = (staleness, config.staleness) {
+        if staleness > threshold {
+            let key = AlertKey {
+                kind: AlertKind::Staleness,
+                function_id: None,
+            };
+            firing.insert(key, (staleness.as_secs_f64(), threshold.as_secs_f64()));
+        }
+    }
+    firing
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Alert {
+    /// A one-line summary.
+    pub text: String,
+    /// `firing` or `resolved`.
+    pub status: &'static str,
+    pub alert: &'static str,
+    pub env: String,
+    pub function_id: Option<Uuid>,
+    /// A fraction of invocations, or seconds of staleness. Not known once resolved.
+    pub value: Option<f64>,
+    pub threshold: f64,
+}
+
+impl Alert {
+    fn new(key: &AlertKey, value: Option<f64>, threshold: f64, env: &str) -> Self {
+        let subject = match (key.kind, key.function_id) {
+            (AlertKind::Staleness, _) => "routing data staleness".to_string(),
+            (AlertKind::ErrorRate, Some(id)) => format!("function {} error rate", id),
+            (AlertKind::UnavailableRate, Some(id)) => {
+                format!("function {} no-backend 503 rate", id)
+            }
+            (_, None) => key.kind.as_str().to_string(),
+        };
+        let format = |value: f64| match key.kind {
+            AlertKind::Staleness => format!("{:.0}s", value),
+            _ => format!("{:.1}%", value * 100.0),
+        };
+        let text = match value {
+            Some(value) => format!(
+                "[{}] {} is {}, above {}",
+                env,
+                subject,
+                format(value),
+                format(threshold)
+            ),
+            None => format!(
+                "[{}] Resolved: {} is back under {}",
+                env,
+                subject,
+                format(threshold)
+            ),
+        };
+        Self {
+            text,
+            status: if value.is_some() {
+                "firing"
+            } else {
+                "resolved"
+            },
+            alert: key.kind.as_str(),
+            env: env.to_string(),
+            function_id: key.function_id,
+            value,
+            threshold,
+        }
+    }
+
+    /// The request body to send it as.
+    fn payload(&self, pagerduty_routing_key: Option<&str>) -> serde_json::Value {
+        let Some(routing_key) = pagerduty_routing_key else {
+            return serde_json::to_value(self).unwrap_or_default();
+        };
+        let dedup_key = match self.function_id {
+            Some(id) => format!("bismuthfe/{}/{}/{}", self.env, self.alert, id),
+            None => format!("bismuthfe/{}/{}", self.env, self.alert),
+        };
+        serde_json::json!({
+            "routing_key": routing_key,
+            "event_action": if self.value.is_some() { "trigger" } else { "resolve" },
+            "dedup_key": dedup_key,
+            "payload": {
+                "summary": self.text,
+                "source": "bismuthfe",
+                "severity": "error",
+                "custom_details": self,
+            },
+        })
+    }
+}
+
+pub struct Alerts {
+    counts: Mutex<HashMap<Uuid, Counts>>,
+}
+
+impl Alerts {
+    /// Starts checking `env`'s thresholds in the background, until dropped. `monitor` is for
+    /// routing data's staleness, so `None` with a dev backend.
+    pub fn new(config: AlertConfig, env: &str, monitor: Option<Arc<BackendMonitor>>) -> Arc<Self> {
+        let alerts = Arc::new(Self {
+            counts: Mutex::new(HashMap::new()),
+        });
+        tokio::spawn(check(
+            Arc::downgrade(&alerts),
+            config,
+            env.to_string(),
+            monitor,
+        ));
+        alerts
+    }
+
+    /// Count an invocation of `function_id` (as in the path), `served` if a backend responded.
+    pub fn record(&self, function_id: &str, status: StatusCode, served: bool) {
+        let Ok(function_id) = Uuid::parse_str(function_id) else {
+            return;
+        };
+        let mut counts = self.counts.lock().unwrap();
+        let counts = counts.entry(function_id).or_default();
+        counts.total += 1;
+        if status.is_server_error() {
+            counts.errors += 1;
+        }
+        if status == StatusCode::SERVICE_UNAVAILABLE && !served {
+            counts.unavailable += 1;
+        }
+    }
+}
+
+async fn check(
+    alerts: std::sync::Weak<Alerts>,
+    config: AlertConfig,
+    env: String,
+    monitor: Option<Arc<BackendMonitor>>,
+) {
+    let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+    let sent = meter
+        .u64_counter("alerts_sent")
+        .with_description("Alerts sent to the webhook, by alert and status")
+        .init();
+    let failures = meter
+        .u64_counter("alert_webhook_failures")
+        .with_description("Alerts that couldn't be sent to the webhook")
+        .init();
+    let client = hyper::Client::new();
+    let mut firing: HashMap<AlertKey, (f64, f64)> = HashMap::new();
+    loop {
+        tokio::time::sleep(config.window).await;
+        let Some(alerts_) = alerts.upgrade() else {
+            return;
+        };
+        let counts = std::mem::take(&mut *alerts_.counts.lock().unwrap());
+        drop(alerts_);
+        let staleness = monitor.as_ref().and_then(|m| m.staleness());
+        let now = evaluate(&counts, staleness, &config);
+
+        let mut changes = Vec::new();
+        for (key, (value, threshold)) in &now {
+            if !firing.contains_key(key) {
+                changes.push(Alert::new(key, Some(*value), *threshold, &env));
+            }
+        }
+        for (key, (_, threshold)) in &firing {
+            if !now.contains_key(key) {
+                changes.push(Alert::new(key, None, *threshold, &env));
+            }
+        }
+        firing = now;
+
+        for alert in changes {
+            match alert.value {
+                Some(_) => {
+                    event!(Level::WARN, alert = alert.alert, function = ?alert.function_id, "{}", alert.text)
+                }
+                None => {
+                    event!(Level::INFO, alert = alert.alert, function = ?alert.function_id, "{}", alert.text)
+                }
+            }
+            let attrs = [
+                KeyValue::new("alert", alert.alert),
+                KeyValue::new("status", alert.status),
+            ];
+            let payload = alert.payload(config.pagerduty_routing_key.as_deref());
+            match send(&client, &config.webhook, &payload).await {
+                Ok(()) => sent.add(1, &attrs),
+                Err(e) => {
+                    event!(Level::WARN, error = %e, alert = alert.alert, "Error sending alert to webhook");
+                    failures.add(1, &attrs);
+                }
+            }
+        }
+    }
+}
+
+async fn send(
+    client: &hyper::Client<hyper::client::HttpConnector>,
+    webhook: &Uri,
+    payload: &serde_json::Value,
+) -> Result<()> {
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(webhook)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(payload)?))?;
+    let resp = tokio::time::timeout(WEBHOOK_TIMEOUT, client.request(req))
+        .await
+        .map_err(|_| anyhow!("Timed out"))??;
+    if !resp.status().is_success() {
+        return Err(anyhow!("Webhook returned {}", resp.status()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> AlertConfig {
+        AlertConfig {
+            webhook: "http://alerts.local/hook".parse().unwrap(),
+            pagerduty_routing_key: None,
+            error_rate: Some(0.05),
+            unavailable_rate: Some(0.01),
+            staleness: Some(Duration::from_secs(60)),
+            window: Duration::from_secs(60),
+            min_requests: 20,
+        }
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let (failing, quiet, fine) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let counts = HashMap::from([
+            (
+                failing,
+                Counts {
+                    total: 100,
+                    errors: 10,
+                    unavailable: 10,
+                },
+            ),
+            (
+                quiet,
+                Counts {
+                    total: 5,
+                    errors: 5,
+                    unavailable: 0,
+                },
+            ),
+            (
+                fine,
+                Counts {
+                    total: 100,
+                    errors: 5,
+                    unavailable: 1,
+                },
+            ),
+        ]);
+        let firing = evaluate(&counts, Some(Duration::from_secs(90)), &config());
+        assert_eq!(firing.len(), 3);
+        let key = |kind, function_id| AlertKey { kind, function_id };
+        assert_eq!(
+            firing[&key(AlertKind::ErrorRate, Some(failing))],
+            (0.1, 0.05)
+        );
+        assert_eq!(
+            firing[&key(AlertKind::UnavailableRate, Some(failing))],
+            (0.1, 0.01)
+        );
+        assert_eq!(firing[&key(AlertKind::Staleness, None)], (90.0, 60.0));
+
+        let unset = AlertConfig {
+            error_rate: None,
+            staleness: None,
+            ..config()
+        };
+        assert_eq!(
+            evaluate(&counts, Some(Duration::from_secs(90)), &unset).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_payload() {
+        let function_id = Uuid::nil();
+        let key = AlertKey {
+            kind: AlertKind::ErrorRate,
+            function_id: Some(function_id),
+        };
+        let alert = Alert::new(&key, Some(0.125), 0.05, "prod");
+        assert_eq!(
+            alert.text,
+            "[prod] function 00000000-0000-0000-0000-000000000000 error rate is 12.5%, above 5.0%"
+        );
+        let json = alert.payload(None);
+        assert_eq!(json["status"], "firing");
+        assert_eq!(json["alert"], "error_rate");
+        assert_eq!(json["value"], 0.125);
+
+        let resolved = Alert::new(&key, None, 0.05, "prod");
+        assert_eq!(
+            resolved.text,
+            "[prod] Resolved: function 00000000-0000-0000-0000-000000000000 error rate is back under 5.0%"
+        );
+        let event = resolved.payload(Some("key"));
+        assert_eq!(event["routing_key"], "key");
+        assert_eq!(event["event_action"], "resolve");
+        assert_eq!(
+            event["dedup_key"],
+            "bismuthfe/prod/error_rate/00000000-0000-0000-0000-000000000000"
+        );
+        assert_eq!(event["payload"]["custom_details"]["status"], "resolved");
+    }
+
+    #[test]
+    fn test_record() {
+        let alerts = Alerts {
+            counts: Mutex::new(HashMap::new()),
+        };
+        let function_id = Uuid::new_v4();
+        let id = function_id.to_string();
+        alerts.record(&id, StatusCode::OK, true);
+        alerts.record(&id, StatusCode::SERVICE_UNAVAILABLE, true);
+        alerts.record(&id, StatusCode::SERVICE_UNAVAILABLE, false);
+        alerts.record("not-a-function", StatusCode::SERVICE_UNAVAILABLE, false);
+        let counts = alerts.counts.lock().unwrap();
+        assert_eq!(counts.len(), 1);
+        assert_eq!(
+            counts[&function_id],
+            Counts {
+                total: 3,
+                errors: 2,
+                unavailable: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(parse_webhook("http://localhost:9000/hook").is_ok());
+        assert!(parse_webhook("https://hooks.slack.com/services/x").is_err());
+        assert_eq!(parse_rate("0.05"), Ok(0.05));
+        assert!(parse_rate("5").is_err());
+    }
+}
@@ -0,0 +1,103 @@
+//! Optional alternative allocators (the `jemalloc` and `mimalloc` features), and their statistics
+//! as metrics, for comparing them with the system allocator under real load.
+
+#[cfg(all(feature = "jemalloc", feature = "mimalloc"))]
+compile_error!("the jemalloc and mimalloc features are mutually exclusive");
+
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[cfg(feature = "mimalloc")]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+/// Report the allocator's statistics as gauges, if it keeps any (the system allocator doesn't).
+pub fn register_metrics() -> anyhow::Result<()> {
+    #[cfg(feature = "jemalloc")]
+    {
+        use tikv_jemalloc_ctl::{epoch, stats};
+
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+        let allocated = meter
+            .u64_observable_gauge("allocator_allocated")
+            .with_description("Bytes allocated by the application")
+            .with_unit(opentelemetry::metrics::Unit::new("By"))
+            .init();
+        let active = meter
+            .u64_observable_gauge("allocator_active")
+            .with_description("Bytes in pages the allocator has in use")
+            .with_unit(opentelemetry::metrics::Unit::new("By"))
+            .init();
+        let resident = meter
+            .u64_observable_gauge("allocator_resident")
+            .with_description("Bytes of physical memory the allocator holds")
+            .with_unit(opentelemetry::metrics::Unit::new("By"))
+            .init();
+        let retained = meter
+            .u64_observable_gauge("allocator_retained")
+            .with_description("Bytes of virtual memory the allocator kept rather than unmapping")
+            .with_unit(opentelemetry::metrics::Unit::new("By"))
+            .init();
+        meter.register_callback(
+            &[
+                allocated.as_any(),
+                active.as_any(),
+                resident.as_any(),
+                retained.as_any(),
+            ],
+            move |observer| {
+                // The statistics are only refreshed when the epoch is advanced.
+                if epoch::advance().is_err() {
+                    return;
+                }
+                for (gauge, value) in [
+                    (&allocated, stats::allocated::read()),
+                    (&active, stats::active::read()),
+                    (&resident, stats::resident::read()),
+                    (&retained, stats::retained::read()),
+                ] {
+                    if let Ok(value) = value {
+                        observer.observe_u64(gauge, value as u64, &[]);
+                    }
+                }
+            },
+        )?;
+    }
+
+    #[cfg(feature = "mimalloc")]
+    {
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+        let resident = meter
+            .u64_observable_gauge("allocator_resident")
+            .with_description("Bytes of physical memory the process holds")
+            .with_unit(opentelemetry::metrics::Unit::new("By"))
+            .init();
+        let committed = meter
+            .u64_observable_gauge("allocator_committed")
+            .with_description("Bytes of memory the allocator has committed")
+            .with_unit(opentelemetry::metrics::Unit::new("By"))
+            .init();
+        meter.register_callback(&[resident.as_any(), committed.as_any()], move |observer| {
+            let (mut elapsed, mut user, mut system) = (0, 0, 0);
+            let (mut rss, mut peak_rss, mut commit, mut peak_commit, mut faults) = (0, 0, 0, 0, 0);
+            // Safety: only writes to the pointed-to locals.
+            unsafe {
+                libmimalloc_sys::mi_process_info(
+                    &mut elapsed,
+                    &mut user,
+                    &mut system,
+                    &mut rss,
+                    &mut peak_rss,
+                    &mut commit,
+                    &mut peak_commit,
+                    &mut faults,
+                );
+            }
+            observer.observe_u64(&resident, rss as u64, &[]);
+            observer.observe_u64(&committed, commit as u64, &[]);
+        })?;
+    }
+
+    Ok(())
+}
@@ -1,29 +1,102 @@
 use anyhow::{anyhow, Context, Result};
 use axum::extract::{ConnectInfo, Path, State};
-use axum::http::{Request, StatusCode};
-use axum::routing::{any, get};
+use axum::http::{Method, Request, StatusCode};
+use axum::routing::any;
 use clap::Parser;
 use conhash::ConsistentHash;
+use futures::TryStreamExt as _;
 use hyper::body::Body;
-use sentry::integrations::tower::{NewSentryLayer, SentryHttpLayer};
 use std::collections::HashMap;
-use std::net::{IpAddr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::sync::Arc;
-use tokio::sync::{Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::sync::RwLock;
 use tokio::time::sleep;
-use tower::ServiceBuilder;
-use tracing::{event, instrument, Level};
+use tracing::{event, info_span, instrument, Instrument as _, Level};
 use tracing_opentelemetry::OpenTelemetrySpanExt as _;
 use tracing_subscriber::{layer::SubscriberExt as _, util::SubscriberInitExt as _};
 use uuid::Uuid;
 
 use bismuth_common::{
-    init_metrics, init_sentry, init_tracer, pack_backends, unpack_backends, ApiError, Backend,
-    GenericError, OtelAxumMetricsLayer, BACKEND_PORT,
+    init_metrics_with_buckets, init_sentry, init_tracer_with_attributes, pack_backends, ApiError,
+    Backend, FeatureFlags, FunctionConfig, GenericError, HistogramBuckets, HostBackend,
+    Maintenance, ResponseMode, Warmup, BACKEND_PORT, IN_FLIGHT_HEADER,
 };
 
+pub mod access;
+pub mod admin;
+pub mod admin_auth;
+pub mod alerts;
+pub mod alloc;
+pub mod breaker;
+pub mod buffers;
+pub mod canary;
+pub mod capture;
+pub mod check;
+pub mod client_io;
+pub mod concurrency;
+pub mod connections;
+pub mod context;
+pub mod deadline;
+pub mod decompress;
+pub mod dev;
+pub mod dns;
+pub mod drain;
+pub mod events;
+pub mod geo;
+pub mod gossip;
+pub mod guard;
+pub mod hash_ring;
+pub mod health;
+pub mod http2;
+pub mod internal;
+pub mod latency;
+pub mod lazy;
+pub mod listener;
+pub mod load;
+pub mod metric_labels;
+pub mod middleware;
+pub mod openapi;
+pub mod overload;
+pub mod peers;
+pub mod priority;
+pub mod rate_limit;
+pub mod request_schema;
+pub mod response_mode;
+pub mod response_size;
+pub mod route_check;
+pub mod routing;
+pub mod routing_cache;
+pub mod server;
+pub mod shedding;
+pub mod signature;
+pub mod singleflight;
+pub mod slo;
+pub mod snapshot;
+pub mod tokens;
+pub mod trailers;
+pub mod upload;
+pub mod upstream;
+pub mod version;
+pub mod warmup;
+pub mod wasm;
+
+pub use server::{Config, Server};
+
 const CONHASH_REPLICAS: usize = 20;
 
+/// Most functions loaded from ZooKeeper at once.
+const LOAD_CONCURRENCY: usize = 32;
+
+/// Feature flags, as a JSON `FeatureFlags`.
+const FEATURE_FLAGS_PATH: &str = "/config/featureflags";
+/// How often to warn while routing data may be out of date.
+const STALE_WARN_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Set on invocation responses routed with data that may be out of date, because the frontend
+/// isn't connected to a ZooKeeper quorum.
+pub const STALE_HEADER: &str = "x-bismuth-stale";
+
 /// bismuthfe
 #[derive(Debug, Parser)]
 #[clap(name = "bismuthfe", version)]
@@ -36,38 +109,692 @@ struct Cli {
     #[clap(long, global = true, default_value = "default")]
     zookeeper_env: String,
 
-    /// Bind IP:port
+    /// Another ZooKeeper environment to serve, under `/env/{name}/...` (e.g. its invocations at
+    /// `/env/{name}/invoke/{function id}/...`), with the same settings. May be repeated
+    #[clap(long = "extra-zookeeper-env", value_name = "NAME", global = true)]
+    extra_zookeeper_envs: Vec<String>,
+
+    /// Bind IP:port (ignored if a socket is passed in via systemd socket activation)
     #[clap(long, global = true, default_value = "0.0.0.0:8000")]
     bind: SocketAddrV4,
+
+    /// Serve the admin API (`/admin/*` and `/debug/*`) on this IP:port or `unix:/path` socket
+    /// instead of --bind, e.g. a private address
+    #[clap(long, global = true)]
+    internal_bind: Option<internal::Bind>,
+
+    /// Don't connect to ZooKeeper; route every function to this IP:PORT, and to container
+    /// CONTAINER_ID on it if given (i.e. a local bismuthd). For local development.
+    #[clap(
+        long,
+        conflicts_with_all = ["admin_token", "admin_role_tokens", "admin_oidc_issuer"]
+    )]
+    dev_backend: Option<dev::DevBackend>,
+
+    /// Bind with SO_REUSEPORT, so a new bismuthfe can take over the address while this one drains
+    #[clap(long)]
+    reuse_port: bool,
+
+    /// Seconds to wait for in-flight requests to finish after SIGTERM before exiting anyway
+    #[clap(long, default_value = "30")]
+    drain_timeout: u64,
+
+    /// DNS server IP:PORT for resolving hostname backends (default: the system's resolvers). May
+    /// be repeated.
+    #[clap(long = "dns-server")]
+    dns_servers: Vec<SocketAddr>,
+
+    /// File to save the routing table to, and route from on startup while ZooKeeper is read
+    #[clap(long)]
+    routing_cache: Option<std::path::PathBuf>,
+
+    /// Only route to backends in this IPv4 network, e.g. 10.0.0.0/8 (default: any). May be
+    /// repeated.
+    #[clap(long = "backend-cidr", value_parser = guard::parse_cidr)]
+    backend_cidrs: Vec<guard::Cidr>,
+
+    /// Route to backends with loopback and link-local addresses too, e.g. for local development
+    #[clap(long)]
+    allow_local_backends: bool,
+
+    /// Seconds after being saved that a routing cache stops being used
+    #[clap(long, default_value = "3600")]
+    routing_cache_max_age: u64,
+
+    /// Seconds routing data may go without being known to be current (e.g. while ZooKeeper is
+    /// unreachable) before invocations fail with 503s (default: keep serving it)
+    #[clap(long)]
+    max_staleness: Option<u64>,
+
+    /// Most client connections to keep open at once; more are closed as soon as they're accepted
+    #[clap(long)]
+    max_connections: Option<usize>,
+
+    /// Most client connections to keep open from a single IP at once
+    #[clap(long)]
+    max_connections_per_ip: Option<usize>,
+
+    /// Milliseconds a client may take to send a request's headers
+    #[clap(long, default_value = "30000")]
+    client_header_timeout_ms: u64,
+
+    /// Milliseconds a client connection may stay open without a request in flight or any traffic
+    #[clap(long, default_value = "60000")]
+    client_idle_timeout_ms: u64,
+
+    /// Cut off request bodies and responses that the client sends or reads slower than this,
+    /// after 10 seconds' grace. Unlimited if not set.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    client_min_bytes_per_sec: Option<u64>,
+
+    /// Initial HTTP/2 flow control window of each stream, in bytes (default 64 KiB)
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..=2_147_483_647))]
+    http2_stream_window_bytes: Option<u32>,
+
+    /// Initial HTTP/2 flow control window of each connection, in bytes (default 64 KiB)
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..=2_147_483_647))]
+    http2_connection_window_bytes: Option<u32>,
+
+    /// Size HTTP/2 windows from each connection's measured bandwidth-delay product, instead of
+    /// the two fixed sizes
+    #[clap(long)]
+    http2_adaptive_window: bool,
+
+    /// Most HTTP/2 streams a client connection may have open at once (default unlimited)
+    #[clap(long)]
+    http2_max_concurrent_streams: Option<u32>,
+
+    /// Largest HTTP/2 frame payload to accept, in bytes (default 16 KiB)
+    #[clap(long, value_parser = clap::value_parser!(u32).range(16_384..=16_777_215))]
+    http2_max_frame_bytes: Option<u32>,
+
+    /// Connect to backends and peers over HTTP/2 (h2c, with prior knowledge), multiplexing
+    /// invocations over fewer connections. They must all support it.
+    #[clap(long)]
+    backend_http2: bool,
+
+    /// Milliseconds to wait for a TCP connection to a backend, so unreachable ones fail fast
+    #[clap(long, default_value = "2000")]
+    connect_timeout_ms: u64,
+
+    /// Default milliseconds to wait for a backend's response headers (functions can override it
+    /// with `header_timeout_ms`)
+    #[clap(long, default_value = "60000")]
+    header_timeout_ms: u64,
+
+    /// Default milliseconds an invocation may take in total, including streaming the response
+    /// body (functions can override it with `timeout_ms`). Unlimited if not set.
+    #[clap(long)]
+    total_timeout_ms: Option<u64>,
+
+    /// Peer bismuthfe base URL (e.g. http://fe.eu-west:8000) to forward requests to when a
+    /// function has no local backends. May be repeated.
+    #[clap(long = "peer")]
+    peers: Vec<hyper::Uri>,
+
+    /// MaxMind GeoIP2/GeoLite2 country database, used to route clients to backends in their region
+    #[clap(long)]
+    geoip_db: Option<std::path::PathBuf>,
+
+    /// Map a country or continent code to a backend region (see `/node/{ip}/region`),
+    /// e.g. US=us-east or EU=eu-west. May be repeated.
+    #[clap(long = "geo-region", value_parser = geo::parse_key_val)]
+    geo_regions: Vec<(String, String)>,
+
+    /// Bearer token for the /admin API, with the admin role. The admin API is disabled if neither
+    /// this, --admin-role-token nor --admin-oidc-issuer is set.
+    #[clap(long)]
+    admin_token: Option<String>,
+
+    /// Bearer token for the /admin API with a role, given as ROLE=TOKEN where ROLE is read-only,
+    /// operator or admin (see `bismuthfe::admin_auth`). May be repeated
+    #[clap(
+        long = "admin-role-token",
+        value_name = "ROLE=TOKEN",
+        value_parser = admin_auth::parse_role_value
+    )]
+    admin_role_tokens: Vec<(admin_auth::Role, String)>,
+
+    /// Also accept OIDC tokens (JWTs) from this issuer for the /admin API
+    #[clap(long, requires_all = ["admin_oidc_audience", "admin_oidc_jwks"])]
+    admin_oidc_issuer: Option<String>,
+
+    /// Audience admin OIDC tokens must be for
+    #[clap(long)]
+    admin_oidc_audience: Option<String>,
+
+    /// JWKS file with the issuer's signing keys, read again every minute
+    #[clap(long)]
+    admin_oidc_jwks: Option<std::path::PathBuf>,
+
+    /// Claim of admin OIDC tokens listing the caller's roles or groups (a dotted path for nested
+    /// claims, e.g. realm_access.roles)
+    #[clap(long, default_value = "roles")]
+    admin_oidc_role_claim: String,
+
+    /// Role granted by a value of the role claim, given as ROLE=VALUE, e.g. operator=sre. May be
+    /// repeated
+    #[clap(
+        long = "admin-oidc-role",
+        value_name = "ROLE=VALUE",
+        value_parser = admin_auth::parse_role_value
+    )]
+    admin_oidc_roles: Vec<(admin_auth::Role, String)>,
+
+    /// Only accept invocations signed by an upstream gateway with this key, given as
+    /// ID=hmac:BASE64_SECRET or ID=ed25519:BASE64_PUBLIC_KEY (see `bismuthfe::signature`). May be
+    /// repeated, e.g. while rotating keys.
+    #[clap(long = "request-signing-key", value_parser = signature::parse_key)]
+    request_signing_keys: Vec<(String, signature::SigningKey)>,
+
+    /// Seconds a signed request's timestamp may be from the frontend's clock
+    #[clap(long, default_value = "300")]
+    request_signing_max_skew: u64,
+
+    /// Only accept invocations with a tenant token (minted through the admin API) scoped to the
+    /// function, in X-Bismuth-Token
+    #[clap(long)]
+    require_tenant_token: bool,
+
+    /// Limit concurrent requests to each function and backend, adapting the limits to observed latency
+    #[clap(long)]
+    adaptive_concurrency: bool,
+
+    /// Upper bound for adaptive concurrency limits
+    #[clap(long, default_value = "1000")]
+    adaptive_concurrency_max: usize,
+
+    /// Scale backends' points in their function's rings by their health (errors and latency),
+    /// rather than only ever taking them out
+    #[clap(long)]
+    health_weighted_ring: bool,
+
+    /// Another frontend (e.g. http://fe-2:8000) to share health scores of degraded backends with,
+    /// with --health-weighted-ring, so they're avoided by both. May be repeated
+    #[clap(
+        long = "gossip-peer",
+        requires_all = ["gossip_token", "health_weighted_ring"]
+    )]
+    gossip_peers: Vec<hyper::Uri>,
+
+    /// Bearer token frontends' health reports to each other carry. Frontends with it accept
+    /// reports at /gossip/health
+    #[clap(long, requires = "health_weighted_ring")]
+    gossip_token: Option<String>,
+
+    /// Reject invocations with 503 once this many are in flight. Load shedding is disabled if not set.
+    #[clap(long)]
+    shed_high_watermark: Option<usize>,
+
+    /// Reject low priority invocations (e.g. `X-Bismuth-Priority: low`) once this many are in flight
+    /// (default: 80% of --shed-high-watermark)
+    #[clap(long, requires = "shed_high_watermark")]
+    shed_low_watermark: Option<usize>,
+
+    /// Enter degraded mode (see README) once the frontend uses this fraction of its CPUs, e.g. 0.9
+    #[clap(long)]
+    cpu_high_watermark: Option<f64>,
+
+    /// Enter degraded mode once the frontend's resident memory reaches this many bytes
+    #[clap(long)]
+    memory_high_watermark_bytes: Option<u64>,
+
+    /// Queue invocations past this many in flight, giving free slots to higher priority ones
+    /// first. Invocations aren't queued if not set.
+    #[clap(long)]
+    max_in_flight: Option<usize>,
+
+    /// Most invocations to queue before shedding the lowest priority ones
+    #[clap(long, requires = "max_in_flight", default_value = "1000")]
+    max_queued: usize,
+
+    /// Longest an invocation may be queued, in milliseconds
+    #[clap(long, requires = "max_in_flight", default_value = "1000")]
+    max_queue_wait_ms: u64,
+
+    /// Share of queued invocations' slots a tenant gets, NAME=WEIGHT, relative to other tenants'
+    /// (default 1). May be repeated.
+    #[clap(
+        long = "tenant-weight",
+        requires = "max_in_flight",
+        value_parser = priority::parse_tenant_weight
+    )]
+    tenant_weights: Vec<(String, u32)>,
+
+    /// Default histogram bucket boundaries, e.g. 5,10,25,50,100,250,500,1000
+    #[clap(long, value_parser = bismuth_common::parse_buckets)]
+    histogram_buckets: Option<latency::Boundaries>,
+
+    /// Named bucket profile (e.g. batch=1000,5000,10000,30000,60000) that functions can select
+    /// for their latency histogram with `latency_buckets` in `/function/{id}/config`. May be repeated.
+    #[clap(long = "histogram-bucket-profile", value_parser = latency::parse_bucket_profile)]
+    histogram_bucket_profiles: Vec<(String, latency::Boundaries)>,
+
+    /// Function that always gets its own `function` label on metrics, whatever the other
+    /// `--metric-*` limits. May be repeated.
+    #[clap(long = "metric-function")]
+    metric_functions: Vec<Uuid>,
+
+    /// Hash the other functions into this many `function` labels (`bucket-0` and so on) rather
+    /// than giving each its own
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    metric_function_buckets: Option<u32>,
+
+    /// Give at most this many of the other functions their own `function` label, and the rest
+    /// `other`
+    #[clap(long, conflicts_with = "metric_function_buckets")]
+    metric_max_functions: Option<usize>,
+
+    /// WebAssembly request/response filter NAME=PATH, which functions can enable with `filters`
+    /// in `/function/{id}/config`. May be repeated.
+    #[clap(long = "wasm-filter", value_parser = wasm::parse_filter)]
+    wasm_filters: Vec<(String, std::path::PathBuf)>,
+
+    /// Fuel (roughly, WASM instructions) each filter call may use before it's aborted
+    #[clap(long, default_value = "10000000")]
+    wasm_filter_fuel: u64,
+
+    /// Reject request bodies larger than this many bytes with a 413, unless the function's config
+    /// sets its own `max_upload_bytes`. Bodies are streamed to the backend either way.
+    #[clap(long)]
+    max_upload_bytes: Option<u64>,
+
+    /// Fail responses larger than this many bytes, unless the function's config sets its own
+    /// `max_response_bytes`: with a 502 if the backend declares the length, otherwise by cutting
+    /// the body off once it passes the ceiling.
+    #[clap(long)]
+    max_response_bytes: Option<u64>,
+
+    /// Directory to record invocations of functions with `capture` in their config to, as
+    /// `{function id}.jsonl`. Capturing is disabled if not set.
+    #[clap(long)]
+    capture_dir: Option<std::path::PathBuf>,
+
+    /// Export a record of every invocation to S3-compatible object storage at
+    /// http://HOST/BUCKET[/PREFIX], with credentials from AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY
+    /// and AWS_SESSION_TOKEN. Disabled if not set.
+    #[clap(long, value_parser = events::parse_url)]
+    event_export_url: Option<hyper::Uri>,
+
+    /// Region to sign object storage requests for
+    #[clap(long, requires = "event_export_url", default_value = "us-east-1")]
+    event_export_region: String,
+
+    /// Most invocation records in one object
+    #[clap(
+        long,
+        requires = "event_export_url",
+        default_value = "10000",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    event_export_batch: u64,
+
+    /// Longest to wait before uploading the records so far, in seconds
+    #[clap(
+        long,
+        requires = "event_export_url",
+        default_value = "60",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    event_export_interval_secs: u64,
+
+    /// POST alerts to this http:// webhook (e.g. a Slack incoming webhook, through a proxy
+    /// originating TLS) when a function's error rate, its rate of 503s for want of a backend, or
+    /// routing data's staleness crosses its threshold, and again when it's back under (see
+    /// `bismuthfe::alerts`). Disabled if not set
+    #[clap(long, value_parser = alerts::parse_webhook)]
+    alert_webhook: Option<hyper::Uri>,
+
+    /// Send alerts as PagerDuty Events API v2 events with this routing key
+    #[clap(long, requires = "alert_webhook")]
+    alert_pagerduty_routing_key: Option<String>,
+
+    /// Alert when more than this fraction of a function's invocations get 5xx responses
+    #[clap(long, requires = "alert_webhook", value_parser = alerts::parse_rate)]
+    alert_error_rate: Option<f64>,
+
+    /// Alert when more than this fraction of a function's invocations get 503s because there's no
+    /// backend to send them to
+    #[clap(long, requires = "alert_webhook", value_parser = alerts::parse_rate)]
+    alert_unavailable_rate: Option<f64>,
+
+    /// Alert when routing data has been possibly stale for longer than this many seconds
+    #[clap(long, requires = "alert_webhook")]
+    alert_staleness_secs: Option<u64>,
+
+    /// Window rates are measured over, and how often thresholds are checked, in seconds
+    #[clap(
+        long,
+        requires = "alert_webhook",
+        default_value = "60",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    alert_window_secs: u64,
+
+    /// Fewest invocations of a function in a window for its rates to be alerted on
+    #[clap(long, requires = "alert_webhook", default_value = "20")]
+    alert_min_requests: u64,
+
+    /// Route around backends reporting at least this many invocations in flight, when another
+    /// backend the client rehashes to isn't. Disabled if not set.
+    #[clap(long)]
+    overload_in_flight: Option<usize>,
+
+    /// Open a backend's circuit breaker after this many consecutive failed invocations, routing
+    /// around it until a probe succeeds. Without it, breakers are only tripped through the admin
+    /// API.
+    #[clap(long)]
+    breaker_failures: Option<u32>,
+
+    /// How long an open breaker waits before letting a probe through.
+    #[clap(long, default_value = "30")]
+    breaker_cooldown_secs: u64,
+
+    /// Enable a middleware compiled into this binary (see `bismuthfe::middleware`). May be
+    /// repeated; they run in order.
+    #[clap(long = "middleware")]
+    middlewares: Vec<String>,
+
+    /// Check the flags, the files they name and (unless using a dev backend) the functions' data
+    /// in ZooKeeper, print any problems and exit, with a non-zero status if there are any
+    #[clap(long)]
+    check_config: bool,
+
+    /// Number of tokio worker threads (default: number of cores)
+    #[clap(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    worker_threads: Option<usize>,
+
+    /// Maximum number of threads in tokio's blocking pool (default: 512)
+    #[clap(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_blocking_threads: Option<usize>,
+
+    /// Number of scheduler ticks between polls for I/O and timer events (default: 61)
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    event_interval: Option<u32>,
+
+    /// Number of scheduler ticks between polls of the global task queue (default: 31)
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    global_queue_interval: Option<u32>,
+}
+
+/// Routing state for one function.
+pub struct FunctionBackends {
+    /// Backends in the order they're listed in ZooKeeper.
+    pub backends: Vec<Backend>,
+    /// Points each backend has in the rings, in the same order: `CONHASH_REPLICAS` unless
+    /// reweighted for its health.
+    pub replicas: Vec<usize>,
+    /// Ring over all backends.
+    pub ring: ConsistentHash<Backend>,
+    /// Rings over the backends in each region, for clients that map to one.
+    pub regional: HashMap<String, ConsistentHash<Backend>>,
+    /// The region of each backend's node that has one.
+    pub regions: HashMap<Ipv4Addr, String>,
+    /// From `/function/{id}/config`.
+    pub config: FunctionConfig,
+    /// When to re-resolve the function's hostname backends, if it has any.
+    pub refresh_at: Option<std::time::Instant>,
+    /// Listed backends left out of the rings while they drain.
+    pub draining: Vec<Backend>,
+}
+
+impl FunctionBackends {
+    pub fn new(backends: Vec<Backend>, regions: &HashMap<Ipv4Addr, String>) -> Self {
+        let mut ring = ConsistentHash::new();
+        let mut regional: HashMap<String, ConsistentHash<Backend>> = HashMap::new();
+        for backend in &backends {
+            ring.add(backend, CONHASH_REPLICAS);
+            if let Some(region) = regions.get(&backend.ip) {
+                regional
+                    .entry(region.clone())
+                    .or_insert_with(ConsistentHash::new)
+                    .add(backend, CONHASH_REPLICAS);
+            }
+        }
+        Self {
+            regions: backends
+                .iter()
+                .filter_map(|b| Some((b.ip, regions.get(&b.ip)?.clone())))
+                .collect(),
+            replicas: vec![CONHASH_REPLICAS; backends.len()],
+            backends,
+            ring,
+            regional,
+            config: FunctionConfig::default(),
+            refresh_at: None,
+            draining: Vec::new(),
+        }
+    }
+
+    /// Give backend `i` this many points in the rings. Its keys at the points it keeps stay put.
+    pub fn set_replicas(&mut self, i: usize, replicas: usize) {
+        let backend = &self.backends[i];
+        self.ring.remove(backend);
+        self.ring.add(backend, replicas);
+        if let Some(ring) = self
+            .regions
+            .get(&backend.ip)
+            .and_then(|region| self.regional.get_mut(region))
+        {
+            ring.remove(backend);
+            ring.add(backend, replicas);
+        }
+        self.replicas[i] = replicas;
+    }
 }
 
 pub struct BackendMonitor {
-    pub backends: RwLock<HashMap<Uuid, ConsistentHash<Backend>>>,
-    pub zk: Mutex<zookeeper_client::Client>,
+    pub backends: RwLock<HashMap<Uuid, FunctionBackends>>,
+    /// `None` until connected when starting from the routing cache. Cloned for each use: clones
+    /// share the session, and their requests are pipelined over it.
+    pub zk: std::sync::RwLock<Option<zookeeper_client::Client>>,
+    /// `None` to ignore hostname backends.
+    pub resolver: Option<dns::Resolver>,
+    /// Which addresses backends may have.
+    guard: guard::BackendGuard,
+    /// Set while the watch session is disconnected or connected to a read-only server, so
+    /// updates may be being missed, to when the routing data was last known to be current.
+    stale_since: std::sync::Mutex<Option<SystemTime>>,
+    /// When the routing cache being served from was saved, until ZooKeeper has been read.
+    cached_at: std::sync::Mutex<Option<SystemTime>>,
+    /// From `/config/featureflags`.
+    flags: RwLock<FeatureFlags>,
+    /// From `/token`.
+    pub tokens: tokens::TokenCache,
+    /// Functions being loaded, and whether to load them again once done because they changed
+    /// meanwhile.
+    loading: std::sync::Mutex<HashMap<Uuid, bool>>,
+    /// Backends kept out of the ring until they've been warmed up.
+    warming: std::sync::Mutex<warmup::WarmingBackends>,
+    /// Invocations in flight to each backend, for reporting when draining ones are done.
+    pub drains: Arc<drain::Drains>,
+    /// Functions invoked before being loaded.
+    lazy: lazy::LazyLoads,
+    /// For warm-up requests.
+    probe_client: hyper::client::Client<hyper::client::HttpConnector, Body>,
 }
 
 impl BackendMonitor {
-    pub async fn new(zk_cluster: &str, zk_env: &str) -> Result<Arc<Self>> {
-        let zk = zookeeper_client::Client::connect(zk_cluster)
+    /// Connect to ZooKeeper, chrooted to the environment. Also used to reconnect the watch.
+    /// Read-only servers are accepted, so routing keeps working (from their possibly stale data)
+    /// while the ensemble has no quorum.
+    #[instrument]
+    pub(crate) async fn connect(
+        zk_cluster: &str,
+        zk_env: &str,
+    ) -> Result<zookeeper_client::Client> {
+        let zk = zookeeper_client::Client::connector()
+            .readonly(true)
+            .connect(zk_cluster)
             .await
             .context("Error connecting to ZooKeeper")?;
         let zk = zk
             .chroot(format!("/{}", zk_env))
             .map_err(|_| anyhow!("Failed to chroot to env {}", zk_env))?;
         event!(Level::TRACE, "Connected to ZooKeeper");
+        Ok(zk)
+    }
 
-        let functions = zk
-            .list_children("/function")
-            .await
-            .context("Error listing functions")?;
+    /// Load every function from ZooKeeper and watch for changes. With a `cache` holding a recent
+    /// enough table, route from that instead while ZooKeeper is connected to in the background.
+    pub async fn new(
+        zk_cluster: &str,
+        zk_env: &str,
+        resolver: Option<dns::Resolver>,
+        guard: guard::BackendGuard,
+        cache: Option<routing_cache::RoutingCache>,
+    ) -> Result<Arc<Self>> {
+        let cached = match &cache {
+            Some(cache) => cache.load().await,
+            None => None,
+        };
+        let monitor = match cached {
+            Some((saved_at, functions)) => {
+                // Saved by an earlier run, possibly with a laxer guard, so it's not trusted either.
+                let functions: HashMap<_, _> = functions
+                    .into_iter()
+                    .map(|(function_id, cached)| {
+                        let backends = guard.filter(&function_id, cached.backends);
+                        let mut function = FunctionBackends::new(backends, &cached.regions);
+                        function.config = cached.config;
+                        metric_labels::known(&function_id);
+                        (function_id, function)
+                    })
+                    .collect();
+                event!(
+                    Level::INFO,
+                    functions = functions.len(),
+                    "Routing from cached table until ZooKeeper is read"
+                );
+                Arc::new(Self {
+                    backends: RwLock::new(functions),
+                    zk: std::sync::RwLock::new(None),
+                    resolver,
+                    guard,
+                    stale_since: std::sync::Mutex::new(Some(saved_at)),
+                    cached_at: std::sync::Mutex::new(Some(saved_at)),
+                    flags: RwLock::new(FeatureFlags::default()),
+                    tokens: tokens::TokenCache::new(),
+                    loading: std::sync::Mutex::new(HashMap::new()),
+                    warming: std::sync::Mutex::new(HashMap::new()),
+                    drains: drain::Drains::new(),
+                    lazy: lazy::LazyLoads::new(),
+                    probe_client: hyper::Client::new(),
+                })
+            }
+            None => {
+                let zk = Self::connect(zk_cluster, zk_env).await?;
 
-        let monitor = Arc::new(Self {
-            backends: RwLock::new(HashMap::new()),
-            zk: Mutex::new(zk),
-        });
+                let functions = zk
+                    .list_children("/function")
+                    .instrument(info_span!("zk.list_children", zk.path = "/function"))
+                    .await
+                    .context("Error listing functions")?;
+
+                let monitor = Arc::new(Self {
+                    backends: RwLock::new(HashMap::new()),
+                    stale_since: std::sync::Mutex::new(
+                        (zk.state() == zookeeper_client::SessionState::ConnectedReadOnly)
+                            .then(SystemTime::now),
+                    ),
+                    zk: std::sync::RwLock::new(Some(zk)),
+                    resolver,
+                    guard,
+                    cached_at: std::sync::Mutex::new(None),
+                    flags: RwLock::new(FeatureFlags::default()),
+                    tokens: tokens::TokenCache::new(),
+                    loading: std::sync::Mutex::new(HashMap::new()),
+                    warming: std::sync::Mutex::new(HashMap::new()),
+                    drains: drain::Drains::new(),
+                    lazy: lazy::LazyLoads::new(),
+                    probe_client: hyper::Client::new(),
+                });
+
+                monitor.load_flags().await?;
+                monitor.load_tokens().await?;
+                let functions = functions
+                    .iter()
+                    .map(|f| Uuid::parse_str(f))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let mon = &monitor;
+                futures::stream::iter(functions.into_iter().map(Ok))
+                    .try_for_each_concurrent(LOAD_CONCURRENCY, move |function| {
+                        mon.load_backends(function)
+                    })
+                    .await?;
+                monitor
+            }
+        };
+
+        Self::register_staleness_metrics(&monitor, zk_env)?;
+        {
+            let monitor = monitor.clone();
+            tokio::spawn(async move {
+                loop {
+                    sleep(STALE_WARN_INTERVAL).await;
+                    if let Some(staleness) = monitor.staleness() {
+                        event!(
+                            Level::WARN,
+                            staleness_secs = staleness.as_secs(),
+                            "Still serving possibly stale routing data"
+                        );
+                    }
+                }
+            });
+        }
+
+        if let Some(cache) = cache {
+            let monitor = monitor.clone();
+            tokio::spawn(async move {
+                loop {
+                    sleep(routing_cache::SAVE_INTERVAL).await;
+                    let cached_at = *monitor.cached_at.lock().unwrap();
+                    if let Some(cached_at) = cached_at {
+                        if cached_at.elapsed().unwrap_or_default() > cache.max_age() {
+                            event!(Level::WARN, "Cached routing table is too old, dropping it");
+                            monitor.backends.write().await.clear();
+                            *monitor.cached_at.lock().unwrap() = None;
+                        }
+                    }
+                    // Only what's known to be current, so the saved time says how fresh it is.
+                    if monitor.is_stale() {
+                        continue;
+                    }
+                    let encoded = routing_cache::encode(&monitor.backends.read().await);
+                    let saved = match encoded {
+                        Ok(encoded) => cache.save(encoded).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = saved {
+                        event!(Level::WARN, error = %e, "Error saving routing cache");
+                    }
+                }
+            });
+        }
 
-        for function in &functions {
-            monitor.load_backends(Uuid::parse_str(function)?).await?;
+        if monitor.resolver.is_some() {
+            let monitor = monitor.clone();
+            tokio::spawn(async move {
+                loop {
+                    sleep(std::time::Duration::from_secs(1)).await;
+                    let now = std::time::Instant::now();
+                    let due: Vec<Uuid> = monitor
+                        .backends
+                        .read()
+                        .await
+                        .iter()
+                        .filter(|(_, f)| f.refresh_at.is_some_and(|at| at <= now))
+                        .map(|(id, _)| *id)
+                        .collect();
+                    let loading = monitor.loading.lock().unwrap().clone();
+                    for function_id in due {
+                        // Already being re-resolved.
+                        if !loading.contains_key(&function_id) {
+                            monitor.schedule_load(function_id);
+                        }
+                    }
+                }
+            });
         }
 
         let mon_ = monitor.clone();
@@ -89,14 +816,53 @@ impl BackendMonitor {
         Ok(monitor)
     }
 
+    fn client(&self) -> Result<zookeeper_client::Client> {
+        Ok(self
+            .zk
+            .read()
+            .unwrap()
+            .clone()
+            .ok_or(GenericError::Unavailable)?)
+    }
+
+    /// Whether routing data may be out of date.
+    pub fn is_stale(&self) -> bool {
+        self.stale_since.lock().unwrap().is_some()
+    }
+
+    /// How long since routing data was last known to be current, if it may be out of date.
+    pub fn staleness(&self) -> Option<Duration> {
+        self.stale_since
+            .lock()
+            .unwrap()
+            .map(|since| since.elapsed().unwrap_or_default())
+    }
+
+    /// By `env`, since every environment's monitor registers it.
+    fn register_staleness_metrics(monitor: &Arc<Self>, zk_env: &str) -> Result<()> {
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+        let gauge = meter
+            .f64_observable_gauge("routing_data_staleness")
+            .with_description(
+                "Seconds since routing data was last known to be current, or 0 while it is",
+            )
+            .init();
+        let monitor = Arc::downgrade(monitor);
+        let attrs = [opentelemetry::KeyValue::new("env", zk_env.to_string())];
+        meter.register_callback(&[gauge.as_any()], move |observer| {
+            if let Some(monitor) = monitor.upgrade() {
+                let staleness = monitor.staleness().unwrap_or_default();
+                observer.observe_f64(&gauge, staleness.as_secs_f64(), &attrs);
+            }
+        })?;
+        Ok(())
+    }
+
     async fn watch(mon: Arc<Self>, zk_cluster: &str, zk_env: &str) -> Result<()> {
-        let zk = zookeeper_client::Client::connect(&zk_cluster)
-            .await
-            .context("Error connecting to ZooKeeper")?;
-        let zk = zk
-            .chroot(format!("/{}", zk_env))
-            .map_err(|_| anyhow!("Failed to chroot to env {}", zk_env))?;
-        event!(Level::TRACE, "Connected to ZooKeeper");
+        let zk = Self::connect(zk_cluster, zk_env).await?;
+        // Replacing the last session's client, which is dead if that session expired, so the
+        // reload below and everything else going through `client()` use this one.
+        *mon.zk.write().unwrap() = Some(zk.clone());
 
         let mut watcher = zk
             .watch(
@@ -104,78 +870,194 @@ impl BackendMonitor {
                 zookeeper_client::AddWatchMode::PersistentRecursive,
             )
             .await?;
+        let mut flags_watcher = zk
+            .watch(
+                FEATURE_FLAGS_PATH,
+                zookeeper_client::AddWatchMode::Persistent,
+            )
+            .await?;
+        let mut tokens_watcher = zk
+            .watch(
+                tokens::TOKENS_PATH,
+                zookeeper_client::AddWatchMode::PersistentRecursive,
+            )
+            .await?;
+
+        // Catch up on whatever changed while the previous session was down.
+        if mon.is_stale() {
+            mon.reload().await?;
+            if zk.state() == zookeeper_client::SessionState::SyncConnected {
+                mon.set_stale(false);
+            }
+        }
 
         loop {
-            let event = watcher.changed().await;
+            let event = tokio::select! {
+                event = watcher.changed() => event,
+                event = flags_watcher.changed() => {
+                    // Session changes are handled with the function watch's.
+                    if event.event_type != zookeeper_client::EventType::Session {
+                        event!(Level::DEBUG, "Feature flags updated");
+                        mon.load_flags().await?;
+                    }
+                    continue;
+                }
+                event = tokens_watcher.changed() => {
+                    if event.event_type != zookeeper_client::EventType::Session {
+                        event!(Level::DEBUG, "Tenant tokens updated");
+                        mon.load_tokens().await?;
+                    }
+                    continue;
+                }
+            };
             event!(Level::TRACE, "ZooKeeper event: {:?}", event);
 
-            if event.event_type == zookeeper_client::EventType::Session
-                && (event.session_state == zookeeper_client::SessionState::Disconnected
-                    || event.session_state == zookeeper_client::SessionState::Expired
-                    || event.session_state == zookeeper_client::SessionState::Closed)
-            {
-                event!(Level::ERROR, "ZooKeeper session disconnected or terminal");
-                return Err(anyhow!("ZooKeeper session disconnected or terminal"));
-            }
-
-            if !event.path.ends_with("/backends") {
+            if event.event_type == zookeeper_client::EventType::Session {
+                match event.session_state {
+                    // The client reconnects by itself within the session timeout, keeping the
+                    // watch, so routing carries on from what's loaded meanwhile.
+                    zookeeper_client::SessionState::Disconnected
+                    | zookeeper_client::SessionState::ConnectedReadOnly => {
+                        mon.set_stale(true);
+                    }
+                    zookeeper_client::SessionState::SyncConnected => {
+                        if mon.is_stale() {
+                            mon.reload().await?;
+                            mon.set_stale(false);
+                        }
+                    }
+                    // Expired, closed or failed authentication.
+                    _ => {
+                        mon.set_stale(true);
+                        event!(Level::ERROR, state = ?event.session_state, "ZooKeeper session terminal");
+                        return Err(anyhow!("ZooKeeper session terminal"));
+                    }
+                }
                 continue;
             }
 
-            match event.event_type {
-                zookeeper_client::EventType::NodeCreated => {
-                    let function = Uuid::parse_str(
-                        event
-                            .path
-                            .split('/')
-                            .nth(2)
-                            .ok_or(anyhow!("Invalid function znode path"))?,
-                    )?;
-                    event!(Level::DEBUG, function = %function, "Function created");
-                    mon.load_backends(function).await?;
-                }
-                zookeeper_client::EventType::NodeDeleted => {
-                    let function = Uuid::parse_str(
-                        event
-                            .path
-                            .split('/')
-                            .nth(2)
-                            .ok_or(anyhow!("Invalid function znode path"))?,
-                    )?;
-                    event!(Level::DEBUG, function = %function, "Function deleted");
-                    mon.backends.write().await.remove(&function);
-                }
-                zookeeper_client::EventType::NodeDataChanged => {
-                    let function = Uuid::parse_str(
-                        event
-                            .path
-                            .split('/')
-                            .nth(2)
-                            .ok_or(anyhow!("Invalid function znode path"))?,
-                    )?;
-                    event!(Level::DEBUG, function = %function, "Function backends updated");
-                    mon.load_backends(function).await?;
-                }
-                _ => {
-                    event!(Level::WARN, "Unexpected ZooKeeper event: {:?}", event);
-                }
+            mon.handle_event(&event)?;
+        }
+    }
+
+    fn set_stale(&self, stale: bool) {
+        let mut stale_since = self.stale_since.lock().unwrap();
+        if stale_since.is_some() != stale {
+            // Once stale, the data stays as current as it was when it became so.
+            *stale_since = stale.then(SystemTime::now);
+            if stale {
+                event!(
+                    Level::WARN,
+                    "Lost ZooKeeper quorum, serving possibly stale routing data"
+                );
+            } else {
+                event!(
+                    Level::INFO,
+                    "ZooKeeper quorum regained, routing data is current"
+                );
             }
         }
     }
 
-    async fn load_backends(&self, function_id: Uuid) -> Result<()> {
-        let (backends_raw, _) = self
-            .zk
-            .lock()
+    /// Reload every function, and forget the ones that have gone, for when watch events may have
+    /// been missed.
+    #[instrument(skip(self))]
+    async fn reload(self: &Arc<Self>) -> Result<()> {
+        let zk = self.client()?;
+        let functions = zk
+            .list_children("/function")
+            .instrument(info_span!("zk.list_children", zk.path = "/function"))
             .await
-            .get_data(&format!("/function/{}/backends", &function_id))
+            .context("Error listing functions")?;
+        self.load_flags().await?;
+        self.load_tokens().await?;
+        let functions = functions
+            .iter()
+            .map(|f| Uuid::parse_str(f))
+            .collect::<Result<Vec<_>, _>>()?;
+        // Spawned, so they're not left marked as loading if this is cancelled.
+        let loads: Vec<_> = functions
+            .iter()
+            .map(|function| tokio::spawn(self.clone().load(*function)))
+            .collect();
+        for load in loads {
+            load.await?;
+        }
+        self.backends
+            .write()
             .await
-            .context("Error getting function backends")?;
+            .retain(|function, _| functions.contains(function));
+        *self.cached_at.lock().unwrap() = None;
+        Ok(())
+    }
 
-        let mut hash = ConsistentHash::new();
-        for backend in unpack_backends(&backends_raw)? {
-            hash.add(&backend, CONHASH_REPLICAS);
+    /// Reloads run in the background, so a burst of changes to many functions is picked up
+    /// concurrently rather than one function at a time.
+    #[instrument(
+        skip(self, event),
+        fields(zk.path = %event.path, zk.event_type = ?event.event_type, function_id)
+    )]
+    fn handle_event(self: &Arc<Self>, event: &zookeeper_client::WatchedEvent) -> Result<()> {
+        let mut parts = event.path.split('/');
+        let (Some(function), Some(child)) = (parts.nth(2), parts.next()) else {
+            return Ok(());
+        };
+        tracing::Span::current().record("function_id", function);
+        if !["backends", "backend_hosts", "draining", "config"].contains(&child) {
+            return Ok(());
         }
+        let function = Uuid::parse_str(function).context("Invalid function znode path")?;
+        event!(Level::DEBUG, function = %function, "Function {} changed", child);
+        self.schedule_load(function);
+        Ok(())
+    }
+
+    fn schedule_load(self: &Arc<Self>, function_id: Uuid) {
+        tokio::spawn(self.clone().load(function_id));
+    }
+
+    /// (Re)load a function, or forget it if it's been deleted. Loads of a function are
+    /// serialized, so an older read can't overwrite a newer one: if one is already running, it
+    /// goes again once done instead.
+    async fn load(self: Arc<Self>, function_id: Uuid) {
+        {
+            let mut loading = self.loading.lock().unwrap();
+            if let Some(again) = loading.get_mut(&function_id) {
+                *again = true;
+                return;
+            }
+            loading.insert(function_id, false);
+        }
+        loop {
+            match self.load_backends(function_id).await {
+                Ok(()) => {}
+                Err(e) if is_no_node(&e) => {
+                    self.warming
+                        .lock()
+                        .unwrap()
+                        .retain(|(function, _), _| *function != function_id);
+                    self.drains.set_draining(&function_id, &[]);
+                    if self.backends.write().await.remove(&function_id).is_some() {
+                        event!(Level::DEBUG, function = %function_id, "Function deleted");
+                    }
+                }
+                Err(e) => {
+                    event!(Level::WARN, function = %function_id, error = %e, "Error loading backends");
+                }
+            }
+            let mut loading = self.loading.lock().unwrap();
+            if loading.get(&function_id) == Some(&true) {
+                loading.insert(function_id, false);
+            } else {
+                loading.remove(&function_id);
+                return;
+            }
+        }
+    }
+
+    #[instrument(skip(self), fields(function_id = %function_id))]
+    async fn load_backends(self: &Arc<Self>, function_id: Uuid) -> Result<()> {
+        let function = self.read_backends(function_id).await?;
 
         event!(
             Level::TRACE,
@@ -185,63 +1067,853 @@ impl BackendMonitor {
                 .read()
                 .await
                 .get(&function_id)
-                .map(|h| h.len() / CONHASH_REPLICAS)
+                .map(|f| f.backends.len())
                 .unwrap_or(0),
-            hash.len() / CONHASH_REPLICAS
+            function.backends.len()
         );
 
-        self.backends.write().await.insert(function_id, hash);
+        self.backends.write().await.insert(function_id, function);
 
         Ok(())
     }
 
-    async fn pick_backend(&self, function_id: &Uuid, peer_ip: &IpAddr) -> Result<Backend> {
-        Ok(self
-            .backends
+    /// Load a function that isn't in the routing table, returning whether it exists. Only added
+    /// if it's still missing once read, so it can't overwrite a newer load prompted by the watch.
+    async fn load_lazily(self: &Arc<Self>, function_id: Uuid) -> bool {
+        let load = async {
+            match self.read_backends(function_id).await {
+                Ok(function) => {
+                    event!(Level::DEBUG, function = %function_id, "Loaded function on demand");
+                    self.backends
+                        .write()
+                        .await
+                        .entry(function_id)
+                        .or_insert(function);
+                    Ok(true)
+                }
+                Err(e) if is_no_node(&e) => Ok(false),
+                Err(e) => Err(e),
+            }
+        };
+        self.lazy
+            .load(function_id, std::time::Instant::now(), load)
+            .await
+    }
+
+    /// Read a function's backends, their regions and its config.
+    async fn read_backends(self: &Arc<Self>, function_id: Uuid) -> Result<FunctionBackends> {
+        let zk = self.client()?;
+        let path = bismuth_common::backends_path(&function_id);
+        let (mut backends, _) = bismuth_common::read_backends(&zk, &function_id)
+            .instrument(info_span!("zk.read_backends", zk.path = %path))
+            .await?;
+
+        let mut refresh_at = None;
+        if let Some(resolver) = &self.resolver {
+            let hosts = Self::read_backend_hosts(&zk, &function_id).await?;
+            if !hosts.is_empty() {
+                let (resolved, refresh) = resolver.resolve(&hosts).await;
+                backends.extend(resolved);
+                refresh_at = Some(refresh);
+            }
+        }
+        let backends = self.guard.filter(&function_id, backends);
+        let marked = Self::read_draining(&zk, &function_id).await?;
+        let (mut draining, mut backends): (Vec<_>, Vec<_>) = backends
+            .into_iter()
+            .partition(|b| marked.contains(&b.container_id));
+        if backends.is_empty() && !draining.is_empty() {
+            // Better to keep sending invocations to them than to fail every one.
+            event!(Level::WARN, function = %function_id, "Every backend is draining, still routing to them");
+            backends = std::mem::take(&mut draining);
+        }
+        self.drains.set_draining(
+            &function_id,
+            &draining.iter().map(|b| b.container_id).collect::<Vec<_>>(),
+        );
+
+        let config = Self::read_config(&zk, &function_id).await?;
+        match &config.warmup {
+            Some(warmup) => {
+                let previous = self
+                    .backends
+                    .read()
+                    .await
+                    .get(&function_id)
+                    .map(|f| f.backends.clone());
+                // A function new to this frontend (e.g. when starting) is routed to straight
+                // away: there's nothing warm to route to instead.
+                if let Some(previous) = previous {
+                    let (admitted, new) = warmup::admit(
+                        function_id,
+                        backends,
+                        &previous,
+                        &mut self.warming.lock().unwrap(),
+                    );
+                    backends = admitted;
+                    for backend in new {
+                        tokio::spawn(self.clone().warm_up(function_id, backend, warmup.clone()));
+                    }
+                }
+            }
+            None => self
+                .warming
+                .lock()
+                .unwrap()
+                .retain(|(function, _), _| *function != function_id),
+        }
+
+        let mut regions = HashMap::new();
+        for backend in &backends {
+            if regions.contains_key(&backend.ip) {
+                continue;
+            }
+            let path = format!("/node/{}/region", backend.ip);
+            match zk
+                .get_data(&path)
+                .instrument(info_span!("zk.get_data", zk.path = %path))
+                .await
+            {
+                Ok((region, _)) => {
+                    regions.insert(backend.ip, String::from_utf8(region)?);
+                }
+                Err(zookeeper_client::Error::NoNode) => {}
+                Err(e) => return Err(e).context("Error getting node region"),
+            }
+        }
+
+        let mut function = FunctionBackends::new(backends, &regions);
+        function.config = config;
+        function.refresh_at = refresh_at;
+        function.draining = draining;
+        metric_labels::known(&function_id);
+        Ok(function)
+    }
+
+    /// Probe a backend until it's warmed up, then reload the function to admit it to the ring.
+    /// Gives up if the backend stops being listed meanwhile.
+    #[instrument(
+        skip(self, backend, warmup),
+        fields(function_id = %function_id, backend = %backend.ip, container_id = %backend.container_id)
+    )]
+    async fn warm_up(self: Arc<Self>, function_id: Uuid, backend: Backend, warmup: Warmup) {
+        let key = (function_id, backend.clone());
+        loop {
+            match warmup::probe(&self.probe_client, &backend, &warmup).await {
+                Ok(()) => {
+                    match self.warming.lock().unwrap().get_mut(&key) {
+                        Some(warming) => *warming = warmup::Warming::Passed,
+                        None => return,
+                    }
+                    event!(Level::DEBUG, "Backend warmed up");
+                    self.schedule_load(function_id);
+                    return;
+                }
+                Err(e) => {
+                    event!(Level::INFO, error = %e, "Backend not warmed up yet");
+                }
+            }
+            sleep(warmup::RETRY_INTERVAL).await;
+            if !self.warming.lock().unwrap().contains_key(&key) {
+                return;
+            }
+        }
+    }
+
+    async fn read_config(
+        zk: &zookeeper_client::Client,
+        function_id: &Uuid,
+    ) -> Result<FunctionConfig> {
+        let path = format!("/function/{}/config", function_id);
+        match zk
+            .get_data(&path)
+            .instrument(info_span!("zk.get_data", zk.path = %path))
+            .await
+        {
+            Ok((config, _)) => Ok(serde_json::from_slice(&config)
+                .with_context(|| format!("Invalid config for function {}", function_id))?),
+            Err(zookeeper_client::Error::NoNode) => Ok(FunctionConfig::default()),
+            Err(e) => Err(e).context("Error getting function config"),
+        }
+    }
+
+    async fn read_backend_hosts(
+        zk: &zookeeper_client::Client,
+        function_id: &Uuid,
+    ) -> Result<Vec<HostBackend>> {
+        let path = format!("/function/{}/backend_hosts", function_id);
+        match zk
+            .get_data(&path)
+            .instrument(info_span!("zk.get_data", zk.path = %path))
+            .await
+        {
+            Ok((hosts, _)) => Ok(serde_json::from_slice(&hosts)
+                .with_context(|| format!("Invalid backend hosts for function {}", function_id))?),
+            Err(zookeeper_client::Error::NoNode) => Ok(Vec::new()),
+            Err(e) => Err(e).context("Error getting function backend hosts"),
+        }
+    }
+
+    async fn read_draining(zk: &zookeeper_client::Client, function_id: &Uuid) -> Result<Vec<Uuid>> {
+        let path = bismuth_common::draining_path(function_id);
+        let (draining, _) = bismuth_common::read_draining(zk, function_id)
+            .instrument(info_span!("zk.get_data", zk.path = %path))
+            .await?;
+        Ok(draining.into_iter().map(|d| d.container_id).collect())
+    }
+
+    /// Invalid flags are logged and ignored, keeping the last valid ones.
+    #[instrument(skip(self))]
+    async fn load_flags(&self) -> Result<()> {
+        let zk = self.client()?;
+        let flags = match zk
+            .get_data(FEATURE_FLAGS_PATH)
+            .instrument(info_span!("zk.get_data", zk.path = FEATURE_FLAGS_PATH))
+            .await
+        {
+            Ok((flags, _)) => match serde_json::from_slice(&flags) {
+                Ok(flags) => flags,
+                Err(e) => {
+                    event!(Level::WARN, error = %e, "Invalid feature flags, keeping the previous ones");
+                    return Ok(());
+                }
+            },
+            Err(zookeeper_client::Error::NoNode) => FeatureFlags::default(),
+            Err(e) => return Err(e).context("Error getting feature flags"),
+        };
+        *self.flags.write().await = flags;
+        Ok(())
+    }
+
+    /// Invalid tokens are logged and left out, so they can't be used.
+    #[instrument(skip(self))]
+    async fn load_tokens(&self) -> Result<()> {
+        let zk = self.client()?;
+        let ids = match zk
+            .list_children(tokens::TOKENS_PATH)
+            .instrument(info_span!(
+                "zk.list_children",
+                zk.path = tokens::TOKENS_PATH
+            ))
+            .await
+        {
+            Ok(ids) => ids,
+            Err(zookeeper_client::Error::NoNode) => Vec::new(),
+            Err(e) => return Err(e).context("Error listing tenant tokens"),
+        };
+        let mut loaded = Vec::with_capacity(ids.len());
+        for id in ids {
+            let Ok(token_id) = Uuid::parse_str(&id) else {
+                event!(Level::WARN, token = %id, "Invalid tenant token ID");
+                continue;
+            };
+            let path = format!("{}/{}", tokens::TOKENS_PATH, id);
+            let data = match zk
+                .get_data(&path)
+                .instrument(info_span!("zk.get_data", zk.path = %path))
+                .await
+            {
+                Ok((data, _)) => data,
+                // Revoked since listing.
+                Err(zookeeper_client::Error::NoNode) => continue,
+                Err(e) => return Err(e).context("Error getting tenant token"),
+            };
+            match serde_json::from_slice(&data) {
+                Ok(token) => loaded.push((token_id, token)),
+                Err(e) => {
+                    event!(Level::WARN, token = %token_id, error = %e, "Invalid tenant token")
+                }
+            }
+        }
+        self.tokens.replace(loaded);
+        event!(
+            Level::DEBUG,
+            tokens = self.tokens.len(),
+            "Loaded tenant tokens"
+        );
+        Ok(())
+    }
+
+    /// Whether feature flag `flag` is on for the function, or `default` if it isn't set.
+    pub async fn flag(&self, flag: &str, function_id: &Uuid, default: bool) -> bool {
+        self.flags.read().await.enabled(flag, function_id, default)
+    }
+
+    pub async fn config(&self, function_id: &Uuid) -> Option<FunctionConfig> {
+        self.backends
             .read()
             .await
             .get(function_id)
-            .ok_or(GenericError::NotFound)?
-            .get(peer_ip.to_string().as_bytes())
-            .map(|b| b.clone())
-            .ok_or(GenericError::Unavailable)?)
+            .map(|f| f.config.clone())
+    }
+
+    /// Pick a backend for the client, preferring ones in `region` if there are any, and ones
+    /// that aren't overloaded if `load` is given. Functions not loaded yet are looked up in
+    /// ZooKeeper, in case they were created since the watch last reported.
+    async fn pick_backend(
+        self: &Arc<Self>,
+        function_id: &Uuid,
+        peer_ip: &IpAddr,
+        region: Option<&str>,
+        load: Option<&load::BackendLoad>,
+        breakers: &breaker::Breakers,
+    ) -> Result<Backend> {
+        let known = self.backends.read().await.contains_key(function_id);
+        if !known && !self.load_lazily(*function_id).await {
+            return Err(GenericError::NotFound.into());
+        }
+        let backends = self.backends.read().await;
+        let function = backends.get(function_id).ok_or(GenericError::NotFound)?;
+        let route = routing::route(
+            function,
+            &peer_ip.to_string(),
+            region,
+            load,
+            breakers,
+            std::time::Instant::now(),
+            routing::Mode::Live,
+        );
+        let picked = route.picked.ok_or(GenericError::Unavailable)?;
+        Ok(picked.backend.clone())
     }
 }
 
-#[instrument(skip(monitor, http_client, req))]
+pub struct FrontendState {
+    /// `None` when running with `--dev-backend`.
+    pub monitor: Option<Arc<BackendMonitor>>,
+    pub dev_backend: Option<dev::DevBackend>,
+    pub http_client: upstream::Client,
+    pub peers: peers::Peers,
+    pub limits: Option<concurrency::ConcurrencyLimits>,
+    pub latency: latency::InvocationLatency,
+    /// WASM filters first, then any `--middleware`s.
+    pub middlewares: Vec<Arc<dyn middleware::ProxyMiddleware>>,
+    /// Default ceiling on request bodies, in bytes.
+    pub max_upload_bytes: Option<u64>,
+    /// Default ceiling on response bodies, in bytes.
+    pub max_response_bytes: Option<u64>,
+    pub response_limits: response_size::ResponseLimits,
+    /// Defaults for functions that don't set their own.
+    pub header_timeout: Duration,
+    pub total_timeout: Option<Duration>,
+    /// In-flight unsafe invocations by function and `singleflight::idempotency_key`.
+    pub idempotent: singleflight::SingleFlight<(Uuid, String)>,
+    /// In-flight GETs of functions with `coalesce_gets`, by function and `singleflight::get_key`.
+    pub coalesced: singleflight::SingleFlight<(Uuid, String)>,
+    pub buffers: buffers::BufferPool,
+    /// `None` without `--capture-dir`.
+    pub capture: Option<capture::Capturer>,
+    pub canary: canary::CanaryAnalyzer,
+    /// `None` without `--overload-in-flight`.
+    pub load: Option<load::BackendLoad>,
+    pub breakers: breaker::Breakers,
+    pub rate_limiter: rate_limit::RateLimiter,
+    /// `None` without `--geoip-db`. Invocations get their client's location from the middleware.
+    pub geo: Option<Arc<geo::Geo>>,
+    pub geo_restrictions: geo::Restrictions,
+    pub request_schemas: request_schema::Validator,
+    /// How long routing data may go without being known to be current before invocations fail.
+    pub max_staleness: Option<Duration>,
+    /// `None` without host resource watermarks.
+    pub host: Option<Arc<overload::HostWatermarks>>,
+    /// `None` without `--health-weighted-ring`.
+    pub health: Option<health::BackendHealth>,
+    pub slo: Arc<slo::SloTracker>,
+}
+
+impl FrontendState {
+    /// Whether the frontend is short of CPU or memory itself.
+    pub fn degraded(&self) -> bool {
+        self.host.as_ref().is_some_and(|host| host.is_degraded())
+    }
+
+    /// Whether routing data has been possibly stale for longer than `--max-staleness`, so
+    /// invocations fail closed rather than going to backends that may be long gone.
+    pub fn too_stale(&self) -> bool {
+        let staleness = self.monitor.as_ref().and_then(|m| m.staleness());
+        matches!((staleness, self.max_staleness), (Some(staleness), Some(max)) if staleness > max)
+    }
+
+    /// The ZooKeeper client for admin operations, which are unavailable without a quorum.
+    pub async fn zk(&self) -> Result<zookeeper_client::Client> {
+        let monitor = self.monitor.as_ref().ok_or(GenericError::Unavailable)?;
+        if monitor.is_stale() {
+            return Err(GenericError::Unavailable.into());
+        }
+        monitor.client()
+    }
+}
+
+/// Whether `e` is from reading a znode that doesn't exist.
+fn is_no_node(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<zookeeper_client::Error>(),
+        Some(zookeeper_client::Error::NoNode)
+    )
+}
+
+/// The URI on `backend`'s bismuthd for a request to `reqpath` (client controlled) of the function.
+pub fn backend_uri(backend: &Backend, reqpath: &str) -> Result<hyper::Uri> {
+    Ok(format!(
+        "http://{}:{}/invoke/{}/{}",
+        backend.ip, BACKEND_PORT, backend.container_id, reqpath
+    )
+    .parse()?)
+}
+
+/// Propagate the current span to the next hop.
+pub(crate) fn inject_trace_context(headers: &mut axum::http::HeaderMap) {
+    let cx = tracing::Span::current().context();
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&cx, &mut opentelemetry_http::HeaderInjector(headers))
+    });
+}
+
+/// Proxy the request to one of the function's backends, as `/invoke/{container_id}/{reqpath}`.
+#[utoipa::path(
+    post,
+    path = "/invoke/{function_id}/{reqpath}",
+    tag = "invoke",
+    params(
+        ("function_id" = Uuid, Path, description = "Function ID"),
+        ("reqpath" = String, Path, description = "Passed on to the function"),
+    ),
+    request_body(content = Vec<u8>, content_type = "*/*", description = "Passed on to the function"),
+    responses(
+        (status = 200, description = "The function's response, whatever its status"),
+        (status = 400, description = "Request body doesn't decompress"),
+        (status = 403, description = "Client's country refused by the function's `geo`, if its `status` is 403"),
+        (status = 404, description = "No such function"),
+        (status = 413, description = "Request body over the function's `max_upload_bytes`"),
+        (status = 415, description = "Request body's `Content-Encoding` can't be decompressed"),
+        (status = 422, description = "Request body doesn't match the function's request schema"),
+        (status = 429, description = "Over the function's rate limit; see the `RateLimit-*` headers"),
+        (status = 451, description = "Client's country refused by the function's `geo`"),
+        (status = 502, description = "Couldn't connect to the backend, or its response is over the function's `max_response_bytes`"),
+        (status = 503, description = "No backend available, or the function is in maintenance"),
+        (status = 504, description = "Deadline passed before the backend responded"),
+    )
+)]
+#[instrument(skip(state, req), fields(client.country))]
 #[axum::debug_handler]
 async fn invoke_function_path(
-    State((monitor, http_client)): State<(
-        Arc<BackendMonitor>,
-        hyper::client::Client<hyper::client::HttpConnector, Body>,
-    )>,
+    State(state): State<Arc<FrontendState>>,
     Path((function_id, reqpath)): Path<(Uuid, String)>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     req: Request<Body>,
 ) -> Result<axum::response::Response<hyper::Body>, ApiError> {
-    let backend = monitor.pick_backend(&function_id, &addr.ip()).await?;
+    let location = req.extensions().get::<geo::ClientLocation>().cloned();
+    if let Some(country) = location.as_ref().and_then(|l| l.country.as_deref()) {
+        tracing::Span::current().record("client.country", country);
+    }
+    let region = location.as_ref().and_then(|l| l.region.as_deref());
+
+    let Some(monitor) = &state.monitor else {
+        let dev_backend = state
+            .dev_backend
+            .as_ref()
+            .expect("no monitor or dev backend");
+        // Every function is routable.
+        metric_labels::known(&function_id);
+        let mut req = req;
+        trailers::restore(&mut req);
+        *req.uri_mut() = dev_backend.uri(&reqpath)?;
+        context::inject(&mut req, &function_id, &function_id);
+        inject_trace_context(req.headers_mut());
+        return Ok(state.http_client.request(req).await?);
+    };
+
+    let config = monitor.config(&function_id).await;
+    let client = routing::Client {
+        country: location.as_ref().and_then(|l| l.country.as_deref()),
+        headers: req.headers(),
+        ip: Some(addr.ip()),
+    };
+    let quota = match routing::admit(
+        &state,
+        function_id,
+        config.as_ref(),
+        &client,
+        std::time::Instant::now(),
+        routing::Mode::Live,
+    ) {
+        routing::Admission::Admitted(quota) => quota,
+        routing::Admission::Stale => return Err(ApiError::Status(StatusCode::SERVICE_UNAVAILABLE)),
+        routing::Admission::Maintenance(maintenance) => {
+            return Ok(maintenance_response(maintenance)?)
+        }
+        routing::Admission::GeoRefused(status) => return Ok(geo::refused(status)),
+        routing::Admission::RateLimited(quota) => return Ok(quota.exceeded()),
+    };
 
+    let now = SystemTime::now();
+    let timeout = config
+        .as_ref()
+        .and_then(|c| c.timeout_ms)
+        .map(Duration::from_millis)
+        .or(state.total_timeout);
+    let deadline = deadline::deadline(req.headers(), timeout, now);
     let mut req = req;
-    *req.uri_mut() = format!(
-        "http://{}:{}/invoke/{}/{}",
-        backend.ip, BACKEND_PORT, backend.container_id, reqpath
-    )
-    .parse()?;
-    let cx = tracing::Span::current().context();
-    opentelemetry::global::get_text_map_propagator(|propagator| {
-        propagator.inject_context(
-            &cx,
-            &mut opentelemetry_http::HeaderInjector(req.headers_mut()),
+    trailers::take(&mut req);
+    if let Some(deadline) = deadline {
+        if deadline <= now {
+            return Err(ApiError::Status(StatusCode::GATEWAY_TIMEOUT));
+        }
+        deadline::propagate(req.headers_mut(), deadline, now);
+    }
+    let max_upload_bytes = config
+        .as_ref()
+        .and_then(|c| c.max_upload_bytes)
+        .or(state.max_upload_bytes);
+    let decompressed = match config.as_ref().filter(|c| c.decompress_requests) {
+        Some(_) => decompress::decompress(
+            &mut req,
+            max_upload_bytes.unwrap_or(decompress::MAX_DECOMPRESSED_BYTES),
+        )
+        .map_err(ApiError::Status)?,
+        None => false,
+    };
+    // Decompressed bodies are already held to it.
+    if let (Some(max), false) = (max_upload_bytes, decompressed) {
+        upload::limit(&mut req, max).map_err(ApiError::Status)?;
+    }
+    if let Some(config) = &config {
+        state
+            .request_schemas
+            .validate(
+                &function_id,
+                &config.request_schemas,
+                &reqpath,
+                max_upload_bytes,
+                &state.buffers,
+                &mut req,
+            )
+            .await?;
+    }
+    // As the client sent it, before middlewares change it, since replays go through them again.
+    if let (Some(capturer), Some(capture)) = (
+        &state.capture,
+        config.as_ref().and_then(|c| c.capture.as_ref()),
+    ) {
+        capturer.capture(capture, function_id, &reqpath, &mut req);
+    }
+
+    let backend_function = routing::backend_function(
+        function_id,
+        config.as_ref(),
+        now,
+        rand::random::<f64>() * 100.0,
+    );
+    let invocation = middleware::Invocation {
+        function_id,
+        backend_function,
+        path: &reqpath,
+        config: config.as_ref(),
+        client: addr,
+    };
+    let (mut parts, body) = req.into_parts();
+    for middleware in &state.middlewares {
+        if let Some(resp) = middleware.on_request(&invocation, &mut parts).await? {
+            return Ok(resp);
+        }
+    }
+    let req = Request::from_parts(parts, body);
+    // Buffering costs memory and compressing CPU, which a degraded frontend is short of.
+    let response_mode = config
+        .as_ref()
+        .and_then(|c| c.response_mode)
+        .filter(|mode| *mode != ResponseMode::Buffer || !state.degraded());
+    let negotiation = response_mode::Negotiation::new(&req);
+    let get_key = config
+        .as_ref()
+        .is_some_and(|c| c.coalesce_gets)
+        .then(|| singleflight::get_key(&req))
+        .flatten();
+
+    let mut resp = match (singleflight::idempotency_key(&req), get_key) {
+        // Retries of an unsafe operation that's still running wait for its response rather than
+        // running it again.
+        (Some(key), _)
+            if !req.method().is_safe()
+                && monitor.flag(singleflight::FLAG, &function_id, true).await =>
+        {
+            let key = (function_id, key);
+            state
+                .idempotent
+                .run(
+                    key,
+                    &state.buffers,
+                    |_| true,
+                    proxy_to_backend(&state, monitor, &invocation, region, deadline, req),
+                )
+                .await
+        }
+        // A burst of the same GET (e.g. when a cache in front expires) goes to the backend once.
+        (_, Some(key)) => {
+            state
+                .coalesced
+                .run(
+                    (function_id, key),
+                    &state.buffers,
+                    singleflight::shareable,
+                    proxy_to_backend(&state, monitor, &invocation, region, deadline, req),
+                )
+                .await
+        }
+        _ => proxy_to_backend(&state, monitor, &invocation, region, deadline, req).await,
+    }?;
+    let mut resp = match response_mode {
+        Some(ResponseMode::Stream) => {
+            response_mode::stream(resp.headers_mut());
+            resp
+        }
+        Some(ResponseMode::Buffer) => {
+            response_mode::buffer(&state.buffers, &negotiation, resp).await?
+        }
+        None => resp,
+    };
+    if let Some(quota) = quota {
+        quota.apply(resp.headers_mut());
+    }
+    Ok(resp)
+}
+
+/// Pick a backend for the invocation and proxy `req` to it (or to a peer frontend if there's
+/// none here).
+async fn proxy_to_backend(
+    state: &FrontendState,
+    monitor: &Arc<BackendMonitor>,
+    invocation: &middleware::Invocation<'_>,
+    region: Option<&str>,
+    deadline: Option<SystemTime>,
+    mut req: Request<Body>,
+) -> Result<axum::response::Response<hyper::Body>, ApiError> {
+    let backend = match monitor
+        .pick_backend(
+            &invocation.backend_function,
+            &invocation.client.ip(),
+            region,
+            state.load.as_ref(),
+            &state.breakers,
         )
+        .await
+    {
+        Ok(backend) => backend,
+        Err(e)
+            if matches!(
+                e.downcast_ref::<GenericError>(),
+                Some(GenericError::Unavailable)
+            ) && state.peers.should_forward(&req) =>
+        {
+            trailers::restore(&mut req);
+            return Ok(state
+                .peers
+                .forward(
+                    &state.http_client,
+                    &invocation.function_id,
+                    invocation.path,
+                    req,
+                )
+                .await?);
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let in_flight = monitor.drains.begin(backend.container_id);
+    let permits = match &state.limits {
+        Some(limits) => Some(
+            limits
+                .try_acquire(&invocation.backend_function, &backend.container_id)
+                .ok_or(GenericError::Unavailable)?,
+        ),
+        None => None,
+    };
+
+    let (mut parts, body) = req.into_parts();
+    for middleware in &state.middlewares {
+        middleware
+            .on_backend_selected(&invocation, &backend, &mut parts)
+            .await?;
+    }
+    let mut req = Request::from_parts(parts, body);
+    let upload_exceeded = req.extensions().get::<upload::Exceeded>().cloned();
+    let decompress_failed = req.extensions().get::<decompress::Failed>().cloned();
+    let tenant = req
+        .extensions()
+        .get::<tokens::Tenant>()
+        .map(|tenant| tenant.0.clone());
+    *req.uri_mut() = backend_uri(&backend, invocation.path)?;
+    // After middlewares, so none can change it.
+    context::inject(
+        &mut req,
+        &invocation.function_id,
+        &invocation.backend_function,
+    );
+    inject_trace_context(req.headers_mut());
+    trailers::restore(&mut req);
+    let accepts_trailers = trailers::accepted(req.headers());
+    let header_timeout = invocation
+        .config
+        .and_then(|c| c.header_timeout_ms)
+        .map(Duration::from_millis)
+        .unwrap_or(state.header_timeout);
+    let deadline = deadline.map(|deadline| {
+        tokio::time::Instant::now()
+            + deadline
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
     });
-    Ok(http_client.request(req).await?)
+    let wait = match deadline {
+        Some(deadline) => {
+            header_timeout.min(deadline.saturating_duration_since(tokio::time::Instant::now()))
+        }
+        None => header_timeout,
+    };
+    // HEAD responses give the length of a body they don't have.
+    let max_response_bytes = invocation
+        .config
+        .and_then(|c| c.max_response_bytes)
+        .or(state.max_response_bytes)
+        .filter(|_| req.method() != Method::HEAD);
+    let start = std::time::Instant::now();
+    let mut response_trailers = None;
+    let resp = match tokio::time::timeout(wait, state.http_client.request(req)).await {
+        Ok(Ok(resp)) => {
+            let resp = match accepts_trailers || trailers::announced(resp.headers()) {
+                true => resp.map(|body| {
+                    let (body, trailers) = trailers::split(body);
+                    response_trailers = Some(trailers);
+                    body
+                }),
+                false => resp,
+            };
+            let resp = match deadline {
+                Some(deadline) => resp.map(|body| deadline::limit_body(body, deadline)),
+                None => resp,
+            };
+            match max_response_bytes {
+                Some(max) => state
+                    .response_limits
+                    .limit(&invocation.function_id, max, resp),
+                None => Ok(resp),
+            }
+        }
+        // Connect errors (including the connect timeout) mean the request was never sent.
+        Ok(Err(e)) if e.is_connect() => {
+            event!(Level::DEBUG, function_id = %invocation.function_id, backend = %backend.ip, error = %e, "Error connecting to backend");
+            Err(ApiError::Status(StatusCode::BAD_GATEWAY))
+        }
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => {
+            event!(Level::DEBUG, function_id = %invocation.function_id, "Timed out waiting for backend response headers");
+            Err(ApiError::Status(StatusCode::GATEWAY_TIMEOUT))
+        }
+    };
+    let resp = match resp {
+        Err(_) if upload_exceeded.is_some_and(|e| e.get()) => {
+            Err(ApiError::Status(StatusCode::PAYLOAD_TOO_LARGE))
+        }
+        Err(e) => Err(match decompress_failed.and_then(|f| f.get()) {
+            Some(status) => ApiError::Status(status),
+            None => e,
+        }),
+        resp => resp,
+    };
+    let elapsed = start.elapsed();
+    let ok = resp.as_ref().is_ok_and(|r| !r.status().is_server_error());
+    state.latency.record(
+        &invocation.backend_function,
+        invocation.config,
+        resp.as_ref().ok().map(|r| r.status()),
+        elapsed,
+    );
+    if let Some(split) = invocation.config.and_then(|c| c.split.as_ref()) {
+        let to_canary = invocation.backend_function == split.canary;
+        if let Some(regression) = state.canary.record(
+            invocation.function_id,
+            split,
+            to_canary,
+            ok,
+            elapsed,
+            std::time::Instant::now(),
+        ) {
+            // Without a quorum the split can't be changed; the next window tries again.
+            if let (false, Ok(zk)) = (monitor.is_stale(), monitor.client()) {
+                state
+                    .canary
+                    .roll_back(zk, invocation.function_id, split.canary, regression);
+            }
+        }
+    }
+    state
+        .breakers
+        .record(backend.container_id, ok, std::time::Instant::now());
+    if let Some(health) = &state.health {
+        health.record(backend.container_id, ok, elapsed, std::time::Instant::now());
+    }
+    if let Some(permits) = permits {
+        permits.finish(
+            resp.as_ref()
+                .map(|r| !r.status().is_server_error())
+                .unwrap_or(false),
+        );
+    }
+    let (mut parts, body) = resp?.into_parts();
+    parts.extensions.insert(events::Upstream {
+        version: invocation.backend_function,
+        tenant,
+        backend: elapsed,
+    });
+    if let Some(load) = &state.load {
+        load.report(
+            backend.container_id,
+            &parts.headers,
+            std::time::Instant::now(),
+        );
+    }
+    parts.headers.remove(IN_FLIGHT_HEADER);
+    if monitor.is_stale() {
+        parts
+            .headers
+            .insert(STALE_HEADER, axum::http::HeaderValue::from_static("1"));
+    }
+    for middleware in &state.middlewares {
+        if let Some(replacement) = middleware.on_response(&invocation, &mut parts).await? {
+            return Ok(replacement);
+        }
+    }
+    let body = drain::hold(body, in_flight);
+    Ok(axum::response::Response::from_parts(
+        parts,
+        match response_trailers {
+            Some(trailers) => trailers::join(body, trailers),
+            None => body,
+        },
+    ))
+}
+
+fn maintenance_response(
+    maintenance: &Maintenance,
+) -> Result<axum::response::Response<hyper::Body>, axum::http::Error> {
+    let mut resp = axum::response::Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header(
+            hyper::header::CONTENT_TYPE,
+            maintenance
+                .content_type
+                .as_deref()
+                .unwrap_or("text/plain; charset=utf-8"),
+        );
+    if let Some(retry_after) = maintenance.retry_after {
+        resp = resp.header(hyper::header::RETRY_AFTER, retry_after);
+    }
+    resp.body(hyper::Body::from(maintenance.body.clone().unwrap_or_else(
+        || "This function is down for maintenance.\n".to_string(),
+    )))
 }
 
 async fn invoke_function(
-    state: State<(
-        Arc<BackendMonitor>,
-        hyper::client::Client<hyper::client::HttpConnector, Body>,
-    )>,
+    state: State<Arc<FrontendState>>,
     Path(function_id): Path<Uuid>,
     addr: ConnectInfo<SocketAddr>,
     req: Request<Body>,
@@ -249,55 +1921,213 @@ async fn invoke_function(
     invoke_function_path(state, Path((function_id, "".to_string())), addr, req).await
 }
 
-pub fn app() -> axum::Router<(
-    Arc<BackendMonitor>,
-    hyper::client::Client<hyper::client::HttpConnector, Body>,
-)> {
+pub fn app() -> axum::Router<Arc<FrontendState>> {
     axum::Router::new()
         .route("/invoke/:function_id", any(invoke_function))
         .route("/invoke/:function_id/", any(invoke_function))
         .route("/invoke/:function_id/*reqpath", any(invoke_function_path))
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    bismuth_common::init_scrubber()?;
     let _sentry_guard = init_sentry();
-    let tracer = init_tracer(env!("CARGO_PKG_NAME"))?;
-    init_metrics(&[opentelemetry::KeyValue::new(
-        "service.name",
-        env!("CARGO_PKG_NAME"),
-    )]);
 
     let args = Cli::parse();
 
+    // Built by hand rather than with #[tokio::main] so the runtime can be tuned from the CLI.
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(worker_threads) = args.worker_threads {
+        runtime.worker_threads(worker_threads);
+    }
+    if let Some(max_blocking_threads) = args.max_blocking_threads {
+        runtime.max_blocking_threads(max_blocking_threads);
+    }
+    if let Some(event_interval) = args.event_interval {
+        runtime.event_interval(event_interval);
+    }
+    if let Some(global_queue_interval) = args.global_queue_interval {
+        runtime.global_queue_interval(global_queue_interval);
+    }
+
+    runtime.build()?.block_on(serve(args))
+}
+
+async fn serve(args: Cli) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let tracer =
+        init_tracer_with_attributes(env!("CARGO_PKG_NAME"), &version::resource_attributes())?;
+    let mut metric_attrs = vec![opentelemetry::KeyValue::new(
+        "service.name",
+        env!("CARGO_PKG_NAME"),
+    )];
+    metric_attrs.extend(version::resource_attributes());
+    init_metrics_with_buckets(
+        &metric_attrs,
+        HistogramBuckets {
+            default: args.histogram_buckets.clone(),
+            profiles: args.histogram_bucket_profiles.iter().cloned().collect(),
+        },
+    );
+    metric_labels::configure(metric_labels::FunctionLabels::new(
+        args.metric_functions.clone(),
+        args.metric_function_buckets,
+        args.metric_max_functions,
+    ));
+
     tracing_subscriber::registry()
         .with(tracing_opentelemetry::layer().with_tracer(tracer))
         .with(tracing_subscriber::EnvFilter::from_default_env())
-        .with(tracing_subscriber::fmt::layer())
+        .with(
+            tracing_subscriber::fmt::layer().with_writer(bismuth_common::Scrubbed(std::io::stdout)),
+        )
+        .with(bismuth_common::file_log_layer()?)
+        .with(bismuth_common::system_log_layers(env!("CARGO_PKG_NAME"))?)
         .init();
 
-    let monitor = BackendMonitor::new(&args.zookeeper, &args.zookeeper_env).await?;
-    let http_client = hyper::Client::new();
-
-    let app = app()
-        .layer(axum_tracing_opentelemetry::middleware::OtelInResponseLayer::default())
-        .layer(axum_tracing_opentelemetry::middleware::OtelAxumLayer::default())
-        .layer(OtelAxumMetricsLayer::new())
-        .route("/healthz", get(|| async { (StatusCode::OK, "OK") }))
-        .with_state((monitor, http_client))
-        .layer(
-            ServiceBuilder::new()
-                .layer(NewSentryLayer::new_from_top())
-                .layer(SentryHttpLayer::with_transaction()),
-        );
+    let check_config = args.check_config;
+    let config = Config {
+        zookeeper: args.zookeeper,
+        zookeeper_env: args.zookeeper_env,
+        extra_zookeeper_envs: args.extra_zookeeper_envs,
+        bind: SocketAddr::from(args.bind),
+        reuse_port: args.reuse_port,
+        internal_bind: args.internal_bind,
+        drain_timeout: std::time::Duration::from_secs(args.drain_timeout),
+        connect_timeout: std::time::Duration::from_millis(args.connect_timeout_ms),
+        header_timeout: std::time::Duration::from_millis(args.header_timeout_ms),
+        total_timeout: args.total_timeout_ms.map(std::time::Duration::from_millis),
+        dev_backend: args.dev_backend,
+        peers: args.peers,
+        geoip_db: args.geoip_db,
+        geo_regions: args.geo_regions,
+        admin_token: args.admin_token,
+        admin_role_tokens: args.admin_role_tokens,
+        admin_oidc: args.admin_oidc_issuer.map(|issuer| admin_auth::OidcConfig {
+            issuer,
+            audience: args.admin_oidc_audience.unwrap_or_default(),
+            jwks: args.admin_oidc_jwks.unwrap_or_default(),
+            role_claim: args.admin_oidc_role_claim,
+            roles: args.admin_oidc_roles,
+        }),
+        request_signing_keys: args.request_signing_keys,
+        request_signing_max_skew: std::time::Duration::from_secs(args.request_signing_max_skew),
+        require_tenant_token: args.require_tenant_token,
+        adaptive_concurrency: args
+            .adaptive_concurrency
+            .then_some(concurrency::LimitConfig {
+                initial: 20,
+                min: 1,
+                max: args.adaptive_concurrency_max,
+            }),
+        latency_bucket_profiles: args
+            .histogram_bucket_profiles
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect(),
+        shed_watermarks: args.shed_high_watermark.map(|high_watermark| {
+            (
+                args.shed_low_watermark.unwrap_or(high_watermark * 4 / 5),
+                high_watermark,
+            )
+        }),
+        host_watermarks: (args.cpu_high_watermark.is_some()
+            || args.memory_high_watermark_bytes.is_some())
+        .then_some(overload::Watermarks {
+            cpu: args.cpu_high_watermark,
+            memory_bytes: args.memory_high_watermark_bytes,
+        }),
+        invocation_queue: args
+            .max_in_flight
+            .map(|max_in_flight| priority::QueueConfig {
+                max_in_flight,
+                max_queued: args.max_queued,
+                max_wait: std::time::Duration::from_millis(args.max_queue_wait_ms),
+            }),
+        tenant_weights: args.tenant_weights,
+        wasm_filters: args.wasm_filters,
+        wasm_filter_fuel: args.wasm_filter_fuel,
+        middlewares: args.middlewares,
+        capture_dir: args.capture_dir,
+        health_weighted_ring: args.health_weighted_ring,
+        gossip: args.gossip_token.map(|token| gossip::GossipConfig {
+            peers: args.gossip_peers,
+            token,
+        }),
+        event_export: match args.event_export_url {
+            Some(url) => Some(events::ExportConfig {
+                url,
+                region: args.event_export_region,
+                credentials: events::Credentials::from_env()?,
+                max_batch: args.event_export_batch as usize,
+                interval: std::time::Duration::from_secs(args.event_export_interval_secs),
+            }),
+            None => None,
+        },
+        alerts: args.alert_webhook.map(|webhook| alerts::AlertConfig {
+            webhook,
+            pagerduty_routing_key: args.alert_pagerduty_routing_key,
+            error_rate: args.alert_error_rate,
+            unavailable_rate: args.alert_unavailable_rate,
+            staleness: args
+                .alert_staleness_secs
+                .map(std::time::Duration::from_secs),
+            window: std::time::Duration::from_secs(args.alert_window_secs),
+            min_requests: args.alert_min_requests,
+        }),
+        overload_in_flight: args.overload_in_flight,
+        breaker_failures: args.breaker_failures,
+        breaker_cooldown: std::time::Duration::from_secs(args.breaker_cooldown_secs),
+        max_upload_bytes: args.max_upload_bytes,
+        max_response_bytes: args.max_response_bytes,
+        max_connections: args.max_connections,
+        dns_servers: args.dns_servers,
+        backend_cidrs: args.backend_cidrs,
+        allow_local_backends: args.allow_local_backends,
+        routing_cache: args.routing_cache,
+        routing_cache_max_age: std::time::Duration::from_secs(args.routing_cache_max_age),
+        max_staleness: args.max_staleness.map(std::time::Duration::from_secs),
+        max_connections_per_ip: args.max_connections_per_ip,
+        client_timeouts: client_io::ClientTimeouts {
+            header: Some(std::time::Duration::from_millis(
+                args.client_header_timeout_ms,
+            )),
+            idle: Some(std::time::Duration::from_millis(
+                args.client_idle_timeout_ms,
+            )),
+            min_bytes_per_sec: args.client_min_bytes_per_sec,
+        },
+        http2: http2::Http2Settings {
+            initial_stream_window_size: args.http2_stream_window_bytes,
+            initial_connection_window_size: args.http2_connection_window_bytes,
+            adaptive_window: args.http2_adaptive_window,
+            max_concurrent_streams: args.http2_max_concurrent_streams,
+            max_frame_size: args.http2_max_frame_bytes,
+        },
+        backend_http2: args.backend_http2,
+    };
+
+    if check_config {
+        let problems = check::check(&config).await;
+        for problem in &problems {
+            eprintln!("{}", problem);
+        }
+        if !problems.is_empty() {
+            std::process::exit(1);
+        }
+        println!("Configuration OK");
+        return Ok(());
+    }
 
-    Ok(axum::Server::bind(&SocketAddr::from(args.bind))
-        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+    Ok(Server::new(config)
+        .await?
+        .run(listener::shutdown_signal())
         .await?)
 }
 
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
+    use std::collections::BTreeSet;
     use tokio::time::sleep;
 
     use super::*;
@@ -323,7 +2153,15 @@ mod tests {
         let env = function!();
         let zk = bismuth_common::test::zk_bootstrap(&zookeeper_cluster, &env).await;
 
-        let monitor = BackendMonitor::new(&zookeeper_cluster, env).await.unwrap();
+        let monitor = BackendMonitor::new(
+            &zookeeper_cluster,
+            env,
+            None,
+            guard::BackendGuard::new(Vec::new(), true),
+            None,
+        )
+        .await
+        .unwrap();
         assert_eq!(monitor.backends.read().await.len(), 0);
 
         let function_id = Uuid::new_v4();
@@ -351,7 +2189,7 @@ mod tests {
             let backends = monitor.backends.read().await;
             assert_eq!(backends.len(), 1);
             assert!(backends.contains_key(&function_id));
-            assert_eq!(backends.get(&function_id).unwrap().len(), 0);
+            assert_eq!(backends.get(&function_id).unwrap().ring.len(), 0);
         }
 
         zk.set_data(
@@ -370,7 +2208,10 @@ mod tests {
             let backends = monitor.backends.read().await;
             assert_eq!(backends.len(), 1);
             assert!(backends.contains_key(&function_id));
-            assert_eq!(backends.get(&function_id).unwrap().len(), CONHASH_REPLICAS);
+            assert_eq!(
+                backends.get(&function_id).unwrap().ring.len(),
+                CONHASH_REPLICAS
+            );
         }
 
         bismuth_common::test::delete_all(&zk, &format!("/function/{}", function_id))
@@ -382,4 +2223,112 @@ mod tests {
             assert_eq!(backends.len(), 0);
         }
     }
+
+    const RING_KEYS: u32 = 2000;
+
+    /// Client addresses, hashed the same way `pick_backend` does.
+    fn ring_keys() -> impl Iterator<Item = Vec<u8>> {
+        (0..RING_KEYS).map(|i| Ipv4Addr::from(0x0a00_0000 + i).to_string().into_bytes())
+    }
+
+    fn ring_backends(ids: BTreeSet<u128>) -> Vec<Backend> {
+        ids.into_iter()
+            .map(|id| Backend {
+                ip: Ipv4Addr::from(id as u32),
+                container_id: Uuid::from_u128(id),
+            })
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn prop_ring_distribution(ids in prop::collection::btree_set(any::<u128>(), 2..16)) {
+            let backends = ring_backends(ids);
+            let function = FunctionBackends::new(backends.clone(), &HashMap::new());
+
+            let mut counts: HashMap<Uuid, u32> = HashMap::new();
+            for key in ring_keys() {
+                *counts
+                    .entry(function.ring.get(&key).unwrap().container_id)
+                    .or_default() += 1;
+            }
+
+            // With CONHASH_REPLICAS points per backend, shares aren't exactly even,
+            // but nobody should be swamped or starved.
+            let fair = RING_KEYS as f64 / backends.len() as f64;
+            for backend in &backends {
+                let count = *counts.get(&backend.container_id).unwrap_or(&0) as f64;
+                prop_assert!(count <= fair * 3.0, "{} got {} keys, fair share {}", backend.container_id, count, fair);
+                prop_assert!(count >= fair * 0.15, "{} got {} keys, fair share {}", backend.container_id, count, fair);
+            }
+        }
+
+        #[test]
+        fn prop_ring_add_backend(ids in prop::collection::btree_set(any::<u128>(), 2..17)) {
+            let mut backends = ring_backends(ids);
+            let added = backends.pop().unwrap();
+            let before = FunctionBackends::new(backends.clone(), &HashMap::new());
+            backends.push(added.clone());
+            let after = FunctionBackends::new(backends.clone(), &HashMap::new());
+
+            let mut moved = 0;
+            for key in ring_keys() {
+                let old = before.ring.get(&key).unwrap();
+                let new = after.ring.get(&key).unwrap();
+                if old != new {
+                    // Keys only ever move to the new backend...
+                    prop_assert_eq!(new, &added);
+                    moved += 1;
+                }
+            }
+            // ... and roughly 1/N of them do.
+            prop_assert!(moved as f64 <= RING_KEYS as f64 * 3.0 / backends.len() as f64);
+        }
+
+        #[test]
+        fn prop_ring_reweight_backend(ids in prop::collection::btree_set(any::<u128>(), 2..17)) {
+            let backends = ring_backends(ids);
+            let before = FunctionBackends::new(backends.clone(), &HashMap::new());
+            let mut after = FunctionBackends::new(backends.clone(), &HashMap::new());
+            let degraded = backends.len() - 1;
+            after.set_replicas(degraded, 2);
+
+            let mut moved = 0;
+            for key in ring_keys() {
+                let old = before.ring.get(&key).unwrap();
+                let new = after.ring.get(&key).unwrap();
+                if old != new {
+                    // Only the reweighted backend's keys move.
+                    prop_assert_eq!(old, &backends[degraded]);
+                    moved += 1;
+                }
+            }
+            prop_assert!(moved > 0);
+            // Restoring its points brings back exactly its keys.
+            after.set_replicas(degraded, CONHASH_REPLICAS);
+            for key in ring_keys() {
+                prop_assert_eq!(before.ring.get(&key), after.ring.get(&key));
+            }
+        }
+
+        #[test]
+        fn prop_ring_remove_backend(ids in prop::collection::btree_set(any::<u128>(), 2..17)) {
+            let mut backends = ring_backends(ids);
+            let before = FunctionBackends::new(backends.clone(), &HashMap::new());
+            let removed = backends.pop().unwrap();
+            let after = FunctionBackends::new(backends.clone(), &HashMap::new());
+
+            let mut moved = 0;
+            for key in ring_keys() {
+                let old = before.ring.get(&key).unwrap();
+                let new = after.ring.get(&key).unwrap();
+                if old != new {
+                    // Only the removed backend's keys move.
+                    prop_assert_eq!(old, &removed);
+                    moved += 1;
+                }
+            }
+            prop_assert!(moved as f64 <= RING_KEYS as f64 * 3.0 / (backends.len() + 1) as f64);
+        }
+    }
 }
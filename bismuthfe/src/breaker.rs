@@ -0,0 +1,458 @@
+//! Per-backend circuit breakers. With `--breaker-failures N`, a backend's breaker opens after N
+//! consecutive failed invocations (5xx responses and errors), and invocations that hash to it go
+//! to another backend instead. After `--breaker-cooldown-secs` it's half-open: one invocation is
+//! let through as a probe, closing it if it succeeds and opening it again if not.
+//!
+//! Operators can also trip a backend's breaker by hand for a while, through the admin API, to cut
+//! off a known-bad backend straight away, and reset any breaker. Breakers are each frontend's own,
+//! like its health scores; drain a backend to take it out of every frontend's rings.
+//!
+//! If every backend an invocation could go to is open, it goes to the one it hashed to anyway.
+
+use axum::extract::{Path, Query, State};
+use axum::Json;
+use conhash::ConsistentHash;
+use opentelemetry::metrics::Counter;
+use opentelemetry::KeyValue;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{event, Level};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use bismuth_common::{ApiError, Backend, GenericError};
+
+use crate::FrontendState;
+
+/// Other backends tried, by rehashing the key, before going to the hashed one regardless.
+const MAX_REHASHES: usize = 16;
+/// Past this many backends tracked, those with closed breakers are pruned.
+const MAX_TRACKED: usize = 10_000;
+/// Longest a breaker can be tripped by hand.
+const MAX_TRIP: Duration = Duration::from_secs(86400);
+
+#[derive(Clone, Debug, Default)]
+struct Breaker {
+    consecutive_failures: u32,
+    /// Open until then.
+    open_until: Option<Instant>,
+    /// Tripped through the admin API, so it closes, rather than going half-open, when it expires.
+    manual: bool,
+    /// When the half-open breaker last let a probe through.
+    probe: Option<Instant>,
+}
+
+impl Breaker {
+    fn state(&self, now: Instant) -> &'static str {
+        match self.open_until {
+            Some(until) if until > now => "open",
+            Some(_) if !self.manual => "half-open",
+            _ => "closed",
+        }
+    }
+
+    /// Whether an invocation may go to the backend now, letting it through as the probe if the
+    /// breaker is half-open.
+    fn allows(&mut self, cooldown: Duration, now: Instant) -> bool {
+        if !self.would_allow(cooldown, now) {
+            return false;
+        }
+        match self.open_until {
+            Some(_) if self.manual => *self = Breaker::default(),
+            Some(_) => self.probe = Some(now),
+            None => {}
+        }
+        true
+    }
+
+    /// Whether `allows` would, without taking the probe.
+    fn would_allow(&self, cooldown: Duration, now: Instant) -> bool {
+        match self.open_until {
+            Some(until) if until > now => false,
+            Some(_) if self.manual => true,
+            // A probe that never finished (e.g. its client went away) doesn't hold it open.
+            Some(_) => !self
+                .probe
+                .is_some_and(|at| now.saturating_duration_since(at) < cooldown),
+            None => true,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq, ToSchema)]
+pub struct BreakerStatus {
+    pub container_id: Uuid,
+    /// `closed`, `open` or `half-open`.
+    pub state: String,
+    /// Tripped through the admin API rather than by failures.
+    pub manual: bool,
+    pub consecutive_failures: u32,
+    /// Seconds until an open breaker goes half-open, or closes if it was tripped by hand.
+    pub open_secs: Option<u64>,
+}
+
+pub struct Breakers {
+    /// Consecutive failures that open a breaker; `None` to only open them by hand.
+    failures: Option<u32>,
+    cooldown: Duration,
+    breakers: Mutex<HashMap<Uuid, Breaker>>,
+    opened: Counter<u64>,
+}
+
+impl Breakers {
+    pub fn new(failures: Option<u32>, cooldown: Duration) -> Self {
+        Self {
+            failures,
+            cooldown,
+            breakers: Mutex::new(HashMap::new()),
+            opened: opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+                .u64_counter("breakers_opened")
+                .with_description("Backend circuit breakers opened, by failures or by hand")
+                .init(),
+        }
+    }
+
+    fn allows(&self, container_id: &Uuid, now: Instant) -> bool {
+        self.breakers
+            .lock()
+            .unwrap()
+            .get_mut(container_id)
+            .map_or(true, |breaker| breaker.allows(self.cooldown, now))
+    }
+
+    /// `hashed`, the backend `key` hashes to in `ring`, unless its breaker is open and rehashing
+    /// finds one whose breaker isn't.
+    pub fn reroute<'a>(
+        &self,
+        ring: &'a ConsistentHash<Backend>,
+        key: &str,
+        hashed: &'a Backend,
+        now: Instant,
+    ) -> &'a Backend {
+        rehash(ring, key, hashed, |id| self.allows(id, now))
+    }
+
+    /// Where `reroute` would send an invocation, without letting it through as a half-open
+    /// breaker's probe.
+    pub fn peek<'a>(
+        &self,
+        ring: &'a ConsistentHash<Backend>,
+        key: &str,
+        hashed: &'a Backend,
+        now: Instant,
+    ) -> &'a Backend {
+        let breakers = self.breakers.lock().unwrap();
+        rehash(ring, key, hashed, |id| {
+            breakers
+                .get(id)
+                .map_or(true, |breaker| breaker.would_allow(self.cooldown, now))
+        })
+    }
+
+    /// Count an invocation `container_id` served, `ok` unless it failed.
+    pub fn record(&self, container_id: Uuid, ok: bool, now: Instant) {
+        let mut breakers = self.breakers.lock().unwrap();
+        if ok {
+            // Closes a half-open breaker; only expiry or a reset closes a manual trip.
+            if !breakers.get(&container_id).is_some_and(|b| b.manual) {
+                breakers.remove(&container_id);
+            }
+            return;
+        }
+        let Some(threshold) = self.failures else {
+            return;
+        };
+        if breakers.len() >= MAX_TRACKED {
+            breakers.retain(|_, b| b.open_until.is_some());
+        }
+        let breaker = breakers.entry(container_id).or_default();
+        if breaker.manual {
+            return;
+        }
+        breaker.consecutive_failures += 1;
+        let probe_failed = breaker.probe.is_some();
+        if probe_failed
+            || (breaker.open_until.is_none() && breaker.consecutive_failures >= threshold)
+        {
+            breaker.open_until = Some(now + self.cooldown);
+            breaker.probe = None;
+            if !probe_failed {
+                event!(Level::WARN, backend = %container_id, failures = breaker.consecutive_failures, "Circuit breaker opened");
+                self.opened.add(1, &[KeyValue::new("reason", "failures")]);
+            }
+        }
+    }
+
+    /// Open `container_id`'s breaker for `ttl`, whatever its failures.
+    pub fn trip(&self, container_id: Uuid, ttl: Duration, now: Instant) {
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(container_id).or_default();
+        breaker.open_until = Some(now + ttl);
+        breaker.manual = true;
+        breaker.probe = None;
+        self.opened.add(1, &[KeyValue::new("reason", "manual")]);
+    }
+
+    /// Close `container_id`'s breaker, forgetting its failures.
+    pub fn reset(&self, container_id: &Uuid) {
+        self.breakers.lock().unwrap().remove(container_id);
+    }
+
+    pub fn status(&self, container_id: &Uuid, now: Instant) -> BreakerStatus {
+        let breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.get(container_id).cloned().unwrap_or_default();
+        BreakerStatus {
+            container_id: *container_id,
+            state: breaker.state(now).to_string(),
+            manual: breaker.manual,
+            consecutive_failures: breaker.consecutive_failures,
+            open_secs: breaker
+                .open_until
+                .filter(|until| *until > now)
+                .map(|until| until.duration_since(now).as_secs()),
+        }
+    }
+}
+
+/// `hashed`, unless `allows` refuses it and rehashing `key` finds another backend it allows.
+fn rehash<'a>(
+    ring: &'a ConsistentHash<Backend>,
+    key: &str,
+    hashed: &'a Backend,
+    mut allows: impl FnMut(&Uuid) -> bool,
+) -> &'a Backend {
+    if allows(&hashed.container_id) {
+        return hashed;
+    }
+    // Salted, like overloaded backends, so its keys spread over the others.
+    (1..=MAX_REHASHES)
+        .filter_map(|n| ring.get(format!("{}#{}", key, n).as_bytes()))
+        .find(|backend| {
+            backend.container_id != hashed.container_id && allows(&backend.container_id)
+        })
+        .unwrap_or(hashed)
+}
+
+/// Whether `container_id` is one of the function's backends, as loaded by this frontend.
+async fn is_backend(
+    state: &FrontendState,
+    function_id: &Uuid,
+    container_id: &Uuid,
+) -> Result<bool, ApiError> {
+    let monitor = state.monitor.as_ref().ok_or(ApiError::NotFound)?;
+    let backends = monitor.backends.read().await;
+    let function = backends.get(function_id).ok_or(ApiError::NotFound)?;
+    Ok(function
+        .backends
+        .iter()
+        .chain(&function.draining)
+        .any(|b| b.container_id == *container_id))
+}
+
+/// The breakers of the function's backends, as loaded by this frontend.
+#[utoipa::path(
+    get,
+    path = "/admin/function/{function_id}/breakers",
+    tag = "admin",
+    params(("function_id" = Uuid, Path, description = "Function ID")),
+    responses(
+        (status = 200, body = Vec<BreakerStatus>),
+        (status = 404, description = "No such function loaded"),
+    ),
+    security(("admin_token" = []))
+)]
+pub async fn breakers_handler(
+    State(state): State<Arc<FrontendState>>,
+    Path(function_id): Path<Uuid>,
+) -> Result<Json<Vec<BreakerStatus>>, ApiError> {
+    let monitor = state.monitor.as_ref().ok_or(ApiError::NotFound)?;
+    let backends = monitor.backends.read().await;
+    let function = backends.get(&function_id).ok_or(ApiError::NotFound)?;
+    let now = Instant::now();
+    Ok(Json(
+        function
+            .backends
+            .iter()
+            .chain(&function.draining)
+            .map(|backend| state.breakers.status(&backend.container_id, now))
+            .collect(),
+    ))
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+pub struct TripParams {
+    /// How long to keep it open, at most a day.
+    #[serde(default = "default_trip_secs")]
+    ttl_secs: u64,
+}
+
+fn default_trip_secs() -> u64 {
+    300
+}
+
+/// Open a backend's breaker on this frontend, so invocations go to its other backends.
+#[utoipa::path(
+    post,
+    path = "/admin/function/{function_id}/breakers/{container_id}",
+    tag = "admin",
+    params(
+        ("function_id" = Uuid, Path, description = "Function ID"),
+        ("container_id" = Uuid, Path, description = "Backend container ID"),
+        TripParams,
+    ),
+    responses(
+        (status = 200, body = BreakerStatus),
+        (status = 400, description = "TTL over a day"),
+        (status = 404, description = "No such function or backend loaded"),
+    ),
+    security(("admin_token" = []))
+)]
+pub async fn breaker_trip(
+    State(state): State<Arc<FrontendState>>,
+    Path((function_id, container_id)): Path<(Uuid, Uuid)>,
+    Query(params): Query<TripParams>,
+) -> Result<Json<BreakerStatus>, ApiError> {
+    let ttl = Duration::from_secs(params.ttl_secs);
+    if ttl.is_zero() || ttl > MAX_TRIP {
+        return Err(GenericError::Invalid("ttl_secs must be from 1 to 86400".to_string()).into());
+    }
+    if !is_backend(&state, &function_id, &container_id).await? {
+        return Err(ApiError::NotFound);
+    }
+    let now = Instant::now();
+    state.breakers.trip(container_id, ttl, now);
+    Ok(Json(state.breakers.status(&container_id, now)))
+}
+
+/// Close a backend's breaker on this frontend.
+#[utoipa::path(
+    delete,
+    path = "/admin/function/{function_id}/breakers/{container_id}",
+    tag = "admin",
+    params(
+        ("function_id" = Uuid, Path, description = "Function ID"),
+        ("container_id" = Uuid, Path, description = "Backend container ID"),
+    ),
+    responses(
+        (status = 200, body = BreakerStatus),
+        (status = 404, description = "No such function or backend loaded"),
+    ),
+    security(("admin_token" = []))
+)]
+pub async fn breaker_reset(
+    State(state): State<Arc<FrontendState>>,
+    Path((function_id, container_id)): Path<(Uuid, Uuid)>,
+) -> Result<Json<BreakerStatus>, ApiError> {
+    if !is_backend(&state, &function_id, &container_id).await? {
+        return Err(ApiError::NotFound);
+    }
+    state.breakers.reset(&container_id);
+    Ok(Json(state.breakers.status(&container_id, Instant::now())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backend(n: u8) -> Backend {
+        Backend {
+            ip: [10, 0, 0, n].into(),
+            container_id: Uuid::from_u128(n as u128),
+        }
+    }
+
+    #[test]
+    fn test_opens_and_probes() {
+        let cooldown = Duration::from_secs(30);
+        let breakers = Breakers::new(Some(3), cooldown);
+        let id = backend(1).container_id;
+        let now = Instant::now();
+        for _ in 0..2 {
+            breakers.record(id, false, now);
+        }
+        assert_eq!(breakers.status(&id, now).state, "closed");
+        // A success starts the count again.
+        breakers.record(id, true, now);
+        for _ in 0..3 {
+            breakers.record(id, false, now);
+        }
+        assert_eq!(breakers.status(&id, now).state, "open");
+        assert_eq!(breakers.status(&id, now).open_secs, Some(30));
+        assert!(!breakers.allows(&id, now));
+
+        // Half-open: one probe at a time.
+        let later = now + cooldown;
+        assert_eq!(breakers.status(&id, later).state, "half-open");
+        // Peeking doesn't take the probe.
+        let mut ring = ConsistentHash::new();
+        ring.add(&backend(1), 10);
+        let hashed = backend(1);
+        assert_eq!(breakers.peek(&ring, "10.1.2.3", &hashed, later), &hashed);
+        assert_eq!(breakers.peek(&ring, "10.1.2.3", &hashed, later), &hashed);
+        assert!(breakers.allows(&id, later));
+        assert!(!breakers.allows(&id, later));
+        breakers.record(id, false, later);
+        assert_eq!(breakers.status(&id, later).state, "open");
+
+        let later = later + cooldown;
+        assert!(breakers.allows(&id, later));
+        breakers.record(id, true, later);
+        assert_eq!(breakers.status(&id, later).state, "closed");
+        assert!(breakers.allows(&id, later));
+    }
+
+    #[test]
+    fn test_manual() {
+        // Without a failure threshold, failures alone never open it.
+        let breakers = Breakers::new(None, Duration::from_secs(30));
+        let id = backend(1).container_id;
+        let now = Instant::now();
+        for _ in 0..100 {
+            breakers.record(id, false, now);
+        }
+        assert!(breakers.allows(&id, now));
+
+        breakers.trip(id, Duration::from_secs(60), now);
+        let status = breakers.status(&id, now);
+        assert_eq!((status.state.as_str(), status.manual), ("open", true));
+        // Successes don't close it.
+        breakers.record(id, true, now);
+        assert!(!breakers.allows(&id, now));
+        // It closes, rather than going half-open, when it expires.
+        let later = now + Duration::from_secs(60);
+        assert_eq!(breakers.status(&id, later).state, "closed");
+        assert!(breakers.allows(&id, later));
+        assert!(breakers.allows(&id, later));
+
+        breakers.trip(id, Duration::from_secs(60), now);
+        breakers.reset(&id);
+        assert!(breakers.allows(&id, now));
+    }
+
+    #[test]
+    fn test_reroute() {
+        let mut ring = ConsistentHash::new();
+        for n in 1..=4 {
+            ring.add(&backend(n), 10);
+        }
+        let breakers = Breakers::new(None, Duration::from_secs(30));
+        let now = Instant::now();
+        let hashed = ring.get(b"10.1.2.3").unwrap().clone();
+        assert_eq!(breakers.reroute(&ring, "10.1.2.3", &hashed, now), &hashed);
+
+        breakers.trip(hashed.container_id, Duration::from_secs(60), now);
+        let picked = breakers.reroute(&ring, "10.1.2.3", &hashed, now);
+        assert_ne!(picked, &hashed);
+        assert_eq!(breakers.peek(&ring, "10.1.2.3", &hashed, now), picked);
+        // Consistently.
+        assert_eq!(breakers.reroute(&ring, "10.1.2.3", &hashed, now), picked);
+
+        // With all of them open, the hashed one regardless.
+        for n in 1..=4 {
+            breakers.trip(backend(n).container_id, Duration::from_secs(60), now);
+        }
+        assert_eq!(breakers.reroute(&ring, "10.1.2.3", &hashed, now), &hashed);
+    }
+}
@@ -0,0 +1,127 @@
+//! Reusable buffers for bodies the frontend collects in full rather than streaming through (e.g.
+//! coalesced responses), so collecting one doesn't allocate afresh. Streamed chunks don't need
+//! this: hyper reads and writes them through its own per-connection buffers.
+
+use bytes::{Bytes, BytesMut};
+use hyper::body::{Body, HttpBody as _};
+use opentelemetry::metrics::Counter;
+use std::sync::{Arc, Mutex};
+
+/// Size of each pooled buffer. Bodies up to this size are collected without allocating.
+pub const BUFFER_SIZE: usize = 64 * 1024;
+/// Most buffers kept for reuse; ones returned past this are freed.
+const MAX_POOLED: usize = 256;
+
+pub struct BufferPool {
+    buffers: Arc<Mutex<Vec<BytesMut>>>,
+    misses: Counter<u64>,
+}
+
+impl BufferPool {
+    pub fn new() -> anyhow::Result<Self> {
+        let buffers: Arc<Mutex<Vec<BytesMut>>> = Arc::new(Mutex::new(Vec::new()));
+
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+        let misses = meter
+            .u64_counter("body_buffer_pool_misses")
+            .with_description("Body buffers allocated because none were pooled")
+            .init();
+        let pooled_gauge = meter
+            .u64_observable_gauge("body_buffers_pooled")
+            .with_description("Body buffers waiting to be reused")
+            .init();
+        let buffers_ = buffers.clone();
+        meter.register_callback(&[pooled_gauge.as_any()], move |observer| {
+            observer.observe_u64(&pooled_gauge, buffers_.lock().unwrap().len() as u64, &[]);
+        })?;
+
+        Ok(Self { buffers, misses })
+    }
+
+    fn take(&self) -> BytesMut {
+        let buf = self.buffers.lock().unwrap().pop();
+        let mut buf = buf.unwrap_or_else(|| {
+            self.misses.add(1, &[]);
+            BytesMut::new()
+        });
+        // Reclaims the buffer's memory in place once everything split off it has been dropped.
+        buf.reserve(BUFFER_SIZE);
+        buf
+    }
+
+    fn give(&self, buf: BytesMut) {
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < MAX_POOLED {
+            buffers.push(buf);
+        }
+    }
+
+    /// Read all of `body`. Single-chunk bodies are passed on as they are, without copying.
+    pub async fn collect(&self, mut body: Body) -> Result<Bytes, hyper::Error> {
+        let Some(first) = body.data().await.transpose()? else {
+            return Ok(Bytes::new());
+        };
+        if body.is_end_stream() {
+            return Ok(first);
+        }
+
+        let mut buf = self.take();
+        buf.extend_from_slice(&first);
+        while let Some(chunk) = body.data().await {
+            match chunk {
+                Ok(chunk) => buf.extend_from_slice(&chunk),
+                Err(e) => {
+                    buf.clear();
+                    self.give(buf);
+                    return Err(e);
+                }
+            }
+        }
+        let len = buf.len();
+        let bytes = buf.split().freeze();
+        // A buffer that grew for a large body isn't kept, so it can be freed along with the body.
+        if len <= BUFFER_SIZE {
+            self.give(buf);
+        }
+        Ok(bytes)
+    }
+
+    #[cfg(test)]
+    fn pooled(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_collect() {
+        let pool = BufferPool::new().unwrap();
+        assert_eq!(pool.collect(Body::from("hello")).await.unwrap(), "hello");
+        assert_eq!(pool.pooled(), 0);
+
+        let chunks = || {
+            let chunks: Vec<Result<_, std::io::Error>> = vec![Ok("hello"), Ok(" world")];
+            Body::wrap_stream(futures::stream::iter(chunks))
+        };
+        let first = pool.collect(chunks()).await.unwrap();
+        assert_eq!(first, "hello world");
+        assert_eq!(pool.pooled(), 1);
+        drop(first);
+        assert_eq!(pool.collect(chunks()).await.unwrap(), "hello world");
+        assert_eq!(pool.pooled(), 1);
+
+        let large = vec![
+            Ok::<_, std::io::Error>(vec![0u8; BUFFER_SIZE]),
+            Ok(vec![0u8; 1]),
+        ];
+        let body = pool
+            .collect(Body::wrap_stream(futures::stream::iter(large)))
+            .await
+            .unwrap();
+        assert_eq!(body.len(), BUFFER_SIZE + 1);
+        assert_eq!(pool.pooled(), 0);
+    }
+}
@@ -0,0 +1,350 @@
+//! Canary analysis for traffic splits with `analysis` set: each frontend compares the error rate
+//! and p99 latency of the invocations it sent to the canary and to the stable function, window by
+//! window, and rolls the split back in ZooKeeper (raising an alert) if the canary regressed. A
+//! split ramping up with `on_regression: pause` is paused where it is instead.
+
+use anyhow::{Context, Result};
+use opentelemetry::metrics::Counter;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{event, Level};
+use uuid::Uuid;
+
+use bismuth_common::{CanaryAnalysis, FunctionConfig, RampAction, TrafficSplit};
+
+use crate::metric_labels;
+
+/// Latencies kept per side and window for the percentile; invocations past it are still counted.
+const MAX_SAMPLES: usize = 10_000;
+
+#[derive(Debug, Default)]
+struct Side {
+    requests: u64,
+    errors: u64,
+    latencies_ms: Vec<f64>,
+}
+
+impl Side {
+    fn record(&mut self, ok: bool, latency: Duration) {
+        self.requests += 1;
+        if !ok {
+            self.errors += 1;
+        }
+        if self.latencies_ms.len() < MAX_SAMPLES {
+            self.latencies_ms.push(latency.as_secs_f64() * 1000.0);
+        }
+    }
+
+    fn error_rate(&self) -> f64 {
+        self.errors as f64 / self.requests.max(1) as f64
+    }
+
+    fn p99(&self) -> f64 {
+        let mut latencies = self.latencies_ms.clone();
+        latencies.sort_by(f64::total_cmp);
+        let index = ((latencies.len() as f64 * 0.99).ceil() as usize).saturating_sub(1);
+        latencies.get(index).copied().unwrap_or_default()
+    }
+}
+
+struct Window {
+    canary: Uuid,
+    started: Instant,
+    stable: Side,
+    canary_side: Side,
+}
+
+/// How the canary compared with the stable function over a window it regressed in.
+#[derive(Debug, PartialEq)]
+pub struct Regression {
+    pub stable_error_rate: f64,
+    pub canary_error_rate: f64,
+    pub stable_p99_ms: f64,
+    pub canary_p99_ms: f64,
+}
+
+impl std::fmt::Display for Regression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "error rate {:.2}% vs {:.2}% stable, p99 {:.0}ms vs {:.0}ms stable",
+            self.canary_error_rate * 100.0,
+            self.stable_error_rate * 100.0,
+            self.canary_p99_ms,
+            self.stable_p99_ms
+        )
+    }
+}
+
+fn compare(stable: &Side, canary: &Side, analysis: &CanaryAnalysis) -> Option<Regression> {
+    if stable.requests < analysis.min_requests || canary.requests < analysis.min_requests {
+        return None;
+    }
+    let regression = Regression {
+        stable_error_rate: stable.error_rate(),
+        canary_error_rate: canary.error_rate(),
+        stable_p99_ms: stable.p99(),
+        canary_p99_ms: canary.p99(),
+    };
+    let errors = regression.canary_error_rate - regression.stable_error_rate
+        > analysis.max_error_rate_increase;
+    let latency = regression.canary_p99_ms > regression.stable_p99_ms * analysis.max_latency_ratio;
+    (errors || latency).then_some(regression)
+}
+
+pub struct CanaryAnalyzer {
+    /// By split (stable) function.
+    windows: Mutex<HashMap<Uuid, Window>>,
+    rollbacks: Counter<u64>,
+    pauses: Counter<u64>,
+}
+
+impl CanaryAnalyzer {
+    pub fn new() -> Self {
+        Self {
+            windows: Mutex::new(HashMap::new()),
+            rollbacks: opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+                .u64_counter("canary_rollbacks")
+                .with_description("Traffic splits rolled back because the canary regressed")
+                .init(),
+            pauses: opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+                .u64_counter("canary_ramps_paused")
+                .with_description("Traffic split ramps paused because the canary regressed")
+                .init(),
+        }
+    }
+
+    /// Record an invocation of `function_id`, served by the canary or not. `ok` is whether it got
+    /// a response that wasn't a 5xx. Returns a regression when a window ends with one.
+    pub fn record(
+        &self,
+        function_id: Uuid,
+        split: &TrafficSplit,
+        to_canary: bool,
+        ok: bool,
+        latency: Duration,
+        now: Instant,
+    ) -> Option<Regression> {
+        let analysis = split.analysis.as_ref()?;
+        // Nothing to compare once rolled back (or not started).
+        if split.current_percent(SystemTime::now()) <= 0.0 {
+            return None;
+        }
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows.entry(function_id).or_insert_with(|| Window {
+            canary: split.canary,
+            started: now,
+            stable: Side::default(),
+            canary_side: Side::default(),
+        });
+        if window.canary != split.canary {
+            *window = Window {
+                canary: split.canary,
+                started: now,
+                stable: Side::default(),
+                canary_side: Side::default(),
+            };
+        }
+        if to_canary {
+            window.canary_side.record(ok, latency);
+        } else {
+            window.stable.record(ok, latency);
+        }
+        if now.duration_since(window.started) < Duration::from_secs(analysis.window_secs) {
+            return None;
+        }
+        let regression = compare(&window.stable, &window.canary_side, analysis);
+        windows.remove(&function_id);
+        regression
+    }
+
+    /// In the background, set the split's percentage to 0, or pause its ramp, (unless it's been
+    /// changed to another canary meanwhile) and alert.
+    pub fn roll_back(
+        &self,
+        zk: zookeeper_client::Client,
+        function_id: Uuid,
+        canary: Uuid,
+        regression: Regression,
+    ) {
+        let (rollbacks, pauses) = (self.rollbacks.clone(), self.pauses.clone());
+        tokio::spawn(async move {
+            let counters = (&rollbacks, &pauses);
+            if let Err(e) = roll_back(&zk, function_id, canary, &regression, counters).await {
+                event!(Level::WARN, function = %function_id, error = %e, "Error rolling back canary");
+            }
+        });
+    }
+}
+
+impl Default for CanaryAnalyzer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+async fn roll_back(
+    zk: &zookeeper_client::Client,
+    function_id: Uuid,
+    canary: Uuid,
+    regression: &Regression,
+    (rollbacks, pauses): (&Counter<u64>, &Counter<u64>),
+) -> Result<()> {
+    let path = format!("/function/{}/config", function_id);
+    let (data, stat) = zk
+        .get_data(&path)
+        .await
+        .context("Error getting function config")?;
+    let mut config: FunctionConfig = serde_json::from_slice(&data)
+        .with_context(|| format!("Invalid config for function {}", function_id))?;
+    let now = SystemTime::now();
+    let paused_at = match &mut config.split {
+        Some(split) if split.canary == canary && split.current_percent(now) > 0.0 => {
+            let percent = split.current_percent(now);
+            match &mut split.ramp {
+                Some(ramp) if ramp.on_regression == RampAction::Pause => {
+                    if ramp.paused_at.is_some() {
+                        return Ok(());
+                    }
+                    ramp.paused_at = Some(now.duration_since(UNIX_EPOCH)?.as_secs());
+                    Some(percent)
+                }
+                _ => {
+                    split.percent = 0.0;
+                    split.ramp = None;
+                    None
+                }
+            }
+        }
+        _ => return Ok(()),
+    };
+    // Fails if the config changed since it was read, e.g. another frontend rolled it back.
+    zk.set_data(&path, &serde_json::to_vec(&config)?, Some(stat.version))
+        .await
+        .context("Error rolling back traffic split")?;
+
+    let attributes = [metric_labels::function(&function_id)];
+    let message = match paused_at {
+        Some(percent) => {
+            pauses.add(1, &attributes);
+            format!(
+                "Paused ramp of canary {} of function {} at {}%: {}",
+                canary, function_id, percent, regression
+            )
+        }
+        None => {
+            rollbacks.add(1, &attributes);
+            format!(
+                "Rolled back canary {} of function {}: {}",
+                canary, function_id, regression
+            )
+        }
+    };
+    event!(Level::ERROR, function = %function_id, canary = %canary, "{}", message);
+    sentry::capture_message(&message, sentry::Level::Error);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(canary: Uuid) -> TrafficSplit {
+        TrafficSplit {
+            canary,
+            percent: 10.0,
+            analysis: Some(CanaryAnalysis {
+                window_secs: 60,
+                min_requests: 10,
+                max_error_rate_increase: 0.05,
+                max_latency_ratio: 2.0,
+            }),
+            ramp: None,
+        }
+    }
+
+    #[test]
+    fn test_ramp() {
+        let mut ramp = bismuth_common::Ramp {
+            started: 1000,
+            duration_secs: 100,
+            steps: 4,
+            from_percent: 1.0,
+            on_regression: RampAction::Pause,
+            paused_at: None,
+        };
+        assert_eq!(ramp.percent_at(900), 1.0);
+        assert_eq!(ramp.percent_at(1024), 1.0);
+        assert_eq!(ramp.percent_at(1025), 25.75);
+        assert_eq!(ramp.percent_at(1075), 75.25);
+        assert_eq!(ramp.percent_at(1100), 100.0);
+        assert_eq!(ramp.percent_at(5000), 100.0);
+        // Held where it was when paused.
+        ramp.paused_at = Some(1050);
+        assert_eq!(ramp.percent_at(5000), 50.5);
+
+        let split = TrafficSplit {
+            ramp: Some(ramp),
+            ..split(Uuid::nil())
+        };
+        assert_eq!(split.current_percent(SystemTime::now()), 50.5);
+    }
+
+    #[test]
+    fn test_compare() {
+        let analysis = split(Uuid::nil()).analysis.unwrap();
+        let side = |requests, errors, latency_ms| {
+            let mut side = Side::default();
+            for i in 0..requests {
+                side.record(i >= errors, Duration::from_millis(latency_ms));
+            }
+            side
+        };
+        assert_eq!(
+            compare(&side(100, 1, 50), &side(100, 3, 80), &analysis),
+            None
+        );
+        assert!(compare(&side(100, 1, 50), &side(100, 10, 50), &analysis).is_some());
+        assert!(compare(&side(100, 0, 50), &side(100, 0, 150), &analysis).is_some());
+        // Too few canary invocations to tell.
+        assert_eq!(
+            compare(&side(100, 0, 50), &side(5, 5, 150), &analysis),
+            None
+        );
+    }
+
+    #[test]
+    fn test_record() {
+        let analyzer = CanaryAnalyzer::new();
+        let function_id = Uuid::new_v4();
+        let split = split(Uuid::new_v4());
+        let start = Instant::now();
+        for i in 0..20 {
+            let now = start + Duration::from_secs(i);
+            let latency = Duration::from_millis(10);
+            assert_eq!(
+                analyzer.record(function_id, &split, false, true, latency, now),
+                None
+            );
+            assert_eq!(
+                analyzer.record(function_id, &split, true, false, latency, now),
+                None
+            );
+        }
+        let regression = analyzer
+            .record(
+                function_id,
+                &split,
+                true,
+                false,
+                Duration::from_millis(10),
+                start + Duration::from_secs(60),
+            )
+            .unwrap();
+        assert_eq!(regression.stable_error_rate, 0.0);
+        assert_eq!(regression.canary_error_rate, 1.0);
+        // A new window starts.
+        assert!(analyzer.windows.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,199 @@
+//! Recording a sample of a function's invocations (see `bismuth_common::Capture`) to a local
+//! directory, for replaying them with `bismuthctl replay`. Request bodies are copied as they
+//! stream to the backend, and records are written by a background task so a slow disk never
+//! holds invocations up: if it falls behind, records are dropped.
+
+use anyhow::{Context, Result};
+use axum::http::Request;
+use futures::StreamExt as _;
+use hyper::body::{Body, HttpBody as _};
+use opentelemetry::metrics::Counter;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt as _;
+use tokio::sync::mpsc;
+use tracing::{event, Level};
+use uuid::Uuid;
+
+use bismuth_common::{Capture, CapturedRequest};
+
+/// Records waiting to be written before new ones are dropped.
+const QUEUE_LEN: usize = 1024;
+
+pub struct Capturer {
+    tx: mpsc::Sender<CapturedRequest>,
+    dropped: Counter<u64>,
+}
+
+impl Capturer {
+    /// Write records to `dir`, which is created if it doesn't exist.
+    pub fn new(dir: PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Error creating capture dir {}", dir.display()))?;
+        let (tx, mut rx) = mpsc::channel::<CapturedRequest>(QUEUE_LEN);
+        tokio::spawn(async move {
+            while let Some(record) = rx.recv().await {
+                if let Err(e) = write(&dir, &record).await {
+                    event!(Level::WARN, function = %record.function_id, error = %e, "Error writing captured request");
+                }
+            }
+        });
+        Ok(Self {
+            tx,
+            dropped: opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+                .u64_counter("captures_dropped")
+                .with_description("Captured requests dropped because the sink fell behind")
+                .init(),
+        })
+    }
+
+    /// Record `req` if it's sampled, once its body has been sent on. `path` is the path within
+    /// the function.
+    pub fn capture(
+        &self,
+        capture: &Capture,
+        function_id: Uuid,
+        path: &str,
+        req: &mut Request<Body>,
+    ) {
+        if rand::random::<f64>() >= capture.sample_rate {
+            return;
+        }
+        let scrubber = bismuth_common::scrubber();
+        let path = match req.uri().query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path.to_string(),
+        };
+        let record = CapturedRequest {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            function_id,
+            method: req.method().to_string(),
+            path: scrubber.scrub(&path).into_owned(),
+            headers: req
+                .headers()
+                .iter()
+                .filter(|(name, _)| !scrubber.is_sensitive_header(name.as_str()))
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect(),
+            body: Vec::new(),
+            body_truncated: false,
+        };
+        let mut recording = Recording {
+            record: Some(record),
+            max_body_bytes: capture.max_body_bytes as usize,
+            tx: self.tx.clone(),
+            dropped: self.dropped.clone(),
+        };
+        if req.body().is_end_stream() {
+            // Recorded straight away, as `recording` is dropped.
+            return;
+        }
+        let body = std::mem::take(req.body_mut()).map(move |chunk| {
+            if let Ok(chunk) = &chunk {
+                recording.append(chunk);
+            }
+            chunk
+        });
+        *req.body_mut() = Body::wrap_stream(body);
+    }
+}
+
+/// Sent once dropped, i.e. when the whole body has been read (or the request was abandoned).
+struct Recording {
+    record: Option<CapturedRequest>,
+    max_body_bytes: usize,
+    tx: mpsc::Sender<CapturedRequest>,
+    dropped: Counter<u64>,
+}
+
+impl Recording {
+    fn append(&mut self, chunk: &[u8]) {
+        let Some(record) = &mut self.record else {
+            return;
+        };
+        let room = self.max_body_bytes.saturating_sub(record.body.len());
+        if chunk.len() > room {
+            record.body_truncated = true;
+        }
+        record
+            .body
+            .extend_from_slice(&chunk[..chunk.len().min(room)]);
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        if let Some(record) = self.record.take() {
+            if self.tx.try_send(record).is_err() {
+                self.dropped.add(1, &[]);
+            }
+        }
+    }
+}
+
+async fn write(dir: &std::path::Path, record: &CapturedRequest) -> Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    let path = dir.join(format!("{}.jsonl", record.function_id));
+    tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await
+        .with_context(|| format!("Error opening {}", path.display()))?
+        .write_all(&line)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_capture() {
+        let dir = std::env::temp_dir().join(format!("bismuthfe-capture-{}", Uuid::new_v4()));
+        let capturer = Capturer::new(dir.clone()).unwrap();
+        let capture = Capture {
+            sample_rate: 1.0,
+            max_body_bytes: 8,
+        };
+        let function_id = Uuid::new_v4();
+        let chunks: Vec<Result<_, std::io::Error>> = vec![Ok("hello"), Ok(" world")];
+        let mut req = Request::post("/invoke/f/run?x=1")
+            .header("authorization", "Bearer secret")
+            .header("x-test", "1")
+            .body(Body::wrap_stream(futures::stream::iter(chunks)))
+            .unwrap();
+        capturer.capture(&capture, function_id, "run", &mut req);
+        // Passed on whole, whatever's recorded.
+        assert_eq!(
+            hyper::body::to_bytes(req.into_body()).await.unwrap(),
+            "hello world"
+        );
+
+        let path = dir.join(format!("{}.jsonl", function_id));
+        let mut written = String::new();
+        for _ in 0..50 {
+            written = tokio::fs::read_to_string(&path).await.unwrap_or_default();
+            if !written.is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let record: CapturedRequest = serde_json::from_str(written.trim_end()).unwrap();
+        assert_eq!(record.path, "run?x=1");
+        assert_eq!(record.body, b"hello wo");
+        assert!(record.body_truncated);
+        assert_eq!(
+            record.headers,
+            vec![("x-test".to_string(), "1".to_string())]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
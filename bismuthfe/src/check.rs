@@ -0,0 +1,420 @@
+//! `--check-config`: what `Server::new` would fail on, checked without binding or serving, and
+//! the functions' data in ZooKeeper that the frontend would only log warnings about at runtime,
+//! so a bad rollout can be caught in CI first.
+
+use anyhow::{anyhow, Context, Result};
+use std::time::Duration;
+use uuid::Uuid;
+
+use bismuth_common::{FeatureFlags, FunctionConfig, FunctionDefinition, TenantToken};
+
+use crate::{dns, geo, middleware, tokens, wasm, BackendMonitor, Config, FEATURE_FLAGS_PATH};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// The HTTP/2 limits on SETTINGS_MAX_FRAME_SIZE and window sizes.
+const MIN_FRAME_SIZE: u32 = 1 << 14;
+const MAX_FRAME_SIZE: u32 = (1 << 24) - 1;
+const MAX_WINDOW_SIZE: u32 = (1 << 31) - 1;
+
+/// Something wrong with the config, and where.
+#[derive(Debug, PartialEq)]
+pub struct Problem {
+    pub what: String,
+    pub error: String,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.what, self.error)
+    }
+}
+
+struct Problems(Vec<Problem>);
+
+impl Problems {
+    fn add(&mut self, what: impl Into<String>, error: impl std::fmt::Display) {
+        self.0.push(Problem {
+            what: what.into(),
+            error: error.to_string(),
+        });
+    }
+
+    fn check<T>(&mut self, what: impl Into<String>, result: Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.add(what, format!("{:#}", e));
+                None
+            }
+        }
+    }
+}
+
+/// A ZooKeeper connection string: comma-separated `host:port`s, optionally followed by a chroot.
+pub fn zookeeper_hosts(connect: &str) -> Result<()> {
+    let (hosts, chroot) = match connect.find('/') {
+        Some(i) => (&connect[..i], Some(&connect[i..])),
+        None => (connect, None),
+    };
+    if hosts.is_empty() {
+        return Err(anyhow!("No hosts"));
+    }
+    for host in hosts.split(',') {
+        let (name, port) = host
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("{:?} has no port", host))?;
+        if name.is_empty() {
+            return Err(anyhow!("{:?} has no host name", host));
+        }
+        match port.parse::<u16>() {
+            Ok(port) if port > 0 => {}
+            _ => return Err(anyhow!("{:?} has an invalid port", host)),
+        }
+    }
+    if let Some(chroot) = chroot {
+        if chroot.len() > 1 && chroot.ends_with('/') {
+            return Err(anyhow!("Chroot {:?} ends with '/'", chroot));
+        }
+    }
+    Ok(())
+}
+
+/// Problems with the settings themselves, and the files they name.
+pub fn check_settings(config: &Config) -> Vec<Problem> {
+    let mut problems = Problems(Vec::new());
+    if config.dev_backend.is_none() {
+        problems.check("--zookeeper", zookeeper_hosts(&config.zookeeper));
+        if config.zookeeper_env.is_empty() || config.zookeeper_env.contains('/') {
+            problems.add("--zookeeper-env", "must be non-empty and not contain '/'");
+        }
+        for (i, env) in config.extra_zookeeper_envs.iter().enumerate() {
+            if env.is_empty() || env.contains('/') {
+                problems.add(
+                    "--extra-zookeeper-env",
+                    format!("{:?} must be non-empty and not contain '/'", env),
+                );
+            } else if *env == config.zookeeper_env || config.extra_zookeeper_envs[..i].contains(env)
+            {
+                problems.add(
+                    "--extra-zookeeper-env",
+                    format!("{:?} is already served", env),
+                );
+            }
+        }
+    } else {
+        if config.require_tenant_token {
+            problems.add(
+                "--require-tenant-token",
+                "tenant tokens need ZooKeeper, not a dev backend",
+            );
+        }
+        if !config.extra_zookeeper_envs.is_empty() {
+            problems.add(
+                "--extra-zookeeper-env",
+                "more environments need ZooKeeper, not a dev backend",
+            );
+        }
+    }
+    if let Some((low, high)) = config.shed_watermarks {
+        if low > high {
+            problems.add(
+                "--shed-low-watermark",
+                format!("{} is above the high watermark {}", low, high),
+            );
+        }
+    }
+    if let Some(limits) = config.adaptive_concurrency {
+        if limits.max < limits.min {
+            problems.add(
+                "--adaptive-concurrency-max",
+                format!("must be at least {}", limits.min),
+            );
+        }
+    }
+    if let Some(queue) = config.invocation_queue {
+        if queue.max_in_flight == 0 {
+            problems.add("--max-in-flight", "must be at least 1");
+        }
+    }
+    if let Some(watermarks) = config.host_watermarks {
+        if watermarks.cpu.is_some_and(|cpu| !(cpu > 0.0 && cpu <= 1.0)) {
+            problems.add("--cpu-high-watermark", "must be over 0 and at most 1");
+        }
+        if watermarks.memory_bytes == Some(0) {
+            problems.add("--memory-high-watermark-bytes", "must be at least 1");
+        }
+    }
+    if let Some(size) = config.http2.max_frame_size {
+        if !(MIN_FRAME_SIZE..=MAX_FRAME_SIZE).contains(&size) {
+            problems.add(
+                "--http2-max-frame-bytes",
+                format!("must be from {} to {}", MIN_FRAME_SIZE, MAX_FRAME_SIZE),
+            );
+        }
+    }
+    for (flag, size) in [
+        (
+            "--http2-stream-window-bytes",
+            config.http2.initial_stream_window_size,
+        ),
+        (
+            "--http2-connection-window-bytes",
+            config.http2.initial_connection_window_size,
+        ),
+    ] {
+        if size.is_some_and(|size| size > MAX_WINDOW_SIZE) {
+            problems.add(flag, format!("must be at most {}", MAX_WINDOW_SIZE));
+        }
+    }
+    if let Some(geoip_db) = &config.geoip_db {
+        problems.check("--geoip-db", geo::Geo::open(geoip_db, &config.geo_regions));
+    }
+    problems.check(
+        "--wasm-filter",
+        wasm::Filters::load(&config.wasm_filters, config.wasm_filter_fuel),
+    );
+    problems.check(
+        "--middleware",
+        middleware::build(&config.middlewares, config),
+    );
+    problems.check("--dns-server", dns::Resolver::new(&config.dns_servers));
+    if let Some(dir) = &config.capture_dir {
+        // Created on startup if it doesn't exist, which needs its parent.
+        let usable = match dir.metadata() {
+            Ok(metadata) => metadata.is_dir(),
+            Err(_) => match dir.parent() {
+                Some(parent) => parent.as_os_str().is_empty() || parent.is_dir(),
+                None => true,
+            },
+        };
+        if !usable {
+            problems.add(
+                "--capture-dir",
+                format!("{} isn't a directory", dir.display()),
+            );
+        }
+    }
+    if let Some(parent) = config.routing_cache.as_ref().and_then(|p| p.parent()) {
+        if !parent.as_os_str().is_empty() && !parent.is_dir() {
+            problems.add(
+                "--routing-cache",
+                format!("{} isn't a directory", parent.display()),
+            );
+        }
+    }
+    problems.0
+}
+
+/// Problems with a function's config that parses, but that frontends can't act on as intended.
+fn check_function_config(config: &Config, function_config: &FunctionConfig) -> Vec<String> {
+    let mut errors = Vec::new();
+    if let Some(profile) = &function_config.latency_buckets {
+        if !config.latency_bucket_profiles.contains(profile) {
+            errors.push(format!("unknown histogram bucket profile {:?}", profile));
+        }
+    }
+    for filter in &function_config.filters {
+        if !config.wasm_filters.iter().any(|(name, _)| name == filter) {
+            errors.push(format!("unknown WASM filter {:?}", filter));
+        }
+    }
+    if let Some(split) = &function_config.split {
+        if !(0.0..=100.0).contains(&split.percent) {
+            errors.push("split percent must be from 0 to 100".to_string());
+        }
+        if let Some(ramp) = &split.ramp {
+            if ramp.duration_secs == 0 || ramp.steps == 0 {
+                errors.push("split ramp duration_secs and steps must be more than 0".to_string());
+            }
+            if !(0.0..=100.0).contains(&ramp.from_percent) {
+                errors.push("split ramp from_percent must be from 0 to 100".to_string());
+            }
+        }
+    }
+    if let Some(capture) = &function_config.capture {
+        if !(0.0..=1.0).contains(&capture.sample_rate) {
+            errors.push("capture sample_rate must be from 0 to 1".to_string());
+        }
+    }
+    if let Some(access_log) = &function_config.access_log {
+        if !(0.0..=1.0).contains(&access_log.sample_rate) {
+            errors.push("access_log sample_rate must be from 0 to 1".to_string());
+        }
+    }
+    if function_config
+        .rate_limit
+        .as_ref()
+        .is_some_and(|r| r.requests == 0)
+    {
+        errors.push("rate_limit requests must be positive".to_string());
+    }
+    errors
+}
+
+async fn get(zk: &zookeeper_client::Client, path: &str) -> Result<Option<Vec<u8>>> {
+    match zk.get_data(path).await {
+        Ok((data, _)) => Ok(Some(data)),
+        Err(zookeeper_client::Error::NoNode) => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Error reading {}", path)),
+    }
+}
+
+/// Problems with what's stored in ZooKeeper: every function's definition and config, the
+/// feature flags and the tenant tokens.
+pub async fn check_zookeeper(config: &Config) -> Vec<Problem> {
+    let mut problems = Problems(Vec::new());
+    let zk = match tokio::time::timeout(
+        CONNECT_TIMEOUT,
+        BackendMonitor::connect(&config.zookeeper, &config.zookeeper_env),
+    )
+    .await
+    {
+        Ok(zk) => zk,
+        Err(_) => Err(anyhow!("Timed out connecting")),
+    };
+    let Some(zk) = problems.check("--zookeeper", zk) else {
+        return problems.0;
+    };
+
+    let functions = match zk.list_children("/function").await {
+        Ok(functions) => functions,
+        Err(e) => {
+            problems.add("/function", e);
+            return problems.0;
+        }
+    };
+    for function in functions {
+        let path = format!("/function/{}", function);
+        if Uuid::parse_str(&function).is_err() {
+            problems.add(&path, "not a function ID");
+            continue;
+        }
+        match problems.check(&path, get(&zk, &path).await).flatten() {
+            Some(data) => match serde_json::from_slice::<FunctionDefinition>(&data) {
+                Ok(definition) => {
+                    if let Err(e) = definition.validate() {
+                        problems.add(&path, e);
+                    }
+                }
+                Err(e) => problems.add(&path, e),
+            },
+            None => continue,
+        }
+        let config_path = format!("{}/config", path);
+        if let Some(data) = problems
+            .check(&config_path, get(&zk, &config_path).await)
+            .flatten()
+        {
+            match serde_json::from_slice::<FunctionConfig>(&data) {
+                Ok(function_config) => {
+                    for error in check_function_config(config, &function_config) {
+                        problems.add(&config_path, error);
+                    }
+                }
+                Err(e) => problems.add(&config_path, e),
+            }
+        }
+    }
+
+    if let Some(data) = problems
+        .check(FEATURE_FLAGS_PATH, get(&zk, FEATURE_FLAGS_PATH).await)
+        .flatten()
+    {
+        if let Err(e) = serde_json::from_slice::<FeatureFlags>(&data) {
+            problems.add(FEATURE_FLAGS_PATH, e);
+        }
+    }
+    let token_ids = match zk.list_children(tokens::TOKENS_PATH).await {
+        Ok(ids) => ids,
+        Err(zookeeper_client::Error::NoNode) => Vec::new(),
+        Err(e) => {
+            problems.add(tokens::TOKENS_PATH, e);
+            Vec::new()
+        }
+    };
+    for id in token_ids {
+        let path = format!("{}/{}", tokens::TOKENS_PATH, id);
+        if let Some(data) = problems.check(&path, get(&zk, &path).await).flatten() {
+            if let Err(e) = serde_json::from_slice::<TenantToken>(&data) {
+                problems.add(&path, e);
+            }
+        }
+    }
+    problems.0
+}
+
+/// Every problem found, in the settings and (unless using a dev backend) in ZooKeeper.
+pub async fn check(config: &Config) -> Vec<Problem> {
+    let mut problems = check_settings(config);
+    // Without a valid connection string, there's nothing to connect to.
+    if config.dev_backend.is_none() && zookeeper_hosts(&config.zookeeper).is_ok() {
+        problems.extend(check_zookeeper(config).await);
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zookeeper_hosts() {
+        assert!(zookeeper_hosts("127.0.0.1:2181").is_ok());
+        assert!(zookeeper_hosts("zk1:2181,zk2:2181,zk3:2181/bismuth").is_ok());
+        assert!(zookeeper_hosts("[::1]:2181").is_ok());
+        assert!(zookeeper_hosts("").is_err());
+        assert!(zookeeper_hosts("zk1").is_err());
+        assert!(zookeeper_hosts("zk1:2181,").is_err());
+        assert!(zookeeper_hosts("zk1:0").is_err());
+        assert!(zookeeper_hosts(":2181").is_err());
+        assert!(zookeeper_hosts("zk1:2181/bismuth/").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_check_settings() {
+        // Not the system's resolvers, which may not be configured here.
+        let dns_servers = vec!["127.0.0.1:53".parse().unwrap()];
+        let config = Config {
+            dns_servers: dns_servers.clone(),
+            ..Config::default()
+        };
+        assert_eq!(check_settings(&config), Vec::new());
+
+        let mut config = Config {
+            zookeeper: "zk1".to_string(),
+            dns_servers,
+            extra_zookeeper_envs: vec!["staging".to_string(), "default".to_string()],
+            shed_watermarks: Some((10, 5)),
+            middlewares: vec!["nonexistent".to_string()],
+            ..Config::default()
+        };
+        config.http2.max_frame_size = Some(1024);
+        let problems: Vec<String> = check_settings(&config)
+            .into_iter()
+            .map(|p| p.what)
+            .collect();
+        assert_eq!(
+            problems,
+            [
+                "--zookeeper",
+                "--extra-zookeeper-env",
+                "--shed-low-watermark",
+                "--http2-max-frame-bytes",
+                "--middleware"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_function_config() {
+        let function_config: FunctionConfig = serde_json::from_str(
+            r#"{"latency_buckets": "slow", "filters": ["auth"], "rate_limit": {"requests": 0}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            check_function_config(&Config::default(), &function_config).len(),
+            3
+        );
+        assert!(check_function_config(&Config::default(), &FunctionConfig::default()).is_empty());
+    }
+}
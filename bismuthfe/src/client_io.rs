@@ -0,0 +1,476 @@
+//! Timeouts on the client side of the proxy, so connections that trickle their bytes (whether to
+//! pin the frontend's resources, as in Slowloris, or just from a bad network) can't each hold a
+//! connection, a task and its buffers indefinitely:
+//! * a request's headers must arrive within the header timeout (enforced by hyper)
+//! * a connection without a request in flight is closed after the idle timeout without traffic
+//! * with a minimum rate, request bodies and responses must move at least that fast, after a
+//!   grace period. Only time spent waiting on the client counts, not e.g. a backend reading the
+//!   request body slowly.
+
+use axum::extract::connect_info::{ConnectInfo, Connected};
+use axum::middleware::AddExtension;
+use axum::response::Response;
+use futures::future::BoxFuture;
+use futures::Stream;
+use hyper::body::{Body, Bytes, HttpBody, SizeHint};
+use hyper::server::accept::Accept;
+use hyper::Request;
+use opentelemetry::metrics::Counter;
+use opentelemetry::KeyValue;
+use std::convert::Infallible;
+use std::future::Future as _;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+use tower::Layer as _;
+
+/// How long a transfer may wait on the client before the minimum rate applies.
+const RATE_GRACE: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClientTimeouts {
+    pub header: Option<Duration>,
+    pub idle: Option<Duration>,
+    /// Must be more than 0.
+    pub min_bytes_per_sec: Option<u64>,
+}
+
+fn timeouts_counter() -> Counter<u64> {
+    opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+        .u64_counter("client_timeouts")
+        .with_description("Client connections and request bodies cut off for being idle or slow")
+        .init()
+}
+
+fn timed_out(reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, reason)
+}
+
+/// Time spent waiting on a client, against the time allowed for the bytes it has transferred.
+#[derive(Default)]
+struct RateBudget {
+    bytes: u64,
+    waited: Duration,
+    waiting_since: Option<Instant>,
+}
+
+impl RateBudget {
+    /// When waiting has to end, given it's (still) waiting as of `now`.
+    fn deadline(&mut self, min_bytes_per_sec: u64, now: Instant) -> Instant {
+        let since = *self.waiting_since.get_or_insert(now);
+        let allowed =
+            RATE_GRACE + Duration::from_secs_f64(self.bytes as f64 / min_bytes_per_sec as f64);
+        since + allowed.saturating_sub(self.waited)
+    }
+
+    fn progress(&mut self, bytes: usize, now: Instant) {
+        if let Some(since) = self.waiting_since.take() {
+            self.waited += now - since;
+        }
+        self.bytes += bytes as u64;
+    }
+}
+
+/// Ready once `deadline` has passed, and otherwise woken then.
+fn poll_deadline(timer: &mut Pin<Box<Sleep>>, deadline: Instant, cx: &mut Context<'_>) -> bool {
+    if timer.deadline() != deadline {
+        timer.as_mut().reset(deadline);
+    }
+    timer.as_mut().poll(cx).is_ready()
+}
+
+/// Wraps accepted connections in `TimedStream`s.
+pub struct TimedIncoming<I> {
+    inner: I,
+    timeouts: ClientTimeouts,
+    counter: Counter<u64>,
+}
+
+impl<I> TimedIncoming<I> {
+    pub fn new(inner: I, timeouts: ClientTimeouts) -> Self {
+        Self {
+            inner,
+            timeouts,
+            counter: timeouts_counter(),
+        }
+    }
+}
+
+impl<I: Accept + Unpin> Accept for TimedIncoming<I> {
+    type Conn = TimedStream<I::Conn>;
+    type Error = I::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = &mut *self;
+        Pin::new(&mut this.inner).poll_accept(cx).map(|conn| {
+            conn.map(|conn| conn.map(|conn| TimedStream::new(conn, this.timeouts, &this.counter)))
+        })
+    }
+}
+
+pub struct TimedStream<S> {
+    inner: S,
+    idle: Option<(Duration, Pin<Box<Sleep>>)>,
+    write_rate: Option<(u64, RateBudget, Pin<Box<Sleep>>)>,
+    /// Requests whose responses haven't been sent yet, counted by `Tracked`.
+    in_flight: Arc<AtomicUsize>,
+    counter: Counter<u64>,
+}
+
+impl<S> TimedStream<S> {
+    fn new(inner: S, timeouts: ClientTimeouts, counter: &Counter<u64>) -> Self {
+        let now = Instant::now();
+        Self {
+            inner,
+            idle: timeouts
+                .idle
+                .map(|idle| (idle, Box::pin(tokio::time::sleep_until(now + idle)))),
+            write_rate: timeouts.min_bytes_per_sec.map(|min| {
+                (
+                    min,
+                    RateBudget::default(),
+                    Box::pin(tokio::time::sleep_until(now)),
+                )
+            }),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            counter: counter.clone(),
+        }
+    }
+
+    fn touch(&mut self) {
+        if let Some((idle, timer)) = &mut self.idle {
+            timer.as_mut().reset(Instant::now() + *idle);
+        }
+    }
+
+    fn on_write(
+        &mut self,
+        cx: &mut Context<'_>,
+        poll: Poll<io::Result<usize>>,
+    ) -> Poll<io::Result<usize>> {
+        match poll {
+            Poll::Ready(Ok(n)) => {
+                self.touch();
+                if let Some((_, budget, _)) = &mut self.write_rate {
+                    budget.progress(n, Instant::now());
+                }
+                Poll::Ready(Ok(n))
+            }
+            Poll::Pending => {
+                if let Some((min, budget, timer)) = &mut self.write_rate {
+                    // Written only as fast as the client reads.
+                    if poll_deadline(timer, budget.deadline(*min, Instant::now()), cx) {
+                        self.counter.add(1, &[KeyValue::new("reason", "slow_read")]);
+                        return Poll::Ready(Err(timed_out("Client reading too slowly")));
+                    }
+                }
+                Poll::Pending
+            }
+            poll => poll,
+        }
+    }
+}
+
+impl<'a, S> Connected<&'a TimedStream<S>> for SocketAddr
+where
+    SocketAddr: Connected<&'a S>,
+{
+    fn connect_info(target: &'a TimedStream<S>) -> Self {
+        SocketAddr::connect_info(&target.inner)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TimedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                if buf.filled().len() > filled {
+                    this.touch();
+                }
+                Poll::Ready(result)
+            }
+            Poll::Pending => {
+                // hyper keeps reading while a request is handled, to notice the client going
+                // away, so the connection is only idle between requests.
+                if let Some((_, timer)) = &mut this.idle {
+                    if this.in_flight.load(Ordering::Relaxed) == 0
+                        && timer.as_mut().poll(cx).is_ready()
+                    {
+                        this.counter.add(1, &[KeyValue::new("reason", "idle")]);
+                        return Poll::Ready(Err(timed_out("Client connection idle")));
+                    }
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TimedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.on_write(cx, poll)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write_vectored(cx, bufs);
+        this.on_write(cx, poll)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Makes a service per connection like `into_make_service_with_connect_info::<SocketAddr>`,
+/// that also tells the connection when it has requests in flight.
+#[derive(Clone)]
+pub struct MakeService {
+    router: axum::Router,
+    min_bytes_per_sec: Option<u64>,
+    counter: Counter<u64>,
+}
+
+impl MakeService {
+    pub fn new(router: axum::Router, timeouts: ClientTimeouts) -> Self {
+        Self {
+            router,
+            min_bytes_per_sec: timeouts.min_bytes_per_sec,
+            counter: timeouts_counter(),
+        }
+    }
+}
+
+impl<'a, S> tower::Service<&'a TimedStream<S>> for MakeService
+where
+    SocketAddr: Connected<&'a S>,
+{
+    type Response = Tracked<AddExtension<axum::Router, ConnectInfo<SocketAddr>>>;
+    type Error = Infallible;
+    type Future = futures::future::Ready<Result<Self::Response, Infallible>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, stream: &'a TimedStream<S>) -> Self::Future {
+        let client = SocketAddr::connect_info(stream);
+        futures::future::ready(Ok(Tracked {
+            inner: axum::Extension(ConnectInfo(client)).layer(self.router.clone()),
+            in_flight: stream.in_flight.clone(),
+            min_bytes_per_sec: self.min_bytes_per_sec,
+            counter: self.counter.clone(),
+        }))
+    }
+}
+
+/// Counts a connection's requests as in flight until their response bodies have been sent (or
+/// dropped), and holds request bodies to the minimum rate.
+#[derive(Clone)]
+pub struct Tracked<S> {
+    inner: S,
+    in_flight: Arc<AtomicUsize>,
+    min_bytes_per_sec: Option<u64>,
+    counter: Counter<u64>,
+}
+
+struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<S> tower::Service<Request<Body>> for Tracked<S>
+where
+    S: tower::Service<Request<Body>, Response = Response, Error = Infallible>,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Response, Infallible>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        let in_flight = InFlight(self.in_flight.clone());
+        let mut req = req;
+        let req = match self.min_bytes_per_sec {
+            Some(min_bytes_per_sec) if !req.body().is_end_stream() => {
+                let counter = self.counter.clone();
+                crate::trailers::take(&mut req);
+                req.map(|body| {
+                    Body::wrap_stream(MinRateBody {
+                        inner: body,
+                        min_bytes_per_sec,
+                        budget: RateBudget::default(),
+                        timer: Box::pin(tokio::time::sleep_until(Instant::now())),
+                        counter,
+                    })
+                })
+            }
+            _ => req,
+        };
+        let response = self.inner.call(req);
+        Box::pin(async move {
+            let response = response.await?;
+            Ok(response.map(|body| {
+                axum::body::boxed(InFlightBody {
+                    inner: body,
+                    _in_flight: in_flight,
+                })
+            }))
+        })
+    }
+}
+
+/// A request body that fails once the client falls behind the minimum rate.
+struct MinRateBody {
+    inner: Body,
+    min_bytes_per_sec: u64,
+    budget: RateBudget,
+    timer: Pin<Box<Sleep>>,
+    counter: Counter<u64>,
+}
+
+impl Stream for MinRateBody {
+    type Item = Result<Bytes, Box<dyn std::error::Error + Send + Sync>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_data(cx) {
+            Poll::Ready(Some(Ok(data))) => {
+                this.budget.progress(data.len(), Instant::now());
+                Poll::Ready(Some(Ok(data)))
+            }
+            Poll::Ready(poll) => Poll::Ready(poll.map(|result| result.map_err(Into::into))),
+            Poll::Pending => {
+                let deadline = this.budget.deadline(this.min_bytes_per_sec, Instant::now());
+                if poll_deadline(&mut this.timer, deadline, cx) {
+                    this.counter.add(1, &[KeyValue::new("reason", "slow_body")]);
+                    return Poll::Ready(Some(Err(timed_out(
+                        "Client sending request body too slowly",
+                    )
+                    .into())));
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// A response body holding its request's place in flight.
+struct InFlightBody<B> {
+    inner: B,
+    _in_flight: InFlight,
+}
+
+impl<B: HttpBody + Unpin> HttpBody for InFlightBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.get_mut().inner).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<axum::http::HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    #[test]
+    fn test_rate_budget() {
+        let start = Instant::now();
+        let mut budget = RateBudget::default();
+        assert_eq!(budget.deadline(100, start), start + RATE_GRACE);
+        // 5s waiting for 1000 bytes leaves 5s of grace, plus 10s for the bytes at 100/s.
+        budget.progress(1000, start + Duration::from_secs(5));
+        let now = start + Duration::from_secs(6);
+        assert_eq!(budget.deadline(100, now), now + Duration::from_secs(15));
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let timeouts = ClientTimeouts {
+            idle: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let (client, server) = tokio::io::duplex(64);
+        let mut server = TimedStream::new(server, timeouts, &timeouts_counter());
+        drop(client);
+        // EOF is passed on rather than timing out.
+        assert_eq!(server.read(&mut [0; 8]).await.unwrap(), 0);
+
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut server = TimedStream::new(server, timeouts, &timeouts_counter());
+        client.write_all(b"GET").await.unwrap();
+        assert_eq!(server.read(&mut [0; 8]).await.unwrap(), 3);
+        // Not idle while a request is in flight.
+        server.in_flight.fetch_add(1, Ordering::Relaxed);
+        let mut buf = [0; 8];
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), server.read(&mut buf))
+                .await
+                .is_err()
+        );
+        server.in_flight.fetch_sub(1, Ordering::Relaxed);
+        let err = server.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
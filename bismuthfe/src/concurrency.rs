@@ -0,0 +1,243 @@
+use opentelemetry::metrics::Counter;
+use opentelemetry::KeyValue;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// A request is considered slow (and the limit backed off) once its latency exceeds the
+/// baseline by this factor.
+const LATENCY_TOLERANCE: f64 = 2.0;
+/// Multiplicative decrease on a slow or failed request.
+const BACKOFF: f64 = 0.9;
+/// How quickly the baseline latency follows latency increases, so a permanently slower function
+/// doesn't stay throttled forever. Decreases are followed immediately.
+const BASELINE_DRIFT: f64 = 0.001;
+
+#[derive(Clone, Copy, Debug)]
+pub struct LimitConfig {
+    pub initial: usize,
+    pub min: usize,
+    pub max: usize,
+}
+
+struct LimitState {
+    limit: f64,
+    in_flight: usize,
+    /// Latency of an unloaded request, estimated from the fastest recent ones.
+    baseline: Option<Duration>,
+}
+
+/// An AIMD concurrency limit that discovers how much concurrency its target can sustain:
+/// it grows by ~1 for every `limit` fast requests while in use, and shrinks by `BACKOFF` whenever
+/// a request fails or takes more than `LATENCY_TOLERANCE` times the baseline latency.
+pub struct AdaptiveLimit {
+    config: LimitConfig,
+    state: Mutex<LimitState>,
+}
+
+impl AdaptiveLimit {
+    pub fn new(config: LimitConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(LimitState {
+                limit: config.initial as f64,
+                in_flight: 0,
+                baseline: None,
+            }),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit as usize
+    }
+
+    pub fn try_acquire(self: &Arc<Self>) -> Option<Permit> {
+        let mut state = self.state.lock().unwrap();
+        if state.in_flight >= state.limit as usize {
+            return None;
+        }
+        state.in_flight += 1;
+        Some(Permit {
+            limit: self.clone(),
+            start: Instant::now(),
+            ok: None,
+        })
+    }
+
+    fn record(&self, latency: Duration, ok: bool, in_flight: usize) {
+        let mut state = self.state.lock().unwrap();
+        let baseline = match state.baseline {
+            Some(baseline) if latency > baseline => {
+                baseline + (latency - baseline).mul_f64(BASELINE_DRIFT)
+            }
+            _ => latency,
+        };
+        state.baseline = Some(baseline);
+
+        if !ok || latency.as_secs_f64() > baseline.as_secs_f64() * LATENCY_TOLERANCE {
+            state.limit = (state.limit * BACKOFF).max(self.config.min as f64);
+        } else if in_flight * 2 >= state.limit as usize {
+            // Only grow while the limit is actually being used, or an idle function would
+            // accumulate a limit it never proved it can handle.
+            state.limit = (state.limit + 1.0 / state.limit).min(self.config.max as f64);
+        }
+    }
+}
+
+/// One request's share of an `AdaptiveLimit`. Call `finish` with the outcome to feed it back
+/// into the limit; a permit dropped without finishing (e.g. the client went away) only frees its slot.
+pub struct Permit {
+    limit: Arc<AdaptiveLimit>,
+    start: Instant,
+    ok: Option<bool>,
+}
+
+impl Permit {
+    pub fn finish(mut self, ok: bool) {
+        self.ok = Some(ok);
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let in_flight = {
+            let mut state = self.limit.state.lock().unwrap();
+            let in_flight = state.in_flight;
+            state.in_flight -= 1;
+            in_flight
+        };
+        if let Some(ok) = self.ok {
+            self.limit.record(self.start.elapsed(), ok, in_flight);
+        }
+    }
+}
+
+/// A request's permits against both its function's and its backend's limits.
+pub struct Permits(Permit, Permit);
+
+impl Permits {
+    pub fn finish(self, ok: bool) {
+        self.0.finish(ok);
+        self.1.finish(ok);
+    }
+}
+
+/// Adaptive concurrency limits per function and per backend container.
+pub struct ConcurrencyLimits {
+    config: LimitConfig,
+    functions: Mutex<HashMap<Uuid, Arc<AdaptiveLimit>>>,
+    backends: Mutex<HashMap<Uuid, Arc<AdaptiveLimit>>>,
+    limited: Counter<u64>,
+}
+
+impl ConcurrencyLimits {
+    pub fn new(config: LimitConfig) -> Self {
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+        let limited = meter
+            .u64_counter("concurrency_limited_requests")
+            .with_description("Requests rejected by adaptive concurrency limits")
+            .init();
+        Self {
+            config,
+            functions: Mutex::new(HashMap::new()),
+            backends: Mutex::new(HashMap::new()),
+            limited,
+        }
+    }
+
+    fn get(
+        &self,
+        limits: &Mutex<HashMap<Uuid, Arc<AdaptiveLimit>>>,
+        id: &Uuid,
+    ) -> Arc<AdaptiveLimit> {
+        limits
+            .lock()
+            .unwrap()
+            .entry(*id)
+            .or_insert_with(|| Arc::new(AdaptiveLimit::new(self.config)))
+            .clone()
+    }
+
+    /// Admit a request to `container_id` of `function_id`, or `None` if either is at its limit.
+    pub fn try_acquire(&self, function_id: &Uuid, container_id: &Uuid) -> Option<Permits> {
+        let Some(function) = self.get(&self.functions, function_id).try_acquire() else {
+            self.limited.add(1, &[KeyValue::new("limit", "function")]);
+            return None;
+        };
+        let Some(backend) = self.get(&self.backends, container_id).try_acquire() else {
+            self.limited.add(1, &[KeyValue::new("limit", "backend")]);
+            return None;
+        };
+        Some(Permits(function, backend))
+    }
+
+    /// Drop the limits of functions and backends that no longer exist.
+    pub fn retain(&self, functions: &[Uuid], backends: &[Uuid]) {
+        self.functions
+            .lock()
+            .unwrap()
+            .retain(|id, _| functions.contains(id));
+        self.backends
+            .lock()
+            .unwrap()
+            .retain(|id, _| backends.contains(id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: LimitConfig = LimitConfig {
+        initial: 10,
+        min: 1,
+        max: 100,
+    };
+
+    #[test]
+    fn test_limit_rejects_at_limit() {
+        let limit = Arc::new(AdaptiveLimit::new(LimitConfig {
+            initial: 2,
+            ..CONFIG
+        }));
+        let a = limit.try_acquire().unwrap();
+        let _b = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+        drop(a);
+        assert!(limit.try_acquire().is_some());
+    }
+
+    #[test]
+    fn test_limit_grows_when_fast() {
+        let limit = AdaptiveLimit::new(CONFIG);
+        for _ in 0..1000 {
+            limit.record(Duration::from_millis(10), true, limit.limit());
+        }
+        assert!(limit.limit() > CONFIG.initial);
+        assert!(limit.limit() <= CONFIG.max);
+    }
+
+    #[test]
+    fn test_limit_does_not_grow_when_idle() {
+        let limit = AdaptiveLimit::new(CONFIG);
+        for _ in 0..1000 {
+            limit.record(Duration::from_millis(10), true, 1);
+        }
+        assert_eq!(limit.limit(), CONFIG.initial);
+    }
+
+    #[test]
+    fn test_limit_backs_off_when_slow_or_failing() {
+        let limit = AdaptiveLimit::new(CONFIG);
+        limit.record(Duration::from_millis(10), true, 1);
+        limit.record(Duration::from_millis(100), true, 10);
+        assert!(limit.limit() < CONFIG.initial);
+
+        let limit = AdaptiveLimit::new(CONFIG);
+        for _ in 0..100 {
+            limit.record(Duration::from_millis(10), false, 10);
+        }
+        assert_eq!(limit.limit(), CONFIG.min);
+    }
+}
@@ -0,0 +1,252 @@
+//! Caps on open client connections, overall and per client IP. Connections over a cap are closed
+//! as soon as they're accepted, before anything is read from them, so a connection flood costs
+//! neither memory for request state nor file descriptors for long.
+//!
+//! Open connections and their lifetimes are reported as metrics whether or not there are caps.
+//! While the frontend is degraded, the overall cap is halved.
+
+use axum::extract::connect_info::Connected;
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use opentelemetry::metrics::{Counter, Histogram};
+use opentelemetry::KeyValue;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::{event, Level};
+
+use crate::overload::HostWatermarks;
+
+pub struct ConnectionLimits {
+    max: Option<usize>,
+    host: Option<Arc<HostWatermarks>>,
+    max_per_ip: Option<usize>,
+    open: AtomicUsize,
+    per_ip: Mutex<HashMap<IpAddr, usize>>,
+    rejected: Counter<u64>,
+    duration: Histogram<f64>,
+}
+
+/// Holds a connection's place under the limits until it's closed.
+pub struct Permit {
+    limits: Arc<ConnectionLimits>,
+    ip: IpAddr,
+    opened: Instant,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limits.open.fetch_sub(1, Ordering::Relaxed);
+        self.limits
+            .duration
+            .record(self.opened.elapsed().as_secs_f64(), &[]);
+        if self.limits.max_per_ip.is_some() {
+            let mut per_ip = self.limits.per_ip.lock().unwrap();
+            if let Some(count) = per_ip.get_mut(&self.ip) {
+                *count -= 1;
+                if *count == 0 {
+                    per_ip.remove(&self.ip);
+                }
+            }
+        }
+    }
+}
+
+impl ConnectionLimits {
+    pub fn new(
+        max: Option<usize>,
+        max_per_ip: Option<usize>,
+        host: Option<Arc<HostWatermarks>>,
+    ) -> anyhow::Result<Arc<Self>> {
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+        let limits = Arc::new(Self {
+            max,
+            host,
+            max_per_ip,
+            open: AtomicUsize::new(0),
+            per_ip: Mutex::new(HashMap::new()),
+            rejected: meter
+                .u64_counter("connections_rejected")
+                .with_description("Client connections closed for being over a connection limit")
+                .init(),
+            duration: meter
+                .f64_histogram("client_connection_duration")
+                .with_description("How long client connections were open")
+                .with_unit(opentelemetry::metrics::Unit::new("s"))
+                .init(),
+        });
+        let open_gauge = meter
+            .u64_observable_gauge("client_connections_open")
+            .with_description("Client connections open")
+            .init();
+        let limits_ = Arc::downgrade(&limits);
+        meter.register_callback(&[open_gauge.as_any()], move |observer| {
+            if let Some(limits) = limits_.upgrade() {
+                observer.observe_u64(&open_gauge, limits.open() as u64, &[]);
+            }
+        })?;
+        Ok(limits)
+    }
+
+    /// Currently open connections.
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    /// `None` if a connection from `ip` would go over a limit.
+    pub fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Option<Permit> {
+        let open = self.open.fetch_add(1, Ordering::Relaxed);
+        if let Some(max) = self.max {
+            let degraded = self.host.as_ref().is_some_and(|host| host.is_degraded());
+            if open >= if degraded { max / 2 } else { max } {
+                self.open.fetch_sub(1, Ordering::Relaxed);
+                let limit = if degraded && open < max {
+                    "degraded"
+                } else {
+                    "total"
+                };
+                self.rejected.add(1, &[KeyValue::new("limit", limit)]);
+                return None;
+            }
+        }
+        if let Some(max_per_ip) = self.max_per_ip {
+            let mut per_ip = self.per_ip.lock().unwrap();
+            let count = per_ip.entry(ip).or_insert(0);
+            if *count >= max_per_ip {
+                drop(per_ip);
+                self.open.fetch_sub(1, Ordering::Relaxed);
+                self.rejected.add(1, &[KeyValue::new("limit", "per_ip")]);
+                return None;
+            }
+            *count += 1;
+        }
+        Some(Permit {
+            limits: self.clone(),
+            ip,
+            opened: Instant::now(),
+        })
+    }
+}
+
+/// Accepts connections from `AddrIncoming`, closing the ones over the limits.
+pub struct LimitedIncoming {
+    inner: AddrIncoming,
+    limits: Arc<ConnectionLimits>,
+}
+
+impl LimitedIncoming {
+    pub fn new(inner: AddrIncoming, limits: Arc<ConnectionLimits>) -> Self {
+        Self { inner, limits }
+    }
+}
+
+impl Accept for LimitedIncoming {
+    type Conn = LimitedStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        loop {
+            let stream = match Pin::new(&mut self.inner).poll_accept(cx) {
+                Poll::Ready(Some(Ok(stream))) => stream,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            let addr = stream.remote_addr();
+            match self.limits.try_acquire(addr.ip()) {
+                Some(permit) => {
+                    return Poll::Ready(Some(Ok(LimitedStream {
+                        inner: stream,
+                        _permit: permit,
+                    })))
+                }
+                None => {
+                    event!(Level::DEBUG, client = %addr, "Connection over limit, closing");
+                }
+            }
+        }
+    }
+}
+
+pub struct LimitedStream {
+    inner: AddrStream,
+    _permit: Permit,
+}
+
+impl Connected<&LimitedStream> for SocketAddr {
+    fn connect_info(target: &LimitedStream) -> Self {
+        target.inner.remote_addr()
+    }
+}
+
+impl AsyncRead for LimitedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for LimitedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let limits = ConnectionLimits::new(Some(3), Some(2), None).unwrap();
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let a1 = limits.try_acquire(a).unwrap();
+        let _a2 = limits.try_acquire(a).unwrap();
+        assert!(limits.try_acquire(a).is_none());
+        let _b1 = limits.try_acquire(b).unwrap();
+        assert!(limits.try_acquire(b).is_none());
+        assert_eq!(limits.open(), 3);
+
+        drop(a1);
+        assert_eq!(limits.open(), 2);
+        assert!(limits.try_acquire(a).is_some());
+    }
+}
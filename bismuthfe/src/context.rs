@@ -0,0 +1,95 @@
+//! Invocation context for function code, as headers on every request proxied to a backend:
+//! * `X-Bismuth-Function-Id`: the function invoked
+//! * `X-Bismuth-Version`: the function whose backends serve the invocation, which is a split's
+//!   canary (e.g. a new version) for its share of invocations, and otherwise the function itself
+//! * `X-Bismuth-Tenant`: the tenant whose token authorized the invocation, if any
+//! * `X-Bismuth-Principal`: how the invocation was authenticated, if it was: `token:{id}` for a
+//!   tenant token, or `signature:{key id}` for a request signed by an upstream gateway
+//!
+//! Clients' own values of these are always removed, so backends can trust them.
+
+use axum::http::{HeaderMap, HeaderValue, Request};
+use uuid::Uuid;
+
+use crate::tokens::Tenant;
+
+pub const FUNCTION_ID_HEADER: &str = "x-bismuth-function-id";
+pub const VERSION_HEADER: &str = "x-bismuth-version";
+pub const TENANT_HEADER: &str = "x-bismuth-tenant";
+pub const PRINCIPAL_HEADER: &str = "x-bismuth-principal";
+
+/// Who authenticated an invocation, as a request extension. A tenant token is preferred over a
+/// signature, being the more specific.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Principal(pub String);
+
+/// Replace whatever context headers `req` has with the frontend's own.
+pub fn inject<B>(req: &mut Request<B>, function_id: &Uuid, backend_function: &Uuid) {
+    let tenant = req.extensions().get::<Tenant>().map(|t| t.0.clone());
+    let principal = req.extensions().get::<Principal>().map(|p| p.0.clone());
+    set(
+        req.headers_mut(),
+        function_id,
+        backend_function,
+        tenant.as_deref(),
+        principal.as_deref(),
+    );
+}
+
+fn set(
+    headers: &mut HeaderMap,
+    function_id: &Uuid,
+    backend_function: &Uuid,
+    tenant: Option<&str>,
+    principal: Option<&str>,
+) {
+    let (function_id, backend_function) = (function_id.to_string(), backend_function.to_string());
+    for (name, value) in [
+        (FUNCTION_ID_HEADER, Some(function_id.as_str())),
+        (VERSION_HEADER, Some(backend_function.as_str())),
+        (TENANT_HEADER, tenant),
+        (PRINCIPAL_HEADER, principal),
+    ] {
+        // Removes every value the client sent, not just the first.
+        headers.remove(name);
+        if let Some(value) = value.and_then(|v| HeaderValue::from_str(v).ok()) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inject() {
+        let function_id = Uuid::new_v4();
+        let canary = Uuid::new_v4();
+        let mut req = Request::builder()
+            .header(TENANT_HEADER, "someone-else")
+            .header(TENANT_HEADER, "and-another")
+            .header(PRINCIPAL_HEADER, "token:forged")
+            .header(FUNCTION_ID_HEADER, "forged")
+            .body(())
+            .unwrap();
+        inject(&mut req, &function_id, &canary);
+        let headers = req.headers();
+        assert_eq!(headers[FUNCTION_ID_HEADER], function_id.to_string());
+        assert_eq!(headers[VERSION_HEADER], canary.to_string());
+        assert!(!headers.contains_key(TENANT_HEADER));
+        assert!(!headers.contains_key(PRINCIPAL_HEADER));
+
+        req.extensions_mut().insert(Tenant("acme".to_string()));
+        req.extensions_mut()
+            .insert(Principal("token:1234".to_string()));
+        inject(&mut req, &function_id, &function_id);
+        let headers = req.headers();
+        assert_eq!(headers[VERSION_HEADER], function_id.to_string());
+        assert_eq!(
+            headers.get_all(TENANT_HEADER).iter().collect::<Vec<_>>(),
+            ["acme"]
+        );
+        assert_eq!(headers[PRINCIPAL_HEADER], "token:1234");
+    }
+}
@@ -0,0 +1,164 @@
+//! Invocation deadlines. The frontend combines the function's `timeout_ms` with any deadline the
+//! client sent, stops waiting for the backend when it passes, and tells the backend about it so
+//! the function can give up on work nobody will see.
+
+use axum::http::{HeaderMap, HeaderValue};
+use futures::StreamExt as _;
+use hyper::body::{Body, Bytes};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Absolute deadline, in milliseconds since the Unix epoch.
+pub const DEADLINE_HEADER: &str = "x-request-deadline";
+/// Relative deadline, as sent by gRPC clients (e.g. `100m`).
+pub const GRPC_TIMEOUT_HEADER: &str = "grpc-timeout";
+
+/// Parse a `grpc-timeout` value: up to 8 digits and a unit (`H`, `M`, `S`, `m`, `u` or `n`).
+pub fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    if value.len() < 2 || value.len() > 9 || !value.is_ascii() {
+        return None;
+    }
+    let (amount, unit) = value.split_at(value.len() - 1);
+    let amount: u64 = amount.parse().ok()?;
+    Some(match unit {
+        "H" => Duration::from_secs(amount * 3600),
+        "M" => Duration::from_secs(amount * 60),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    })
+}
+
+/// The earliest of the client's `X-Request-Deadline`, its `grpc-timeout`, and `timeout` from
+/// `now`. `None` if there's no deadline at all. Unparseable headers are ignored.
+pub fn deadline(
+    headers: &HeaderMap,
+    timeout: Option<Duration>,
+    now: SystemTime,
+) -> Option<SystemTime> {
+    let client = headers
+        .get(DEADLINE_HEADER)
+        .and_then(|v| v.to_str().ok()?.parse().ok())
+        .map(|millis| UNIX_EPOCH + Duration::from_millis(millis));
+    let grpc = headers
+        .get(GRPC_TIMEOUT_HEADER)
+        .and_then(|v| parse_grpc_timeout(v.to_str().ok()?))
+        .map(|timeout| now + timeout);
+    [client, grpc, timeout.map(|timeout| now + timeout)]
+        .into_iter()
+        .flatten()
+        .min()
+}
+
+/// Set the headers telling the backend about `deadline`: always `X-Request-Deadline`, and
+/// `grpc-timeout` for gRPC requests, which backends' gRPC servers enforce themselves.
+pub fn propagate(headers: &mut HeaderMap, deadline: SystemTime, now: SystemTime) {
+    let millis = deadline
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    headers.insert(DEADLINE_HEADER, HeaderValue::from(millis as u64));
+
+    let grpc = headers
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/grpc"));
+    if grpc || headers.contains_key(GRPC_TIMEOUT_HEADER) {
+        let remaining = deadline.duration_since(now).unwrap_or_default();
+        // 8 digits of milliseconds is over a day, far longer than anything we'd proxy.
+        let millis = remaining.as_millis().min(99_999_999);
+        headers.insert(
+            GRPC_TIMEOUT_HEADER,
+            HeaderValue::try_from(format!("{}m", millis)).expect("valid header value"),
+        );
+    }
+}
+
+/// Cut `body` off with an error if it's still streaming at `deadline`, so the client can tell
+/// the response is incomplete.
+pub fn limit_body(body: Body, deadline: tokio::time::Instant) -> Body {
+    type Chunk = Result<Bytes, Box<dyn std::error::Error + Send + Sync>>;
+    let sleep = Box::pin(tokio::time::sleep_until(deadline));
+    Body::wrap_stream(futures::stream::unfold(
+        Some((body, sleep)),
+        |state| async move {
+            let (mut body, mut sleep) = state?;
+            tokio::select! {
+                chunk = body.next() => {
+                    let chunk: Chunk = chunk?.map_err(Into::into);
+                    Some((chunk, Some((body, sleep))))
+                }
+                _ = &mut sleep => {
+                    let chunk: Chunk = Err("Invocation deadline exceeded".into());
+                    Some((chunk, None))
+                }
+            }
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_grpc_timeout() {
+        assert_eq!(parse_grpc_timeout("100m"), Some(Duration::from_millis(100)));
+        assert_eq!(parse_grpc_timeout("2S"), Some(Duration::from_secs(2)));
+        assert_eq!(parse_grpc_timeout("1H"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_grpc_timeout("m"), None);
+        assert_eq!(parse_grpc_timeout("123456789m"), None);
+        assert_eq!(parse_grpc_timeout("10x"), None);
+    }
+
+    #[test]
+    fn test_deadline_is_earliest() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000);
+        let mut headers = HeaderMap::new();
+        assert_eq!(deadline(&headers, None, now), None);
+        assert_eq!(
+            deadline(&headers, Some(Duration::from_secs(5)), now),
+            Some(now + Duration::from_secs(5))
+        );
+
+        headers.insert(DEADLINE_HEADER, HeaderValue::from_static("1002000"));
+        headers.insert(GRPC_TIMEOUT_HEADER, HeaderValue::from_static("3S"));
+        assert_eq!(
+            deadline(&headers, Some(Duration::from_secs(5)), now),
+            Some(now + Duration::from_secs(2))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_limit_body() {
+        let body = limit_body(
+            Body::from("hello"),
+            tokio::time::Instant::now() + Duration::from_secs(1),
+        );
+        assert_eq!(hyper::body::to_bytes(body).await.unwrap(), "hello");
+
+        let (_sender, body) = Body::channel();
+        let body = limit_body(
+            body,
+            tokio::time::Instant::now() + Duration::from_millis(10),
+        );
+        assert!(hyper::body::to_bytes(body).await.is_err());
+    }
+
+    #[test]
+    fn test_propagate() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000);
+        let mut headers = HeaderMap::new();
+        propagate(&mut headers, now + Duration::from_millis(1500), now);
+        assert_eq!(headers[DEADLINE_HEADER], "1001500");
+        assert!(!headers.contains_key(GRPC_TIMEOUT_HEADER));
+
+        headers.insert(
+            hyper::header::CONTENT_TYPE,
+            HeaderValue::from_static("application/grpc+proto"),
+        );
+        propagate(&mut headers, now + Duration::from_millis(1500), now);
+        assert_eq!(headers[GRPC_TIMEOUT_HEADER], "1500m");
+    }
+}
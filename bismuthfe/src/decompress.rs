@@ -0,0 +1,258 @@
+//! Request decompression for functions with `decompress_requests` in their config, whose
+//! backends can't handle a `Content-Encoding` themselves: gzip, deflate and brotli bodies are
+//! decompressed as they stream through, and passed on without the header (or a length). Other
+//! encodings get a 415.
+//!
+//! Decompressed bodies are held to the function's `max_upload_bytes` (or `MAX_DECOMPRESSED_BYTES`)
+//! as they're produced, so a small body that decompresses to gigabytes is cut off with a 413
+//! without the frontend ever holding more than the ceiling. Corrupt bodies are cut off with a 400.
+
+use axum::http::{header, Request, StatusCode};
+use futures::StreamExt as _;
+use hyper::body::{Body, Bytes};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+
+/// Ceiling on decompressed bodies, for functions without their own `max_upload_bytes`.
+pub const MAX_DECOMPRESSED_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Set once a decompressed body is cut off, which fails the request to the backend. Stored in
+/// the request's extensions so the error can be reported with the right status.
+#[derive(Clone, Debug, Default)]
+pub struct Failed(Arc<AtomicU16>);
+
+impl Failed {
+    pub fn get(&self) -> Option<StatusCode> {
+        match self.0.load(Ordering::Relaxed) {
+            0 => None,
+            status => StatusCode::from_u16(status).ok(),
+        }
+    }
+
+    fn set(&self, status: StatusCode) {
+        self.0.store(status.as_u16(), Ordering::Relaxed);
+    }
+}
+
+/// Collects a decoder's output, failing its writes once there's more than `max` bytes in all.
+struct Sink {
+    buf: Vec<u8>,
+    total: u64,
+    max: u64,
+}
+
+impl Write for Sink {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.total += data.len() as u64;
+        if self.total > self.max {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Decompressed body over the ceiling",
+            ));
+        }
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum Decoder {
+    Gzip(flate2::write::GzDecoder<Sink>),
+    /// HTTP's `deflate` is zlib-wrapped.
+    Deflate(flate2::write::ZlibDecoder<Sink>),
+    Brotli(Box<brotli::DecompressorWriter<Sink>>),
+}
+
+impl Decoder {
+    fn new(encoding: &str, max: u64) -> Option<Self> {
+        let sink = Sink {
+            buf: Vec::new(),
+            total: 0,
+            max,
+        };
+        Some(match encoding {
+            "gzip" | "x-gzip" => Decoder::Gzip(flate2::write::GzDecoder::new(sink)),
+            "deflate" => Decoder::Deflate(flate2::write::ZlibDecoder::new(sink)),
+            "br" => Decoder::Brotli(Box::new(brotli::DecompressorWriter::new(sink, 4096))),
+            _ => return None,
+        })
+    }
+
+    fn sink(&mut self) -> &mut Sink {
+        match self {
+            Decoder::Gzip(d) => d.get_mut(),
+            Decoder::Deflate(d) => d.get_mut(),
+            Decoder::Brotli(d) => d.get_mut(),
+        }
+    }
+
+    /// What `result`, of writing to the decoder, produced.
+    fn output(&mut self, result: io::Result<()>) -> Result<Bytes, StatusCode> {
+        let sink = self.sink();
+        if sink.total > sink.max {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        result.map_err(|_| StatusCode::BAD_REQUEST)?;
+        Ok(Bytes::from(std::mem::take(&mut sink.buf)))
+    }
+
+    /// Decompress `chunk`.
+    fn write(&mut self, chunk: &[u8]) -> Result<Bytes, StatusCode> {
+        let result = match self {
+            Decoder::Gzip(d) => d.write_all(chunk).and_then(|_| d.flush()),
+            Decoder::Deflate(d) => d.write_all(chunk).and_then(|_| d.flush()),
+            Decoder::Brotli(d) => d.write_all(chunk).and_then(|_| d.flush()),
+        };
+        self.output(result)
+    }
+
+    /// The rest of the body, failing if it was cut short.
+    fn finish(&mut self) -> Result<Bytes, StatusCode> {
+        let result = match self {
+            Decoder::Gzip(d) => d.try_finish(),
+            Decoder::Deflate(d) => d.try_finish(),
+            Decoder::Brotli(d) => d.close(),
+        };
+        self.output(result)
+    }
+}
+
+/// Decompress `req`'s body as it streams, to at most `max` bytes, if it has a `Content-Encoding`.
+/// Returns whether it did.
+pub fn decompress(req: &mut Request<Body>, max: u64) -> Result<bool, StatusCode> {
+    let Some(encoding) = req.headers().get(header::CONTENT_ENCODING) else {
+        return Ok(false);
+    };
+    // Several encodings (e.g. `gzip, br`) aren't supported, nor used in practice.
+    let encoding = encoding
+        .to_str()
+        .map_err(|_| StatusCode::UNSUPPORTED_MEDIA_TYPE)?
+        .trim()
+        .to_ascii_lowercase();
+    if encoding == "identity" {
+        return Ok(false);
+    }
+    let decoder = Decoder::new(&encoding, max).ok_or(StatusCode::UNSUPPORTED_MEDIA_TYPE)?;
+
+    type Chunk = Result<Bytes, Box<dyn std::error::Error + Send + Sync>>;
+    let failed = Failed::default();
+    let failed_ = failed.clone();
+    let body = std::mem::take(req.body_mut());
+    *req.body_mut() = Body::wrap_stream(futures::stream::unfold(
+        Some((body, decoder)),
+        move |state| {
+            let failed = failed_.clone();
+            async move {
+                let (mut body, mut decoder) = state?;
+                loop {
+                    let output = match body.next().await {
+                        Some(Ok(chunk)) => decoder.write(&chunk),
+                        Some(Err(e)) => {
+                            let chunk: Chunk = Err(e.into());
+                            return Some((chunk, None));
+                        }
+                        None => {
+                            let chunk: Chunk = match decoder.finish() {
+                                Ok(output) => Ok(output),
+                                Err(status) => {
+                                    failed.set(status);
+                                    Err("Invalid compressed body".into())
+                                }
+                            };
+                            return Some((chunk, None));
+                        }
+                    };
+                    match output {
+                        // Not enough yet to produce anything.
+                        Ok(output) if output.is_empty() => continue,
+                        Ok(output) => return Some((Ok(output), Some((body, decoder)))),
+                        Err(status) => {
+                            failed.set(status);
+                            let chunk: Chunk =
+                                Err(format!("Decompression failed ({})", status).into());
+                            return Some((chunk, None));
+                        }
+                    }
+                }
+            }
+        },
+    ));
+    let headers = req.headers_mut();
+    headers.remove(header::CONTENT_ENCODING);
+    headers.remove(header::CONTENT_LENGTH);
+    req.extensions_mut().insert(failed);
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn request(encoding: &str, body: Vec<u8>) -> Request<Body> {
+        Request::post("/")
+            .header(header::CONTENT_ENCODING, encoding)
+            .header(header::CONTENT_LENGTH, body.len())
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_decompress() {
+        let mut req = request("gzip", gzip(b"hello world"));
+        assert_eq!(decompress(&mut req, 100), Ok(true));
+        assert!(!req.headers().contains_key(header::CONTENT_ENCODING));
+        assert!(!req.headers().contains_key(header::CONTENT_LENGTH));
+        assert_eq!(
+            hyper::body::to_bytes(req.into_body()).await.unwrap(),
+            "hello world"
+        );
+
+        let mut compressed = Vec::new();
+        {
+            let mut encoder = brotli::CompressorWriter::new(&mut compressed, 4096, 5, 22);
+            encoder.write_all(b"hello brotli").unwrap();
+        }
+        let mut req = request("br", compressed);
+        assert_eq!(decompress(&mut req, 100), Ok(true));
+        assert_eq!(
+            hyper::body::to_bytes(req.into_body()).await.unwrap(),
+            "hello brotli"
+        );
+
+        let mut req = Request::post("/").body(Body::from("plain")).unwrap();
+        assert_eq!(decompress(&mut req, 100), Ok(false));
+        let mut req = request("zstd", b"whatever".to_vec());
+        assert_eq!(
+            decompress(&mut req, 100),
+            Err(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        // A megabyte of zeros compresses to about a kilobyte.
+        let mut req = request("gzip", gzip(&vec![0; 1024 * 1024]));
+        decompress(&mut req, 64 * 1024).unwrap();
+        let failed = req.extensions().get::<Failed>().unwrap().clone();
+        assert!(hyper::body::to_bytes(req.into_body()).await.is_err());
+        assert_eq!(failed.get(), Some(StatusCode::PAYLOAD_TOO_LARGE));
+
+        let mut truncated = gzip(b"hello world");
+        truncated.truncate(truncated.len() - 4);
+        let mut req = request("gzip", truncated);
+        decompress(&mut req, 100).unwrap();
+        let failed = req.extensions().get::<Failed>().unwrap().clone();
+        assert!(hyper::body::to_bytes(req.into_body()).await.is_err());
+        assert_eq!(failed.get(), Some(StatusCode::BAD_REQUEST));
+    }
+}
@@ -0,0 +1,68 @@
+use anyhow::{anyhow, Result};
+use std::net::SocketAddrV4;
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// A single local backend that every function is routed to, for developing without ZooKeeper.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DevBackend {
+    pub addr: SocketAddrV4,
+    /// If set, `addr` is a bismuthd and requests go to this container on it.
+    /// Otherwise `addr` is the function's own server, and gets the request path as is.
+    pub container_id: Option<Uuid>,
+}
+
+impl DevBackend {
+    pub fn uri(&self, reqpath: &str) -> Result<hyper::Uri> {
+        Ok(match self.container_id {
+            Some(container_id) => {
+                format!("http://{}/invoke/{}/{}", self.addr, container_id, reqpath)
+            }
+            None => format!("http://{}/{}", self.addr, reqpath),
+        }
+        .parse()?)
+    }
+}
+
+/// Parses `IP:PORT[:CONTAINER_ID]`.
+impl FromStr for DevBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.splitn(3, ':');
+        let (Some(ip), Some(port)) = (parts.next(), parts.next()) else {
+            return Err(anyhow!("Expected IP:PORT[:CONTAINER_ID], got '{}'", s));
+        };
+        Ok(Self {
+            addr: format!("{}:{}", ip, port).parse()?,
+            container_id: parts.next().map(Uuid::parse_str).transpose()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dev_backend() {
+        let backend: DevBackend = "127.0.0.1:9000".parse().unwrap();
+        assert_eq!(backend.addr, "127.0.0.1:9000".parse().unwrap());
+        assert_eq!(backend.container_id, None);
+        assert_eq!(
+            backend.uri("foo/bar").unwrap(),
+            "http://127.0.0.1:9000/foo/bar"
+        );
+
+        let container_id = Uuid::new_v4();
+        let backend: DevBackend = format!("127.0.0.1:9000:{}", container_id).parse().unwrap();
+        assert_eq!(backend.container_id, Some(container_id));
+        assert_eq!(
+            backend.uri("foo").unwrap().to_string(),
+            format!("http://127.0.0.1:9000/invoke/{}/foo", container_id)
+        );
+
+        assert!("127.0.0.1".parse::<DevBackend>().is_err());
+        assert!("127.0.0.1:9000:not-a-uuid".parse::<DevBackend>().is_err());
+    }
+}
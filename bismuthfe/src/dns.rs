@@ -0,0 +1,109 @@
+//! Resolution of hostname backends (`/function/{id}/backend_hosts`). Each entry is resolved to
+//! its lowest IPv4 address (so the pick is stable across refreshes), and re-resolved when the
+//! shortest TTL among the function's records runs out.
+
+use anyhow::{Context, Result};
+use hickory_resolver::config::{NameServerConfig, NameServerConfigGroup, Protocol, ResolverConfig};
+use hickory_resolver::TokioAsyncResolver;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tracing::{event, Level};
+
+use bismuth_common::{Backend, HostBackend};
+
+/// Bounds on how long a resolution is used for, whatever the records' TTLs say.
+const MIN_REFRESH: Duration = Duration::from_secs(5);
+const MAX_REFRESH: Duration = Duration::from_secs(300);
+
+pub struct Resolver {
+    inner: TokioAsyncResolver,
+}
+
+impl Resolver {
+    /// Use `servers` (UDP, falling back to TCP), or the system's resolver config if empty.
+    pub fn new(servers: &[SocketAddr]) -> Result<Self> {
+        let inner = if servers.is_empty() {
+            TokioAsyncResolver::tokio_from_system_conf()
+                .context("Error reading system resolver config")?
+        } else {
+            let group: Vec<NameServerConfig> = servers
+                .iter()
+                .flat_map(|&addr| {
+                    [
+                        NameServerConfig::new(addr, Protocol::Udp),
+                        NameServerConfig::new(addr, Protocol::Tcp),
+                    ]
+                })
+                .collect();
+            TokioAsyncResolver::tokio(
+                ResolverConfig::from_parts(None, vec![], NameServerConfigGroup::from(group)),
+                Default::default(),
+            )
+        };
+        Ok(Self { inner })
+    }
+
+    /// Resolve `hosts` to backends, and say when to do it again. Hosts that don't resolve are
+    /// left out (and retried soon), rather than failing the whole function.
+    pub async fn resolve(&self, hosts: &[HostBackend]) -> (Vec<Backend>, Instant) {
+        let now = Instant::now();
+        let mut refresh = now + MAX_REFRESH;
+        let mut backends = Vec::with_capacity(hosts.len());
+        for host in hosts {
+            if let Ok(ip) = host.host.parse::<Ipv4Addr>() {
+                backends.push(Backend {
+                    ip,
+                    container_id: host.container_id,
+                });
+                continue;
+            }
+            match self.inner.ipv4_lookup(host.host.as_str()).await {
+                Ok(lookup) => {
+                    refresh = refresh.min(lookup.valid_until());
+                    match lookup.iter().map(|a| a.0).min() {
+                        Some(ip) => backends.push(Backend {
+                            ip,
+                            container_id: host.container_id,
+                        }),
+                        None => refresh = now,
+                    }
+                }
+                Err(e) => {
+                    event!(Level::WARN, host = %host.host, error = %e, "Error resolving backend host");
+                    refresh = now;
+                }
+            }
+        }
+        (
+            backends,
+            refresh.clamp(now + MIN_REFRESH, now + MAX_REFRESH),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_ip_hosts_skip_dns() {
+        // No servers are queried for literal IPs.
+        let resolver = Resolver::new(&["127.0.0.1:1".parse().unwrap()]).unwrap();
+        let container_id = Uuid::new_v4();
+        let (backends, refresh) = resolver
+            .resolve(&[HostBackend {
+                host: "10.0.0.1".to_string(),
+                container_id,
+            }])
+            .await;
+        assert_eq!(
+            backends,
+            vec![Backend {
+                ip: "10.0.0.1".parse().unwrap(),
+                container_id
+            }]
+        );
+        assert!(refresh > Instant::now() + MIN_REFRESH / 2);
+    }
+}
@@ -0,0 +1,220 @@
+//! Backend draining: backends marked in `/function/{id}/draining` are left out of their
+//! function's rings, so they get no new invocations, while the ones already sent to them finish.
+//! Each frontend counts the invocations it has in flight to every backend until their response
+//! bodies are done, logs when a draining backend has none left, and lists its draining backends
+//! at `GET /admin/function/{function_id}/draining`.
+
+use axum::extract::{Path, State};
+use axum::Json;
+use futures::StreamExt as _;
+use hyper::Body;
+use opentelemetry::metrics::Counter;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tracing::{event, Level};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use bismuth_common::{ApiError, Backend};
+
+use crate::FrontendState;
+
+#[derive(Default)]
+struct State {
+    /// By container; only those with invocations in flight.
+    in_flight: HashMap<Uuid, usize>,
+    /// Draining containers' functions, and whether they've been reported drained.
+    draining: HashMap<Uuid, (Uuid, bool)>,
+}
+
+impl State {
+    /// Report `container_id` drained if it's draining, hasn't been yet, and has nothing in
+    /// flight.
+    fn check(&mut self, container_id: &Uuid, drained: &Counter<u64>) {
+        if self.in_flight.contains_key(container_id) {
+            return;
+        }
+        if let Some((function_id, reported @ false)) = self.draining.get_mut(container_id) {
+            *reported = true;
+            event!(
+                Level::INFO,
+                function = %function_id,
+                container_id = %container_id,
+                "Backend drained"
+            );
+            drained.add(1, &[]);
+        }
+    }
+}
+
+pub struct Drains {
+    state: Mutex<State>,
+    drained: Counter<u64>,
+}
+
+/// Counts an invocation as in flight to a backend until dropped.
+pub struct InFlight {
+    drains: Arc<Drains>,
+    container_id: Uuid,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut state = self.drains.state.lock().unwrap();
+        if let Some(count) = state.in_flight.get_mut(&self.container_id) {
+            *count -= 1;
+            if *count == 0 {
+                state.in_flight.remove(&self.container_id);
+                state.check(&self.container_id, &self.drains.drained);
+            }
+        }
+    }
+}
+
+impl Drains {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(State::default()),
+            drained: opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+                .u64_counter("backends_drained")
+                .with_description("Draining backends left with no invocations in flight")
+                .init(),
+        })
+    }
+
+    pub fn begin(self: &Arc<Self>, container_id: Uuid) -> InFlight {
+        *self
+            .state
+            .lock()
+            .unwrap()
+            .in_flight
+            .entry(container_id)
+            .or_default() += 1;
+        InFlight {
+            drains: self.clone(),
+            container_id,
+        }
+    }
+
+    /// Replace the function's draining backends with `containers`.
+    pub fn set_draining(&self, function_id: &Uuid, containers: &[Uuid]) {
+        let mut state = self.state.lock().unwrap();
+        state.draining.retain(|container_id, (function, _)| {
+            function != function_id || containers.contains(container_id)
+        });
+        for container_id in containers {
+            state
+                .draining
+                .entry(*container_id)
+                .or_insert((*function_id, false));
+            state.check(container_id, &self.drained);
+        }
+    }
+
+    /// Invocations this frontend has in flight to the backend.
+    pub fn in_flight(&self, container_id: &Uuid) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .in_flight
+            .get(container_id)
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+/// Keep `in_flight` until `body` has been sent (or dropped).
+pub fn hold(body: Body, in_flight: InFlight) -> Body {
+    if body.is_end_stream() {
+        return body;
+    }
+    Body::wrap_stream(body.map(move |chunk| {
+        let _ = &in_flight;
+        chunk
+    }))
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct DrainStatus {
+    backend: Backend,
+    /// Invocations this frontend still has in flight to it.
+    in_flight: usize,
+}
+
+/// A function's draining backends, as loaded by this frontend, with what it still has in flight
+/// to each. A backend is drained once every frontend reports nothing in flight to it.
+#[utoipa::path(
+    get,
+    path = "/admin/function/{function_id}/draining",
+    tag = "admin",
+    params(("function_id" = Uuid, Path, description = "Function ID")),
+    responses(
+        (status = 200, body = Vec<DrainStatus>),
+        (status = 404, description = "No such function loaded"),
+    ),
+    security(("admin_token" = []))
+)]
+pub async fn draining_handler(
+    State(state): State<Arc<FrontendState>>,
+    Path(function_id): Path<Uuid>,
+) -> Result<Json<Vec<DrainStatus>>, ApiError> {
+    let monitor = state.monitor.as_ref().ok_or(ApiError::NotFound)?;
+    let backends = monitor.backends.read().await;
+    let function = backends.get(&function_id).ok_or(ApiError::NotFound)?;
+    Ok(Json(
+        function
+            .draining
+            .iter()
+            .map(|backend| DrainStatus {
+                backend: backend.clone(),
+                in_flight: monitor.drains.in_flight(&backend.container_id),
+            })
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drained() {
+        let drains = Drains::new();
+        let function_id = Uuid::new_v4();
+        let (draining, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let first = drains.begin(draining);
+        let second = drains.begin(draining);
+        let _other = drains.begin(other);
+        assert_eq!(drains.in_flight(&draining), 2);
+
+        drains.set_draining(&function_id, &[draining]);
+        let reported = |drains: &Drains| drains.state.lock().unwrap().draining[&draining].1;
+        assert!(!reported(&drains));
+        drop(first);
+        assert!(!reported(&drains));
+        drop(second);
+        assert_eq!(drains.in_flight(&draining), 0);
+        assert!(reported(&drains));
+
+        // Marked again after being taken off, with nothing in flight.
+        drains.set_draining(&function_id, &[]);
+        assert!(drains.state.lock().unwrap().draining.is_empty());
+        drains.set_draining(&function_id, &[draining]);
+        assert!(reported(&drains));
+    }
+
+    #[tokio::test]
+    async fn test_hold_until_body_sent() {
+        let drains = Drains::new();
+        let container_id = Uuid::new_v4();
+        let body = hold(Body::from("hello"), drains.begin(container_id));
+        assert_eq!(drains.in_flight(&container_id), 1);
+        assert_eq!(hyper::body::to_bytes(body).await.unwrap(), "hello");
+        assert_eq!(drains.in_flight(&container_id), 0);
+
+        let body = hold(Body::empty(), drains.begin(container_id));
+        assert_eq!(drains.in_flight(&container_id), 0);
+        drop(body);
+    }
+}
@@ -0,0 +1,174 @@
+use anyhow::{Context, Result};
+use axum::extract::{ConnectInfo, State};
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse as _, Response};
+use hyper::Body;
+use opentelemetry::metrics::Counter;
+use opentelemetry::KeyValue;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tracing::{event, Level};
+use uuid::Uuid;
+
+use bismuth_common::{GeoRestriction, MetricAttributes};
+
+use crate::metric_labels;
+use crate::routing::Mode;
+
+/// Where a client is, as far as the GeoIP database can tell.
+/// Inserted as a request extension by `middleware`.
+#[derive(Clone, Debug, Default)]
+pub struct ClientLocation {
+    /// ISO 3166-1 country code.
+    pub country: Option<String>,
+    /// Backend region (as tagged in `/node/{ip}/region`) preferred for this client.
+    pub region: Option<String>,
+}
+
+pub struct Geo {
+    reader: maxminddb::Reader<Vec<u8>>,
+    /// Country or continent code to region name. Country codes take precedence.
+    regions: HashMap<String, String>,
+}
+
+impl Geo {
+    pub fn open(path: &std::path::Path, regions: &[(String, String)]) -> Result<Self> {
+        let reader = maxminddb::Reader::open_readfile(path)
+            .with_context(|| format!("Error opening GeoIP database {}", path.display()))?;
+        event!(Level::INFO, db = %reader.metadata.database_type, "Loaded GeoIP database");
+        Ok(Self {
+            reader,
+            regions: regions.iter().cloned().collect(),
+        })
+    }
+
+    pub fn locate(&self, ip: IpAddr) -> ClientLocation {
+        let Ok(record) = self.reader.lookup::<maxminddb::geoip2::Country>(ip) else {
+            return ClientLocation::default();
+        };
+        let country = record.country.and_then(|c| c.iso_code);
+        let continent = record.continent.and_then(|c| c.code);
+        let region = country
+            .and_then(|c| self.regions.get(c))
+            .or_else(|| continent.and_then(|c| self.regions.get(c)))
+            .cloned();
+        ClientLocation {
+            country: country.map(str::to_string),
+            region,
+        }
+    }
+}
+
+/// Look up the client's location, for routing and as a metrics dimension.
+pub async fn middleware(
+    State(geo): State<Arc<Geo>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    mut req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let location = geo.locate(addr.ip());
+    if let Some(country) = &location.country {
+        req.extensions_mut()
+            .insert(MetricAttributes(vec![opentelemetry::KeyValue::new(
+                "client.country",
+                country.clone(),
+            )]));
+    }
+    req.extensions_mut().insert(location);
+    next.run(req).await
+}
+
+/// Whether a client in `country` may invoke a function with `restriction`.
+pub fn allowed(restriction: &GeoRestriction, country: Option<&str>) -> bool {
+    let listed =
+        |list: &[String]| country.is_some_and(|c| list.iter().any(|l| l.eq_ignore_ascii_case(c)));
+    (restriction.allow.is_empty() || listed(&restriction.allow)) && !listed(&restriction.deny)
+}
+
+/// Enforces functions' `geo` restrictions.
+pub struct Restrictions {
+    refused: Counter<u64>,
+}
+
+impl Restrictions {
+    pub fn new() -> Self {
+        Self {
+            refused: opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+                .u64_counter("invocations_geo_refused")
+                .with_description("Invocations refused for the country the client is in")
+                .init(),
+        }
+    }
+
+    /// The status a client in `country` gets if it may not invoke the function.
+    pub fn check(
+        &self,
+        function_id: &Uuid,
+        restriction: &GeoRestriction,
+        country: Option<&str>,
+        mode: Mode,
+    ) -> Option<StatusCode> {
+        if allowed(restriction, country) {
+            return None;
+        }
+        if mode == Mode::Live {
+            self.refused.add(
+                1,
+                &[
+                    metric_labels::function(function_id),
+                    KeyValue::new("client.country", country.unwrap_or("unknown").to_string()),
+                ],
+            );
+        }
+        Some(match restriction.status {
+            403 => StatusCode::FORBIDDEN,
+            _ => StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS,
+        })
+    }
+}
+
+/// The response for a refused invocation.
+pub fn refused(status: StatusCode) -> Response {
+    (status, "This function isn't available in your location.\n").into_response()
+}
+
+impl Default for Restrictions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Parse `KEY=VALUE` command line arguments.
+pub fn parse_key_val(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .ok_or_else(|| format!("Expected KEY=VALUE, got '{}'", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed() {
+        let restriction = |allow: &[&str], deny: &[&str]| GeoRestriction {
+            allow: allow.iter().map(|c| c.to_string()).collect(),
+            deny: deny.iter().map(|c| c.to_string()).collect(),
+            status: 451,
+        };
+        let allow = restriction(&["US", "ca"], &[]);
+        assert!(allowed(&allow, Some("US")));
+        assert!(allowed(&allow, Some("CA")));
+        assert!(!allowed(&allow, Some("DE")));
+        assert!(!allowed(&allow, None));
+
+        let deny = restriction(&[], &["KP"]);
+        assert!(!allowed(&deny, Some("KP")));
+        assert!(allowed(&deny, Some("DE")));
+        assert!(allowed(&deny, None));
+
+        assert!(!allowed(&restriction(&["US"], &["US"]), Some("US")));
+    }
+}
@@ -0,0 +1,161 @@
+//! Health gossip between frontends: with `--health-weighted-ring` and `--gossip-peer`s, every
+//! `GOSSIP_INTERVAL` each frontend POSTs the health scores of the backends it has seen degraded
+//! (see `health`) to each peer's `/gossip/health`, and reweights its own rings as soon as a peer
+//! reports a change. Each report replaces the sender's last one, so a backend that has recovered
+//! is taken out of it by simply not being listed. Reports are authenticated with the shared
+//! `--gossip-token`; frontends with it serve the endpoint whether or not they have peers of
+//! their own.
+
+use axum::extract::State;
+use axum::http::{header, Method, Request, StatusCode};
+use axum::routing::post;
+use axum::Json;
+use hyper::Body;
+use opentelemetry::metrics::Counter;
+use opentelemetry::KeyValue;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{event, Level};
+use uuid::Uuid;
+
+use crate::FrontendState;
+
+pub const GOSSIP_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug)]
+pub struct GossipConfig {
+    /// Other frontends' base URIs.
+    pub peers: Vec<hyper::Uri>,
+    pub token: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BackendScore {
+    pub container_id: Uuid,
+    /// From `health::MIN_SCORE` to 1.
+    pub score: f64,
+}
+
+/// A frontend's scores for the backends it has seen degraded.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HealthReport {
+    /// The sending frontend's `BackendHealth::id`.
+    pub from: Uuid,
+    pub backends: Vec<BackendScore>,
+}
+
+async fn receive_handler(
+    State(state): State<Arc<FrontendState>>,
+    Json(report): Json<HealthReport>,
+) -> StatusCode {
+    match &state.health {
+        Some(health) => {
+            health.receive(report, Instant::now());
+            StatusCode::NO_CONTENT
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+/// The route peers report to. Requires `Authorization: Bearer <token>`.
+pub fn app(token: &str) -> axum::Router<Arc<FrontendState>> {
+    axum::Router::new()
+        .route("/gossip/health", post(receive_handler))
+        .route_layer(tower_http::validate_request::ValidateRequestHeaderLayer::bearer(token))
+}
+
+/// Report this frontend's scores to its peers until `state` is dropped.
+pub fn spawn_reports(state: &Arc<FrontendState>, config: &GossipConfig) {
+    let (Some(monitor), true) = (&state.monitor, state.health.is_some()) else {
+        return;
+    };
+    let monitor = monitor.clone();
+    let weak = Arc::downgrade(state);
+    let peers = config.peers.clone();
+    let authorization = format!("Bearer {}", config.token);
+    let failures: Counter<u64> = opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+        .u64_counter("gossip_report_failures")
+        .with_description("Health reports that couldn't be sent to a peer frontend")
+        .init();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(GOSSIP_INTERVAL).await;
+            let Some(state) = weak.upgrade() else {
+                return;
+            };
+            let health = state.health.as_ref().unwrap();
+            let report = {
+                let backends = monitor.backends.read().await;
+                health.report(backends.values(), Instant::now())
+            };
+            let body = match serde_json::to_vec(&report) {
+                Ok(body) => body,
+                Err(e) => {
+                    event!(Level::ERROR, error = %e, "Error encoding health report");
+                    continue;
+                }
+            };
+            let sends = peers.iter().map(|peer| {
+                let base = peer.to_string();
+                let uri = format!("{}/gossip/health", base.trim_end_matches('/'));
+                let req = Request::builder()
+                    .method(Method::POST)
+                    .uri(uri)
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .header(header::AUTHORIZATION, &authorization)
+                    .body(Body::from(body.clone()));
+                let http_client = &state.http_client;
+                let failures = &failures;
+                async move {
+                    let result = match req {
+                        Ok(req) => {
+                            match tokio::time::timeout(GOSSIP_INTERVAL, http_client.request(req))
+                                .await
+                            {
+                                Ok(Ok(resp)) if resp.status().is_success() => Ok(()),
+                                Ok(Ok(resp)) => Err(resp.status().to_string()),
+                                Ok(Err(e)) => Err(e.to_string()),
+                                Err(_) => Err("timed out".to_string()),
+                            }
+                        }
+                        Err(e) => Err(e.to_string()),
+                    };
+                    if let Err(e) = result {
+                        event!(Level::DEBUG, peer = %peer, error = %e, "Error sending health report");
+                        failures.add(1, &[KeyValue::new("peer", base)]);
+                    }
+                }
+            });
+            futures::future::join_all(sends).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_format() {
+        let report = HealthReport {
+            from: Uuid::nil(),
+            backends: vec![BackendScore {
+                container_id: Uuid::from_u128(1),
+                score: 0.5,
+            }],
+        };
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "from": "00000000-0000-0000-0000-000000000000",
+                "backends": [{"container_id": "00000000-0000-0000-0000-000000000001", "score": 0.5}],
+            })
+        );
+        assert_eq!(
+            serde_json::from_value::<HealthReport>(json).unwrap(),
+            report
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! Which addresses backends may have. Backends are checked as they're loaded from ZooKeeper (or
+//! resolved), before they enter the ring, so a bad or compromised control plane entry can't turn
+//! the frontend into a proxy to arbitrary internal services, such as a cloud metadata endpoint.
+
+use opentelemetry::metrics::Counter;
+use opentelemetry::KeyValue;
+use std::net::Ipv4Addr;
+use tracing::{event, Level};
+use uuid::Uuid;
+
+use bismuth_common::Backend;
+
+/// An IPv4 network, e.g. `10.0.0.0/8`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    network: Ipv4Addr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        let mask = u32::MAX
+            .checked_shl(32 - u32::from(self.prefix_len))
+            .unwrap_or(0);
+        u32::from(ip) & mask == u32::from(self.network) & mask
+    }
+}
+
+/// Parse `--backend-cidr` arguments. A bare address is a /32.
+pub fn parse_cidr(s: &str) -> Result<Cidr, String> {
+    let (network, prefix_len) = s.split_once('/').unwrap_or((s, "32"));
+    let network = network
+        .parse()
+        .map_err(|_| format!("Invalid IPv4 network '{}'", s))?;
+    match prefix_len.parse() {
+        Ok(prefix_len @ 0..=32) => Ok(Cidr {
+            network,
+            prefix_len,
+        }),
+        _ => Err(format!("Invalid prefix length in '{}'", s)),
+    }
+}
+
+pub struct BackendGuard {
+    /// Empty to allow any otherwise acceptable address.
+    allowed: Vec<Cidr>,
+    /// Whether loopback and link-local addresses are acceptable.
+    allow_local: bool,
+    rejected: Counter<u64>,
+}
+
+impl BackendGuard {
+    pub fn new(allowed: Vec<Cidr>, allow_local: bool) -> Self {
+        Self {
+            allowed,
+            allow_local,
+            rejected: opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+                .u64_counter("backends_rejected")
+                .with_description("Backends left out of the ring for their address, by reason")
+                .init(),
+        }
+    }
+
+    /// Why a backend mustn't have address `ip`, if it mustn't.
+    fn reject(&self, ip: Ipv4Addr) -> Option<&'static str> {
+        if ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast() {
+            Some("unroutable")
+        } else if !self.allow_local && (ip.is_loopback() || ip.is_link_local()) {
+            Some("local")
+        } else if !self.allowed.is_empty() && !self.allowed.iter().any(|c| c.contains(ip)) {
+            Some("not_allowed")
+        } else {
+            None
+        }
+    }
+
+    /// `backends` without the ones whose addresses aren't acceptable, each logged.
+    pub fn filter(&self, function_id: &Uuid, mut backends: Vec<Backend>) -> Vec<Backend> {
+        backends.retain(|backend| match self.reject(backend.ip) {
+            Some(reason) => {
+                event!(
+                    Level::WARN,
+                    function = %function_id,
+                    backend = %backend.ip,
+                    reason,
+                    "Rejected backend address"
+                );
+                self.rejected.add(1, &[KeyValue::new("reason", reason)]);
+                false
+            }
+            None => true,
+        });
+        backends
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cidr() {
+        let cidr = parse_cidr("10.0.0.0/8").unwrap();
+        assert!(cidr.contains([10, 1, 2, 3].into()));
+        assert!(!cidr.contains([11, 0, 0, 1].into()));
+        let host = parse_cidr("192.168.1.5").unwrap();
+        assert!(host.contains([192, 168, 1, 5].into()));
+        assert!(!host.contains([192, 168, 1, 6].into()));
+        assert!(parse_cidr("0.0.0.0/0")
+            .unwrap()
+            .contains([1, 2, 3, 4].into()));
+        assert!(parse_cidr("10.0.0.0/33").is_err());
+        assert!(parse_cidr("10.0.0/8").is_err());
+    }
+
+    #[test]
+    fn test_reject() {
+        let guard = BackendGuard::new(vec![parse_cidr("10.0.0.0/8").unwrap()], false);
+        assert_eq!(guard.reject([10, 0, 0, 1].into()), None);
+        assert_eq!(guard.reject([172, 16, 0, 1].into()), Some("not_allowed"));
+        assert_eq!(guard.reject([127, 0, 0, 1].into()), Some("local"));
+        assert_eq!(guard.reject([169, 254, 169, 254].into()), Some("local"));
+        assert_eq!(guard.reject([0, 0, 0, 0].into()), Some("unroutable"));
+
+        let guard = BackendGuard::new(Vec::new(), true);
+        assert_eq!(guard.reject([127, 0, 0, 1].into()), None);
+        assert_eq!(guard.reject([172, 16, 0, 1].into()), None);
+        assert_eq!(guard.reject([224, 0, 0, 1].into()), Some("unroutable"));
+
+        let function_id = Uuid::new_v4();
+        let backends = vec![
+            Backend {
+                ip: [127, 0, 0, 1].into(),
+                container_id: Uuid::new_v4(),
+            },
+            Backend {
+                ip: [10, 0, 0, 1].into(),
+                container_id: Uuid::new_v4(),
+            },
+        ];
+        let guard = BackendGuard::new(Vec::new(), false);
+        assert_eq!(guard.filter(&function_id, backends.clone()), &backends[1..]);
+    }
+}
@@ -0,0 +1,148 @@
+//! `GET /debug/ring/:function_id?key=...`: where a key lands on a function's ring and how the
+//! keyspace is split between its backends, to answer "why did this client hit that backend".
+
+use axum::extract::{Path, Query, State};
+use axum::Json;
+use conhash::ConsistentHash;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use bismuth_common::{ApiError, Backend};
+
+use crate::{FrontendState, FunctionBackends};
+
+/// Keys hashed to estimate each backend's share of the keyspace. The ring's points aren't
+/// exposed, but keys hash uniformly, so this is within about a percentage point.
+const SHARE_SAMPLES: u32 = 10_000;
+
+#[derive(Deserialize, Debug)]
+pub struct RingQuery {
+    key: String,
+    region: Option<String>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct RingPlacement {
+    key: String,
+    /// The region whose ring was used, if the client's region has backends.
+    region: Option<String>,
+    /// Where the key lands, before any load-aware rehashing. `None` if there are no backends.
+    backend: Option<Backend>,
+    /// Every backend on the ring, in the order they're listed in ZooKeeper.
+    shares: Vec<BackendShare>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct BackendShare {
+    backend: Backend,
+    region: Option<String>,
+    /// Estimated fraction of keys that land on this backend.
+    share: f64,
+}
+
+fn placement(function: &FunctionBackends, key: &str, region: Option<&str>) -> RingPlacement {
+    let (region, ring): (Option<&str>, &ConsistentHash<Backend>) =
+        match region.and_then(|r| Some((r, function.regional.get(r)?))) {
+            Some((region, ring)) => (Some(region), ring),
+            None => (None, &function.ring),
+        };
+
+    let mut counts: HashMap<Uuid, u32> = HashMap::new();
+    for i in 0..SHARE_SAMPLES {
+        if let Some(backend) = ring.get(format!("sample-{}", i).as_bytes()) {
+            *counts.entry(backend.container_id).or_default() += 1;
+        }
+    }
+    let shares = function
+        .backends
+        .iter()
+        .filter(|b| region.is_none() || function.regions.get(&b.ip).map(String::as_str) == region)
+        .map(|backend| BackendShare {
+            backend: backend.clone(),
+            region: function.regions.get(&backend.ip).cloned(),
+            share: f64::from(counts.get(&backend.container_id).copied().unwrap_or(0))
+                / f64::from(SHARE_SAMPLES),
+        })
+        .collect();
+
+    RingPlacement {
+        key: key.to_string(),
+        region: region.map(str::to_string),
+        backend: ring.get(key.as_bytes()).cloned(),
+        shares,
+    }
+}
+
+/// Where `key` lands on a function's ring. Clients are hashed by their IP address, so that's
+/// the key to ask about; give their `region` to use the regional ring as geo-aware routing
+/// would.
+#[utoipa::path(
+    get,
+    path = "/debug/ring/{function_id}",
+    tag = "admin",
+    params(
+        ("function_id" = Uuid, Path, description = "Function ID"),
+        ("key" = String, Query, description = "Key to place, e.g. a client IP address"),
+        ("region" = Option<String>, Query, description = "The client's region"),
+    ),
+    responses(
+        (status = 200, body = RingPlacement),
+        (status = 404, description = "No such function loaded"),
+    ),
+    security(("admin_token" = []))
+)]
+pub async fn ring_handler(
+    State(state): State<Arc<FrontendState>>,
+    Path(function_id): Path<Uuid>,
+    Query(query): Query<RingQuery>,
+) -> Result<Json<RingPlacement>, ApiError> {
+    let monitor = state.monitor.as_ref().ok_or(ApiError::NotFound)?;
+    let backends = monitor.backends.read().await;
+    let function = backends.get(&function_id).ok_or(ApiError::NotFound)?;
+    Ok(Json(placement(
+        function,
+        &query.key,
+        query.region.as_deref(),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_placement() {
+        let backends: Vec<Backend> = (1..=4)
+            .map(|n| Backend {
+                ip: Ipv4Addr::new(10, 0, 0, n),
+                container_id: Uuid::from_u128(n.into()),
+            })
+            .collect();
+        let regions = HashMap::from([
+            (backends[0].ip, "us-east".to_string()),
+            (backends[1].ip, "us-east".to_string()),
+        ]);
+        let function = FunctionBackends::new(backends.clone(), &regions);
+
+        let placed = placement(&function, "10.1.2.3", None);
+        assert_eq!(placed.backend.as_ref(), function.ring.get(b"10.1.2.3"));
+        assert_eq!(placed.shares.len(), 4);
+        let total: f64 = placed.shares.iter().map(|s| s.share).sum();
+        assert!((total - 1.0).abs() < 1e-9);
+        assert!(placed.shares.iter().all(|s| s.share > 0.0));
+
+        let placed = placement(&function, "10.1.2.3", Some("us-east"));
+        assert_eq!(placed.region.as_deref(), Some("us-east"));
+        assert!(backends[..2].contains(placed.backend.as_ref().unwrap()));
+        assert_eq!(placed.shares.len(), 2);
+
+        // Regions without backends use the whole ring.
+        let placed = placement(&function, "10.1.2.3", Some("eu-west"));
+        assert_eq!(placed.region, None);
+        assert_eq!(placed.shares.len(), 4);
+    }
+}
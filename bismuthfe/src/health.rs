@@ -0,0 +1,353 @@
+//! Health-weighted rings: with `--health-weighted-ring`, each backend's points in its function's
+//! rings are scaled by a health score from the invocations it served, rather than it being either
+//! in the ring or out. A backend that's slow or failing keeps only some of its points, so most of
+//! its keys move to other backends while the rest stay where they were, and as it recovers it
+//! gets them back.
+//!
+//! The score is the square of the backend's success rate (5xx responses and errors count as
+//! failures) times how much faster than it the function's median backend is, if it's slower,
+//! each a moving average over recent invocations. It recovers towards 1 while the backend gets
+//! no invocations, and never goes below `MIN_SCORE`, so a degraded backend keeps enough traffic
+//! to show when it's healthy again.
+//!
+//! With `--gossip-peer`s, frontends also share the scores of the backends they've seen degraded
+//! (see `gossip`), and each backend gets the lowest of its own score and those reported for it,
+//! so one that a frontend finds failing is avoided by the others too. A reported score recovers
+//! towards 1 the same way, from when it was received, so one from a frontend that's gone quiet
+//! doesn't stick.
+
+use opentelemetry::metrics::Counter;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use crate::gossip::{BackendScore, HealthReport};
+use crate::{FunctionBackends, CONHASH_REPLICAS};
+
+/// Weight of each invocation in the moving averages.
+const ALPHA: f64 = 0.05;
+/// How fast an idle backend's score recovers: halfway to 1 in this long.
+const RECOVERY_HALF_LIFE: Duration = Duration::from_secs(30);
+/// Lowest score, i.e. fraction of its points a backend keeps.
+const MIN_SCORE: f64 = 0.1;
+/// How often rings are reweighted.
+pub const REWEIGHT_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a peer's last report is kept after it stops reporting.
+const REPORT_TTL: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Stats {
+    /// Moving average of failures, from 0 to 1.
+    errors: f64,
+    /// Moving average of response header latency, in seconds.
+    latency: f64,
+    last: Instant,
+}
+
+impl Stats {
+    fn record(&mut self, ok: bool, latency: Duration, now: Instant) {
+        let failure = if ok { 0.0 } else { 1.0 };
+        self.errors += ALPHA * (failure - self.errors);
+        self.latency += ALPHA * (latency.as_secs_f64() - self.latency);
+        self.last = now;
+    }
+}
+
+/// A score from `MIN_SCORE` to 1, given the function's median backend latency.
+fn score(stats: &Stats, median_latency: f64, now: Instant) -> f64 {
+    let success = (1.0 - stats.errors).powi(2);
+    let speed = if stats.latency > median_latency && stats.latency > 0.0 {
+        median_latency / stats.latency
+    } else {
+        1.0
+    };
+    recovered(success * speed, now.saturating_duration_since(stats.last))
+}
+
+/// `score` after recovering for `idle`.
+fn recovered(score: f64, idle: Duration) -> f64 {
+    let unhealthy =
+        (1.0 - score) * 0.5f64.powf(idle.as_secs_f64() / RECOVERY_HALF_LIFE.as_secs_f64());
+    (1.0 - unhealthy).max(MIN_SCORE)
+}
+
+/// A peer's last report: when it was received and the scores of its degraded backends.
+struct Report {
+    received: Instant,
+    scores: HashMap<Uuid, f64>,
+}
+
+fn replicas(score: f64) -> usize {
+    ((CONHASH_REPLICAS as f64 * score).round() as usize).clamp(1, CONHASH_REPLICAS)
+}
+
+pub struct BackendHealth {
+    /// Identifies this frontend's reports to its peers.
+    pub id: Uuid,
+    /// By container.
+    stats: Mutex<HashMap<Uuid, Stats>>,
+    /// By peer.
+    reports: Mutex<HashMap<Uuid, Report>>,
+    /// Notified when a peer reports a change, to reweight straight away.
+    pub reported: Notify,
+    reweighted: Counter<u64>,
+}
+
+impl BackendHealth {
+    pub fn new() -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            stats: Mutex::new(HashMap::new()),
+            reports: Mutex::new(HashMap::new()),
+            reported: Notify::new(),
+            reweighted: opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+                .u64_counter("backends_reweighted")
+                .with_description(
+                    "Changes to backends' points in their function's rings for their health",
+                )
+                .init(),
+        }
+    }
+
+    /// Note an invocation `container_id` served, successfully or not, in `latency`.
+    pub fn record(&self, container_id: Uuid, ok: bool, latency: Duration, now: Instant) {
+        self.stats
+            .lock()
+            .unwrap()
+            .entry(container_id)
+            .or_insert(Stats {
+                errors: 0.0,
+                latency: latency.as_secs_f64(),
+                last: now,
+            })
+            .record(ok, latency, now);
+    }
+
+    /// This frontend's own scores for each backend of `function`, `None` for those it has no
+    /// invocations of.
+    fn scores(&self, function: &FunctionBackends, now: Instant) -> Vec<Option<f64>> {
+        let stats = self.stats.lock().unwrap();
+        let backend_stats = function
+            .backends
+            .iter()
+            .map(|backend| stats.get(&backend.container_id).copied())
+            .collect::<Vec<_>>();
+        let mut latencies = backend_stats
+            .iter()
+            .flatten()
+            .map(|s| s.latency)
+            .collect::<Vec<_>>();
+        latencies.sort_by(f64::total_cmp);
+        let median_latency = latencies.get(latencies.len() / 2).copied().unwrap_or(0.0);
+        backend_stats
+            .iter()
+            .map(|stats| stats.map(|s| score(&s, median_latency, now)))
+            .collect()
+    }
+
+    /// The points each backend of `function` should have, for those whose points should change.
+    pub fn changes(&self, function: &FunctionBackends, now: Instant) -> Vec<(usize, usize)> {
+        let scores = self.scores(function, now);
+        let reports = self.reports.lock().unwrap();
+        function
+            .backends
+            .iter()
+            .zip(scores)
+            .enumerate()
+            .filter_map(|(i, (backend, score))| {
+                let reported = reports
+                    .values()
+                    .filter_map(|report| {
+                        let score = report.scores.get(&backend.container_id)?;
+                        Some(recovered(
+                            *score,
+                            now.saturating_duration_since(report.received),
+                        ))
+                    })
+                    .fold(1.0, f64::min);
+                let wanted = replicas(score.unwrap_or(1.0).min(reported));
+                (wanted != function.replicas[i]).then_some((i, wanted))
+            })
+            .collect()
+    }
+
+    /// This frontend's scores for the backends of `functions` it has seen degraded, for its peers.
+    pub fn report<'a>(
+        &self,
+        functions: impl IntoIterator<Item = &'a FunctionBackends>,
+        now: Instant,
+    ) -> HealthReport {
+        HealthReport {
+            from: self.id,
+            backends: functions
+                .into_iter()
+                .flat_map(|function| {
+                    function
+                        .backends
+                        .iter()
+                        .zip(self.scores(function, now))
+                        .filter_map(|(backend, score)| {
+                            let score = score.filter(|score| *score < 1.0)?;
+                            Some(BackendScore {
+                                container_id: backend.container_id,
+                                score,
+                            })
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect(),
+        }
+    }
+
+    /// Take a peer's report, replacing its last one. Returns whether it changed anything.
+    pub fn receive(&self, report: HealthReport, now: Instant) -> bool {
+        if report.from == self.id {
+            return false;
+        }
+        let scores = report
+            .backends
+            .into_iter()
+            .map(|b| (b.container_id, b.score.clamp(MIN_SCORE, 1.0)))
+            .collect::<HashMap<_, _>>();
+        let mut reports = self.reports.lock().unwrap();
+        let changed = reports
+            .get(&report.from)
+            .map_or(!scores.is_empty(), |last| last.scores != scores);
+        reports.insert(
+            report.from,
+            Report {
+                received: now,
+                scores,
+            },
+        );
+        if changed {
+            self.reported.notify_one();
+        }
+        changed
+    }
+
+    /// Reweight `function`'s backends by `changes`.
+    pub fn apply(&self, function: &mut FunctionBackends, changes: &[(usize, usize)]) {
+        for &(i, replicas) in changes {
+            function.set_replicas(i, replicas);
+        }
+        self.reweighted.add(changes.len() as u64, &[]);
+    }
+
+    /// Forget backends no longer listed.
+    pub fn retain(&self, containers: &[Uuid]) {
+        let containers: std::collections::HashSet<_> = containers.iter().collect();
+        self.stats
+            .lock()
+            .unwrap()
+            .retain(|id, _| containers.contains(id));
+        let now = Instant::now();
+        self.reports.lock().unwrap().retain(|_, report| {
+            report.scores.retain(|id, _| containers.contains(id));
+            now.saturating_duration_since(report.received) < REPORT_TTL
+        });
+    }
+}
+
+impl Default for BackendHealth {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_score() {
+        let now = Instant::now();
+        let healthy = Stats {
+            errors: 0.0,
+            latency: 0.1,
+            last: now,
+        };
+        assert_eq!(score(&healthy, 0.1, now), 1.0);
+        // Faster than the median is no better.
+        assert_eq!(score(&healthy, 0.2, now), 1.0);
+        let slow = Stats {
+            latency: 0.4,
+            ..healthy
+        };
+        assert_eq!(score(&slow, 0.1, now), 0.25);
+        let failing = Stats {
+            errors: 0.5,
+            ..healthy
+        };
+        assert_eq!(score(&failing, 0.1, now), 0.25);
+        let down = Stats {
+            errors: 1.0,
+            ..healthy
+        };
+        assert_eq!(score(&down, 0.1, now), MIN_SCORE);
+        // Recovering while idle.
+        assert_eq!(score(&slow, 0.1, now + RECOVERY_HALF_LIFE), 0.625);
+
+        assert_eq!(replicas(1.0), CONHASH_REPLICAS);
+        assert_eq!(replicas(MIN_SCORE), CONHASH_REPLICAS / 10);
+    }
+
+    #[test]
+    fn test_peer_reports() {
+        let now = Instant::now();
+        let backends = (1..=2)
+            .map(|n| bismuth_common::Backend {
+                ip: [10, 0, 0, n].into(),
+                container_id: Uuid::from_u128(n.into()),
+            })
+            .collect::<Vec<_>>();
+        let function = FunctionBackends::new(backends.clone(), &HashMap::new());
+        let (ours, peer) = (BackendHealth::new(), BackendHealth::new());
+        for _ in 0..100 {
+            peer.record(
+                backends[0].container_id,
+                false,
+                Duration::from_millis(10),
+                now,
+            );
+            peer.record(
+                backends[1].container_id,
+                true,
+                Duration::from_millis(10),
+                now,
+            );
+        }
+        let report = peer.report([&function], now);
+        assert_eq!(report.from, peer.id);
+        assert_eq!(report.backends.len(), 1);
+        assert_eq!(report.backends[0].container_id, backends[0].container_id);
+
+        assert!(ours.receive(report.clone(), now));
+        assert!(!ours.receive(report, now));
+        assert_eq!(ours.changes(&function, now), [(0, replicas(MIN_SCORE))]);
+        // Recovering since it was received.
+        let later = now + RECOVERY_HALF_LIFE * 10;
+        assert!(ours.changes(&function, later).is_empty());
+
+        // A report without it takes it back.
+        assert!(ours.receive(peer.report([], now), now));
+        assert!(ours.changes(&function, now).is_empty());
+        // Our own reports are ignored.
+        assert!(!peer.receive(peer.report([&function], now), now));
+    }
+
+    #[test]
+    fn test_record() {
+        let now = Instant::now();
+        let mut stats = Stats {
+            errors: 0.0,
+            latency: 1.0,
+            last: now,
+        };
+        stats.record(false, Duration::from_secs(3), now);
+        assert_eq!(stats.errors, ALPHA);
+        assert_eq!(stats.latency, 1.0 + 2.0 * ALPHA);
+    }
+}
@@ -0,0 +1,42 @@
+//! HTTP/2 flow control and stream limits, for client connections (which hyper accepts as h2c
+//! alongside HTTP/1.1) and, with `--backend-http2`, connections to backends and peers.
+//!
+//! hyper's defaults (64 KiB initial windows, 16 KiB frames) cap each stream at one window per
+//! round trip, so large payloads over high-latency links crawl unless they're raised.
+
+/// Settings left unset keep hyper's defaults.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Http2Settings {
+    pub initial_stream_window_size: Option<u32>,
+    pub initial_connection_window_size: Option<u32>,
+    /// Size windows from the measured bandwidth-delay product instead, overriding the two above.
+    pub adaptive_window: bool,
+    /// Streams each client connection may have open at once. Not negotiated upstream: backends
+    /// advertise their own.
+    pub max_concurrent_streams: Option<u32>,
+    pub max_frame_size: Option<u32>,
+}
+
+impl Http2Settings {
+    pub fn configure_server<I, E>(
+        &self,
+        builder: hyper::server::Builder<I, E>,
+    ) -> hyper::server::Builder<I, E> {
+        builder
+            .http2_initial_stream_window_size(self.initial_stream_window_size)
+            .http2_initial_connection_window_size(self.initial_connection_window_size)
+            .http2_adaptive_window(self.adaptive_window)
+            .http2_max_concurrent_streams(self.max_concurrent_streams)
+            .http2_max_frame_size(self.max_frame_size)
+    }
+
+    /// `only` speaks HTTP/2 with prior knowledge to every upstream, which must all support h2c.
+    pub fn configure_client(&self, builder: &mut hyper::client::Builder, only: bool) {
+        builder
+            .http2_only(only)
+            .http2_initial_stream_window_size(self.initial_stream_window_size)
+            .http2_initial_connection_window_size(self.initial_connection_window_size)
+            .http2_adaptive_window(self.adaptive_window)
+            .http2_max_frame_size(self.max_frame_size);
+    }
+}
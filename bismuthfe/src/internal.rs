@@ -0,0 +1,109 @@
+//! The internal listener: with `--internal-bind`, the admin API (`/admin/*` and `/debug/*`) is
+//! served on a second address, say a private IP or a Unix socket, with its own middleware, and
+//! not at all on `--bind`, so operational endpoints are never exposed on the public data plane.
+
+use anyhow::{Context, Result};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use tracing::{event, Level};
+
+use crate::listener;
+
+/// Where the internal listener is bound.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Bind {
+    Tcp(SocketAddr),
+    /// A Unix socket, replaced if the file already exists.
+    Unix(PathBuf),
+}
+
+impl FromStr for Bind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err("Expected unix:/path/to/socket".to_string()),
+            Some(path) => Ok(Bind::Unix(path.into())),
+            None => s
+                .parse()
+                .map(Bind::Tcp)
+                .map_err(|_| format!("Expected IP:port or unix:/path, got '{}'", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Bind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Bind::Tcp(addr) => write!(f, "{}", addr),
+            Bind::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Bind `bind` and return the server for `router` on it, which runs until `shutdown` completes
+/// and its requests have finished.
+pub fn serve(
+    bind: &Bind,
+    reuse_port: bool,
+    router: axum::Router,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<impl Future<Output = Result<()>>> {
+    let server: std::pin::Pin<Box<dyn Future<Output = hyper::Result<()>> + Send>> = match bind {
+        Bind::Tcp(addr) => {
+            let listener = listener::bind(*addr, reuse_port)?;
+            Box::pin(
+                axum::Server::from_tcp(listener)?
+                    .serve(router.into_make_service_with_connect_info::<SocketAddr>())
+                    .with_graceful_shutdown(shutdown),
+            )
+        }
+        Bind::Unix(path) => {
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(e).with_context(|| format!("Error removing {}", path.display()));
+                }
+                _ => {}
+            }
+            let listener = tokio::net::UnixListener::bind(path)
+                .with_context(|| format!("Error binding {}", path.display()))?;
+            let incoming = hyper::server::accept::poll_fn(move |cx| {
+                listener
+                    .poll_accept(cx)
+                    .map(|accepted| Some(accepted.map(|(stream, _)| stream)))
+            });
+            Box::pin(
+                axum::Server::builder(incoming)
+                    .serve(router.into_make_service())
+                    .with_graceful_shutdown(shutdown),
+            )
+        }
+    };
+    event!(Level::INFO, bind = %bind, "Serving the admin API on the internal listener");
+    Ok(async move { Ok(server.await?) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bind() {
+        assert_eq!(
+            "127.0.0.1:9000".parse::<Bind>(),
+            Ok(Bind::Tcp("127.0.0.1:9000".parse().unwrap()))
+        );
+        assert_eq!(
+            "[::1]:9000".parse::<Bind>(),
+            Ok(Bind::Tcp("[::1]:9000".parse().unwrap()))
+        );
+        assert_eq!(
+            "unix:/run/bismuthfe.sock".parse::<Bind>(),
+            Ok(Bind::Unix("/run/bismuthfe.sock".into()))
+        );
+        assert!("unix:".parse::<Bind>().is_err());
+        assert!("localhost".parse::<Bind>().is_err());
+    }
+}
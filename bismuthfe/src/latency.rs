@@ -0,0 +1,76 @@
+use axum::http::StatusCode;
+use opentelemetry::metrics::{Histogram, Meter};
+use opentelemetry::KeyValue;
+use std::collections::HashMap;
+use std::time::Duration;
+use uuid::Uuid;
+
+use bismuth_common::{bucket_profile_meter, parse_buckets, FunctionConfig};
+
+use crate::metric_labels;
+
+/// Histogram bucket boundaries. An alias so clap takes them as one value instead of repeated ones.
+pub type Boundaries = Vec<f64>;
+
+/// Per-function invocation latency, recorded with the bucket profile named in the function's
+/// config (or the default buckets).
+pub struct InvocationLatency {
+    default: Histogram<f64>,
+    profiles: HashMap<String, Histogram<f64>>,
+}
+
+fn histogram(meter: Meter) -> Histogram<f64> {
+    meter
+        .f64_histogram("invocation_duration")
+        .with_description("Time from sending an invocation to a backend until its response headers")
+        .with_unit(opentelemetry::metrics::Unit::new("ms"))
+        .init()
+}
+
+impl InvocationLatency {
+    pub fn new<'a>(profiles: impl IntoIterator<Item = &'a String>) -> Self {
+        Self {
+            default: histogram(opentelemetry::global::meter(env!("CARGO_PKG_NAME"))),
+            profiles: profiles
+                .into_iter()
+                .map(|profile| {
+                    (
+                        profile.clone(),
+                        histogram(bucket_profile_meter(env!("CARGO_PKG_NAME"), profile)),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    pub fn record(
+        &self,
+        function_id: &Uuid,
+        config: Option<&FunctionConfig>,
+        status: Option<StatusCode>,
+        elapsed: Duration,
+    ) {
+        let histogram = config
+            .and_then(|c| c.latency_buckets.as_ref())
+            .and_then(|profile| self.profiles.get(profile))
+            .unwrap_or(&self.default);
+        histogram.record(
+            elapsed.as_secs_f64() * 1000.0,
+            &[
+                metric_labels::function(function_id),
+                KeyValue::new(
+                    "http.response.status_code",
+                    status
+                        .map(|s| s.as_u16().to_string())
+                        .unwrap_or_else(|| "error".to_string()),
+                ),
+            ],
+        );
+    }
+}
+
+/// Parse `NAME=BOUNDARY,BOUNDARY,...` command line arguments.
+pub fn parse_bucket_profile(s: &str) -> Result<(String, Boundaries), String> {
+    let (name, buckets) = crate::geo::parse_key_val(s)?;
+    Ok((name, parse_buckets(&buckets)?))
+}
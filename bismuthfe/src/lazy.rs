@@ -0,0 +1,186 @@
+//! On-demand loading of functions invoked before this frontend has heard of them, e.g. ones
+//! created moments ago whose watch event is still on its way, so they aren't 404ed meanwhile.
+//! Concurrent invocations of the same function share one load, and functions found not to exist
+//! are remembered briefly, so traffic to them doesn't all go to ZooKeeper.
+
+use anyhow::Result;
+use opentelemetry::metrics::Counter;
+use opentelemetry::KeyValue;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::{event, Level};
+use uuid::Uuid;
+
+/// How long a function found not to exist is taken not to without asking again.
+const MISSING_TTL: Duration = Duration::from_secs(2);
+/// Past this many functions remembered as missing, expired ones are pruned.
+const MAX_MISSING: usize = 10_000;
+
+/// `None` while the load is in flight, then whether the function exists.
+type Outcome = Option<bool>;
+
+pub struct LazyLoads {
+    loads: Mutex<HashMap<Uuid, watch::Receiver<Outcome>>>,
+    /// When each function was found not to exist.
+    missing: Mutex<HashMap<Uuid, Instant>>,
+    loaded: Counter<u64>,
+}
+
+/// Forgets the first load once it's done or cancelled, so later ones start afresh.
+struct Leader<'a> {
+    loads: &'a Mutex<HashMap<Uuid, watch::Receiver<Outcome>>>,
+    function_id: Uuid,
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        self.loads.lock().unwrap().remove(&self.function_id);
+    }
+}
+
+impl LazyLoads {
+    pub fn new() -> Self {
+        Self {
+            loads: Mutex::new(HashMap::new()),
+            missing: Mutex::new(HashMap::new()),
+            loaded: opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+                .u64_counter("lazy_function_loads")
+                .with_description(
+                    "Functions loaded from ZooKeeper on being invoked before being heard of, by outcome",
+                )
+                .init(),
+        }
+    }
+
+    /// Whether `function_id` exists, running `load` (which says whether it does, having added it
+    /// to the routing table if so) unless one is already in flight, in which case its outcome is
+    /// waited for instead. Errors, and the first load being cancelled, count as not existing.
+    pub async fn load<F>(&self, function_id: Uuid, now: Instant, load: F) -> bool
+    where
+        F: Future<Output = Result<bool>>,
+    {
+        if self
+            .missing
+            .lock()
+            .unwrap()
+            .get(&function_id)
+            .is_some_and(|at| now.duration_since(*at) < MISSING_TTL)
+        {
+            return false;
+        }
+
+        let (tx, mut rx) = {
+            let mut loads = self.loads.lock().unwrap();
+            match loads.get(&function_id) {
+                Some(rx) => (None, rx.clone()),
+                None => {
+                    let (tx, rx) = watch::channel(None);
+                    loads.insert(function_id, rx.clone());
+                    (Some(tx), rx)
+                }
+            }
+        };
+
+        let Some(tx) = tx else {
+            return match rx.wait_for(|outcome| outcome.is_some()).await {
+                Ok(outcome) => outcome.unwrap_or(false),
+                Err(_) => false,
+            };
+        };
+
+        let _leader = Leader {
+            loads: &self.loads,
+            function_id,
+        };
+        let (exists, outcome) = match load.await {
+            Ok(true) => (true, "found"),
+            Ok(false) => {
+                let mut missing = self.missing.lock().unwrap();
+                if missing.len() >= MAX_MISSING {
+                    missing.retain(|_, at| now.duration_since(*at) < MISSING_TTL);
+                }
+                missing.insert(function_id, now);
+                (false, "missing")
+            }
+            Err(e) => {
+                event!(Level::WARN, function = %function_id, error = %e, "Error loading function on demand");
+                (false, "error")
+            }
+        };
+        self.loaded.add(1, &[KeyValue::new("outcome", outcome)]);
+        let _ = tx.send(Some(exists));
+        exists
+    }
+}
+
+impl Default for LazyLoads {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_shares_concurrent_loads() {
+        let lazy = Arc::new(LazyLoads::new());
+        let loads = Arc::new(AtomicUsize::new(0));
+        let function_id = Uuid::new_v4();
+        let tasks: Vec<_> = (0..5)
+            .map(|_| {
+                let lazy = lazy.clone();
+                let loads = loads.clone();
+                tokio::spawn(async move {
+                    lazy.load(function_id, Instant::now(), async {
+                        loads.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        Ok(true)
+                    })
+                    .await
+                })
+            })
+            .collect();
+        for task in tasks {
+            assert!(task.await.unwrap());
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_remembers_missing() {
+        let lazy = LazyLoads::new();
+        let loads = AtomicUsize::new(0);
+        let function_id = Uuid::new_v4();
+        let start = Instant::now();
+        let (lazy_, loads_) = (&lazy, &loads);
+        let load = move |at| {
+            lazy_.load(function_id, at, async move {
+                loads_.fetch_add(1, Ordering::SeqCst);
+                Ok(false)
+            })
+        };
+        assert!(!load(start).await);
+        assert!(!load(start + Duration::from_secs(1)).await);
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert!(!load(start + MISSING_TTL).await);
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+
+        // Errors aren't remembered.
+        let other = Uuid::new_v4();
+        assert!(
+            !lazy
+                .load(other, start, async {
+                    Err(anyhow::anyhow!("connection loss"))
+                })
+                .await
+        );
+        assert!(lazy.load(other, start, async { Ok(true) }).await);
+    }
+}
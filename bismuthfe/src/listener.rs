@@ -0,0 +1,88 @@
+use anyhow::{anyhow, Context, Result};
+use std::net::{SocketAddr, TcpListener};
+use std::os::fd::FromRawFd as _;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{event, Level};
+
+/// First file descriptor passed by systemd (`SD_LISTEN_FDS_START` in sd-daemon.h).
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// Take over the listening socket passed in by systemd socket activation, if there is one.
+/// https://www.freedesktop.org/software/systemd/man/latest/sd_listen_fds.html
+pub fn systemd_listener() -> Result<Option<TcpListener>> {
+    let Ok(listen_pid) = std::env::var("LISTEN_PID") else {
+        return Ok(None);
+    };
+    // The variables may have been inherited from a parent which was the actual target.
+    if listen_pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok(None);
+    }
+    let listen_fds: i32 = std::env::var("LISTEN_FDS")
+        .context("LISTEN_PID set without LISTEN_FDS")?
+        .parse()
+        .context("Invalid LISTEN_FDS")?;
+
+    // Don't leak these to anything we spawn.
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    if listen_fds != 1 {
+        return Err(anyhow!(
+            "Expected exactly one socket from systemd, got {}",
+            listen_fds
+        ));
+    }
+
+    let listener = unsafe { TcpListener::from_raw_fd(SD_LISTEN_FDS_START) };
+    listener
+        .set_nonblocking(true)
+        .context("Error setting inherited socket non-blocking")?;
+    event!(Level::INFO, addr = ?listener.local_addr().ok(), "Using socket from systemd");
+    Ok(Some(listener))
+}
+
+/// Use the socket passed by systemd if present, otherwise bind `addr`.
+///
+/// With `reuse_port`, the socket is bound with `SO_REUSEPORT` so that a new process can bind the
+/// same address while this one is still serving. The kernel balances new connections across both
+/// until the old process stops listening (see `shutdown_signal`), after which the new one gets
+/// everything.
+pub fn listen(addr: SocketAddr, reuse_port: bool) -> Result<TcpListener> {
+    if let Some(listener) = systemd_listener()? {
+        return Ok(listener);
+    }
+    bind(addr, reuse_port)
+}
+
+/// Bind `addr`, ignoring any socket passed by systemd.
+pub fn bind(addr: SocketAddr, reuse_port: bool) -> Result<TcpListener> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        None,
+    )?;
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    socket
+        .bind(&addr.into())
+        .with_context(|| format!("Error binding {}", addr))?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+/// Resolves on SIGTERM or SIGINT.
+/// Used for graceful shutdown: the listener is closed immediately, so a replacement process
+/// (bound with `SO_REUSEPORT` or holding the systemd socket) takes all new connections,
+/// while in-flight requests on this one are allowed to finish.
+pub async fn shutdown_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Error installing SIGTERM handler");
+    tokio::select! {
+        _ = sigterm.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    event!(Level::INFO, "Shutting down, draining connections");
+}
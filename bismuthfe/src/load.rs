@@ -0,0 +1,161 @@
+//! Load-aware routing: bismuthd reports how many invocations a container has in flight on each of
+//! its responses (`IN_FLIGHT_HEADER`), and backends reporting at least `--overload-in-flight` are
+//! skipped even when the client hashes to them, so one hot key can't pile onto one container.
+
+use axum::http::HeaderMap;
+use conhash::ConsistentHash;
+use opentelemetry::metrics::Counter;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use bismuth_common::{Backend, IN_FLIGHT_HEADER};
+
+/// Reports older than this say nothing about the backend's load now.
+const MAX_AGE: Duration = Duration::from_secs(5);
+/// Other backends tried, by rehashing the key, before going to the hashed one regardless.
+const MAX_REHASHES: usize = 3;
+/// Past this many containers reported on, stale reports are pruned.
+const MAX_REPORTS: usize = 10_000;
+
+pub struct BackendLoad {
+    threshold: usize,
+    /// In-flight count and when it was reported, by container.
+    reports: Mutex<HashMap<Uuid, (usize, Instant)>>,
+    skipped: Counter<u64>,
+}
+
+impl BackendLoad {
+    pub fn new(threshold: usize) -> Self {
+        Self {
+            threshold,
+            reports: Mutex::new(HashMap::new()),
+            skipped: opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+                .u64_counter("overloaded_backends_skipped")
+                .with_description("Invocations routed away from the backend they hashed to")
+                .init(),
+        }
+    }
+
+    /// Note the load reported on a response from `container_id`, if any.
+    pub fn report(&self, container_id: Uuid, headers: &HeaderMap, now: Instant) {
+        let Some(in_flight) = headers
+            .get(IN_FLIGHT_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+        else {
+            return;
+        };
+        let mut reports = self.reports.lock().unwrap();
+        if reports.len() >= MAX_REPORTS {
+            reports.retain(|_, (_, at)| now.duration_since(*at) < MAX_AGE);
+        }
+        reports.insert(container_id, (in_flight, now));
+    }
+
+    fn is_overloaded(&self, container_id: &Uuid, now: Instant) -> bool {
+        self.reports
+            .lock()
+            .unwrap()
+            .get(container_id)
+            .is_some_and(|(in_flight, at)| {
+                *in_flight >= self.threshold && now.duration_since(*at) < MAX_AGE
+            })
+    }
+
+    /// The backend `key` hashes to, unless it's overloaded and rehashing finds one that isn't.
+    pub fn pick<'a>(
+        &self,
+        ring: &'a ConsistentHash<Backend>,
+        key: &str,
+        now: Instant,
+    ) -> Option<&'a Backend> {
+        let picked = self.peek(ring, key, now)?;
+        if ring
+            .get(key.as_bytes())
+            .is_some_and(|hashed| hashed.container_id != picked.container_id)
+        {
+            self.skipped.add(1, &[]);
+        }
+        Some(picked)
+    }
+
+    /// The backend `pick` would choose, without counting it as skipping one.
+    pub fn peek<'a>(
+        &self,
+        ring: &'a ConsistentHash<Backend>,
+        key: &str,
+        now: Instant,
+    ) -> Option<&'a Backend> {
+        let hashed = ring.get(key.as_bytes())?;
+        if !self.is_overloaded(&hashed.container_id, now) {
+            return Some(hashed);
+        }
+        // Salted rather than walking the ring, so one overloaded backend's keys spread over the
+        // others instead of all landing on its successor.
+        (1..=MAX_REHASHES)
+            .filter_map(|n| ring.get(format!("{}#{}", key, n).as_bytes()))
+            .find(|backend| !self.is_overloaded(&backend.container_id, now))
+            .or(Some(hashed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backend(n: u8) -> Backend {
+        Backend {
+            ip: [10, 0, 0, n].into(),
+            container_id: Uuid::from_u128(n as u128),
+        }
+    }
+
+    fn headers(in_flight: usize) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(IN_FLIGHT_HEADER, in_flight.into());
+        headers
+    }
+
+    #[test]
+    fn test_pick() {
+        let mut ring = ConsistentHash::new();
+        for n in 1..=16 {
+            ring.add(&backend(n), 10);
+        }
+        let load = BackendLoad::new(8);
+        let now = Instant::now();
+        let hashed = ring.get(b"10.1.2.3").unwrap().clone();
+        assert_eq!(load.pick(&ring, "10.1.2.3", now), Some(&hashed));
+
+        load.report(hashed.container_id, &headers(7), now);
+        assert_eq!(load.pick(&ring, "10.1.2.3", now), Some(&hashed));
+
+        load.report(hashed.container_id, &headers(8), now);
+        let picked = load.pick(&ring, "10.1.2.3", now).unwrap();
+        assert_ne!(picked, &hashed);
+        // The same key keeps going to the same other backend.
+        assert_eq!(load.pick(&ring, "10.1.2.3", now), Some(picked));
+        assert_eq!(load.peek(&ring, "10.1.2.3", now), Some(picked));
+
+        // The report has gone stale.
+        assert_eq!(load.pick(&ring, "10.1.2.3", now + MAX_AGE), Some(&hashed));
+    }
+
+    #[test]
+    fn test_all_overloaded() {
+        let mut ring = ConsistentHash::new();
+        ring.add(&backend(1), 10);
+        ring.add(&backend(2), 10);
+        let load = BackendLoad::new(1);
+        let now = Instant::now();
+        load.report(backend(1).container_id, &headers(5), now);
+        load.report(backend(2).container_id, &headers(5), now);
+        let hashed = ring.get(b"key").unwrap().clone();
+        assert_eq!(load.pick(&ring, "key", now), Some(&hashed));
+        // Responses without a report are ignored.
+        load.report(backend(1).container_id, &HeaderMap::new(), now);
+        assert_eq!(load.reports.lock().unwrap()[&backend(1).container_id].0, 5);
+    }
+}
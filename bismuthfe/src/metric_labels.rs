@@ -0,0 +1,195 @@
+//! Cardinality controls for the `function` label on metrics, which on installations with tens of
+//! thousands of functions would otherwise give every per-function metric that many series.
+//! Functions given with `--metric-function` always get their own label. With
+//! `--metric-function-buckets N` the rest are hashed into N shared labels, by ID so they're the same
+//! on every frontend; with `--metric-max-functions N` the first N seen get their own and the rest
+//! share `other`, counted in `metric_labels_overflowed`. Without either, every function gets its
+//! own, as before. Only functions that have been in a routing table count: any other ID (e.g.
+//! made up by a client) is labelled `unknown`, so they can't take up the labels.
+//!
+//! Per-function gauges (SLO compliance and burn rates) mean nothing summed over functions, so
+//! they're only reported for functions with their own label.
+
+use opentelemetry::metrics::Counter;
+use opentelemetry::KeyValue;
+use std::collections::HashSet;
+use std::sync::{Mutex, OnceLock, RwLock};
+use uuid::Uuid;
+
+/// The label shared by functions past `--metric-max-functions`.
+pub const OTHER: &str = "other";
+/// The label of functions that have never been in a routing table.
+pub const UNKNOWN: &str = "unknown";
+/// The label of metrics for function IDs that aren't UUIDs, e.g. from mistyped invocation URLs.
+pub const INVALID: &str = "invalid";
+
+pub struct FunctionLabels {
+    /// Functions that always get their own label.
+    allow: HashSet<Uuid>,
+    /// Shared labels the others are hashed into, if any.
+    buckets: Option<u32>,
+    /// Most other functions given their own label.
+    max: Option<usize>,
+    /// Those given their own label under `max`, for the frontend's lifetime.
+    labelled: Mutex<HashSet<Uuid>>,
+    /// Functions that have been in a routing table.
+    known: RwLock<HashSet<Uuid>>,
+    overflowed: Counter<u64>,
+}
+
+impl FunctionLabels {
+    pub fn new(allow: Vec<Uuid>, buckets: Option<u32>, max: Option<usize>) -> Self {
+        Self {
+            allow: allow.into_iter().collect(),
+            buckets,
+            max,
+            labelled: Mutex::new(HashSet::new()),
+            known: RwLock::new(HashSet::new()),
+            overflowed: opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+                .u64_counter("metric_labels_overflowed")
+                .with_description(
+                    "Measurements labelled `other` for functions past --metric-max-functions",
+                )
+                .init(),
+        }
+    }
+
+    /// Note that the function exists, so it may get a label.
+    pub fn add_known(&self, function_id: &Uuid) {
+        if !self.known.read().unwrap().contains(function_id) {
+            self.known.write().unwrap().insert(*function_id);
+        }
+    }
+
+    fn is_known(&self, function_id: &Uuid) -> bool {
+        self.known.read().unwrap().contains(function_id)
+    }
+
+    /// The function's own label, if it gets one.
+    pub fn own(&self, function_id: &Uuid) -> Option<String> {
+        if self.allow.contains(function_id) {
+            return Some(function_id.to_string());
+        }
+        if self.buckets.is_some() || !self.is_known(function_id) {
+            return None;
+        }
+        let Some(max) = self.max else {
+            return Some(function_id.to_string());
+        };
+        let mut labelled = self.labelled.lock().unwrap();
+        if labelled.len() < max {
+            labelled.insert(*function_id);
+        }
+        labelled
+            .contains(function_id)
+            .then(|| function_id.to_string())
+    }
+
+    /// The label for the function's metrics.
+    pub fn label(&self, function_id: &Uuid) -> String {
+        if let Some(own) = self.own(function_id) {
+            return own;
+        }
+        if !self.is_known(function_id) {
+            return UNKNOWN.to_string();
+        }
+        match self.buckets {
+            // IDs are random, so they spread evenly.
+            Some(buckets) => format!("bucket-{}", function_id.as_u128() % u128::from(buckets)),
+            None => {
+                self.overflowed.add(1, &[]);
+                OTHER.to_string()
+            }
+        }
+    }
+}
+
+static LABELS: OnceLock<FunctionLabels> = OnceLock::new();
+
+/// Set how functions are labelled, process-wide like the meters themselves. Only the first call
+/// has any effect; until then, every function gets its own label.
+pub fn configure(labels: FunctionLabels) {
+    let _ = LABELS.set(labels);
+}
+
+/// Note that the function is in a routing table (or routable at all, with a dev backend).
+pub fn known(function_id: &Uuid) {
+    if let Some(labels) = LABELS.get() {
+        labels.add_known(function_id);
+    }
+}
+
+/// The `function` attribute for a measurement of the function.
+pub fn function(function_id: &Uuid) -> KeyValue {
+    let label = match LABELS.get() {
+        Some(labels) => labels.label(function_id),
+        None => function_id.to_string(),
+    };
+    KeyValue::new("function", label)
+}
+
+/// The `function` attribute for a gauge of the function, if it gets its own label.
+pub fn own_function(function_id: &Uuid) -> Option<KeyValue> {
+    let label = match LABELS.get() {
+        Some(labels) => labels.own(function_id)?,
+        None => function_id.to_string(),
+    };
+    Some(KeyValue::new("function", label))
+}
+
+/// `function` for a function ID as routed, which may not be one. Empty (on routes that aren't a
+/// function's) stays empty.
+pub fn function_str(function_id: &str) -> KeyValue {
+    match Uuid::parse_str(function_id) {
+        Ok(function_id) => function(&function_id),
+        Err(_) if function_id.is_empty() => KeyValue::new("function", ""),
+        Err(_) => KeyValue::new("function", INVALID),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowlist_and_buckets() {
+        let important = Uuid::new_v4();
+        let labels = FunctionLabels::new(vec![important], Some(16), None);
+        let other = Uuid::from_u128(0x1234_5678_9abc_def0_1234_5678_9abc_def0);
+        labels.add_known(&other);
+        labels.add_known(&Uuid::from_u128(17));
+        assert_eq!(labels.label(&important), important.to_string());
+        assert_eq!(labels.own(&important), Some(important.to_string()));
+
+        assert_eq!(labels.label(&other), "bucket-0");
+        // The same every time, and on every frontend.
+        assert_eq!(labels.label(&other), labels.label(&other));
+        assert_eq!(labels.label(&Uuid::from_u128(17)), "bucket-1");
+        assert_eq!(labels.own(&other), None);
+    }
+
+    #[test]
+    fn test_max() {
+        let important = Uuid::new_v4();
+        let labels = FunctionLabels::new(vec![important], None, Some(2));
+        let functions: Vec<_> = (0..3).map(|_| Uuid::new_v4()).collect();
+        // Made-up IDs don't use up the labels.
+        assert_eq!(labels.label(&Uuid::new_v4()), UNKNOWN);
+        assert_eq!(labels.label(&Uuid::new_v4()), UNKNOWN);
+        for function_id in &functions {
+            labels.add_known(function_id);
+        }
+        assert_eq!(labels.label(&functions[0]), functions[0].to_string());
+        assert_eq!(labels.label(&functions[1]), functions[1].to_string());
+        assert_eq!(labels.label(&functions[2]), OTHER);
+        // Those seen first keep theirs, and allowlisted ones don't count towards it.
+        assert_eq!(labels.label(&functions[0]), functions[0].to_string());
+        assert_eq!(labels.label(&important), important.to_string());
+        assert_eq!(labels.own(&functions[2]), None);
+
+        let unlimited = FunctionLabels::new(Vec::new(), None, None);
+        unlimited.add_known(&functions[2]);
+        assert_eq!(labels.label(&functions[2]), OTHER);
+        assert_eq!(unlimited.label(&functions[2]), functions[2].to_string());
+    }
+}
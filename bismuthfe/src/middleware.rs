@@ -0,0 +1,117 @@
+//! Hooks into the invocation path for code compiled into `bismuthfe`, so forks can add auth,
+//! header rewriting, auditing etc. without patching `invoke_function_path`.
+//!
+//! Implement `ProxyMiddleware` and register it from anywhere in the binary:
+//!
+//! ```ignore
+//! inventory::submit! {
+//!     bismuthfe::middleware::Registration {
+//!         name: "audit",
+//!         build: |_config| Ok(std::sync::Arc::new(Audit)),
+//!     }
+//! }
+//! ```
+//!
+//! then enable it with `--middleware audit`. Middlewares run in the order they're enabled, after
+//! the built-in WASM filters.
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use axum::http::{request, response};
+use hyper::Body;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use bismuth_common::{Backend, FunctionConfig};
+
+/// What's known about the invocation being proxied.
+#[derive(Debug)]
+pub struct Invocation<'a> {
+    pub function_id: Uuid,
+    /// The function whose backends serve the invocation: `function_id`, unless a traffic split
+    /// sent it to the canary.
+    pub backend_function: Uuid,
+    /// Path within the function, without the `/invoke/{id}/` prefix.
+    pub path: &'a str,
+    /// `None` if the function has no config znode.
+    pub config: Option<&'a FunctionConfig>,
+    pub client: SocketAddr,
+}
+
+#[async_trait]
+pub trait ProxyMiddleware: Send + Sync {
+    /// Before a backend is picked. Returning a response answers the request with it instead of
+    /// proxying; an error fails the request.
+    async fn on_request(
+        &self,
+        _invocation: &Invocation<'_>,
+        _req: &mut request::Parts,
+    ) -> Result<Option<axum::response::Response<Body>>> {
+        Ok(None)
+    }
+
+    /// After a backend is picked, just before the request is sent to it.
+    async fn on_backend_selected(
+        &self,
+        _invocation: &Invocation<'_>,
+        _backend: &Backend,
+        _req: &mut request::Parts,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// With the backend's response headers. Returning a response replaces the backend's.
+    async fn on_response(
+        &self,
+        _invocation: &Invocation<'_>,
+        _resp: &mut response::Parts,
+    ) -> Result<Option<axum::response::Response<Body>>> {
+        Ok(None)
+    }
+}
+
+/// A middleware that can be enabled by name with `--middleware`.
+pub struct Registration {
+    pub name: &'static str,
+    pub build: fn(&crate::Config) -> Result<Arc<dyn ProxyMiddleware>>,
+}
+
+inventory::collect!(Registration);
+
+/// Build the named middlewares, in order.
+pub fn build(names: &[String], config: &crate::Config) -> Result<Vec<Arc<dyn ProxyMiddleware>>> {
+    names
+        .iter()
+        .map(|name| {
+            let registration = inventory::iter::<Registration>
+                .into_iter()
+                .find(|r| r.name == name)
+                .ok_or_else(|| anyhow!("Unknown middleware {}", name))?;
+            (registration.build)(config)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Noop;
+
+    impl ProxyMiddleware for Noop {}
+
+    inventory::submit! {
+        Registration {
+            name: "test-noop",
+            build: |_| Ok(Arc::new(Noop)),
+        }
+    }
+
+    #[test]
+    fn test_build() {
+        let config = crate::Config::default();
+        assert_eq!(build(&["test-noop".to_string()], &config).unwrap().len(), 1);
+        assert!(build(&["missing".to_string()], &config).is_err());
+    }
+}
@@ -0,0 +1,60 @@
+//! The OpenAPI document served at `/openapi.json`, generated from the handlers' annotations so
+//! client SDKs and gateway configs don't have to be written by hand.
+
+use utoipa::openapi::path::PathItemType;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::OpenApi;
+
+const INVOKE_PATH: &str = "/invoke/{function_id}/{reqpath}";
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(crate::invoke_function_path, crate::rate_limit::quota_handler),
+    components(schemas(crate::rate_limit::Quota))
+)]
+struct InvokeApi;
+
+/// The whole frontend API: invocations and the admin API (which is only served with admin
+/// tokens or OIDC configured).
+pub fn document() -> utoipa::openapi::OpenApi {
+    let mut doc = InvokeApi::openapi();
+    doc.merge(crate::admin::ApiDoc::openapi());
+    doc.components
+        .get_or_insert_with(Default::default)
+        .add_security_scheme(
+            "admin_token",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+
+    // Invocations take any method, but an annotation only describes one.
+    if let Some(invoke) = doc.paths.paths.get_mut(INVOKE_PATH) {
+        if let Some(post) = invoke.operations.get(&PathItemType::Post).cloned() {
+            for (method, name) in [
+                (PathItemType::Get, "get"),
+                (PathItemType::Put, "put"),
+                (PathItemType::Patch, "patch"),
+                (PathItemType::Delete, "delete"),
+            ] {
+                let mut operation = post.clone();
+                operation.operation_id = Some(format!("invoke_function_{}", name));
+                invoke.operations.insert(method, operation);
+            }
+        }
+    }
+    doc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_document() {
+        let doc = serde_json::to_value(document()).unwrap();
+        let paths = doc["paths"].as_object().unwrap();
+        assert!(paths[INVOKE_PATH]["get"].is_object());
+        assert!(paths["/admin/function/{function_id}/config"]["put"].is_object());
+        assert!(doc["components"]["schemas"]["FunctionConfig"].is_object());
+        assert!(doc["components"]["securitySchemes"]["admin_token"].is_object());
+    }
+}
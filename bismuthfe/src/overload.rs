@@ -0,0 +1,213 @@
+//! Degraded mode, for when the frontend itself is short of CPU or memory (e.g. under attack)
+//! rather than its backends being overloaded. Its own usage is sampled every second, and above a
+//! watermark it sheds low priority invocations, stops buffering (and so compressing and tagging)
+//! responses, and halves `--max-connections`, until usage is back under `RECOVERY` of every
+//! watermark.
+
+use axum::extract::State;
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse as _, Response};
+use hyper::Body;
+use opentelemetry::metrics::Counter;
+use opentelemetry::KeyValue;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{event, Level};
+
+use bismuth_common::Priority;
+
+use crate::priority;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+/// Fraction of each watermark usage must be back under to leave degraded mode, so it doesn't
+/// flap around the watermark.
+const RECOVERY: f64 = 0.8;
+/// Units of `/proc/{pid}/stat` CPU times. Fixed on Linux, whatever the kernel's own tick rate.
+const USER_HZ: f64 = 100.0;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Watermarks {
+    /// Fraction of the CPUs available to the process, e.g. 0.9.
+    pub cpu: Option<f64>,
+    /// Resident memory, in bytes.
+    pub memory_bytes: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Usage {
+    cpu: f64,
+    memory_bytes: u64,
+}
+
+impl Watermarks {
+    /// Whether to be degraded given `usage`, having been `degraded` so far.
+    fn degraded(&self, degraded: bool, usage: Usage) -> bool {
+        let threshold = if degraded { RECOVERY } else { 1.0 };
+        self.cpu.is_some_and(|cpu| usage.cpu >= cpu * threshold)
+            || self
+                .memory_bytes
+                .is_some_and(|memory| usage.memory_bytes as f64 >= memory as f64 * threshold)
+    }
+}
+
+/// CPU time the process has used, from the contents of `/proc/self/stat`.
+fn parse_cpu_time(stat: &str) -> Option<Duration> {
+    // The command name can contain spaces and parentheses, so look after the last ')'. The
+    // fields after it start from the third, the state; utime and stime are the 14th and 15th.
+    let mut fields = stat
+        .get(stat.rfind(')')? + 1..)?
+        .split_whitespace()
+        .skip(11);
+    let utime: u64 = fields.next()?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    Some(Duration::from_secs_f64((utime + stime) as f64 / USER_HZ))
+}
+
+/// Resident memory, from the contents of `/proc/self/status`.
+fn parse_rss(status: &str) -> Option<u64> {
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+fn read_usage() -> std::io::Result<(Duration, u64)> {
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "unexpected format");
+    let cpu = parse_cpu_time(&std::fs::read_to_string("/proc/self/stat")?).ok_or_else(invalid)?;
+    let rss = parse_rss(&std::fs::read_to_string("/proc/self/status")?).ok_or_else(invalid)?;
+    Ok((cpu, rss))
+}
+
+pub struct HostWatermarks {
+    watermarks: Watermarks,
+    degraded: AtomicBool,
+    transitions: Counter<u64>,
+    shed: Counter<u64>,
+}
+
+impl HostWatermarks {
+    /// Starts sampling usage in the background, for as long as it's kept.
+    pub fn new(watermarks: Watermarks) -> Arc<Self> {
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+        let this = Arc::new(Self {
+            watermarks,
+            degraded: AtomicBool::new(false),
+            transitions: meter
+                .u64_counter("degraded_mode_transitions")
+                .with_description("Times the frontend entered or left degraded mode, by direction")
+                .init(),
+            shed: meter
+                .u64_counter("degraded_shed_requests")
+                .with_description("Low priority invocations rejected in degraded mode")
+                .init(),
+        });
+        let weak = Arc::downgrade(&this);
+        tokio::spawn(async move {
+            let cpus = std::thread::available_parallelism().map_or(1, |n| n.get()) as f64;
+            let mut last: Option<(Instant, Duration)> = None;
+            loop {
+                tokio::time::sleep(SAMPLE_INTERVAL).await;
+                let Some(this) = weak.upgrade() else {
+                    return;
+                };
+                let (cpu_time, memory_bytes) = match read_usage() {
+                    Ok(usage) => usage,
+                    Err(e) => {
+                        event!(Level::WARN, error = %e, "Error reading resource usage, not watching it");
+                        return;
+                    }
+                };
+                let now = Instant::now();
+                let Some((then, last_cpu_time)) = last.replace((now, cpu_time)) else {
+                    continue;
+                };
+                let usage = Usage {
+                    cpu: cpu_time.saturating_sub(last_cpu_time).as_secs_f64()
+                        / now.duration_since(then).as_secs_f64()
+                        / cpus,
+                    memory_bytes,
+                };
+                this.update(usage);
+            }
+        });
+        this
+    }
+
+    fn update(&self, usage: Usage) {
+        let degraded = self.is_degraded();
+        if self.watermarks.degraded(degraded, usage) == degraded {
+            return;
+        }
+        self.degraded.store(!degraded, Ordering::Relaxed);
+        let direction = if degraded { "left" } else { "entered" };
+        event!(
+            Level::WARN,
+            cpu = usage.cpu,
+            memory_bytes = usage.memory_bytes,
+            "Frontend {} degraded mode",
+            direction
+        );
+        self.transitions
+            .add(1, &[KeyValue::new("direction", direction)]);
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::Relaxed)
+    }
+}
+
+/// Route layer for the invocation routes, inside `priority::middleware`.
+pub async fn middleware(
+    State(host): State<Arc<HostWatermarks>>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    if host.is_degraded() && priority::of(&req) == Priority::Low {
+        host.shed.add(1, &[]);
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(axum::http::header::RETRY_AFTER, "1")],
+        )
+            .into_response();
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let stat = "4242 (bismuth fe) S 1 4242 4242 0 -1 4194560 8107 0 0 0 250 75 0 0 20 0 9 0";
+        assert_eq!(parse_cpu_time(stat), Some(Duration::from_millis(3250)));
+        assert_eq!(parse_cpu_time("4242 (bismuthfe"), None);
+
+        let status = "Name:\tbismuthfe\nVmPeak:\t  200000 kB\nVmRSS:\t   51200 kB\n";
+        assert_eq!(parse_rss(status), Some(50 * 1024 * 1024));
+        assert_eq!(parse_rss("Name:\tbismuthfe\n"), None);
+    }
+
+    #[test]
+    fn test_hysteresis() {
+        let watermarks = Watermarks {
+            cpu: Some(0.9),
+            memory_bytes: Some(1000),
+        };
+        let usage = |cpu, memory_bytes| Usage { cpu, memory_bytes };
+        assert!(!watermarks.degraded(false, usage(0.5, 500)));
+        assert!(watermarks.degraded(false, usage(0.95, 500)));
+        assert!(watermarks.degraded(false, usage(0.5, 1000)));
+        // Stays degraded until under 80% of both.
+        assert!(watermarks.degraded(true, usage(0.8, 500)));
+        assert!(watermarks.degraded(true, usage(0.5, 850)));
+        assert!(!watermarks.degraded(true, usage(0.5, 500)));
+
+        let none = Watermarks {
+            cpu: None,
+            memory_bytes: None,
+        };
+        assert!(!none.degraded(false, usage(1.0, u64::MAX)));
+    }
+}
@@ -0,0 +1,137 @@
+use anyhow::Result;
+use axum::http::{HeaderValue, Request, Response};
+use hyper::Body;
+use opentelemetry::metrics::Histogram;
+use opentelemetry::KeyValue;
+use rand::seq::SliceRandom as _;
+use std::time::Instant;
+use tracing::{event, Level};
+use uuid::Uuid;
+
+use crate::{tokens, upstream};
+
+/// Set on requests forwarded to a peer, so the peer never forwards them again.
+pub const FORWARDED_HEADER: &str = "x-bismuth-forwarded";
+
+/// Remote bismuthfe instances (e.g. in other regions) that requests are forwarded to
+/// when a function has no local backends.
+pub struct Peers {
+    peers: Vec<hyper::Uri>,
+    forward_duration: Histogram<f64>,
+}
+
+impl Peers {
+    pub fn new(peers: Vec<hyper::Uri>) -> Self {
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+        let forward_duration = meter
+            .f64_histogram("peer_forward_duration")
+            .with_description("Time taken by requests forwarded to a peer frontend")
+            .with_unit(opentelemetry::metrics::Unit::new("ms"))
+            .init();
+        Self {
+            peers,
+            forward_duration,
+        }
+    }
+
+    /// Whether `req` can be failed over: there are peers, and it wasn't already forwarded by one.
+    pub fn should_forward<B>(&self, req: &Request<B>) -> bool {
+        !self.peers.is_empty() && !req.headers().contains_key(FORWARDED_HEADER)
+    }
+
+    pub async fn forward(
+        &self,
+        http_client: &upstream::Client,
+        function_id: &Uuid,
+        reqpath: &str,
+        req: Request<Body>,
+    ) -> Result<Response<Body>> {
+        let peer = self
+            .peers
+            .choose(&mut rand::thread_rng())
+            .expect("forward called without peers");
+
+        let mut req = req;
+        let base = peer.to_string();
+        let base = base.trim_end_matches('/');
+        *req.uri_mut() = match req.uri().query() {
+            Some(query) => format!("{}/invoke/{}/{}?{}", base, function_id, reqpath, query),
+            None => format!("{}/invoke/{}/{}", base, function_id, reqpath),
+        }
+        .parse()?;
+        req.headers_mut()
+            .insert(FORWARDED_HEADER, HeaderValue::from_static("1"));
+        // Taken off for the backends, but a peer requiring tenant tokens needs it too.
+        if let Some(tokens::ForwardedToken(token)) = req.extensions_mut().remove() {
+            req.headers_mut().insert(tokens::TOKEN_HEADER, token);
+        }
+        // Let hyper fill in the peer's host.
+        req.headers_mut().remove(hyper::header::HOST);
+        crate::inject_trace_context(req.headers_mut());
+
+        event!(Level::DEBUG, function = %function_id, peer = %peer, "Forwarding request to peer");
+        let start = Instant::now();
+        let resp = http_client.request(req).await;
+        self.forward_duration.record(
+            start.elapsed().as_secs_f64() * 1000.0,
+            &[
+                KeyValue::new("peer", base.to_string()),
+                KeyValue::new(
+                    "http.response.status_code",
+                    resp.as_ref()
+                        .map(|r| r.status().as_u16().to_string())
+                        .unwrap_or_else(|_| "error".to_string()),
+                ),
+            ],
+        );
+        Ok(resp?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_forward_token() {
+        // A peer answering with the token it got.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = axum::Router::new().route(
+            "/invoke/:function_id/*path",
+            axum::routing::any(|headers: axum::http::HeaderMap| async move {
+                let token = headers.get(tokens::TOKEN_HEADER).cloned();
+                token
+                    .map(|t| t.to_str().unwrap().to_string())
+                    .unwrap_or_default()
+            }),
+        );
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(peer.into_make_service()),
+        );
+
+        let peers = Peers::new(vec![format!("http://{}", addr).parse().unwrap()]);
+        let client = hyper::Client::builder()
+            .build(upstream::Connector::new(hyper::client::HttpConnector::new()).unwrap());
+        let function_id = Uuid::new_v4();
+        let forward = |token: Option<&'static str>| {
+            let mut req = Request::get("/invoke/f/run").body(Body::empty()).unwrap();
+            assert!(peers.should_forward(&req));
+            if let Some(token) = token {
+                req.extensions_mut()
+                    .insert(tokens::ForwardedToken(HeaderValue::from_static(token)));
+            }
+            peers.forward(&client, &function_id, "run", req)
+        };
+        let body = |resp: Response<Body>| async move {
+            hyper::body::to_bytes(resp.into_body()).await.unwrap()
+        };
+
+        let resp = forward(Some("bt_secret")).await.unwrap();
+        assert_eq!(body(resp).await, "bt_secret");
+        let resp = forward(None).await.unwrap();
+        assert_eq!(body(resp).await, "");
+    }
+}
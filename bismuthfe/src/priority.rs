@@ -0,0 +1,635 @@
+//! Invocation priorities, and the queue that orders invocations by them once the frontend is at
+//! its `--max-in-flight`.
+//!
+//! An invocation's priority is its tenant token's, else its function's (`priority` in its config),
+//! else normal. Clients can lower it with `X-Bismuth-Priority: low`, but never raise it. Queued
+//! invocations get free slots by weighted fair queueing: each priority gets a share of them in
+//! proportion to its weight for as long as it has invocations waiting, so high priority ones go
+//! first without starving the rest.
+//!
+//! Within a priority, tenants (by their tokens; invocations without one count as one tenant) share
+//! slots the same way, in proportion to their `--tenant-weight` (default 1), so the busiest tenant
+//! can't take all of the frontend's capacity once it's saturated. When the queue is full, the
+//! invocation that would be admitted last of a lower priority's, or else of its own (if that's
+//! another tenant's), makes way for a new one.
+
+use axum::extract::State;
+use axum::http::{HeaderMap, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse as _, Response};
+use hyper::Body;
+use opentelemetry::metrics::Counter;
+use opentelemetry::KeyValue;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+use bismuth_common::Priority;
+
+use crate::{tokens, BackendMonitor};
+
+/// Clients mark traffic that can be dropped first under load with `X-Bismuth-Priority: low`.
+pub const PRIORITY_HEADER: &str = "x-bismuth-priority";
+
+/// Slots each priority gets, relative to the others, while they all have invocations queued.
+const WEIGHTS: [u64; 3] = [1, 4, 8];
+/// Divisible by every weight, so each priority's stride is a whole number.
+const STRIDE: u64 = 8;
+/// Tenants' strides are this over their weight, fine grained enough for any weight.
+const TENANT_STRIDE: u64 = 1 << 20;
+/// Heaviest tenant weight, over which a stride would round down to 0 and the tenant, never
+/// advancing, would starve the others.
+const MAX_TENANT_WEIGHT: u32 = TENANT_STRIDE as u32;
+
+/// A tenant token's priority, as a request extension.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TokenPriority(pub Priority);
+
+fn header_priority(headers: &HeaderMap) -> Option<Priority> {
+    headers
+        .get(PRIORITY_HEADER)
+        .and_then(|v| v.to_str().ok()?.parse().ok())
+}
+
+/// The priority granted by the token or function, lowered to the client's if that's lower.
+fn effective(
+    header: Option<Priority>,
+    token: Option<Priority>,
+    function: Option<Priority>,
+) -> Priority {
+    let granted = token.or(function).unwrap_or_default();
+    match header {
+        Some(header) => header.min(granted),
+        None => granted,
+    }
+}
+
+/// Route layer for the invocation routes, outside load shedding and the queue, adding each
+/// invocation's `Priority` as an extension.
+pub async fn middleware(
+    State(monitor): State<Option<Arc<BackendMonitor>>>,
+    mut req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let function = match (&monitor, tokens::function_id(req.uri().path())) {
+        (Some(monitor), Some(function_id)) => monitor
+            .config(&function_id)
+            .await
+            .and_then(|config| config.priority),
+        _ => None,
+    };
+    let token = req.extensions().get::<TokenPriority>().map(|p| p.0);
+    let priority = effective(header_priority(req.headers()), token, function);
+    req.extensions_mut().insert(priority);
+    next.run(req).await
+}
+
+/// The priority `middleware` gave an invocation.
+pub fn of<B>(req: &Request<B>) -> Priority {
+    req.extensions()
+        .get::<Priority>()
+        .copied()
+        .unwrap_or_default()
+}
+
+/// Parse `--tenant-weight` arguments, NAME=WEIGHT.
+pub fn parse_tenant_weight(s: &str) -> Result<(String, u32), String> {
+    let (tenant, weight) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected NAME=WEIGHT, got '{}'", s))?;
+    match weight.parse() {
+        Ok(weight @ 1..=MAX_TENANT_WEIGHT) => Ok((tenant.to_string(), weight)),
+        _ => Err(format!(
+            "Invalid weight in '{}', expected 1 to {}",
+            s, MAX_TENANT_WEIGHT
+        )),
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct QueueConfig {
+    pub max_in_flight: usize,
+    pub max_queued: usize,
+    pub max_wait: Duration,
+}
+
+struct Waiter {
+    id: u64,
+    admit: oneshot::Sender<()>,
+}
+
+/// A tenant's invocations queued at one priority, oldest first.
+struct TenantQueue {
+    waiting: VecDeque<Waiter>,
+    /// As for priorities, the tenant with the lowest pass goes next.
+    pass: u64,
+    stride: u64,
+}
+
+impl TenantQueue {
+    /// The pass the tenant will have when its newest invocation goes.
+    fn last_pass(&self) -> u64 {
+        self.pass + (self.waiting.len() as u64 - 1) * self.stride
+    }
+}
+
+/// Invocations queued at one priority, by tenant.
+#[derive(Default)]
+struct Class {
+    tenants: HashMap<String, TenantQueue>,
+    /// Like `Queues::now`, among these tenants.
+    now: u64,
+    len: usize,
+}
+
+impl Class {
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The pass `tenant` would have when a new invocation of its goes.
+    fn next_pass(&self, tenant: &str, stride: u64) -> u64 {
+        match self.tenants.get(tenant) {
+            Some(queue) => queue.last_pass() + queue.stride,
+            None => self.now + stride,
+        }
+    }
+
+    fn push(&mut self, tenant: &str, stride: u64, waiter: Waiter) {
+        let now = self.now;
+        self.tenants
+            .entry(tenant.to_string())
+            .or_insert_with(|| TenantQueue {
+                waiting: VecDeque::new(),
+                pass: now + stride,
+                stride,
+            })
+            .waiting
+            .push_back(waiter);
+        self.len += 1;
+    }
+
+    /// The next invocation to go.
+    fn pop(&mut self) -> Option<Waiter> {
+        // Ties go by name, to be deterministic.
+        let (tenant, queue) = self
+            .tenants
+            .iter_mut()
+            .min_by_key(|(tenant, queue)| (queue.pass, *tenant))?;
+        let waiter = queue.waiting.pop_front()?;
+        self.len -= 1;
+        self.now = queue.pass;
+        if queue.waiting.is_empty() {
+            let tenant = tenant.clone();
+            self.tenants.remove(&tenant);
+        } else {
+            queue.pass += queue.stride;
+        }
+        Some(waiter)
+    }
+
+    /// The tenant whose newest invocation would go last, and when.
+    fn furthest_behind(&self) -> Option<(&str, u64)> {
+        self.tenants
+            .iter()
+            .map(|(tenant, queue)| (tenant.as_str(), queue.last_pass()))
+            .max_by_key(|&(tenant, pass)| (pass, std::cmp::Reverse(tenant)))
+    }
+
+    /// Drop the invocation that would go last. Dropping its sender tells it so.
+    fn evict(&mut self) {
+        let Some((tenant, _)) = self.furthest_behind() else {
+            return;
+        };
+        let tenant = tenant.to_string();
+        let queue = self.tenants.get_mut(&tenant).unwrap();
+        queue.waiting.pop_back();
+        self.len -= 1;
+        if queue.waiting.is_empty() {
+            self.tenants.remove(&tenant);
+        }
+    }
+
+    /// Whether `tenant`'s invocation `id` was still queued.
+    fn remove(&mut self, tenant: &str, id: u64) -> bool {
+        let Some(queue) = self.tenants.get_mut(tenant) else {
+            return false;
+        };
+        let Some(i) = queue.waiting.iter().position(|w| w.id == id) else {
+            return false;
+        };
+        queue.waiting.remove(i);
+        self.len -= 1;
+        if queue.waiting.is_empty() {
+            self.tenants.remove(tenant);
+        }
+        true
+    }
+}
+
+struct Queues {
+    in_flight: usize,
+    /// By priority.
+    waiting: [Class; 3],
+    /// Stride scheduling: the priority with the lowest pass goes next, and its pass then grows
+    /// by `STRIDE / weight`.
+    pass: [u64; 3],
+    /// The pass of the last priority to go, which priorities start one stride on from once they
+    /// have invocations waiting again, so time spent idle isn't banked.
+    now: u64,
+    next_id: u64,
+}
+
+impl Queues {
+    fn queued(&self) -> usize {
+        self.waiting.iter().map(|class| class.len).sum()
+    }
+
+    /// Hand a finished invocation's slot to the next waiter, if there is one.
+    fn release(&mut self) {
+        loop {
+            // Ties go to the higher priority.
+            let Some(class) = (0..3)
+                .rev()
+                .filter(|&c| !self.waiting[c].is_empty())
+                .min_by_key(|&c| self.pass[c])
+            else {
+                self.in_flight -= 1;
+                return;
+            };
+            let waiter = self.waiting[class].pop().unwrap();
+            self.now = self.pass[class];
+            if !self.waiting[class].is_empty() {
+                self.pass[class] += STRIDE / WEIGHTS[class];
+            }
+            // Gone if it gave up waiting since.
+            if waiter.admit.send(()).is_ok() {
+                return;
+            }
+        }
+    }
+}
+
+/// Caps invocations in flight, queueing the rest by priority.
+pub struct InvocationQueue {
+    config: QueueConfig,
+    /// Tenants' strides, for those with a weight other than 1.
+    tenant_strides: HashMap<String, u64>,
+    queues: Mutex<Queues>,
+    queued: Counter<u64>,
+    shed: Counter<u64>,
+}
+
+/// An invocation's place among the `max_in_flight`, given up when dropped.
+pub struct Slot(Arc<InvocationQueue>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.queues.lock().unwrap().release();
+    }
+}
+
+/// Takes an invocation out of the queue if it stops waiting, passing on a slot it was given
+/// meanwhile.
+struct Waiting<'a> {
+    queue: &'a InvocationQueue,
+    class: usize,
+    tenant: &'a str,
+    id: u64,
+    admitted: oneshot::Receiver<()>,
+    done: bool,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let mut queues = self.queue.queues.lock().unwrap();
+        if !queues.waiting[self.class].remove(self.tenant, self.id)
+            && self.admitted.try_recv().is_ok()
+        {
+            queues.release();
+        }
+    }
+}
+
+impl InvocationQueue {
+    pub fn new(config: QueueConfig, tenant_weights: &[(String, u32)]) -> Self {
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+        Self {
+            config,
+            tenant_strides: tenant_weights
+                .iter()
+                // Weights over the max (only settable without the CLI) are treated as the max.
+                .map(|(tenant, weight)| {
+                    let stride = TENANT_STRIDE / u64::from(*weight);
+                    (tenant.clone(), stride.max(1))
+                })
+                .collect(),
+            queues: Mutex::new(Queues {
+                in_flight: 0,
+                waiting: Default::default(),
+                pass: [0; 3],
+                now: 0,
+                next_id: 0,
+            }),
+            queued: meter
+                .u64_counter("queued_invocations")
+                .with_description("Invocations queued for a slot, by priority")
+                .init(),
+            shed: meter
+                .u64_counter("queue_shed_invocations")
+                .with_description(
+                    "Invocations rejected by the queue, by priority and reason (full, evicted or timeout)",
+                )
+                .init(),
+        }
+    }
+
+    fn shed(&self, priority: Priority, reason: &'static str) {
+        self.shed.add(
+            1,
+            &[
+                KeyValue::new("priority", priority.as_str()),
+                KeyValue::new("reason", reason),
+            ],
+        );
+    }
+
+    fn tenant_stride(&self, tenant: &str) -> u64 {
+        self.tenant_strides
+            .get(tenant)
+            .copied()
+            .unwrap_or(TENANT_STRIDE)
+    }
+
+    /// A slot, once one is free. `None` if the queue is full of invocations that would go before
+    /// this one, if another takes this one's place, or after `max_wait`.
+    pub async fn admit(self: &Arc<Self>, priority: Priority, tenant: &str) -> Option<Slot> {
+        let class = priority as usize;
+        let stride = self.tenant_stride(tenant);
+        let (id, admitted) = {
+            let mut queues = self.queues.lock().unwrap();
+            if queues.in_flight < self.config.max_in_flight {
+                queues.in_flight += 1;
+                return Some(Slot(self.clone()));
+            }
+            if queues.queued() >= self.config.max_queued {
+                // Make room by shedding the last to go of the lowest priority below this one, or
+                // else of this one's, if that would go after this one and is another tenant's.
+                let own = &queues.waiting[class];
+                let victim = (0..class)
+                    .find(|&c| !queues.waiting[c].is_empty())
+                    .or_else(|| {
+                        let (last, pass) = own.furthest_behind()?;
+                        (last != tenant && pass > own.next_pass(tenant, stride)).then_some(class)
+                    });
+                let Some(victim) = victim else {
+                    drop(queues);
+                    self.shed(priority, "full");
+                    return None;
+                };
+                queues.waiting[victim].evict();
+            }
+            if queues.waiting[class].is_empty() {
+                queues.pass[class] = queues.pass[class].max(queues.now) + STRIDE / WEIGHTS[class];
+            }
+            let id = queues.next_id;
+            queues.next_id += 1;
+            let (admit, admitted) = oneshot::channel();
+            queues.waiting[class].push(tenant, stride, Waiter { id, admit });
+            (id, admitted)
+        };
+        self.queued
+            .add(1, &[KeyValue::new("priority", priority.as_str())]);
+
+        let mut waiting = Waiting {
+            queue: self,
+            class,
+            tenant,
+            id,
+            admitted,
+            done: false,
+        };
+        let admitted = tokio::time::timeout(self.config.max_wait, &mut waiting.admitted).await;
+        match admitted {
+            Ok(Ok(())) => {
+                waiting.done = true;
+                Some(Slot(self.clone()))
+            }
+            Ok(Err(_)) => {
+                waiting.done = true;
+                self.shed(priority, "evicted");
+                None
+            }
+            Err(_) => {
+                drop(waiting);
+                self.shed(priority, "timeout");
+                None
+            }
+        }
+    }
+}
+
+/// Route layer for the invocation routes, inside load shedding. Like shedding, only covers the
+/// time until the response headers are ready.
+pub async fn queue_middleware(
+    State(queue): State<Arc<InvocationQueue>>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let tenant = req
+        .extensions()
+        .get::<tokens::Tenant>()
+        .map_or("", |tenant| tenant.0.as_str());
+    let Some(_slot) = queue.admit(of(&req), tenant).await else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(axum::http::header::RETRY_AFTER, "1")],
+        )
+            .into_response();
+    };
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_effective() {
+        use Priority::*;
+        assert_eq!(effective(None, None, None), Normal);
+        assert_eq!(effective(None, None, Some(High)), High);
+        assert_eq!(effective(None, Some(Low), Some(High)), Low);
+        assert_eq!(effective(Some(Low), Some(High), None), Low);
+        // Clients can't raise their own priority.
+        assert_eq!(effective(Some(High), None, None), Normal);
+
+        assert_eq!(parse_tenant_weight("acme=3"), Ok(("acme".to_string(), 3)));
+        assert!(parse_tenant_weight("acme=0").is_err());
+        assert_eq!(
+            parse_tenant_weight("acme=1048576"),
+            Ok(("acme".to_string(), MAX_TENANT_WEIGHT))
+        );
+        assert!(parse_tenant_weight("acme=1048577").is_err());
+        assert!(parse_tenant_weight("acme").is_err());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(PRIORITY_HEADER, "low".parse().unwrap());
+        assert_eq!(header_priority(&headers), Some(Low));
+        headers.insert(PRIORITY_HEADER, "urgent".parse().unwrap());
+        assert_eq!(header_priority(&headers), None);
+    }
+
+    fn queue(max_queued: usize) -> Arc<InvocationQueue> {
+        Arc::new(InvocationQueue::new(
+            QueueConfig {
+                max_in_flight: 1,
+                max_queued,
+                max_wait: Duration::from_secs(10),
+            },
+            &[("big".to_string(), 3)],
+        ))
+    }
+
+    async fn wait_queued(queue: &InvocationQueue, n: usize) {
+        while queue.queues.lock().unwrap().queued() < n {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_weighted_order() {
+        let queue = queue(100);
+        let first = queue.admit(Priority::Normal, "").await.unwrap();
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut tasks = Vec::new();
+        // Two of each, queued lowest first.
+        for (n, priority) in [Priority::Low, Priority::Normal, Priority::High]
+            .into_iter()
+            .flat_map(|p| [p, p])
+            .enumerate()
+        {
+            let (queue_, order) = (queue.clone(), order.clone());
+            tasks.push(tokio::spawn(async move {
+                let _slot = queue_.admit(priority, "").await.unwrap();
+                order.lock().unwrap().push(priority);
+            }));
+            wait_queued(&queue, n + 1).await;
+        }
+
+        drop(first);
+        for task in tasks {
+            task.await.unwrap();
+        }
+        use Priority::*;
+        assert_eq!(
+            *order.lock().unwrap(),
+            [High, High, Normal, Normal, Low, Low]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_sheds_lowest() {
+        let queue = queue(1);
+        let first = queue.admit(Priority::Normal, "").await.unwrap();
+
+        let queue_ = queue.clone();
+        let low = tokio::spawn(async move { queue_.admit(Priority::Low, "").await.is_some() });
+        wait_queued(&queue, 1).await;
+        // Full of higher priority invocations: shed straight away.
+        assert!(queue.admit(Priority::Low, "").await.is_none());
+
+        let queue_ = queue.clone();
+        let normal =
+            tokio::spawn(async move { queue_.admit(Priority::Normal, "").await.is_some() });
+        assert!(!low.await.unwrap());
+        wait_queued(&queue, 1).await;
+        drop(first);
+        assert!(normal.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_tenant_shares() {
+        let queue = queue(100);
+        let first = queue.admit(Priority::Normal, "").await.unwrap();
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut tasks = Vec::new();
+        // All of the heavier tenant's first.
+        for (n, tenant) in ["big"; 6].into_iter().chain(["small"; 2]).enumerate() {
+            let (queue_, order) = (queue.clone(), order.clone());
+            tasks.push(tokio::spawn(async move {
+                let _slot = queue_.admit(Priority::Normal, tenant).await.unwrap();
+                order.lock().unwrap().push(tenant);
+            }));
+            wait_queued(&queue, n + 1).await;
+        }
+
+        drop(first);
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(
+            *order.lock().unwrap(),
+            ["big", "big", "big", "small", "big", "big", "big", "small"]
+        );
+    }
+
+    #[test]
+    fn test_huge_tenant_weight() {
+        let queue = InvocationQueue::new(
+            QueueConfig {
+                max_in_flight: 1,
+                max_queued: 100,
+                max_wait: Duration::from_secs(10),
+            },
+            &[("huge".to_string(), u32::MAX)],
+        );
+        let stride = queue.tenant_stride("huge");
+        assert_eq!(stride, 1);
+
+        // Its pass still advances as its invocations go, so it can't keep going first forever.
+        let mut class = Class::default();
+        for id in 0..2 {
+            let (admit, _) = oneshot::channel();
+            class.push("huge", stride, Waiter { id, admit });
+        }
+        let (admit, _) = oneshot::channel();
+        class.push("small", TENANT_STRIDE, Waiter { id: 2, admit });
+        class.pop().unwrap();
+        assert!(class.next_pass("huge", stride) > class.now);
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_sheds_busiest_tenant() {
+        let queue = queue(2);
+        let first = queue.admit(Priority::Normal, "").await.unwrap();
+
+        // Invocations without a tenant token.
+        let mut anonymous = Vec::new();
+        for n in 0..2 {
+            let queue_ = queue.clone();
+            anonymous.push(tokio::spawn(async move {
+                queue_.admit(Priority::Normal, "").await.is_some()
+            }));
+            wait_queued(&queue, n + 1).await;
+        }
+        // Would go before the newest of the other tenant's, which makes way for it.
+        let queue_ = queue.clone();
+        let small =
+            tokio::spawn(async move { queue_.admit(Priority::Normal, "small").await.is_some() });
+        assert!(!anonymous.pop().unwrap().await.unwrap());
+        // Another would go last: shed straight away.
+        wait_queued(&queue, 2).await;
+        assert!(queue.admit(Priority::Normal, "small").await.is_none());
+
+        drop(first);
+        assert!(anonymous.pop().unwrap().await.unwrap());
+        assert!(small.await.unwrap());
+    }
+}
@@ -0,0 +1,364 @@
+//! Per-function rate limits (`RateLimit` in the function's config), counted by each frontend for
+//! its own invocations in fixed windows per key: the client's IP, or a header such as an API key.
+//!
+//! Every response for a limited function carries the IETF draft `RateLimit-*` headers, and
+//! `/quota/{function_id}` reports the caller's quota without using it, so clients can pace
+//! themselves instead of finding the limit with 429s.
+
+use axum::extract::{ConnectInfo, Path, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use opentelemetry::metrics::Counter;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use bismuth_common::{ApiError, RateLimit};
+
+use crate::routing::Mode;
+use crate::{metric_labels, FrontendState};
+
+/// Past this many keys, ones whose windows have ended are pruned, at most every
+/// `PRUNE_INTERVAL`, and new keys share their function's `OVERFLOW_KEY` window until there's
+/// room.
+const MAX_KEYS: usize = 100_000;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+const OVERFLOW_KEY: &str = "overflow";
+
+/// A key's standing in its current window.
+#[derive(Clone, Debug, PartialEq, Serialize, utoipa::ToSchema)]
+pub struct Quota {
+    pub limit: u64,
+    pub remaining: u64,
+    /// Seconds until the window ends and `remaining` is back to `limit`.
+    pub reset_secs: u64,
+    pub window_secs: u64,
+}
+
+impl Quota {
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        headers.insert("ratelimit-limit", HeaderValue::from(self.limit));
+        headers.insert("ratelimit-remaining", HeaderValue::from(self.remaining));
+        headers.insert("ratelimit-reset", HeaderValue::from(self.reset_secs));
+        if let Ok(policy) = HeaderValue::from_str(&format!("{};w={}", self.limit, self.window_secs))
+        {
+            headers.insert("ratelimit-policy", policy);
+        }
+    }
+
+    /// The 429 for an invocation over the limit.
+    pub fn exceeded(&self) -> axum::response::Response<hyper::Body> {
+        let mut resp = axum::response::Response::new(hyper::Body::from("Rate limit exceeded\n"));
+        *resp.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+        self.apply(resp.headers_mut());
+        resp.headers_mut().insert(
+            axum::http::header::RETRY_AFTER,
+            HeaderValue::from(self.reset_secs),
+        );
+        resp
+    }
+}
+
+/// What invocations are counted by: the `key_header` if the function sets one and the request
+/// has it, otherwise the client's IP.
+pub fn key(rate_limit: &RateLimit, headers: &HeaderMap, client: IpAddr) -> String {
+    header_key(rate_limit, headers).unwrap_or_else(|| format!("ip:{}", client))
+}
+
+/// The key from the `key_header`, if the function sets one and the request has it.
+pub fn header_key(rate_limit: &RateLimit, headers: &HeaderMap) -> Option<String> {
+    rate_limit
+        .key_header
+        .as_deref()
+        .and_then(|name| headers.get(name))
+        .and_then(|v| v.to_str().ok())
+        .map(|v| format!("h:{}", v))
+}
+
+#[derive(Clone, Copy)]
+struct Window {
+    started: Instant,
+    len: Duration,
+    count: u64,
+}
+
+struct Windows {
+    windows: HashMap<(Uuid, String), Window>,
+    pruned_at: Option<Instant>,
+}
+
+pub struct RateLimiter {
+    windows: Mutex<Windows>,
+    limited: Counter<u64>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self {
+            windows: Mutex::new(Windows {
+                windows: HashMap::new(),
+                pruned_at: None,
+            }),
+            limited: opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+                .u64_counter("rate_limited_requests")
+                .with_description("Invocations rejected for exceeding their function's rate limit")
+                .init(),
+        }
+    }
+
+    /// Count an invocation against `key`'s quota, returning the quota left after it, or the
+    /// (exhausted) quota if it's over the limit and mustn't go ahead. Dry runs count nothing.
+    pub fn check(
+        &self,
+        function_id: Uuid,
+        rate_limit: &RateLimit,
+        key: String,
+        now: Instant,
+        mode: Mode,
+    ) -> Result<Quota, Quota> {
+        let window_len = Duration::from_secs(rate_limit.window_secs.max(1));
+        let mut windows = self.windows.lock().unwrap();
+        let mut key = (function_id, key);
+        if windows.windows.len() >= MAX_KEYS && !windows.windows.contains_key(&key) {
+            // Keys are the client's to choose, so not on every request.
+            if mode == Mode::Live
+                && !matches!(windows.pruned_at, Some(at) if now.duration_since(at) < PRUNE_INTERVAL)
+            {
+                windows
+                    .windows
+                    .retain(|_, w| now.duration_since(w.started) < w.len);
+                windows.pruned_at = Some(now);
+            }
+            if windows.windows.len() >= MAX_KEYS {
+                key.1 = OVERFLOW_KEY.to_string();
+            }
+        }
+        let mut window = match windows.windows.get(&key) {
+            Some(window) if now.duration_since(window.started) < window_len => *window,
+            _ => Window {
+                started: now,
+                len: window_len,
+                count: 0,
+            },
+        };
+        if window.count >= rate_limit.requests {
+            if mode == Mode::Live {
+                self.limited
+                    .add(1, &[metric_labels::function(&function_id)]);
+            }
+            return Err(quota(rate_limit, &window, now));
+        }
+        window.count += 1;
+        if mode == Mode::Live {
+            windows.windows.insert(key, window);
+        }
+        Ok(quota(rate_limit, &window, now))
+    }
+
+    /// `key`'s quota, without counting anything against it.
+    pub fn peek(
+        &self,
+        function_id: Uuid,
+        rate_limit: &RateLimit,
+        key: String,
+        now: Instant,
+    ) -> Quota {
+        let windows = self.windows.lock().unwrap();
+        match windows.windows.get(&(function_id, key)) {
+            Some(window)
+                if now.duration_since(window.started)
+                    < Duration::from_secs(rate_limit.window_secs.max(1)) =>
+            {
+                quota(rate_limit, window, now)
+            }
+            _ => Quota {
+                limit: rate_limit.requests,
+                remaining: rate_limit.requests,
+                reset_secs: rate_limit.window_secs,
+                window_secs: rate_limit.window_secs,
+            },
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn quota(rate_limit: &RateLimit, window: &Window, now: Instant) -> Quota {
+    let left = Duration::from_secs(rate_limit.window_secs.max(1))
+        .saturating_sub(now.duration_since(window.started));
+    Quota {
+        limit: rate_limit.requests,
+        remaining: rate_limit.requests.saturating_sub(window.count),
+        // Rounded up, so a client waiting this long finds the window reset.
+        reset_secs: left.as_secs() + u64::from(left.subsec_nanos() > 0),
+        window_secs: rate_limit.window_secs,
+    }
+}
+
+/// The caller's quota for invoking a function, as this frontend counts it.
+#[utoipa::path(
+    get,
+    path = "/quota/{function_id}",
+    tag = "invoke",
+    params(("function_id" = Uuid, Path, description = "Function ID")),
+    responses(
+        (status = 200, description = "The caller's quota", body = Quota),
+        (status = 404, description = "No such function, or it isn't rate limited"),
+    )
+)]
+pub async fn quota_handler(
+    State(state): State<Arc<FrontendState>>,
+    Path(function_id): Path<Uuid>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<axum::Json<Quota>, ApiError> {
+    let monitor = state.monitor.as_ref().ok_or(ApiError::NotFound)?;
+    let rate_limit = monitor
+        .config(&function_id)
+        .await
+        .and_then(|c| c.rate_limit)
+        .ok_or(ApiError::NotFound)?;
+    let key = key(&rate_limit, &headers, addr.ip());
+    Ok(axum::Json(state.rate_limiter.peek(
+        function_id,
+        &rate_limit,
+        key,
+        Instant::now(),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate_limit() -> RateLimit {
+        RateLimit {
+            requests: 2,
+            window_secs: 10,
+            key_header: Some("x-api-key".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_key() {
+        let client: IpAddr = [10, 0, 0, 1].into();
+        let mut headers = HeaderMap::new();
+        assert_eq!(key(&rate_limit(), &headers, client), "ip:10.0.0.1");
+        headers.insert("x-api-key", "abc".parse().unwrap());
+        assert_eq!(key(&rate_limit(), &headers, client), "h:abc");
+    }
+
+    #[test]
+    fn test_check() {
+        let limiter = RateLimiter::new();
+        let function_id = Uuid::new_v4();
+        let rate_limit = rate_limit();
+        let start = Instant::now();
+        let check = |key: &str, ms| {
+            limiter.check(
+                function_id,
+                &rate_limit,
+                key.to_string(),
+                start + Duration::from_millis(ms),
+                Mode::Live,
+            )
+        };
+
+        assert_eq!(check("a", 0).unwrap().remaining, 1);
+        let quota = check("a", 1500).unwrap();
+        assert_eq!(quota.remaining, 0);
+        assert_eq!(quota.reset_secs, 9);
+        let quota = check("a", 2000).unwrap_err();
+        assert_eq!(quota.remaining, 0);
+        assert_eq!(quota.reset_secs, 8);
+        // Other keys have their own quota.
+        assert!(check("b", 2000).is_ok());
+        // Dry runs decide the same without counting.
+        let dry_run = |ms| {
+            limiter.check(
+                function_id,
+                &rate_limit,
+                "b".to_string(),
+                start + Duration::from_millis(ms),
+                Mode::DryRun,
+            )
+        };
+        assert_eq!(dry_run(2500).unwrap().remaining, 0);
+        assert_eq!(dry_run(2500), check("b", 2500));
+        assert_eq!(dry_run(2600), check("b", 2600));
+        assert!(dry_run(2700).is_err());
+
+        assert_eq!(
+            limiter.peek(
+                function_id,
+                &rate_limit,
+                "a".to_string(),
+                start + Duration::from_secs(5)
+            ),
+            Quota {
+                limit: 2,
+                remaining: 0,
+                reset_secs: 5,
+                window_secs: 10,
+            }
+        );
+        // The window has ended.
+        assert_eq!(check("a", 10_000).unwrap().remaining, 1);
+    }
+
+    #[test]
+    fn test_max_keys() {
+        let limiter = RateLimiter::new();
+        let function_id = Uuid::new_v4();
+        let rate_limit = rate_limit();
+        let start = Instant::now();
+        let check = |key: &str, ms| {
+            limiter.check(
+                function_id,
+                &rate_limit,
+                key.to_string(),
+                start + Duration::from_millis(ms),
+                Mode::Live,
+            )
+        };
+        for i in 0..MAX_KEYS {
+            check(&i.to_string(), 0).unwrap();
+        }
+
+        // New keys share one window while the others' are live, and don't grow the map.
+        assert_eq!(check("new-1", 500).unwrap().remaining, 1);
+        assert_eq!(check("new-2", 600).unwrap().remaining, 0);
+        assert!(check("new-3", 700).is_err());
+        assert_eq!(limiter.windows.lock().unwrap().windows.len(), MAX_KEYS + 1);
+        // Ones already counted keep theirs.
+        assert_eq!(check("0", 800).unwrap().remaining, 0);
+
+        // Once those windows end, they're pruned for new keys.
+        assert_eq!(check("new-4", 10_500).unwrap().remaining, 1);
+        assert_eq!(check("new-5", 10_500).unwrap().remaining, 1);
+        assert!(limiter.windows.lock().unwrap().windows.len() < 10);
+    }
+
+    #[test]
+    fn test_headers() {
+        let quota = Quota {
+            limit: 100,
+            remaining: 0,
+            reset_secs: 30,
+            window_secs: 60,
+        };
+        let resp = quota.exceeded();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers()["ratelimit-limit"], "100");
+        assert_eq!(resp.headers()["ratelimit-remaining"], "0");
+        assert_eq!(resp.headers()["ratelimit-reset"], "30");
+        assert_eq!(resp.headers()["ratelimit-policy"], "100;w=60");
+        assert_eq!(resp.headers()["retry-after"], "30");
+    }
+}
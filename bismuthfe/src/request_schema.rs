@@ -0,0 +1,294 @@
+//! Request validation for functions with `request_schemas` in their config: the body of an
+//! invocation one applies to is read in full (up to the function's `max_upload_bytes`, or
+//! `MAX_VALIDATED_BYTES`) and checked against the schema before a backend is picked, and passed on
+//! if it's valid. Otherwise the invocation gets a 422 listing what's wrong, so garbage never
+//! reaches (or cold-starts) a backend.
+//!
+//! Schemas are compiled when a function's are first used or change, and cached until then.
+//! Remote `$ref`s aren't resolved. Schemas that don't compile are rejected by the admin API, and
+//! skipped (with an error logged) if written to ZooKeeper directly.
+
+use axum::http::{Request, StatusCode};
+use axum::response::IntoResponse as _;
+use axum::Json;
+use hyper::Body;
+use jsonschema::JSONSchema;
+use opentelemetry::metrics::Counter;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tracing::{event, Level};
+use uuid::Uuid;
+
+use bismuth_common::{ApiError, RequestSchema};
+
+use crate::{buffers, decompress, metric_labels, upload};
+
+/// Ceiling on bodies read to validate, for functions without their own `max_upload_bytes`.
+pub const MAX_VALIDATED_BYTES: u64 = 10 * 1024 * 1024;
+/// Most errors listed in a 422.
+const MAX_ERRORS: usize = 20;
+
+/// Compile `schema`.
+pub fn compile(schema: &serde_json::Value) -> Result<JSONSchema, String> {
+    JSONSchema::compile(schema).map_err(|e| e.to_string())
+}
+
+/// Whether `pattern` matches `path`, both relative to the function.
+fn path_matches(pattern: &str, path: &str) -> bool {
+    let mut pattern = pattern
+        .trim_matches('/')
+        .split('/')
+        .filter(|s| !s.is_empty());
+    let mut path = path.trim_matches('/').split('/').filter(|s| !s.is_empty());
+    loop {
+        match (pattern.next(), path.next()) {
+            (Some("**"), _) => return true,
+            (Some("*"), Some(_)) => {}
+            (Some(segment), Some(actual)) if segment == actual => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+fn applies(schema: &RequestSchema, method: &str, path: &str) -> bool {
+    (schema.methods.is_empty()
+        || schema
+            .methods
+            .iter()
+            .any(|m| m.eq_ignore_ascii_case(method)))
+        && schema
+            .path
+            .as_deref()
+            .map_or(true, |p| path_matches(p, path))
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SchemaError {
+    /// JSON Pointer to the offending part of the body.
+    pub path: String,
+    pub message: String,
+}
+
+/// What's wrong with `body`, if anything.
+fn check(schema: &JSONSchema, body: &[u8]) -> Result<(), Vec<SchemaError>> {
+    let instance: serde_json::Value = serde_json::from_slice(body).map_err(|e| {
+        vec![SchemaError {
+            path: String::new(),
+            message: format!("Body isn't valid JSON: {}", e),
+        }]
+    })?;
+    schema.validate(&instance).map_err(|errors| {
+        errors
+            .take(MAX_ERRORS)
+            .map(|e| SchemaError {
+                path: e.instance_path.to_string(),
+                message: e.to_string(),
+            })
+            .collect()
+    })
+}
+
+struct Compiled {
+    source: Vec<RequestSchema>,
+    /// In the same order; `None` for those that don't compile.
+    schemas: Vec<Option<Arc<JSONSchema>>>,
+}
+
+pub struct Validator {
+    /// By function.
+    compiled: Mutex<HashMap<Uuid, Compiled>>,
+    rejected: Counter<u64>,
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Self {
+            compiled: Mutex::new(HashMap::new()),
+            rejected: opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+                .u64_counter("invocations_invalid")
+                .with_description(
+                    "Invocations rejected for not matching their function's request schema",
+                )
+                .init(),
+        }
+    }
+
+    /// The compiled schema for an invocation of `function_id` with `method` on `path`, if one
+    /// applies to it.
+    fn schema(
+        &self,
+        function_id: &Uuid,
+        schemas: &[RequestSchema],
+        method: &str,
+        path: &str,
+    ) -> Option<Arc<JSONSchema>> {
+        let index = schemas.iter().position(|s| applies(s, method, path))?;
+        let mut compiled = self.compiled.lock().unwrap();
+        let current = compiled
+            .get(function_id)
+            .is_some_and(|c| c.source == schemas);
+        if !current {
+            let compiled_schemas = schemas
+                .iter()
+                .enumerate()
+                .map(|(i, s)| match compile(&s.schema) {
+                    Ok(schema) => Some(Arc::new(schema)),
+                    Err(e) => {
+                        event!(Level::ERROR, function = %function_id, index = i, error = e, "Invalid request schema, not validating against it");
+                        None
+                    }
+                })
+                .collect();
+            compiled.insert(
+                *function_id,
+                Compiled {
+                    source: schemas.to_vec(),
+                    schemas: compiled_schemas,
+                },
+            );
+        }
+        compiled[function_id].schemas[index].clone()
+    }
+
+    /// Check `req`'s body against the function's schema that applies to it, if any, putting it
+    /// back once read. `max` is the function's upload ceiling, if it has one.
+    pub async fn validate(
+        &self,
+        function_id: &Uuid,
+        schemas: &[RequestSchema],
+        path: &str,
+        max: Option<u64>,
+        buffers: &buffers::BufferPool,
+        req: &mut Request<Body>,
+    ) -> Result<(), ApiError> {
+        if schemas.is_empty() {
+            return Ok(());
+        }
+        let Some(schema) = self.schema(function_id, schemas, req.method().as_str(), path) else {
+            return Ok(());
+        };
+        if max.is_none() {
+            upload::limit(req, MAX_VALIDATED_BYTES).map_err(ApiError::Status)?;
+        }
+        let body = match buffers.collect(std::mem::take(req.body_mut())).await {
+            Ok(body) => body,
+            Err(_) => {
+                let extensions = req.extensions();
+                let status = if extensions
+                    .get::<upload::Exceeded>()
+                    .is_some_and(|e| e.get())
+                {
+                    StatusCode::PAYLOAD_TOO_LARGE
+                } else {
+                    extensions
+                        .get::<decompress::Failed>()
+                        .and_then(|f| f.get())
+                        .unwrap_or(StatusCode::BAD_REQUEST)
+                };
+                return Err(ApiError::Status(status));
+            }
+        };
+        if let Err(errors) = check(&schema, &body) {
+            self.rejected
+                .add(1, &[metric_labels::function(function_id)]);
+            return Err(ApiError::Response(
+                (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Json(serde_json::json!({
+                        "error": "Request body doesn't match the function's schema",
+                        "errors": errors,
+                    })),
+                )
+                    .into_response(),
+            ));
+        }
+        *req.body_mut() = Body::from(body);
+        Ok(())
+    }
+}
+
+impl Default for Validator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema(methods: &[&str], path: Option<&str>) -> RequestSchema {
+        RequestSchema {
+            methods: methods.iter().map(|m| m.to_string()).collect(),
+            path: path.map(str::to_string),
+            schema: serde_json::json!({
+                "type": "object",
+                "required": ["id"],
+                "properties": {"id": {"type": "integer"}},
+            }),
+        }
+    }
+
+    #[test]
+    fn test_applies() {
+        assert!(path_matches("/orders/*", "orders/12"));
+        assert!(!path_matches("/orders/*", "orders/12/items"));
+        assert!(path_matches("/orders/**", "orders/12/items"));
+        assert!(path_matches("/orders/**", "orders"));
+        assert!(path_matches("/", ""));
+        assert!(!path_matches("/orders", "users"));
+
+        assert!(applies(&schema(&[], None), "GET", "anything"));
+        assert!(applies(&schema(&["post"], None), "POST", "x"));
+        assert!(!applies(&schema(&["POST"], None), "GET", "x"));
+        assert!(!applies(&schema(&["POST"], Some("/a")), "POST", "b"));
+    }
+
+    #[test]
+    fn test_check() {
+        let compiled = compile(&schema(&[], None).schema).unwrap();
+        assert_eq!(check(&compiled, br#"{"id": 1}"#), Ok(()));
+        let errors = check(&compiled, br#"{"id": "one"}"#).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path, "/id");
+        let errors = check(&compiled, b"not json").unwrap_err();
+        assert!(errors[0].message.starts_with("Body isn't valid JSON"));
+        assert!(compile(&serde_json::json!({"type": 12})).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_validate() {
+        let validator = Validator::new();
+        let buffers = buffers::BufferPool::new().unwrap();
+        let function_id = Uuid::new_v4();
+        let schemas = vec![schema(&["POST"], Some("/orders"))];
+
+        let mut req = Request::post("/").body(Body::from(r#"{"id": 7}"#)).unwrap();
+        validator
+            .validate(&function_id, &schemas, "orders", None, &buffers, &mut req)
+            .await
+            .unwrap();
+        // Passed on as it was.
+        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+        assert_eq!(body, r#"{"id": 7}"#);
+
+        let mut req = Request::post("/").body(Body::from("{}")).unwrap();
+        let err = validator
+            .validate(&function_id, &schemas, "orders", None, &buffers, &mut req)
+            .await
+            .unwrap_err();
+        let ApiError::Response(resp) = err else {
+            panic!("expected a response");
+        };
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        // Not one the schema applies to.
+        let mut req = Request::post("/").body(Body::from("{}")).unwrap();
+        validator
+            .validate(&function_id, &schemas, "users", None, &buffers, &mut req)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,271 @@
+//! A function's `ResponseMode`: response bodies streamed through chunk by chunk for event streams
+//! and long polls, or read in full so they can be sent with a length, compressed and revalidated.
+
+use axum::http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode};
+use base64::Engine as _;
+use hyper::Body;
+use std::io::Write as _;
+
+use crate::buffers::BufferPool;
+
+/// Smaller bodies aren't worth compressing: the gzip header and trailer alone are 18 bytes.
+const MIN_GZIP_BYTES: usize = 1024;
+
+/// What of the request decides how a buffered response is sent.
+pub struct Negotiation {
+    method: Method,
+    gzip: bool,
+    if_none_match: Option<HeaderValue>,
+}
+
+impl Negotiation {
+    pub fn new<B>(req: &Request<B>) -> Self {
+        Self {
+            method: req.method().clone(),
+            gzip: accepts_gzip(req.headers()),
+            if_none_match: req.headers().get(header::IF_NONE_MATCH).cloned(),
+        }
+    }
+}
+
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            (name.eq_ignore_ascii_case("gzip") || name == "*")
+                && !params.any(|p| {
+                    p.strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .is_some_and(|q| q == 0.0)
+                })
+        })
+}
+
+fn is_compressible(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return false;
+    };
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(
+            essence.as_str(),
+            "application/json" | "application/javascript" | "application/xml" | "image/svg+xml"
+        )
+}
+
+/// Whether `If-None-Match` lists `etag`, by weak comparison as RFC 9110 has for it.
+fn etag_matches(if_none_match: &HeaderValue, etag: &HeaderValue) -> bool {
+    let (Ok(if_none_match), Ok(etag)) = (if_none_match.to_str(), etag.to_str()) else {
+        return false;
+    };
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match.trim() == "*"
+        || if_none_match
+            .split(',')
+            .any(|tag| opaque(tag) == opaque(etag))
+}
+
+/// Ask the client's intermediaries to pass chunks on as they come: nginx-style proxies buffer
+/// responses unless told not to, and ones that compress hold chunks back to fill a block.
+pub fn stream(headers: &mut HeaderMap) {
+    headers.insert("x-accel-buffering", HeaderValue::from_static("no"));
+    let cache_control = match headers
+        .get(header::CACHE_CONTROL)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(v) if v.contains("no-transform") => return,
+        Some(v) => format!("{}, no-transform", v),
+        None => "no-transform".to_string(),
+    };
+    if let Ok(cache_control) = HeaderValue::from_str(&cache_control) {
+        headers.insert(header::CACHE_CONTROL, cache_control);
+    }
+}
+
+/// Read the whole body, then send it with a `Content-Length`, a weak `ETag` (unless the function
+/// set one) answering matching conditional requests with a 304, and gzipped if it's text the
+/// client accepts compressed.
+pub async fn buffer(
+    pool: &BufferPool,
+    negotiation: &Negotiation,
+    resp: axum::response::Response<Body>,
+) -> Result<axum::response::Response<Body>, hyper::Error> {
+    let (mut parts, body) = resp.into_parts();
+    // No body to read: HEAD responses keep the length the function gave.
+    if negotiation.method == Method::HEAD
+        || parts.status.is_informational()
+        || parts.status == StatusCode::NO_CONTENT
+        || parts.status == StatusCode::NOT_MODIFIED
+    {
+        return Ok(axum::response::Response::from_parts(parts, body));
+    }
+    let mut body = pool.collect(body).await?;
+
+    if negotiation.method == Method::GET
+        && parts.status == StatusCode::OK
+        && !parts.headers.contains_key(header::ETAG)
+    {
+        let digest = ring::digest::digest(&ring::digest::SHA256, &body);
+        let etag = format!(
+            "W/\"{}\"",
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(&digest.as_ref()[..16])
+        );
+        if let Ok(etag) = HeaderValue::from_str(&etag) {
+            parts.headers.insert(header::ETAG, etag);
+        }
+    }
+    if let (Some(if_none_match), Some(etag)) =
+        (&negotiation.if_none_match, parts.headers.get(header::ETAG))
+    {
+        if parts.status == StatusCode::OK && etag_matches(if_none_match, etag) {
+            parts.status = StatusCode::NOT_MODIFIED;
+            parts.headers.remove(header::CONTENT_LENGTH);
+            parts.headers.remove(header::TRANSFER_ENCODING);
+            return Ok(axum::response::Response::from_parts(parts, Body::empty()));
+        }
+    }
+
+    if body.len() >= MIN_GZIP_BYTES
+        && !parts.headers.contains_key(header::CONTENT_ENCODING)
+        && is_compressible(&parts.headers)
+    {
+        // Whether or not this client gets it compressed, caches must tell them apart.
+        parts
+            .headers
+            .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+        if negotiation.gzip {
+            let mut encoder = flate2::write::GzEncoder::new(
+                Vec::with_capacity(body.len() / 2),
+                flate2::Compression::fast(),
+            );
+            // Writing to a Vec can't fail.
+            encoder.write_all(&body).expect("gzip to Vec");
+            let compressed = encoder.finish().expect("gzip to Vec");
+            if compressed.len() < body.len() {
+                body = compressed.into();
+                parts
+                    .headers
+                    .insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+            }
+        }
+    }
+
+    parts.headers.remove(header::TRANSFER_ENCODING);
+    parts
+        .headers
+        .insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+    Ok(axum::response::Response::from_parts(
+        parts,
+        Body::from(body),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read as _;
+
+    fn negotiation(method: Method, headers: &[(header::HeaderName, &str)]) -> Negotiation {
+        let mut req = Request::builder().method(method).uri("/");
+        for (name, value) in headers {
+            req = req.header(name, *value);
+        }
+        Negotiation::new(&req.body(()).unwrap())
+    }
+
+    fn chunked(content_type: &str, body: String) -> axum::response::Response<Body> {
+        let chunks: Vec<Result<_, std::io::Error>> = vec![Ok(body.clone()), Ok(body)];
+        axum::response::Response::builder()
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Body::wrap_stream(futures::stream::iter(chunks)))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_accepts_gzip() {
+        let accepts = |v: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT_ENCODING, v.parse().unwrap());
+            accepts_gzip(&headers)
+        };
+        assert!(accepts("gzip"));
+        assert!(accepts("br, GZIP;q=0.5"));
+        assert!(accepts("*"));
+        assert!(!accepts("br"));
+        assert!(!accepts("gzip;q=0"));
+        assert!(!accepts_gzip(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_stream() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CACHE_CONTROL, "no-cache".parse().unwrap());
+        stream(&mut headers);
+        assert_eq!(headers["x-accel-buffering"], "no");
+        assert_eq!(headers[header::CACHE_CONTROL], "no-cache, no-transform");
+        stream(&mut headers);
+        assert_eq!(headers[header::CACHE_CONTROL], "no-cache, no-transform");
+    }
+
+    #[tokio::test]
+    async fn test_buffer() {
+        let pool = BufferPool::new().unwrap();
+        let text = "hello ".repeat(200);
+
+        let gzip = negotiation(Method::GET, &[(header::ACCEPT_ENCODING, "gzip")]);
+        let resp = buffer(&pool, &gzip, chunked("text/plain", text.clone()))
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(resp.headers()[header::VARY], "accept-encoding");
+        let etag = resp.headers()[header::ETAG].to_str().unwrap().to_string();
+        let len: usize = resp.headers()[header::CONTENT_LENGTH]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let compressed = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(compressed.len(), len);
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, text.repeat(2));
+
+        let plain = negotiation(Method::GET, &[]);
+        let resp = buffer(
+            &pool,
+            &plain,
+            chunked("application/octet-stream", text.clone()),
+        )
+        .await
+        .unwrap();
+        assert!(!resp.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(resp.headers()[header::CONTENT_LENGTH], "2400");
+
+        let revalidate = negotiation(Method::GET, &[(header::IF_NONE_MATCH, &etag)]);
+        let resp = buffer(&pool, &revalidate, chunked("text/plain", text.clone()))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert!(hyper::body::to_bytes(resp.into_body())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,114 @@
+//! Response size ceilings, the counterpart of `upload`: response bodies are streamed to the
+//! client as they arrive, and these stop a function from sending more than it's configured to.
+//! A `Content-Length` over the ceiling gets the client a 502 without any of the body; otherwise
+//! the body is counted as it streams and cut off with an error at the ceiling, so the client can
+//! tell the response is incomplete. Either way it's logged and counted in
+//! `responses_too_large`.
+
+use axum::http::StatusCode;
+use futures::StreamExt as _;
+use hyper::body::{Body, Bytes};
+use opentelemetry::metrics::Counter;
+use tracing::{event, Level};
+use uuid::Uuid;
+
+use bismuth_common::ApiError;
+
+use crate::metric_labels;
+
+pub struct ResponseLimits {
+    exceeded: Counter<u64>,
+}
+
+impl ResponseLimits {
+    pub fn new() -> Self {
+        Self {
+            exceeded: opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+                .u64_counter("responses_too_large")
+                .with_description(
+                    "Responses rejected or cut off for going over the function's ceiling",
+                )
+                .init(),
+        }
+    }
+
+    /// Enforce a ceiling of `max` bytes on `resp`, a response to an invocation of `function_id`.
+    pub fn limit(
+        &self,
+        function_id: &Uuid,
+        max: u64,
+        resp: axum::response::Response<Body>,
+    ) -> Result<axum::response::Response<Body>, ApiError> {
+        let exceeded = self.exceeded.clone();
+        let function_id = *function_id;
+        let attrs = [metric_labels::function(&function_id)];
+        if let Some(len) = resp
+            .headers()
+            .get(hyper::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse::<u64>().ok())
+        {
+            // hyper won't read past the declared length, so there's nothing else to check.
+            if len > max {
+                event!(Level::WARN, function = %function_id, bytes = len, max, "Response over the function's ceiling");
+                exceeded.add(1, &attrs);
+                return Err(ApiError::Status(StatusCode::BAD_GATEWAY));
+            }
+            return Ok(resp);
+        }
+
+        let mut seen = 0u64;
+        Ok(resp.map(|body| {
+            Body::wrap_stream(body.map(
+                move |chunk| -> Result<Bytes, Box<dyn std::error::Error + Send + Sync>> {
+                    let chunk = chunk?;
+                    seen += chunk.len() as u64;
+                    if seen > max {
+                        // Only once: the error ends the body, so later chunks aren't polled.
+                        event!(Level::WARN, function = %function_id, max, "Response cut off at the function's ceiling");
+                        exceeded.add(1, &attrs);
+                        return Err(format!("Response exceeds {} bytes", max).into());
+                    }
+                    Ok(chunk)
+                },
+            ))
+        }))
+    }
+}
+
+impl Default for ResponseLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_limit() {
+        let limits = ResponseLimits::new();
+        let function_id = Uuid::new_v4();
+
+        let resp = axum::response::Response::builder()
+            .header(hyper::header::CONTENT_LENGTH, "11")
+            .body(Body::from("hello world"))
+            .unwrap();
+        let Err(ApiError::Status(status)) = limits.limit(&function_id, 5, resp) else {
+            panic!("expected a status");
+        };
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+
+        let chunks: Vec<Result<_, std::io::Error>> = vec![Ok("hello"), Ok(" world")];
+        let resp = axum::response::Response::new(Body::wrap_stream(futures::stream::iter(chunks)));
+        let resp = limits.limit(&function_id, 8, resp).unwrap();
+        assert!(hyper::body::to_bytes(resp.into_body()).await.is_err());
+
+        let resp = axum::response::Response::new(Body::from("hello"));
+        let resp = limits.limit(&function_id, 8, resp).unwrap();
+        assert_eq!(
+            hyper::body::to_bytes(resp.into_body()).await.unwrap(),
+            "hello"
+        );
+    }
+}
@@ -0,0 +1,320 @@
+//! `GET /admin/route-check?function=...&key=...`: a dry run of routing an invocation, through the
+//! same steps in the same order (staleness, maintenance, geo restrictions, rate limits, traffic
+//! splits, the regional ring with its health weighting and draining backends left out, then load
+//! and breaker rerouting), saying what each decided and which backend it would end up at, without
+//! sending anything, counting against any limit or taking a half-open breaker's probe. The
+//! decisions are `routing`'s, which invocations take live.
+//!
+//! Functions have no aliases or routing rules to resolve, so it starts from the function ID.
+//! Splits are random per invocation, so it reports the canary's share and follows the stable
+//! function unless asked for the canary (and it gets any). Middlewares aren't run, since they see the whole request.
+
+use axum::extract::{Query, State};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Request};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use bismuth_common::{ApiError, Backend, GenericError};
+
+use crate::routing::{self, Admission, Mode};
+use crate::{geo, rate_limit, FrontendState, CONHASH_REPLICAS};
+
+#[derive(Deserialize, Debug, IntoParams)]
+pub struct RouteCheckQuery {
+    /// Function to invoke.
+    function: Uuid,
+    /// What the rings hash: the client's IP address.
+    key: String,
+    /// The client's region, instead of looking it up in the GeoIP database.
+    region: Option<String>,
+    /// The client's country, instead of looking it up in the GeoIP database.
+    country: Option<String>,
+    /// Follow a traffic split to the canary rather than the stable function.
+    #[serde(default)]
+    canary: bool,
+    /// Request headers, as comma-separated `name:value` pairs, e.g. a rate limit's `key_header`.
+    headers: Option<String>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct RouteCheck {
+    /// `routed`, or the step invocations would stop at: `stale`, `maintenance`, `geo_refused` or
+    /// `rate_limited`; or, with no backend to go to, `forwarded` (to a peer frontend) or
+    /// `unavailable`.
+    outcome: String,
+    /// The function whose backends it would go to: the canary if the split was followed to it.
+    backend_function: Uuid,
+    /// The region whose ring was used, if the client's region has backends.
+    region: Option<String>,
+    /// Where the key lands on the ring, before rerouting around overloaded backends and open
+    /// breakers.
+    hashed: Option<Backend>,
+    /// Where it would go.
+    backend: Option<Backend>,
+    /// What each step decided, in order.
+    steps: Vec<String>,
+}
+
+/// Parse `name:value` pairs separated by commas.
+fn parse_headers(s: &str) -> Result<HeaderMap, String> {
+    let mut headers = HeaderMap::new();
+    for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (name, value) = pair
+            .split_once(':')
+            .ok_or_else(|| format!("Expected name:value, got '{}'", pair))?;
+        let name = HeaderName::try_from(name.trim())
+            .map_err(|_| format!("Invalid header name '{}'", name))?;
+        let value = HeaderValue::try_from(value.trim())
+            .map_err(|_| format!("Invalid value for header {}", name))?;
+        headers.append(name, value);
+    }
+    Ok(headers)
+}
+
+/// How an invocation of a function would be routed on this frontend, and why. Clients are hashed
+/// by their IP address, so that's the key to give; their location is looked up from it unless
+/// given.
+#[utoipa::path(
+    get,
+    path = "/admin/route-check",
+    tag = "admin",
+    params(RouteCheckQuery),
+    responses(
+        (status = 200, body = RouteCheck),
+        (status = 400, description = "Malformed headers"),
+        (status = 404, description = "No such function"),
+    ),
+    security(("admin_token" = []))
+)]
+pub async fn route_check_handler(
+    State(state): State<Arc<FrontendState>>,
+    Query(query): Query<RouteCheckQuery>,
+) -> Result<Json<RouteCheck>, ApiError> {
+    let monitor = state.monitor.as_ref().ok_or(ApiError::NotFound)?;
+    let headers = parse_headers(query.headers.as_deref().unwrap_or_default())
+        .map_err(GenericError::Invalid)?;
+    let client = query.key.parse::<IpAddr>().ok();
+    let mut check = RouteCheck {
+        outcome: "routed".to_string(),
+        backend_function: query.function,
+        region: None,
+        hashed: None,
+        backend: None,
+        steps: Vec::new(),
+    };
+    let stop = |mut check: RouteCheck, outcome: &str| -> Result<Json<RouteCheck>, ApiError> {
+        check.outcome = outcome.to_string();
+        Ok(Json(check))
+    };
+
+    // As invoking it would, when picking a backend.
+    let known = monitor.backends.read().await.contains_key(&query.function);
+    if !known && !monitor.load_lazily(query.function).await {
+        return Err(ApiError::NotFound);
+    }
+
+    let config = monitor.config(&query.function).await.unwrap_or_default();
+    let located = match (&state.geo, client) {
+        (Some(geo), Some(ip)) => geo.locate(ip),
+        _ => geo::ClientLocation::default(),
+    };
+    let country = query.country.or(located.country);
+    let region = query.region.or(located.region);
+    let admission = routing::admit(
+        &state,
+        query.function,
+        Some(&config),
+        &routing::Client {
+            country: country.as_deref(),
+            headers: &headers,
+            ip: client,
+        },
+        Instant::now(),
+        Mode::DryRun,
+    );
+    if admission == Admission::Stale {
+        check.steps.push(
+            "staleness: routing data has been possibly stale for over --max-staleness, so \
+             invocations fail with 503"
+                .to_string(),
+        );
+        return stop(check, "stale");
+    }
+    if let Admission::Maintenance(_) = admission {
+        check.steps.push(
+            "maintenance: the function is down for maintenance, so invocations get 503".to_string(),
+        );
+        return stop(check, "maintenance");
+    }
+    check.steps.push(format!(
+        "location: country {}, region {}",
+        country.as_deref().unwrap_or("unknown"),
+        region.as_deref().unwrap_or("unknown"),
+    ));
+    if let Admission::GeoRefused(status) = admission {
+        check.steps.push(format!(
+            "geo: not available in country {}, so invocations get {}",
+            country.as_deref().unwrap_or("unknown"),
+            status.as_u16(),
+        ));
+        return stop(check, "geo_refused");
+    }
+    if config.geo.is_some() {
+        check.steps.push("geo: allowed".to_string());
+    }
+    if let Some(rate_limit) = &config.rate_limit {
+        let key = match client {
+            Some(ip) => rate_limit::key(rate_limit, &headers, ip),
+            None => rate_limit::header_key(rate_limit, &headers).unwrap_or_default(),
+        };
+        match &admission {
+            Admission::RateLimited(quota) => {
+                check.steps.push(format!(
+                    "rate limit: none of {} left for {}, so invocations get 429 for the {}s \
+                     until the window resets",
+                    quota.limit, key, quota.reset_secs,
+                ));
+                return stop(check, "rate_limited");
+            }
+            Admission::Admitted(Some(quota)) => check.steps.push(format!(
+                "rate limit: {} of {} left for {} after the invocation, with {}s until the \
+                 window resets",
+                quota.remaining, quota.limit, key, quota.reset_secs,
+            )),
+            _ => check.steps.push(
+                "rate limit: counted by client IP address, which the key isn't, so not checked"
+                    .to_string(),
+            ),
+        }
+    }
+
+    let now = SystemTime::now();
+    check.backend_function = routing::backend_function(
+        query.function,
+        Some(&config),
+        now,
+        if query.canary { 0.0 } else { 100.0 },
+    );
+    if let Some(split) = &config.split {
+        check.steps.push(format!(
+            "split: {:.1}% of invocations go to canary {}; following the {}",
+            split.current_percent(now),
+            split.canary,
+            if check.backend_function == split.canary {
+                "canary"
+            } else {
+                "stable function"
+            },
+        ));
+        let known = monitor.backends.read().await.contains_key(&split.canary);
+        if check.backend_function == split.canary
+            && !known
+            && !monitor.load_lazily(split.canary).await
+        {
+            return Err(ApiError::NotFound);
+        }
+    }
+
+    let backends = monitor.backends.read().await;
+    let function = backends
+        .get(&check.backend_function)
+        .ok_or(ApiError::NotFound)?;
+    let now = Instant::now();
+    let route = routing::route(
+        function,
+        &query.key,
+        region.as_deref(),
+        state.load.as_ref(),
+        &state.breakers,
+        now,
+        Mode::DryRun,
+    );
+    check.region = route.region.map(str::to_string);
+    let in_ring = function
+        .backends
+        .iter()
+        .filter(|b| {
+            route.region.is_none()
+                || function.regions.get(&b.ip).map(String::as_str) == route.region
+        })
+        .count();
+    let reweighted = function
+        .replicas
+        .iter()
+        .filter(|&&points| points != CONHASH_REPLICAS)
+        .count();
+    check.steps.push(format!(
+        "ring: {} with {} backends ({} draining left out, {} reweighted for their health)",
+        match route.region {
+            Some(region) => format!("region {}'s", region),
+            None => "the function's".to_string(),
+        },
+        in_ring,
+        function.draining.len(),
+        reweighted,
+    ));
+
+    let Some(picked) = route.picked else {
+        let mut req = Request::new(());
+        *req.headers_mut() = headers;
+        if state.peers.should_forward(&req) {
+            check
+                .steps
+                .push("hash: no backends, so invocations go to a peer frontend".to_string());
+            return stop(check, "forwarded");
+        }
+        check
+            .steps
+            .push("hash: no backends, so invocations get 503".to_string());
+        return stop(check, "unavailable");
+    };
+    check.hashed = Some(picked.hashed.clone());
+    check.steps.push(format!(
+        "hash: lands on {} ({})",
+        picked.hashed.container_id, picked.hashed.ip
+    ));
+    if state.load.is_some() {
+        check.steps.push(if picked.unloaded == picked.hashed {
+            "load: not overloaded".to_string()
+        } else {
+            format!(
+                "load: overloaded, rehashed to {} ({})",
+                picked.unloaded.container_id, picked.unloaded.ip
+            )
+        });
+    }
+    let breaker = state
+        .breakers
+        .status(&picked.unloaded.container_id, now)
+        .state;
+    check.steps.push(if picked.backend == picked.unloaded {
+        format!("breaker: {}", breaker)
+    } else {
+        format!(
+            "breaker: {}, rehashed to {} ({})",
+            breaker, picked.backend.container_id, picked.backend.ip
+        )
+    });
+    check.backend = Some(picked.backend.clone());
+    stop(check, "routed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_headers() {
+        let headers = parse_headers("x-api-key: abc, X-Tenant:acme,").unwrap();
+        assert_eq!(headers["x-api-key"], "abc");
+        assert_eq!(headers["x-tenant"], "acme");
+        assert!(parse_headers("").unwrap().is_empty());
+        assert!(parse_headers("x-api-key").is_err());
+        assert!(parse_headers("bad header:1").is_err());
+    }
+}
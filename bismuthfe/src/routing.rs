@@ -0,0 +1,341 @@
+//! The decisions routing an invocation takes, shared by invoking a function and
+//! `/admin/route-check`'s dry run of it so the two can't disagree. Live decisions take effect:
+//! they count against rate limits, let invocations through as half-open breakers' probes and
+//! count metrics. Dry runs only say what would happen.
+
+use axum::http::{HeaderMap, StatusCode};
+use std::net::IpAddr;
+use std::time::{Instant, SystemTime};
+use uuid::Uuid;
+
+use bismuth_common::{Backend, FunctionConfig, Maintenance};
+
+use crate::rate_limit::{self, Quota};
+use crate::{breaker, load, FrontendState, FunctionBackends};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Live,
+    DryRun,
+}
+
+/// Who's invoking, as far as the checks before picking a backend care.
+pub struct Client<'a> {
+    pub country: Option<&'a str>,
+    pub headers: &'a HeaderMap,
+    /// `None` in a dry run for a key that isn't an IP address. Rate limits counted by IP aren't
+    /// checked then.
+    pub ip: Option<IpAddr>,
+}
+
+/// Whether an invocation gets as far as picking a backend.
+#[derive(Debug, PartialEq)]
+pub enum Admission<'a> {
+    /// With the quota left after it, if it was counted against a rate limit.
+    Admitted(Option<Quota>),
+    /// Routing data has been possibly stale for over `--max-staleness`: 503.
+    Stale,
+    Maintenance(&'a Maintenance),
+    /// Not available in the client's country.
+    GeoRefused(StatusCode),
+    RateLimited(Quota),
+}
+
+/// The checks before picking a backend, in the order invocations go through them.
+pub fn admit<'a>(
+    state: &FrontendState,
+    function_id: Uuid,
+    config: Option<&'a FunctionConfig>,
+    client: &Client,
+    now: Instant,
+    mode: Mode,
+) -> Admission<'a> {
+    if state.too_stale() {
+        return Admission::Stale;
+    }
+    let Some(config) = config else {
+        return Admission::Admitted(None);
+    };
+    if let Some(maintenance) = &config.maintenance {
+        return Admission::Maintenance(maintenance);
+    }
+    if let Some(restriction) = &config.geo {
+        if let Some(status) =
+            state
+                .geo_restrictions
+                .check(&function_id, restriction, client.country, mode)
+        {
+            return Admission::GeoRefused(status);
+        }
+    }
+    let Some(rate_limit) = &config.rate_limit else {
+        return Admission::Admitted(None);
+    };
+    let key = match client.ip {
+        Some(ip) => rate_limit::key(rate_limit, client.headers, ip),
+        None => match rate_limit::header_key(rate_limit, client.headers) {
+            Some(key) => key,
+            None => return Admission::Admitted(None),
+        },
+    };
+    match state
+        .rate_limiter
+        .check(function_id, rate_limit, key, now, mode)
+    {
+        Ok(quota) => Admission::Admitted(Some(quota)),
+        Err(quota) => Admission::RateLimited(quota),
+    }
+}
+
+/// The function whose backends an invocation goes to: a traffic split's canary if `roll`, from 0
+/// to 100, is below its current percentage.
+pub fn backend_function(
+    function_id: Uuid,
+    config: Option<&FunctionConfig>,
+    now: SystemTime,
+    roll: f64,
+) -> Uuid {
+    match config.and_then(|c| c.split.as_ref()) {
+        Some(split) if roll < split.current_percent(now) => split.canary,
+        _ => function_id,
+    }
+}
+
+/// Where an invocation's key lands among a function's backends.
+pub struct Route<'a> {
+    /// The region whose ring was used, if the client's region has backends.
+    pub region: Option<&'a str>,
+    /// `None` if the ring is empty.
+    pub picked: Option<Picked<'a>>,
+}
+
+pub struct Picked<'a> {
+    /// Where the key hashes to.
+    pub hashed: &'a Backend,
+    /// After rerouting around overloaded backends.
+    pub unloaded: &'a Backend,
+    /// After rerouting around open breakers: where the invocation goes.
+    pub backend: &'a Backend,
+}
+
+/// Pick the backend for `key`, from the client's region's ring if it has one.
+pub fn route<'a>(
+    function: &'a FunctionBackends,
+    key: &str,
+    region: Option<&str>,
+    load: Option<&load::BackendLoad>,
+    breakers: &breaker::Breakers,
+    now: Instant,
+    mode: Mode,
+) -> Route<'a> {
+    let (region, ring) = match region.and_then(|r| function.regional.get_key_value(r)) {
+        Some((region, ring)) => (Some(region.as_str()), ring),
+        None => (None, &function.ring),
+    };
+    let picked = ring.get(key.as_bytes()).map(|hashed| {
+        let unloaded = match (load, mode) {
+            (Some(load), Mode::Live) => load.pick(ring, key, now),
+            (Some(load), Mode::DryRun) => load.peek(ring, key, now),
+            (None, _) => None,
+        }
+        .unwrap_or(hashed);
+        let backend = match mode {
+            Mode::Live => breakers.reroute(ring, key, unloaded, now),
+            Mode::DryRun => breakers.peek(ring, key, unloaded, now),
+        };
+        Picked {
+            hashed,
+            unloaded,
+            backend,
+        }
+    });
+    Route { region, picked }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bismuth_common::{GeoRestriction, RateLimit, TrafficSplit};
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    fn backend(n: u8) -> Backend {
+        Backend {
+            ip: Ipv4Addr::new(10, 0, 0, n),
+            container_id: Uuid::from_u128(n as u128),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admit() {
+        let (state, _, _) = crate::server::build(
+            &crate::server::Config {
+                dev_backend: Some("127.0.0.1:9000".parse().unwrap()),
+                ..Default::default()
+            },
+            &Default::default(),
+            axum::Router::new(),
+        )
+        .await
+        .unwrap();
+        let function_id = Uuid::new_v4();
+        let config = FunctionConfig {
+            geo: Some(GeoRestriction {
+                allow: Vec::new(),
+                deny: vec!["KP".to_string()],
+                status: 403,
+            }),
+            rate_limit: Some(RateLimit {
+                requests: 2,
+                window_secs: 10,
+                key_header: Some("x-api-key".to_string()),
+            }),
+            ..Default::default()
+        };
+        let now = Instant::now();
+        // A dry run decides what the invocation after it does, without counting against the
+        // limit.
+        let both = |client: &Client| {
+            let dry_run = admit(
+                &state,
+                function_id,
+                Some(&config),
+                client,
+                now,
+                Mode::DryRun,
+            );
+            let live = admit(&state, function_id, Some(&config), client, now, Mode::Live);
+            assert_eq!(dry_run, live);
+            live
+        };
+
+        let no_headers = HeaderMap::new();
+        let mut api_key = HeaderMap::new();
+        api_key.insert("x-api-key", "abc".parse().unwrap());
+        let ip = Some(IpAddr::from([10, 1, 0, 1]));
+        let refused = Client {
+            country: Some("KP"),
+            headers: &no_headers,
+            ip,
+        };
+        assert_eq!(both(&refused), Admission::GeoRefused(StatusCode::FORBIDDEN));
+        let by_ip = Client {
+            country: Some("DE"),
+            headers: &no_headers,
+            ip,
+        };
+        assert!(matches!(both(&by_ip), Admission::Admitted(Some(q)) if q.remaining == 1));
+        assert!(matches!(both(&by_ip), Admission::Admitted(Some(q)) if q.remaining == 0));
+        assert!(matches!(both(&by_ip), Admission::RateLimited(_)));
+        // Without an IP address, only limits counted by header are checked.
+        let unknown = Client {
+            country: None,
+            headers: &no_headers,
+            ip: None,
+        };
+        assert_eq!(both(&unknown), Admission::Admitted(None));
+        let by_header = Client {
+            country: None,
+            headers: &api_key,
+            ip: None,
+        };
+        assert!(matches!(both(&by_header), Admission::Admitted(Some(q)) if q.remaining == 1));
+
+        let config = FunctionConfig {
+            maintenance: Some(Maintenance::default()),
+            ..config.clone()
+        };
+        assert_eq!(
+            admit(
+                &state,
+                function_id,
+                Some(&config),
+                &by_ip,
+                now,
+                Mode::DryRun
+            ),
+            Admission::Maintenance(&Maintenance::default())
+        );
+    }
+
+    #[test]
+    fn test_backend_function() {
+        let function_id = Uuid::new_v4();
+        let canary = Uuid::new_v4();
+        let config = FunctionConfig {
+            split: Some(TrafficSplit {
+                canary,
+                percent: 10.0,
+                analysis: None,
+                ramp: None,
+            }),
+            ..Default::default()
+        };
+        let now = SystemTime::now();
+        assert_eq!(
+            backend_function(function_id, Some(&config), now, 9.9),
+            canary
+        );
+        assert_eq!(
+            backend_function(function_id, Some(&config), now, 10.0),
+            function_id
+        );
+        assert_eq!(backend_function(function_id, None, now, 0.0), function_id);
+    }
+
+    #[test]
+    fn test_route() {
+        let regions = HashMap::from([
+            (backend(1).ip, "eu".to_string()),
+            (backend(2).ip, "eu".to_string()),
+        ]);
+        let function = FunctionBackends::new((1..=8).map(backend).collect(), &regions);
+        let load = load::BackendLoad::new(1);
+        let breakers = breaker::Breakers::new(Some(1), Duration::from_secs(1));
+        let now = Instant::now();
+        let mut in_flight = HeaderMap::new();
+        in_flight.insert(bismuth_common::IN_FLIGHT_HEADER, 1.into());
+        load.report(backend(3).container_id, &in_flight, now);
+        breakers.trip(backend(4).container_id, Duration::from_secs(60), now);
+        // Half-open once the cooldown's over: the probe only a live invocation takes.
+        breakers.record(backend(5).container_id, false, now);
+        let later = now + Duration::from_secs(2);
+
+        let mut rerouted = 0;
+        for key in (0..200).map(|n| format!("10.2.0.{}", n)) {
+            for region in [None, Some("eu"), Some("us")] {
+                let dry_run = route(
+                    &function,
+                    &key,
+                    region,
+                    Some(&load),
+                    &breakers,
+                    later,
+                    Mode::DryRun,
+                );
+                let live = route(
+                    &function,
+                    &key,
+                    region,
+                    Some(&load),
+                    &breakers,
+                    later,
+                    Mode::Live,
+                );
+                assert_eq!(dry_run.region, live.region);
+                assert_eq!(dry_run.region, region.filter(|r| *r == "eu"));
+                let (dry_run, live) = (dry_run.picked.unwrap(), live.picked.unwrap());
+                assert_eq!(dry_run.hashed, live.hashed);
+                assert_eq!(dry_run.unloaded, live.unloaded);
+                assert_eq!(dry_run.backend, live.backend);
+                assert_ne!(live.unloaded, &backend(3));
+                assert_ne!(live.backend, &backend(4));
+                if live.backend != live.hashed {
+                    rerouted += 1;
+                }
+            }
+        }
+        assert!(rerouted > 0);
+    }
+}
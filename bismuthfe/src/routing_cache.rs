@@ -0,0 +1,154 @@
+//! The last known routing table, saved to disk so a restarted frontend can route straight away
+//! while it (re)connects to ZooKeeper, rather than failing every invocation until it has.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{event, Level};
+use uuid::Uuid;
+
+use bismuth_common::{Backend, FunctionConfig};
+
+use crate::FunctionBackends;
+
+/// How often the table is saved while it's current.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize)]
+struct CachedFunction {
+    backends: Vec<Backend>,
+    regions: HashMap<Ipv4Addr, String>,
+    config: FunctionConfig,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Saved {
+    /// Unix seconds.
+    saved_at: u64,
+    functions: HashMap<Uuid, CachedFunction>,
+}
+
+pub struct RoutingCache {
+    path: PathBuf,
+    max_age: Duration,
+}
+
+impl RoutingCache {
+    /// Keep the table at `path`, and don't route from one older than `max_age`.
+    pub fn new(path: PathBuf, max_age: Duration) -> Self {
+        Self { path, max_age }
+    }
+
+    pub fn max_age(&self) -> Duration {
+        self.max_age
+    }
+
+    /// The saved table and when it was saved, unless there's none, it's unreadable, or it's past
+    /// its max age.
+    pub async fn load(&self) -> Option<(SystemTime, HashMap<Uuid, FunctionBackends>)> {
+        let saved = match tokio::fs::read(&self.path).await {
+            Ok(saved) => saved,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                event!(Level::WARN, path = %self.path.display(), error = %e, "Error reading routing cache");
+                return None;
+            }
+        };
+        let saved: Saved = match serde_json::from_slice(&saved) {
+            Ok(saved) => saved,
+            Err(e) => {
+                event!(Level::WARN, path = %self.path.display(), error = %e, "Invalid routing cache");
+                return None;
+            }
+        };
+        let saved_at = UNIX_EPOCH + Duration::from_secs(saved.saved_at);
+        if saved_at.elapsed().unwrap_or_default() > self.max_age {
+            event!(Level::INFO, path = %self.path.display(), "Routing cache is too old, ignoring it");
+            return None;
+        }
+        let functions = saved
+            .functions
+            .into_iter()
+            .map(|(function_id, cached)| {
+                let mut function = FunctionBackends::new(cached.backends, &cached.regions);
+                function.config = cached.config;
+                (function_id, function)
+            })
+            .collect();
+        Some((saved_at, functions))
+    }
+
+    /// Replace the saved table with one from `encode`, atomically so a crash mid-write can't
+    /// leave a truncated one.
+    pub async fn save(&self, encoded: Vec<u8>) -> Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        tokio::fs::write(&tmp, encoded)
+            .await
+            .with_context(|| format!("Error writing {}", tmp.display()))?;
+        tokio::fs::rename(&tmp, &self.path)
+            .await
+            .with_context(|| format!("Error renaming {} into place", tmp.display()))?;
+        Ok(())
+    }
+}
+
+/// Serialize `functions` for saving, separately so the routing table isn't locked while writing.
+pub fn encode(functions: &HashMap<Uuid, FunctionBackends>) -> Result<Vec<u8>> {
+    let saved = Saved {
+        saved_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        functions: functions
+            .iter()
+            .map(|(function_id, function)| {
+                (
+                    *function_id,
+                    CachedFunction {
+                        backends: function.backends.clone(),
+                        regions: function.regions.clone(),
+                        config: function.config.clone(),
+                    },
+                )
+            })
+            .collect(),
+    };
+    Ok(serde_json::to_vec(&saved)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("bismuthfe-routing-{}.json", Uuid::new_v4()));
+        let cache = RoutingCache::new(path.clone(), Duration::from_secs(60));
+        assert!(cache.load().await.is_none());
+
+        let backend = Backend {
+            ip: "10.0.0.1".parse().unwrap(),
+            container_id: Uuid::new_v4(),
+        };
+        let regions = HashMap::from([(backend.ip, "us-east".to_string())]);
+        let mut function = FunctionBackends::new(vec![backend.clone()], &regions);
+        function.config.timeout_ms = Some(1000);
+        let function_id = Uuid::new_v4();
+        let encoded = encode(&HashMap::from([(function_id, function)])).unwrap();
+        cache.save(encoded).await.unwrap();
+
+        let (_, functions) = cache.load().await.unwrap();
+        let function = &functions[&function_id];
+        assert_eq!(function.backends, vec![backend]);
+        assert!(function.regional.contains_key("us-east"));
+        assert_eq!(function.config.timeout_ms, Some(1000));
+
+        let expired = RoutingCache::new(path.clone(), Duration::ZERO);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert!(expired.load().await.is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,719 @@
+use anyhow::{anyhow, Result};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse as _;
+use axum::routing::get;
+use futures::FutureExt as _;
+use opentelemetry::trace::TraceContextExt as _;
+use sentry::integrations::tower::{NewSentryLayer, SentryHttpLayer};
+use serde_json::json;
+use std::future::Future;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+use tower::ServiceBuilder;
+use tower_http::catch_panic::CatchPanicLayer;
+use tracing::{event, Level};
+use tracing_opentelemetry::OpenTelemetrySpanExt as _;
+
+use bismuth_common::OtelAxumMetricsLayer;
+
+use crate::{
+    access, admin, admin_auth, alerts, alloc, app, breaker, buffers, canary, capture, client_io,
+    concurrency, connections, dev, dns, events, geo, gossip, guard, health, http2, internal,
+    latency, listener, load, middleware, openapi, overload, peers, priority, rate_limit,
+    request_schema, response_size, routing_cache, shedding, signature, slo, tokens, upstream,
+    version, wasm, BackendMonitor, FrontendState,
+};
+
+/// Everything needed to run a frontend. See the `bismuthfe` CLI flags for what each option does.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub zookeeper: String,
+    pub zookeeper_env: String,
+    pub bind: SocketAddr,
+    pub reuse_port: bool,
+    /// Where to serve the admin API instead of `bind`.
+    pub internal_bind: Option<internal::Bind>,
+    pub drain_timeout: Duration,
+    /// Upstream timeouts: connecting to a backend, waiting for its response headers, and the
+    /// whole invocation (functions can override the last two).
+    pub connect_timeout: Duration,
+    pub header_timeout: Duration,
+    pub total_timeout: Option<Duration>,
+    pub dev_backend: Option<dev::DevBackend>,
+    pub peers: Vec<hyper::Uri>,
+    pub geoip_db: Option<std::path::PathBuf>,
+    pub geo_regions: Vec<(String, String)>,
+    pub admin_token: Option<String>,
+    /// Admin API tokens with other roles, and OIDC tokens' issuer and roles.
+    pub admin_role_tokens: Vec<(admin_auth::Role, String)>,
+    pub admin_oidc: Option<admin_auth::OidcConfig>,
+    /// Keys invocations must be signed with, if any, and how far off their timestamps may be.
+    pub request_signing_keys: Vec<(String, signature::SigningKey)>,
+    pub request_signing_max_skew: Duration,
+    pub require_tenant_token: bool,
+    pub adaptive_concurrency: Option<concurrency::LimitConfig>,
+    /// Histogram bucket profiles functions may select (the boundaries themselves are set up when
+    /// initializing metrics, see `bismuth_common::init_metrics_with_buckets`).
+    pub latency_bucket_profiles: Vec<String>,
+    /// Low and high load shedding watermarks.
+    pub shed_watermarks: Option<(usize, usize)>,
+    /// Cap on invocations in flight past which they're queued by priority.
+    pub invocation_queue: Option<priority::QueueConfig>,
+    /// Tenants' shares of queued invocations' slots, relative to the default of 1.
+    pub tenant_weights: Vec<(String, u32)>,
+    /// The frontend's own CPU and memory use past which it's degraded.
+    pub host_watermarks: Option<overload::Watermarks>,
+    /// WASM filters by name, and the fuel each call gets.
+    pub wasm_filters: Vec<(String, std::path::PathBuf)>,
+    pub wasm_filter_fuel: u64,
+    /// Names of registered `middleware::ProxyMiddleware`s to run, in order.
+    pub middlewares: Vec<String>,
+    /// Where to record captured invocations.
+    pub capture_dir: Option<std::path::PathBuf>,
+    /// Where to export invocation records.
+    pub event_export: Option<events::ExportConfig>,
+    /// Where to send alerts, and their thresholds.
+    pub alerts: Option<alerts::AlertConfig>,
+    pub health_weighted_ring: bool,
+    /// Frontends to share backends' health with, and the token reports carry.
+    pub gossip: Option<gossip::GossipConfig>,
+    /// In-flight count at which a backend is considered overloaded.
+    pub overload_in_flight: Option<usize>,
+    /// Consecutive failures that open a backend's circuit breaker, and how long it stays open.
+    pub breaker_failures: Option<u32>,
+    pub breaker_cooldown: Duration,
+    /// Default ceiling on request bodies, in bytes.
+    pub max_upload_bytes: Option<u64>,
+    /// Default ceiling on response bodies, in bytes.
+    pub max_response_bytes: Option<u64>,
+    /// Caps on open client connections, overall and per client IP.
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub client_timeouts: client_io::ClientTimeouts,
+    pub http2: http2::Http2Settings,
+    /// Speak HTTP/2 to backends and peers rather than HTTP/1.1.
+    pub backend_http2: bool,
+    /// Resolvers for hostname backends, or the system's if empty.
+    pub dns_servers: Vec<SocketAddr>,
+    /// Networks backends must be in (any if empty), and whether loopback and link-local
+    /// addresses are allowed.
+    pub backend_cidrs: Vec<guard::Cidr>,
+    pub allow_local_backends: bool,
+    /// Where to save the routing table, and how long a saved one can be routed from.
+    pub routing_cache: Option<std::path::PathBuf>,
+    pub routing_cache_max_age: Duration,
+    /// How long routing data may be possibly stale before invocations fail.
+    pub max_staleness: Option<Duration>,
+    /// More ZooKeeper environments to serve, each under `/env/{name}`.
+    pub extra_zookeeper_envs: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            zookeeper: "127.0.0.1:2181".to_string(),
+            zookeeper_env: "default".to_string(),
+            bind: "0.0.0.0:8000".parse().unwrap(),
+            reuse_port: false,
+            internal_bind: None,
+            drain_timeout: Duration::from_secs(30),
+            connect_timeout: Duration::from_secs(2),
+            header_timeout: Duration::from_secs(60),
+            total_timeout: None,
+            dev_backend: None,
+            peers: Vec::new(),
+            geoip_db: None,
+            geo_regions: Vec::new(),
+            admin_token: None,
+            admin_role_tokens: Vec::new(),
+            admin_oidc: None,
+            request_signing_keys: Vec::new(),
+            request_signing_max_skew: Duration::from_secs(300),
+            require_tenant_token: false,
+            adaptive_concurrency: None,
+            latency_bucket_profiles: Vec::new(),
+            shed_watermarks: None,
+            invocation_queue: None,
+            tenant_weights: Vec::new(),
+            host_watermarks: None,
+            wasm_filters: Vec::new(),
+            wasm_filter_fuel: 10_000_000,
+            middlewares: Vec::new(),
+            capture_dir: None,
+            event_export: None,
+            alerts: None,
+            health_weighted_ring: false,
+            gossip: None,
+            overload_in_flight: None,
+            breaker_failures: None,
+            breaker_cooldown: Duration::from_secs(30),
+            max_upload_bytes: None,
+            max_response_bytes: None,
+            max_connections: None,
+            max_connections_per_ip: None,
+            client_timeouts: client_io::ClientTimeouts {
+                header: Some(Duration::from_secs(30)),
+                idle: Some(Duration::from_secs(60)),
+                min_bytes_per_sec: None,
+            },
+            http2: http2::Http2Settings::default(),
+            backend_http2: false,
+            dns_servers: Vec::new(),
+            backend_cidrs: Vec::new(),
+            allow_local_backends: false,
+            routing_cache: None,
+            routing_cache_max_age: Duration::from_secs(3600),
+            max_staleness: None,
+            extra_zookeeper_envs: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// The same settings, for serving `zookeeper_env` instead. Its routing cache is kept next to
+    /// this one's, and invocations are forwarded (and health reported) to the peers' `/env/{name}`
+    /// routes.
+    fn for_env(&self, zookeeper_env: &str) -> Result<Self> {
+        Ok(Self {
+            zookeeper_env: zookeeper_env.to_string(),
+            peers: env_uris(&self.peers, zookeeper_env)?,
+            gossip: self
+                .gossip
+                .as_ref()
+                .map(|gossip| -> Result<_> {
+                    Ok(gossip::GossipConfig {
+                        peers: env_uris(&gossip.peers, zookeeper_env)?,
+                        token: gossip.token.clone(),
+                    })
+                })
+                .transpose()?,
+            routing_cache: self.routing_cache.as_ref().map(|path| {
+                let mut path = path.clone().into_os_string();
+                path.push(format!(".{}", zookeeper_env));
+                path.into()
+            }),
+            extra_zookeeper_envs: Vec::new(),
+            ..self.clone()
+        })
+    }
+}
+
+/// Other frontends' `/env/{name}` routes, given their base URIs.
+fn env_uris(uris: &[hyper::Uri], zookeeper_env: &str) -> Result<Vec<hyper::Uri>> {
+    Ok(uris
+        .iter()
+        .map(|uri| {
+            let base = uri.to_string();
+            format!("{}/env/{}", base.trim_end_matches('/'), zookeeper_env).parse()
+        })
+        .collect::<Result<_, _>>()?)
+}
+
+/// A frontend, for embedding in other binaries and integration tests.
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// let server = bismuthfe::Server::new(bismuthfe::Config::default()).await?;
+/// server.run(bismuthfe::listener::shutdown_signal()).await
+/// # }
+/// ```
+///
+/// Doesn't install a tracing subscriber or metrics exporter: that's left to the embedding binary.
+pub struct Server {
+    config: Config,
+    state: Arc<FrontendState>,
+    router: axum::Router,
+    /// `None` without `internal_bind`, or the admin API.
+    internal_router: Option<axum::Router>,
+}
+
+impl Server {
+    /// Connect to ZooKeeper (unless using a dev backend) and build the router.
+    pub async fn new(config: Config) -> Result<Self> {
+        if config.dev_backend.is_some() && !config.extra_zookeeper_envs.is_empty() {
+            return Err(anyhow!(
+                "More environments need ZooKeeper, not a dev backend"
+            ));
+        }
+        alloc::register_metrics()?;
+        let shared = Shared {
+            host: config.host_watermarks.map(overload::HostWatermarks::new),
+            verifier: (!config.request_signing_keys.is_empty()).then(|| {
+                Arc::new(signature::Verifier::new(
+                    &config.request_signing_keys,
+                    config.request_signing_max_skew,
+                ))
+            }),
+        };
+        let (state, mut router, mut internal_router) = build(&config, &shared, app()).await?;
+        for env in &config.extra_zookeeper_envs {
+            let (_, env_router, env_internal) =
+                build(&config.for_env(env)?, &shared, app()).await?;
+            // Added after the layers, so these only go through the environment's own.
+            router = router.nest(&format!("/env/{}", env), env_router);
+            if let Some(env_internal) = env_internal {
+                internal_router = internal_router
+                    .map(|internal| internal.nest(&format!("/env/{}", env), env_internal));
+            }
+        }
+
+        Ok(Self {
+            config,
+            state,
+            router,
+            internal_router,
+        })
+    }
+}
+
+/// What every environment shares: the host, and the nonces of signed invocations, so one can't
+/// be replayed in another environment.
+#[derive(Default)]
+pub(crate) struct Shared {
+    host: Option<Arc<overload::HostWatermarks>>,
+    verifier: Option<Arc<signature::Verifier>>,
+}
+
+/// One environment's state and fully layered routers: the public one (`routes` with every
+/// layer), and with `internal_bind` the internal listener's.
+pub(crate) async fn build(
+    config: &Config,
+    shared: &Shared,
+    routes: axum::Router<Arc<FrontendState>>,
+) -> Result<(Arc<FrontendState>, axum::Router, Option<axum::Router>)> {
+    let monitor = match &config.dev_backend {
+        Some(dev_backend) => {
+            event!(Level::WARN, backend = ?dev_backend, "Routing all functions to a dev backend");
+            None
+        }
+        None => Some(
+            BackendMonitor::new(
+                &config.zookeeper,
+                &config.zookeeper_env,
+                Some(dns::Resolver::new(&config.dns_servers)?),
+                guard::BackendGuard::new(config.backend_cidrs.clone(), config.allow_local_backends),
+                config.routing_cache.clone().map(|path| {
+                    routing_cache::RoutingCache::new(path, config.routing_cache_max_age)
+                }),
+            )
+            .await?,
+        ),
+    };
+
+    let mut router = routes;
+    if let Some(queue) = config.invocation_queue {
+        let queue = Arc::new(priority::InvocationQueue::new(
+            queue,
+            &config.tenant_weights,
+        ));
+        router = router.route_layer(axum::middleware::from_fn_with_state(
+            queue,
+            priority::queue_middleware,
+        ));
+    }
+    if let Some((low_watermark, high_watermark)) = config.shed_watermarks {
+        let shedder = Arc::new(shedding::LoadShedder::new(low_watermark, high_watermark)?);
+        // Only invocations are shed, never the admin API.
+        router = router.route_layer(axum::middleware::from_fn_with_state(
+            shedder,
+            shedding::middleware,
+        ));
+    }
+    if let Some(host) = &shared.host {
+        router = router.route_layer(axum::middleware::from_fn_with_state(
+            host.clone(),
+            overload::middleware,
+        ));
+    }
+    // Outside all those, and inside tenant tokens, which can set it.
+    router = router.route_layer(axum::middleware::from_fn_with_state(
+        monitor.clone(),
+        priority::middleware,
+    ));
+    if let Some(verifier) = &shared.verifier {
+        // Outside load shedding, so unsigned invocations aren't counted as in flight.
+        router = router.route_layer(axum::middleware::from_fn_with_state(
+            verifier.clone(),
+            signature::middleware,
+        ));
+    }
+    if config.require_tenant_token {
+        let monitor = monitor
+            .clone()
+            .ok_or_else(|| anyhow!("Tenant tokens need ZooKeeper, not a dev backend"))?;
+        // Outside load shedding too, like signatures.
+        router = router.route_layer(axum::middleware::from_fn_with_state(
+            monitor,
+            tokens::middleware,
+        ));
+    }
+    let slo = slo::SloTracker::new();
+    // Outside load shedding, so shed invocations are logged too.
+    router = router.route_layer(axum::middleware::from_fn_with_state(
+        Arc::new(access::AccessLogs::new(
+            monitor.clone(),
+            config
+                .event_export
+                .clone()
+                .map(|export| events::EventExporter::new(export, &config.zookeeper_env)),
+            config
+                .alerts
+                .clone()
+                .map(|alerts| alerts::Alerts::new(alerts, &config.zookeeper_env, monitor.clone())),
+            slo.clone(),
+        )),
+        access::middleware,
+    ));
+    let mut internal = None;
+    if let Some(auth) = admin_auth::AdminAuth::new(
+        config.admin_token.as_deref(),
+        &config.admin_role_tokens,
+        config.admin_oidc.clone(),
+    )? {
+        match config.internal_bind {
+            Some(_) => internal = Some(admin::app(auth)),
+            None => router = router.merge(admin::app(auth)),
+        }
+    }
+    if let Some(gossip) = &config.gossip {
+        router = router.merge(gossip::app(&gossip.token));
+    }
+
+    let mut router = router
+        // Innermost, so the panic is caught while the request's span is still entered.
+        .layer(CatchPanicLayer::custom(panic_response))
+        .layer(axum_tracing_opentelemetry::middleware::OtelInResponseLayer::default())
+        .layer(axum_tracing_opentelemetry::middleware::OtelAxumLayer::default())
+        .layer(OtelAxumMetricsLayer::new());
+    let geo = match &config.geoip_db {
+        Some(geoip_db) => Some(Arc::new(geo::Geo::open(geoip_db, &config.geo_regions)?)),
+        None => None,
+    };
+    if let Some(geo) = &geo {
+        // Outside the metrics layer, so it can add the client's country as a dimension.
+        router = router.layer(axum::middleware::from_fn_with_state(
+            geo.clone(),
+            geo::middleware,
+        ));
+    }
+
+    let mut middlewares: Vec<Arc<dyn middleware::ProxyMiddleware>> =
+        vec![Arc::new(wasm::WasmMiddleware(Arc::new(
+            wasm::Filters::load(&config.wasm_filters, config.wasm_filter_fuel)?,
+        )))];
+    middlewares.extend(middleware::build(&config.middlewares, config)?);
+
+    let state = Arc::new(FrontendState {
+        monitor,
+        dev_backend: config.dev_backend.clone(),
+        http_client: {
+            let mut builder = hyper::Client::builder();
+            config
+                .http2
+                .configure_client(&mut builder, config.backend_http2);
+            builder.build(upstream::Connector::new({
+                let mut connector = hyper::client::HttpConnector::new();
+                connector.set_connect_timeout(Some(config.connect_timeout));
+                connector
+            })?)
+        },
+        peers: peers::Peers::new(config.peers.clone()),
+        limits: config
+            .adaptive_concurrency
+            .map(concurrency::ConcurrencyLimits::new),
+        latency: latency::InvocationLatency::new(&config.latency_bucket_profiles),
+        middlewares,
+        max_upload_bytes: config.max_upload_bytes,
+        max_response_bytes: config.max_response_bytes,
+        response_limits: response_size::ResponseLimits::new(),
+        header_timeout: config.header_timeout,
+        total_timeout: config.total_timeout,
+        idempotent: Default::default(),
+        coalesced: Default::default(),
+        buffers: buffers::BufferPool::new()?,
+        capture: config
+            .capture_dir
+            .clone()
+            .map(capture::Capturer::new)
+            .transpose()?,
+        canary: canary::CanaryAnalyzer::new(),
+        load: config.overload_in_flight.map(load::BackendLoad::new),
+        breakers: breaker::Breakers::new(config.breaker_failures, config.breaker_cooldown),
+        rate_limiter: rate_limit::RateLimiter::new(),
+        geo,
+        geo_restrictions: geo::Restrictions::new(),
+        request_schemas: request_schema::Validator::new(),
+        max_staleness: config.max_staleness,
+        host: shared.host.clone(),
+        health: config.health_weighted_ring.then(health::BackendHealth::new),
+        slo,
+    });
+    if let (Some(monitor), true) = (&state.monitor, state.health.is_some()) {
+        let monitor = monitor.clone();
+        let state_ = state.clone();
+        tokio::spawn(async move {
+            let health = state_.health.as_ref().unwrap();
+            loop {
+                // Or straight away when a peer reports a change.
+                tokio::select! {
+                    _ = sleep(health::REWEIGHT_INTERVAL) => {}
+                    _ = health.reported.notified() => {}
+                }
+                let now = std::time::Instant::now();
+                // Worked out under the read lock, so invocations aren't held up unless a ring
+                // changes.
+                let backends = monitor.backends.read().await;
+                let changes = backends
+                    .iter()
+                    .map(|(id, function)| (*id, health.changes(function, now)))
+                    .filter(|(_, changes)| !changes.is_empty())
+                    .collect::<Vec<_>>();
+                let containers = backends
+                    .values()
+                    .flat_map(|f| f.backends.iter().map(|b| b.container_id))
+                    .collect::<Vec<_>>();
+                drop(backends);
+                health.retain(&containers);
+                if changes.is_empty() {
+                    continue;
+                }
+                let mut backends = monitor.backends.write().await;
+                for (id, changes) in changes {
+                    // Unless it was reloaded meanwhile, with new backends or indices.
+                    if let Some(function) = backends.get_mut(&id) {
+                        if changes.iter().all(|&(i, _)| i < function.backends.len()) {
+                            health.apply(function, &changes);
+                        }
+                    }
+                }
+            }
+        });
+    }
+    if let Some(gossip) = &config.gossip {
+        gossip::spawn_reports(&state, gossip);
+    }
+    if let (Some(monitor), true) = (&state.monitor, state.limits.is_some()) {
+        let monitor = monitor.clone();
+        let state_ = state.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(Duration::from_secs(60)).await;
+                let backends = monitor.backends.read().await;
+                let functions = backends.keys().copied().collect::<Vec<_>>();
+                let containers = backends
+                    .values()
+                    .flat_map(|f| f.backends.iter().map(|b| b.container_id))
+                    .collect::<Vec<_>>();
+                drop(backends);
+                if let Some(limits) = &state_.limits {
+                    limits.retain(&functions, &containers);
+                }
+            }
+        });
+    }
+
+    let router = router
+        .route(
+            "/version",
+            get(|| async { axum::Json(version::build_info()) }),
+        )
+        .route(
+            "/openapi.json",
+            get(|| async { axum::Json(openapi::document()) }),
+        )
+        .route("/quota/:function_id", get(rate_limit::quota_handler))
+        .route(
+            "/healthz",
+            get(|State(state): State<Arc<FrontendState>>| async move {
+                // Still healthy: invocations are served, just from possibly stale data,
+                // until it's too stale to.
+                match &state.monitor {
+                    _ if state.too_stale() => (StatusCode::SERVICE_UNAVAILABLE, "STALE"),
+                    Some(monitor) if monitor.is_stale() => (StatusCode::OK, "STALE"),
+                    _ => (StatusCode::OK, "OK"),
+                }
+            }),
+        )
+        .with_state(state.clone())
+        .layer(
+            ServiceBuilder::new()
+                .layer(NewSentryLayer::new_from_top())
+                .layer(SentryHttpLayer::with_transaction()),
+        );
+    // None of the invocation layers: just tracing, and catching panics.
+    let internal = internal.map(|internal| {
+        internal
+            .with_state(state.clone())
+            .layer(CatchPanicLayer::custom(panic_response))
+            .layer(axum_tracing_opentelemetry::middleware::OtelInResponseLayer::default())
+            .layer(axum_tracing_opentelemetry::middleware::OtelAxumLayer::default())
+            .layer(
+                ServiceBuilder::new()
+                    .layer(NewSentryLayer::new_from_top())
+                    .layer(SentryHttpLayer::with_transaction()),
+            )
+    });
+
+    Ok((state, router, internal))
+}
+
+impl Server {
+    pub fn state(&self) -> &Arc<FrontendState> {
+        &self.state
+    }
+
+    /// The fully layered router, e.g. to drive with `tower::ServiceExt::oneshot` in tests.
+    pub fn router(&self) -> &axum::Router {
+        &self.router
+    }
+
+    /// The internal listener's router, if there is one.
+    pub fn internal_router(&self) -> Option<&axum::Router> {
+        self.internal_router.as_ref()
+    }
+
+    /// Replace the router, e.g. to merge in extra routes or wrap it in more layers.
+    pub fn map_router(mut self, f: impl FnOnce(axum::Router) -> axum::Router) -> Self {
+        self.router = f(self.router);
+        self
+    }
+
+    /// Bind `config.bind` (or take over a systemd socket) and serve until `shutdown` completes
+    /// and in-flight requests have drained, or `drain_timeout` has passed. The internal listener,
+    /// if any, is served alongside, and shut down with it.
+    pub async fn run(self, shutdown: impl Future<Output = ()> + Send + 'static) -> Result<()> {
+        let listener = listener::listen(self.config.bind, self.config.reuse_port)?;
+        self.run_on(listener, shutdown).await
+    }
+
+    /// Like `run`, on an already bound listener (e.g. on port 0 in tests).
+    pub async fn run_on(
+        self,
+        listener: TcpListener,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<()> {
+        let shutdown = shutdown.shared();
+        if let (Some(bind), Some(router)) = (&self.config.internal_bind, self.internal_router) {
+            let internal = internal::serve(bind, self.config.reuse_port, router, shutdown.clone())?;
+            tokio::spawn(async move {
+                if let Err(e) = internal.await {
+                    event!(Level::ERROR, error = %e, "Internal listener failed");
+                }
+            });
+        }
+        let (drain_tx, drain_rx) = tokio::sync::oneshot::channel();
+        let incoming = hyper::server::conn::AddrIncoming::from_listener(
+            tokio::net::TcpListener::from_std(listener)?,
+        )?;
+        let limits = connections::ConnectionLimits::new(
+            self.config.max_connections,
+            self.config.max_connections_per_ip,
+            self.state.host.clone(),
+        )?;
+        let timeouts = self.config.client_timeouts;
+        let mut builder = self.config.http2.configure_server(axum::Server::builder(
+            client_io::TimedIncoming::new(
+                connections::LimitedIncoming::new(incoming, limits),
+                timeouts,
+            ),
+        ));
+        if let Some(header_timeout) = timeouts.header {
+            builder = builder.http1_header_read_timeout(header_timeout);
+        }
+        let server = builder
+            .serve(client_io::MakeService::new(self.router, timeouts))
+            .with_graceful_shutdown(async move {
+                shutdown.await;
+                let _ = drain_tx.send(());
+            });
+
+        let drain_timeout = self.config.drain_timeout;
+        tokio::select! {
+            result = server => Ok(result?),
+            _ = async {
+                let _ = drain_rx.await;
+                sleep(drain_timeout).await;
+            } => {
+                event!(
+                    Level::WARN,
+                    "Drain timeout reached, exiting with requests in flight"
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Turns a panic in the request path into a 500 carrying the trace id (rather than resetting the
+/// client connection), and reports it to Sentry tagged with the same id.
+fn panic_response(err: Box<dyn std::any::Any + Send + 'static>) -> axum::response::Response {
+    let message = if let Some(s) = err.downcast_ref::<String>() {
+        s.clone()
+    } else if let Some(s) = err.downcast_ref::<&str>() {
+        s.to_string()
+    } else {
+        "unknown panic payload".to_string()
+    };
+    let trace_id = tracing::Span::current()
+        .context()
+        .span()
+        .span_context()
+        .trace_id()
+        .to_string();
+
+    event!(Level::ERROR, trace_id = %trace_id, panic = %message, "Panic in request handler");
+    sentry::with_scope(
+        |scope| scope.set_tag("trace_id", &trace_id),
+        || sentry::capture_message(&format!("Panic: {}", message), sentry::Level::Fatal),
+    );
+
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        axum::Json(json!({
+            "error": "Internal server error",
+            "trace_id": trace_id,
+        })),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{Body, Request};
+    use tower::ServiceExt as _;
+
+    #[tokio::test]
+    async fn test_panic_response() {
+        let config = Config {
+            dev_backend: Some("127.0.0.1:9000".parse().unwrap()),
+            ..Config::default()
+        };
+        // With a function ID, like the invocation routes the access log wraps.
+        let routes = app().route(
+            "/panic/:function_id",
+            get(|| async { panic!("handler bug") }),
+        );
+        let (_, router, _) = build(&config, &Shared::default(), routes).await.unwrap();
+        let resp = router
+            .oneshot(
+                Request::get(format!("/panic/{}", uuid::Uuid::new_v4()))
+                    .extension(axum::extract::ConnectInfo(SocketAddr::from((
+                        [127, 0, 0, 1],
+                        4000,
+                    ))))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "Internal server error");
+        assert!(body["trace_id"].is_string());
+    }
+}
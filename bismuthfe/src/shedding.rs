@@ -0,0 +1,116 @@
+use axum::extract::State;
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse as _, Response};
+use hyper::Body;
+use opentelemetry::metrics::Counter;
+use opentelemetry::KeyValue;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use bismuth_common::Priority;
+
+use crate::priority;
+
+/// Rejects requests early once too many are already in flight, rather than letting
+/// latency collapse for everyone.
+/// Low priority requests (see `priority`) are shed from `low_watermark`, everything from
+/// `high_watermark`.
+pub struct LoadShedder {
+    in_flight: Arc<AtomicUsize>,
+    low_watermark: usize,
+    high_watermark: usize,
+    shed: Counter<u64>,
+}
+
+/// Counts a request as in flight until dropped.
+pub struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl LoadShedder {
+    pub fn new(low_watermark: usize, high_watermark: usize) -> anyhow::Result<Self> {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+        let shed = meter
+            .u64_counter("shed_requests")
+            .with_description("Requests rejected by load shedding")
+            .init();
+        let in_flight_gauge = meter
+            .u64_observable_gauge("in_flight_requests")
+            .with_description("Requests currently being handled")
+            .init();
+        let in_flight_ = in_flight.clone();
+        meter.register_callback(&[in_flight_gauge.as_any()], move |observer| {
+            observer.observe_u64(
+                &in_flight_gauge,
+                in_flight_.load(Ordering::Relaxed) as u64,
+                &[],
+            );
+        })?;
+
+        Ok(Self {
+            in_flight,
+            low_watermark,
+            high_watermark,
+            shed,
+        })
+    }
+
+    pub fn try_admit(&self, priority: Priority) -> Option<InFlight> {
+        let limit = match priority {
+            Priority::Low => self.low_watermark,
+            Priority::Normal | Priority::High => self.high_watermark,
+        };
+        let prev = self.in_flight.fetch_add(1, Ordering::Relaxed);
+        let guard = InFlight(self.in_flight.clone());
+        if prev >= limit {
+            self.shed
+                .add(1, &[KeyValue::new("priority", priority.as_str())]);
+            return None;
+        }
+        Some(guard)
+    }
+}
+
+/// Note this only covers the time until the response headers are ready, not streaming the body.
+pub async fn middleware(
+    State(shedder): State<Arc<LoadShedder>>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let Some(_in_flight) = shedder.try_admit(priority::of(&req)) else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(axum::http::header::RETRY_AFTER, "1")],
+        )
+            .into_response();
+    };
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shed_low_priority_first() {
+        let shedder = LoadShedder::new(1, 2).unwrap();
+
+        let first = shedder.try_admit(Priority::Normal);
+        assert!(first.is_some());
+        assert!(shedder.try_admit(Priority::Low).is_none());
+        let second = shedder.try_admit(Priority::Normal);
+        assert!(second.is_some());
+        assert!(shedder.try_admit(Priority::Normal).is_none());
+
+        drop(first);
+        drop(second);
+        assert!(shedder.try_admit(Priority::Low).is_some());
+    }
+}
@@ -0,0 +1,370 @@
+//! Verification of invocations signed by an upstream API gateway or CDN, for deployments where
+//! the frontend must only be reached through it. With `--request-signing-key`, invocations
+//! without a valid signature are rejected with a 401.
+//!
+//! The gateway signs `{timestamp}\n{nonce}\n{method}\n{path and query}` with HMAC-SHA256 or
+//! Ed25519 and sends:
+//! * `X-Bismuth-Timestamp`: Unix seconds, within `--request-signing-max-skew` of the frontend's
+//!   clock
+//! * `X-Bismuth-Nonce`: a unique value, which the frontend won't accept again while the timestamp
+//!   is valid
+//! * `X-Bismuth-Signature`: `{key id}:{base64 signature}`
+//!
+//! The body isn't signed, since it's streamed to the backend rather than buffered.
+
+use axum::extract::{OriginalUri, State};
+use axum::http::{HeaderMap, Method, Request, StatusCode, Uri};
+use axum::middleware::Next;
+use axum::response::{IntoResponse as _, Response};
+use base64::Engine as _;
+use hyper::Body;
+use opentelemetry::metrics::Counter;
+use opentelemetry::KeyValue;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::context::Principal;
+
+pub const SIGNATURE_HEADER: &str = "x-bismuth-signature";
+pub const TIMESTAMP_HEADER: &str = "x-bismuth-timestamp";
+pub const NONCE_HEADER: &str = "x-bismuth-nonce";
+
+/// Longest nonce accepted, so remembering them takes bounded memory per request.
+const MAX_NONCE_LEN: usize = 128;
+
+#[derive(Clone)]
+pub enum SigningKey {
+    Hmac(ring::hmac::Key),
+    /// A raw 32 byte public key.
+    Ed25519(Vec<u8>),
+}
+
+impl std::fmt::Debug for SigningKey {
+    /// Without the key material.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SigningKey::Hmac(_) => f.write_str("Hmac"),
+            SigningKey::Ed25519(_) => f.write_str("Ed25519"),
+        }
+    }
+}
+
+impl SigningKey {
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match self {
+            SigningKey::Hmac(key) => ring::hmac::verify(key, message, signature).is_ok(),
+            SigningKey::Ed25519(public_key) => {
+                ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, public_key)
+                    .verify(message, signature)
+                    .is_ok()
+            }
+        }
+    }
+}
+
+/// Parse a `--request-signing-key` of the form `ID=hmac:BASE64 SECRET` or
+/// `ID=ed25519:BASE64 PUBLIC KEY`.
+pub fn parse_key(s: &str) -> Result<(String, SigningKey), String> {
+    let (id, key) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected ID=ALGORITHM:KEY, got '{}'", s))?;
+    let (algorithm, key) = key
+        .split_once(':')
+        .ok_or_else(|| format!("Expected ALGORITHM:KEY for key {}", id))?;
+    let key = base64::engine::general_purpose::STANDARD
+        .decode(key)
+        .map_err(|e| format!("Invalid base64 for key {}: {}", id, e))?;
+    let key = match algorithm {
+        "hmac" => SigningKey::Hmac(ring::hmac::Key::new(ring::hmac::HMAC_SHA256, &key)),
+        "ed25519" if key.len() == 32 => SigningKey::Ed25519(key),
+        "ed25519" => return Err(format!("Ed25519 key {} isn't 32 bytes", id)),
+        _ => return Err(format!("Unknown algorithm {} for key {}", algorithm, id)),
+    };
+    Ok((id.to_string(), key))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Rejection {
+    Missing,
+    UnknownKey,
+    BadSignature,
+    Expired,
+    Replayed,
+}
+
+impl Rejection {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Rejection::Missing => "missing",
+            Rejection::UnknownKey => "unknown_key",
+            Rejection::BadSignature => "bad_signature",
+            Rejection::Expired => "expired",
+            Rejection::Replayed => "replayed",
+        }
+    }
+}
+
+struct Nonces {
+    /// Nonce, and the Unix second after which its timestamp is no longer accepted anyway.
+    seen: HashMap<String, u64>,
+    pruned_at: u64,
+}
+
+pub struct Verifier {
+    keys: HashMap<String, SigningKey>,
+    max_skew: u64,
+    nonces: Mutex<Nonces>,
+    rejected: Counter<u64>,
+}
+
+impl Verifier {
+    /// Accept signatures by any of `keys` (several, so keys can be rotated without downtime),
+    /// with timestamps up to `max_skew` from now.
+    pub fn new(keys: &[(String, SigningKey)], max_skew: Duration) -> Self {
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+        Self {
+            keys: keys.iter().cloned().collect(),
+            max_skew: max_skew.as_secs(),
+            nonces: Mutex::new(Nonces {
+                seen: HashMap::new(),
+                pruned_at: 0,
+            }),
+            rejected: meter
+                .u64_counter("unsigned_requests")
+                .with_description("Invocations rejected for a missing or invalid signature")
+                .init(),
+        }
+    }
+
+    /// Check a request's signature as of `now` (Unix seconds), and remember its nonce.
+    pub fn verify(
+        &self,
+        method: &Method,
+        uri: &Uri,
+        headers: &HeaderMap,
+        now: u64,
+    ) -> Result<(), Rejection> {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .ok_or(Rejection::Missing)
+        };
+        let (timestamp, nonce, signature) = (
+            header(TIMESTAMP_HEADER)?,
+            header(NONCE_HEADER)?,
+            header(SIGNATURE_HEADER)?,
+        );
+        let (key_id, signature) = signature.split_once(':').ok_or(Rejection::BadSignature)?;
+        let key = self.keys.get(key_id).ok_or(Rejection::UnknownKey)?;
+        let signature = base64::engine::general_purpose::STANDARD
+            .decode(signature)
+            .map_err(|_| Rejection::BadSignature)?;
+        if nonce.is_empty() || nonce.len() > MAX_NONCE_LEN {
+            return Err(Rejection::BadSignature);
+        }
+        let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+        let message = format!("{}\n{}\n{}\n{}", timestamp, nonce, method, path);
+        if !key.verify(message.as_bytes(), &signature) {
+            return Err(Rejection::BadSignature);
+        }
+
+        // Only checked once the signature is known to be good, so unsigned traffic can't fill up
+        // the nonces.
+        let timestamp: u64 = timestamp.parse().map_err(|_| Rejection::BadSignature)?;
+        if timestamp.abs_diff(now) > self.max_skew {
+            return Err(Rejection::Expired);
+        }
+        let mut nonces = self.nonces.lock().unwrap();
+        if now > nonces.pruned_at + self.max_skew {
+            nonces.seen.retain(|_, expires| *expires >= now);
+            nonces.pruned_at = now;
+        }
+        match nonces.seen.get(nonce) {
+            Some(expires) if *expires >= now => Err(Rejection::Replayed),
+            _ => {
+                nonces
+                    .seen
+                    .insert(nonce.to_string(), timestamp + self.max_skew);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Route layer for the invocation routes.
+pub async fn middleware(
+    State(verifier): State<Arc<Verifier>>,
+    mut req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    // As the client sent it, with any `/env/{name}` prefix that nesting stripped from `uri()`.
+    let uri = match req.extensions().get::<OriginalUri>() {
+        Some(OriginalUri(uri)) => uri,
+        None => req.uri(),
+    };
+    if let Err(rejection) = verifier.verify(req.method(), uri, req.headers(), now) {
+        verifier
+            .rejected
+            .add(1, &[KeyValue::new("reason", rejection.as_str())]);
+        return (StatusCode::UNAUTHORIZED, "Invalid request signature").into_response();
+    }
+    // Inside tenant tokens, whose principal is the more specific.
+    if req.extensions().get::<Principal>().is_none() {
+        let key_id = req
+            .headers()
+            .get(SIGNATURE_HEADER)
+            .and_then(|v| v.to_str().ok()?.split_once(':'))
+            .map(|(key_id, _)| format!("signature:{}", key_id));
+        if let Some(key_id) = key_id {
+            req.extensions_mut().insert(Principal(key_id));
+        }
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::KeyPair as _;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn signed(sign: impl Fn(&[u8]) -> Vec<u8>, key_id: &str, ts: u64, nonce: &str) -> HeaderMap {
+        signed_path(sign, key_id, ts, nonce, "/invoke/f/run?x=1")
+    }
+
+    fn signed_path(
+        sign: impl Fn(&[u8]) -> Vec<u8>,
+        key_id: &str,
+        ts: u64,
+        nonce: &str,
+        path: &str,
+    ) -> HeaderMap {
+        let message = format!("{}\n{}\nPOST\n{}", ts, nonce, path);
+        let signature = base64::engine::general_purpose::STANDARD.encode(sign(message.as_bytes()));
+        let mut headers = HeaderMap::new();
+        headers.insert(TIMESTAMP_HEADER, ts.to_string().parse().unwrap());
+        headers.insert(NONCE_HEADER, nonce.parse().unwrap());
+        headers.insert(
+            SIGNATURE_HEADER,
+            format!("{}:{}", key_id, signature).parse().unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_hmac() {
+        let (id, key) = parse_key("gw=hmac:c2VjcmV0").unwrap();
+        let verifier = Verifier::new(&[(id, key)], Duration::from_secs(300));
+        let hmac = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, b"secret");
+        let sign = |m: &[u8]| ring::hmac::sign(&hmac, m).as_ref().to_vec();
+        let uri: Uri = "/invoke/f/run?x=1".parse().unwrap();
+        let verify = |headers: &HeaderMap| verifier.verify(&Method::POST, &uri, headers, NOW);
+
+        assert_eq!(verify(&HeaderMap::new()), Err(Rejection::Missing));
+        let headers = signed(sign, "gw", NOW - 10, "a");
+        assert_eq!(verify(&headers), Ok(()));
+        assert_eq!(verify(&headers), Err(Rejection::Replayed));
+        assert_eq!(
+            verifier.verify(&Method::GET, &uri, &signed(sign, "gw", NOW, "b"), NOW),
+            Err(Rejection::BadSignature)
+        );
+        assert_eq!(
+            verify(&signed(sign, "other", NOW, "c")),
+            Err(Rejection::UnknownKey)
+        );
+        assert_eq!(
+            verify(&signed(sign, "gw", NOW - 301, "d")),
+            Err(Rejection::Expired)
+        );
+    }
+
+    #[test]
+    fn test_ed25519() {
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = ring::signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let pair = ring::signature::Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let public_key =
+            base64::engine::general_purpose::STANDARD.encode(pair.public_key().as_ref());
+        let (id, key) = parse_key(&format!("cdn=ed25519:{}", public_key)).unwrap();
+        let verifier = Verifier::new(&[(id, key)], Duration::from_secs(300));
+        let sign = |m: &[u8]| pair.sign(m).as_ref().to_vec();
+        let uri: Uri = "/invoke/f/run?x=1".parse().unwrap();
+        assert_eq!(
+            verifier.verify(&Method::POST, &uri, &signed(sign, "cdn", NOW, "a"), NOW),
+            Ok(())
+        );
+        assert!(parse_key("cdn=ed25519:c2VjcmV0").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_nested_environment() {
+        use tower::ServiceExt as _;
+
+        let (id, key) = parse_key("gw=hmac:c2VjcmV0").unwrap();
+        let verifier = Arc::new(Verifier::new(&[(id, key)], Duration::from_secs(300)));
+        let hmac = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, b"secret");
+        let sign = |m: &[u8]| ring::hmac::sign(&hmac, m).as_ref().to_vec();
+        let env = |verifier: &Arc<Verifier>| {
+            axum::Router::new()
+                .route(
+                    "/invoke/:function_id/*path",
+                    axum::routing::post(|| async { "ok" }),
+                )
+                .route_layer(axum::middleware::from_fn_with_state(
+                    verifier.clone(),
+                    middleware,
+                ))
+        };
+        // As server.rs nests an `--extra-zookeeper-env`'s routes, sharing the verifier.
+        let app = env(&verifier)
+            .nest("/env/staging", env(&verifier))
+            .nest("/env/prod", env(&verifier));
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let post = |path: &str, headers: &HeaderMap| {
+            let mut req = Request::post(path).body(Body::empty()).unwrap();
+            *req.headers_mut() = headers.clone();
+            req
+        };
+        let status = |req: Request<Body>| {
+            let app = app.clone();
+            async move { app.oneshot(req).await.unwrap().status() }
+        };
+
+        let headers = signed_path(sign, "gw", now, "a", "/env/staging/invoke/f/run?x=1");
+        assert_eq!(
+            status(post("/env/staging/invoke/f/run?x=1", &headers)).await,
+            StatusCode::OK
+        );
+        // Signed for staging, so not valid anywhere else.
+        let headers = signed_path(sign, "gw", now, "b", "/env/staging/invoke/f/run?x=1");
+        assert_eq!(
+            status(post("/env/prod/invoke/f/run?x=1", &headers)).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(post("/invoke/f/run?x=1", &headers)).await,
+            StatusCode::UNAUTHORIZED
+        );
+        // One nonce store across environments, so a nonce used in one isn't accepted in another.
+        let headers = signed(sign, "gw", now, "c");
+        assert_eq!(
+            status(post("/invoke/f/run?x=1", &headers)).await,
+            StatusCode::OK
+        );
+        let headers = signed_path(sign, "gw", now, "c", "/env/prod/invoke/f/run?x=1");
+        assert_eq!(
+            status(post("/env/prod/invoke/f/run?x=1", &headers)).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+}
@@ -0,0 +1,403 @@
+//! Collapses concurrent duplicate invocations into one upstream request, whose response is
+//! buffered and handed to every caller: retries of unsafe invocations with the same
+//! `Idempotency-Key`, and, for functions with `coalesce_gets`, identical GETs.
+
+use axum::http::{header, HeaderMap, Method, Request, StatusCode, Version};
+use hyper::body::{Body, Bytes};
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+use bismuth_common::{ApiError, GenericError};
+
+use crate::buffers::BufferPool;
+use crate::context::Principal;
+use crate::tokens::Tenant;
+
+/// Feature flag for coalescing, on by default.
+pub const FLAG: &str = "idempotency_coalescing";
+
+/// The header clients set to mark retries of the same operation.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Largest GET response shared between callers, in bytes.
+pub const MAX_SHARED_BYTES: u64 = 1024 * 1024;
+
+/// Request headers that can change a GET's response, so are part of its key.
+const VARY_HEADERS: [header::HeaderName; 4] = [
+    header::ACCEPT,
+    header::ACCEPT_ENCODING,
+    header::ACCEPT_LANGUAGE,
+    header::RANGE,
+];
+
+/// The key for coalescing `req` with identical GETs of the same function, unless it's not a GET
+/// or its response may be meant for its client alone.
+pub fn get_key<B>(req: &Request<B>) -> Option<String> {
+    let headers = req.headers();
+    if req.method() != Method::GET
+        || headers.contains_key(header::AUTHORIZATION)
+        || headers.contains_key(header::COOKIE)
+        || has_directive(headers, header::CACHE_CONTROL, &["no-cache", "no-store"])
+    {
+        return None;
+    }
+    let mut key = req.uri().to_string();
+    push_caller(&mut key, req);
+    for name in VARY_HEADERS {
+        for value in headers.get_all(&name) {
+            key.push_str(&format!(
+                "\n{name}: {}",
+                String::from_utf8_lossy(value.as_bytes())
+            ));
+        }
+    }
+    Some(key)
+}
+
+/// The key for coalescing `req` with retries of the same operation: its `Idempotency-Key`, which
+/// clients choose themselves, so scoped to the caller like `get_key` is.
+pub fn idempotency_key<B>(req: &Request<B>) -> Option<String> {
+    let mut key = req
+        .headers()
+        .get(IDEMPOTENCY_KEY_HEADER)?
+        .to_str()
+        .ok()?
+        .to_string();
+    push_caller(&mut key, req);
+    Some(key)
+}
+
+/// Add who `req` is from, its tenant and principal, to `key`.
+fn push_caller<B>(key: &mut String, req: &Request<B>) {
+    for (name, value) in [
+        (
+            "tenant",
+            req.extensions().get::<Tenant>().map(|t| t.0.as_str()),
+        ),
+        (
+            "principal",
+            req.extensions().get::<Principal>().map(|p| p.0.as_str()),
+        ),
+    ] {
+        if let Some(value) = value {
+            key.push_str(&format!("\n{name}: {value}"));
+        }
+    }
+}
+
+/// Whether a GET's response can be handed to other callers: it's not marked as private or
+/// setting a cookie, and small enough to buffer.
+pub fn shareable(resp: &axum::response::Response<Body>) -> bool {
+    let headers = resp.headers();
+    !headers.contains_key(header::SET_COOKIE)
+        && !has_directive(headers, header::CACHE_CONTROL, &["private", "no-store"])
+        && headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse::<u64>().ok())
+            .is_some_and(|len| len <= MAX_SHARED_BYTES)
+}
+
+fn has_directive(headers: &HeaderMap, name: header::HeaderName, directives: &[&str]) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|d| d.split('=').next().unwrap_or_default().trim())
+        .any(|d| {
+            directives
+                .iter()
+                .any(|wanted| d.eq_ignore_ascii_case(wanted))
+        })
+}
+
+struct SharedResponse {
+    status: StatusCode,
+    version: Version,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl SharedResponse {
+    fn to_response(&self) -> axum::response::Response<Body> {
+        let mut resp = axum::response::Response::new(Body::from(self.body.clone()));
+        *resp.status_mut() = self.status;
+        *resp.version_mut() = self.version;
+        *resp.headers_mut() = self.headers.clone();
+        resp
+    }
+}
+
+#[derive(Clone)]
+enum Shared {
+    Response(Arc<SharedResponse>),
+    Failed,
+    /// The first call's response wasn't for sharing, so each caller makes its own.
+    Unshareable,
+}
+
+/// `None` while the first call is in flight.
+type Outcome = Option<Shared>;
+
+pub struct SingleFlight<K> {
+    calls: Mutex<HashMap<K, watch::Receiver<Outcome>>>,
+}
+
+/// Forgets the first call's key once it's done or cancelled, so later calls start afresh.
+struct Leader<'a, K: Eq + Hash> {
+    calls: &'a Mutex<HashMap<K, watch::Receiver<Outcome>>>,
+    key: K,
+}
+
+impl<K: Eq + Hash> Drop for Leader<'_, K> {
+    fn drop(&mut self) {
+        self.calls.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl<K: Eq + Hash + Clone> Default for SingleFlight<K> {
+    fn default() -> Self {
+        Self {
+            calls: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone> SingleFlight<K> {
+    /// Run `call` unless one with the same key is already in flight, in which case wait for its
+    /// response instead. Waiters get a 503 if that call fails or its client goes away, and run
+    /// their own `call` if its response isn't `shareable`, which is then passed on unbuffered.
+    pub async fn run<F>(
+        &self,
+        key: K,
+        buffers: &BufferPool,
+        shareable: impl FnOnce(&axum::response::Response<Body>) -> bool,
+        call: F,
+    ) -> Result<axum::response::Response<Body>, ApiError>
+    where
+        F: Future<Output = Result<axum::response::Response<Body>, ApiError>>,
+    {
+        let (tx, mut rx) = {
+            let mut calls = self.calls.lock().unwrap();
+            match calls.get(&key) {
+                Some(rx) => (None, rx.clone()),
+                None => {
+                    let (tx, rx) = watch::channel(None);
+                    calls.insert(key.clone(), rx.clone());
+                    (Some(tx), rx)
+                }
+            }
+        };
+
+        let Some(tx) = tx else {
+            let outcome = match rx.wait_for(|outcome| outcome.is_some()).await {
+                Ok(outcome) => outcome.clone(),
+                // The first call was cancelled.
+                Err(_) => None,
+            };
+            return match outcome {
+                Some(Shared::Response(resp)) => Ok(resp.to_response()),
+                Some(Shared::Unshareable) => call.await,
+                _ => Err(GenericError::Unavailable.into()),
+            };
+        };
+
+        let _leader = Leader {
+            calls: &self.calls,
+            key,
+        };
+        let resp = match call.await {
+            Ok(resp) if !shareable(&resp) => {
+                let _ = tx.send(Some(Shared::Unshareable));
+                return Ok(resp);
+            }
+            Ok(resp) => resp,
+            Err(e) => {
+                let _ = tx.send(Some(Shared::Failed));
+                return Err(e);
+            }
+        };
+        let (parts, body) = resp.into_parts();
+        let body = match buffers.collect(body).await {
+            Ok(body) => body,
+            Err(e) => {
+                let _ = tx.send(Some(Shared::Failed));
+                return Err(e.into());
+            }
+        };
+        let resp = Arc::new(SharedResponse {
+            status: parts.status,
+            version: parts.version,
+            headers: parts.headers,
+            body,
+        });
+        let _ = tx.send(Some(Shared::Response(resp.clone())));
+        Ok(resp.to_response())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_coalesces_concurrent_calls() {
+        let flight = Arc::new(SingleFlight::default());
+        let buffers = Arc::new(BufferPool::new().unwrap());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let tasks: Vec<_> = (0..5)
+            .map(|_| {
+                let flight = flight.clone();
+                let calls = calls.clone();
+                let buffers = buffers.clone();
+                tokio::spawn(async move {
+                    let resp = flight
+                        .run("key", &buffers, |_| true, async {
+                            calls.fetch_add(1, Ordering::SeqCst);
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                            Ok(axum::response::Response::new(Body::from("hello")))
+                        })
+                        .await
+                        .unwrap_or_else(|_| panic!("call failed"));
+                    hyper::body::to_bytes(resp.into_body()).await.unwrap()
+                })
+            })
+            .collect();
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), "hello");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Finished calls aren't remembered.
+        flight
+            .run("key", &buffers, |_| true, async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Ok(axum::response::Response::new(Body::empty()))
+            })
+            .await
+            .unwrap_or_else(|_| panic!("call failed"));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_unshareable_responses() {
+        let flight = Arc::new(SingleFlight::default());
+        let buffers = Arc::new(BufferPool::new().unwrap());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let tasks: Vec<_> = (0..3)
+            .map(|_| {
+                let flight = flight.clone();
+                let calls = calls.clone();
+                let buffers = buffers.clone();
+                tokio::spawn(async move {
+                    flight
+                        .run("key", &buffers, shareable, async {
+                            calls.fetch_add(1, Ordering::SeqCst);
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                            // No Content-Length.
+                            Ok(axum::response::Response::new(Body::from("hello")))
+                        })
+                        .await
+                        .unwrap_or_else(|_| panic!("call failed"));
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_get_key() {
+        let get = |headers: &[(&str, &str)]| {
+            let mut req = Request::get("/invoke/f/path?q=1");
+            for (name, value) in headers {
+                req = req.header(*name, *value);
+            }
+            req.body(()).unwrap()
+        };
+        let key = get_key(&get(&[])).unwrap();
+        assert_eq!(key, "/invoke/f/path?q=1");
+        assert_eq!(get_key(&get(&[("x-request-id", "1")])).unwrap(), key);
+        assert_ne!(get_key(&get(&[("accept", "text/html")])).unwrap(), key);
+        assert_eq!(get_key(&get(&[("authorization", "Bearer x")])), None);
+        assert_eq!(get_key(&get(&[("cookie", "session=1")])), None);
+        assert_eq!(
+            get_key(&get(&[("cache-control", "max-age=0, no-cache")])),
+            None
+        );
+        let post = Request::post("/invoke/f/path").body(()).unwrap();
+        assert_eq!(get_key(&post), None);
+
+        let mut req = get(&[]);
+        req.extensions_mut().insert(Tenant("acme".to_string()));
+        assert_eq!(get_key(&req).unwrap(), "/invoke/f/path?q=1\ntenant: acme");
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_key_per_tenant() {
+        let flight = Arc::new(SingleFlight::default());
+        let buffers = Arc::new(BufferPool::new().unwrap());
+        let tasks: Vec<_> = ["acme", "globex"]
+            .into_iter()
+            .map(|tenant| {
+                let mut req = Request::post("/invoke/f/path")
+                    .header(IDEMPOTENCY_KEY_HEADER, "1")
+                    .body(())
+                    .unwrap();
+                req.extensions_mut().insert(Tenant(tenant.to_string()));
+                let key = idempotency_key(&req).unwrap();
+                let flight = flight.clone();
+                let buffers = buffers.clone();
+                tokio::spawn(async move {
+                    let resp = flight
+                        .run(key, &buffers, |_| true, async {
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                            Ok(axum::response::Response::new(Body::from(tenant)))
+                        })
+                        .await
+                        .unwrap_or_else(|_| panic!("call failed"));
+                    (
+                        tenant,
+                        hyper::body::to_bytes(resp.into_body()).await.unwrap(),
+                    )
+                })
+            })
+            .collect();
+        for task in tasks {
+            let (tenant, body) = task.await.unwrap();
+            assert_eq!(body, tenant);
+        }
+
+        let req = Request::post("/").body(()).unwrap();
+        assert_eq!(idempotency_key(&req), None);
+    }
+
+    #[test]
+    fn test_shareable() {
+        let resp = |headers: &[(&str, &str)]| {
+            let mut resp = axum::response::Response::builder();
+            for (name, value) in headers {
+                resp = resp.header(*name, *value);
+            }
+            resp.body(Body::empty()).unwrap()
+        };
+        assert!(shareable(&resp(&[("content-length", "5")])));
+        assert!(!shareable(&resp(&[])));
+        assert!(!shareable(&resp(&[("content-length", "2000000")])));
+        assert!(!shareable(&resp(&[
+            ("content-length", "5"),
+            ("cache-control", "Private")
+        ])));
+        assert!(!shareable(&resp(&[
+            ("content-length", "5"),
+            ("set-cookie", "session=1")
+        ])));
+    }
+}
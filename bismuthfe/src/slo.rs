@@ -0,0 +1,380 @@
+//! SLO tracking for functions with `slo` in their config: each frontend counts the function's
+//! invocations by minute, as the client saw them (including those that never reached a backend),
+//! and works out, over the SLO's window, the fraction that met each objective, and over the last
+//! 5 minutes, hour and the whole window, the error budget burn rate: the fraction that missed it
+//! over the fraction allowed to, so 1 spends the budget exactly by the window's end. Both are
+//! exported as `slo_compliance` and `slo_burn_rate` gauges and served at
+//! `GET /admin/function/{function_id}/slo`.
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use opentelemetry::KeyValue;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{event, Level};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use bismuth_common::{ApiError, Slo};
+
+use crate::{metric_labels, FrontendState};
+
+const BUCKET_SECS: u64 = 60;
+/// Longest window kept, whatever the SLO says.
+const MAX_WINDOW_SECS: u64 = 86400;
+/// Short windows burn rates are given over, besides the SLO's own.
+const BURN_RATE_WINDOWS_SECS: [u64; 2] = [300, 3600];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Counts {
+    total: u64,
+    /// Not a 5xx.
+    available: u64,
+    /// Within the latency objective's threshold.
+    fast: u64,
+}
+
+impl Counts {
+    fn add(&mut self, other: &Counts) {
+        self.total += other.total;
+        self.available += other.available;
+        self.fast += other.fast;
+    }
+}
+
+struct FunctionSlo {
+    slo: Slo,
+    /// Minutes with invocations, oldest first.
+    buckets: VecDeque<(u64, Counts)>,
+}
+
+impl FunctionSlo {
+    fn window_secs(&self) -> u64 {
+        self.slo.window_secs.clamp(BUCKET_SECS, MAX_WINDOW_SECS)
+    }
+
+    /// Invocations in the `secs` up to `now`.
+    fn counts(&self, secs: u64, now: u64) -> Counts {
+        let since = now.saturating_sub(secs) / BUCKET_SECS;
+        let mut counts = Counts::default();
+        for (_, bucket) in self.buckets.iter().filter(|(minute, _)| *minute > since) {
+            counts.add(bucket);
+        }
+        counts
+    }
+
+    fn status(&self, now: u64) -> SloStatus {
+        let window_secs = self.window_secs();
+        let windows = BURN_RATE_WINDOWS_SECS
+            .into_iter()
+            .filter(|secs| *secs < window_secs)
+            .chain([window_secs])
+            .map(|secs| (secs, self.counts(secs, now)))
+            .collect::<Vec<_>>();
+        let objectives: [(&'static str, Option<f64>, fn(&Counts) -> u64); 2] = [
+            ("availability", self.slo.availability, |c| c.available),
+            (
+                "latency",
+                self.slo.latency.as_ref().map(|l| l.target),
+                |c| c.fast,
+            ),
+        ];
+        let objectives = objectives
+            .into_iter()
+            .filter_map(|(objective, target, good)| {
+                let target = target?;
+                let (_, window) = windows.last()?;
+                let fraction = |counts: &Counts| {
+                    (counts.total > 0).then(|| good(counts) as f64 / counts.total as f64)
+                };
+                Some(ObjectiveStatus {
+                    objective: objective.to_string(),
+                    target,
+                    invocations: window.total,
+                    compliance: fraction(window),
+                    burn_rates: windows
+                        .iter()
+                        .map(|(secs, counts)| BurnRate {
+                            window_secs: *secs,
+                            burn_rate: fraction(counts)
+                                .map(|met| (1.0 - met) / (1.0 - target).max(f64::EPSILON)),
+                        })
+                        .collect(),
+                })
+            })
+            .collect();
+        SloStatus {
+            window_secs,
+            objectives,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq, ToSchema)]
+pub struct SloStatus {
+    pub window_secs: u64,
+    pub objectives: Vec<ObjectiveStatus>,
+}
+
+#[derive(Serialize, Debug, PartialEq, ToSchema)]
+pub struct ObjectiveStatus {
+    /// `availability` or `latency`.
+    pub objective: String,
+    pub target: f64,
+    /// Over the SLO's window.
+    pub invocations: u64,
+    /// Fraction of invocations over the SLO's window that met the objective, if there were any.
+    pub compliance: Option<f64>,
+    pub burn_rates: Vec<BurnRate>,
+}
+
+#[derive(Serialize, Debug, PartialEq, ToSchema)]
+pub struct BurnRate {
+    pub window_secs: u64,
+    /// `None` without invocations in the window.
+    pub burn_rate: Option<f64>,
+}
+
+#[derive(Default)]
+pub struct SloTracker {
+    functions: Mutex<HashMap<Uuid, FunctionSlo>>,
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl SloTracker {
+    pub fn new() -> Arc<Self> {
+        let tracker = Arc::new(Self::default());
+        if let Err(e) = Self::register_metrics(&tracker) {
+            event!(Level::ERROR, error = %e, "Error registering SLO metrics");
+        }
+        tracker
+    }
+
+    fn register_metrics(tracker: &Arc<Self>) -> anyhow::Result<()> {
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+        let compliance = meter
+            .f64_observable_gauge("slo_compliance")
+            .with_description(
+                "Fraction of a function's invocations over its SLO window that met the objective",
+            )
+            .init();
+        let burn_rate = meter
+            .f64_observable_gauge("slo_burn_rate")
+            .with_description(
+                "Rate a function is spending its error budget at over a window, 1 spending it exactly",
+            )
+            .init();
+        let tracker = Arc::downgrade(tracker);
+        meter.register_callback(
+            &[compliance.as_any(), burn_rate.as_any()],
+            move |observer| {
+                let Some(tracker) = tracker.upgrade() else {
+                    return;
+                };
+                for (function_id, status) in tracker.statuses(unix_now()) {
+                    let Some(function) = metric_labels::own_function(&function_id) else {
+                        continue;
+                    };
+                    for objective in status.objectives {
+                        let attrs = [
+                            function.clone(),
+                            KeyValue::new("objective", objective.objective.clone()),
+                        ];
+                        if let Some(value) = objective.compliance {
+                            observer.observe_f64(&compliance, value, &attrs);
+                        }
+                        for rate in objective.burn_rates {
+                            if let Some(value) = rate.burn_rate {
+                                let mut attrs = attrs.to_vec();
+                                attrs.push(KeyValue::new(
+                                    "window",
+                                    format!("{}s", rate.window_secs),
+                                ));
+                                observer.observe_f64(&burn_rate, value, &attrs);
+                            }
+                        }
+                    }
+                }
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Count an invocation of a function with `slo`, `latency` being until its response headers.
+    pub fn record(
+        &self,
+        function_id: Uuid,
+        slo: &Slo,
+        status: StatusCode,
+        latency: Duration,
+        now: u64,
+    ) {
+        let mut functions = self.functions.lock().unwrap();
+        let function = functions.entry(function_id).or_insert_with(|| FunctionSlo {
+            slo: slo.clone(),
+            buckets: VecDeque::new(),
+        });
+        if function.slo != *slo {
+            function.slo = slo.clone();
+        }
+        let minute = now / BUCKET_SECS;
+        if function.buckets.back().map(|(m, _)| *m) != Some(minute) {
+            function.buckets.push_back((minute, Counts::default()));
+        }
+        let counts = &mut function.buckets.back_mut().unwrap().1;
+        counts.total += 1;
+        if !status.is_server_error() {
+            counts.available += 1;
+        }
+        if slo
+            .latency
+            .as_ref()
+            .is_some_and(|l| latency <= Duration::from_millis(l.threshold_ms))
+        {
+            counts.fast += 1;
+        }
+        let oldest = minute.saturating_sub(function.window_secs() / BUCKET_SECS);
+        while function.buckets.front().is_some_and(|(m, _)| *m <= oldest) {
+            function.buckets.pop_front();
+        }
+    }
+
+    /// Every tracked function's status, forgetting those without invocations in their window
+    /// (e.g. since their SLO was removed).
+    fn statuses(&self, now: u64) -> Vec<(Uuid, SloStatus)> {
+        let mut functions = self.functions.lock().unwrap();
+        functions.retain(|_, function| {
+            function.buckets.back().is_some_and(|(minute, _)| {
+                (minute + 1) * BUCKET_SECS + function.window_secs() > now
+            })
+        });
+        functions
+            .iter()
+            .map(|(id, function)| (*id, function.status(now)))
+            .collect()
+    }
+
+    pub fn status(&self, function_id: &Uuid, now: u64) -> Option<SloStatus> {
+        let functions = self.functions.lock().unwrap();
+        functions
+            .get(function_id)
+            .map(|function| function.status(now))
+    }
+}
+
+/// The function's compliance with its SLO and burn rates, as seen by this frontend.
+#[utoipa::path(
+    get,
+    path = "/admin/function/{function_id}/slo",
+    tag = "admin",
+    params(("function_id" = Uuid, Path, description = "Function ID")),
+    responses(
+        (status = 200, body = SloStatus),
+        (status = 404, description = "No SLO, or no invocations of it tracked"),
+    ),
+    security(("admin_token" = []))
+)]
+pub async fn slo_handler(
+    State(state): State<Arc<FrontendState>>,
+    Path(function_id): Path<Uuid>,
+) -> Result<Json<SloStatus>, ApiError> {
+    Ok(Json(
+        state
+            .slo
+            .status(&function_id, unix_now())
+            .ok_or(ApiError::NotFound)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bismuth_common::LatencyObjective;
+
+    fn slo() -> Slo {
+        Slo {
+            availability: Some(0.99),
+            latency: Some(LatencyObjective {
+                target: 0.9,
+                threshold_ms: 100,
+            }),
+            window_secs: 3600,
+        }
+    }
+
+    #[test]
+    fn test_compliance_and_burn_rates() {
+        let tracker = SloTracker::default();
+        let function_id = Uuid::new_v4();
+        let now = 100 * 3600;
+        let fast = Duration::from_millis(50);
+        // An hour ago, all good.
+        for _ in 0..100 {
+            tracker.record(function_id, &slo(), StatusCode::OK, fast, now - 3000);
+        }
+        // In the last 5 minutes, 10% errors and 20% slow.
+        for i in 0..100 {
+            let status = if i < 10 {
+                StatusCode::BAD_GATEWAY
+            } else {
+                StatusCode::OK
+            };
+            let latency = if i < 20 {
+                Duration::from_millis(200)
+            } else {
+                fast
+            };
+            tracker.record(function_id, &slo(), status, latency, now - 60);
+        }
+
+        let status = tracker.status(&function_id, now).unwrap();
+        assert_eq!(status.window_secs, 3600);
+        let availability = &status.objectives[0];
+        assert_eq!(availability.objective, "availability");
+        assert_eq!(availability.invocations, 200);
+        assert_eq!(availability.compliance, Some(0.95));
+        let windows = availability
+            .burn_rates
+            .iter()
+            .map(|b| b.window_secs)
+            .collect::<Vec<_>>();
+        assert_eq!(windows, [300, 3600]);
+        let burn = |i: usize| availability.burn_rates[i].burn_rate.unwrap();
+        assert!((burn(0) - 10.0).abs() < 1e-9);
+        assert!((burn(1) - 5.0).abs() < 1e-9);
+        let latency = &status.objectives[1];
+        assert_eq!(latency.compliance, Some(0.9));
+        assert!((latency.burn_rates[0].burn_rate.unwrap() - 2.0).abs() < 1e-9);
+
+        // The good hour falls out of the window.
+        let later = tracker.status(&function_id, now + 600).unwrap();
+        assert_eq!(later.objectives[0].invocations, 100);
+        assert_eq!(later.objectives[0].burn_rates[0].burn_rate, None);
+        assert!(tracker.status(&Uuid::new_v4(), now).is_none());
+    }
+
+    #[test]
+    fn test_forgets_old_functions() {
+        let tracker = SloTracker::default();
+        let function_id = Uuid::new_v4();
+        let slo = Slo {
+            latency: None,
+            ..slo()
+        };
+        tracker.record(function_id, &slo, StatusCode::OK, Duration::ZERO, 1000);
+        let statuses = tracker.statuses(1000);
+        assert_eq!(statuses.len(), 1);
+        assert_eq!(statuses[0].1.objectives.len(), 1);
+        assert!(tracker.statuses(1000 + 3600 + BUCKET_SECS).is_empty());
+        assert!(tracker.status(&function_id, 1000).is_none());
+    }
+}
@@ -0,0 +1,207 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tracing::{event, Level};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use bismuth_common::{
+    is_chunked, pack_backends, prepare_backends, read_backends, unpack_backends, Backend,
+    FunctionConfig, FunctionDefinition, FunctionMetadata, BACKENDS_PER_CHUNK,
+};
+
+/// Everything the routing layer reads from ZooKeeper, in a form that can be saved and restored.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct Snapshot {
+    #[serde(default)]
+    pub functions: BTreeMap<Uuid, FunctionSnapshot>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct FunctionSnapshot {
+    pub definition: FunctionDefinition,
+    #[serde(default)]
+    pub backends: Vec<Backend>,
+    #[serde(default)]
+    pub metadata: Option<FunctionMetadata>,
+    #[serde(default)]
+    pub config: Option<FunctionConfig>,
+}
+
+/// Read the current state of every function.
+/// The reads are done in a single multi-read, so the snapshot is consistent, except for backend
+/// lists too long for one znode: their chunks are read after, so may be from a later list.
+pub async fn export(zk: &zookeeper_client::Client) -> Result<Snapshot> {
+    let function_ids = zk
+        .list_children("/function")
+        .await
+        .context("Error listing functions")?
+        .iter()
+        .map(|f| Uuid::parse_str(f))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut reader = zk.new_multi_reader();
+    for function_id in &function_ids {
+        reader.add_get_data(&format!("/function/{}", function_id))?;
+        reader.add_get_data(&format!("/function/{}/backends", function_id))?;
+        reader.add_get_data(&format!("/function/{}/metadata", function_id))?;
+        reader.add_get_data(&format!("/function/{}/config", function_id))?;
+    }
+    let results = reader
+        .commit()
+        .await
+        .context("Error reading function data")?;
+
+    let mut snapshot = Snapshot::default();
+    for (function_id, results) in function_ids.iter().zip(results.chunks(4)) {
+        let (
+            zookeeper_client::MultiReadResult::Data {
+                data: definition, ..
+            },
+            zookeeper_client::MultiReadResult::Data { data: backends, .. },
+        ) = (&results[0], &results[1])
+        else {
+            // Deleted between listing and reading.
+            event!(Level::DEBUG, function = %function_id, "Function disappeared during export");
+            continue;
+        };
+        let metadata = match &results[2] {
+            zookeeper_client::MultiReadResult::Data { data, .. } => Some(
+                serde_json::from_slice(data)
+                    .with_context(|| format!("Invalid metadata for function {}", function_id))?,
+            ),
+            _ => None,
+        };
+        let config = match &results[3] {
+            zookeeper_client::MultiReadResult::Data { data, .. } => Some(
+                serde_json::from_slice(data)
+                    .with_context(|| format!("Invalid config for function {}", function_id))?,
+            ),
+            _ => None,
+        };
+        snapshot.functions.insert(
+            *function_id,
+            FunctionSnapshot {
+                definition: serde_json::from_slice(definition)
+                    .with_context(|| format!("Invalid definition for function {}", function_id))?,
+                backends: if is_chunked(backends) {
+                    read_backends(zk, function_id).await?.0
+                } else {
+                    unpack_backends(backends)?
+                },
+                metadata,
+                config,
+            },
+        );
+    }
+
+    Ok(snapshot)
+}
+
+/// Write a snapshot back to ZooKeeper, creating or overwriting each function in it.
+/// Functions not in the snapshot are left alone.
+/// Metadata and config are only written if the snapshot has them.
+/// Only the routing data is restored: container znodes on nodes are the scheduler's responsibility.
+pub async fn import(zk: &zookeeper_client::Client, snapshot: &Snapshot) -> Result<()> {
+    for (function_id, function) in &snapshot.functions {
+        let function_key = format!("/function/{}", function_id);
+        let backends_key = format!("{}/backends", function_key);
+        let definition = serde_json::to_vec(&function.definition)?;
+        let metadata_key = format!("{}/metadata", function_key);
+        let config_key = format!("{}/config", function_key);
+
+        let mut function_exists = zk.check_stat(&function_key).await?.is_some();
+        let mut backends_exist = function_exists && zk.check_stat(&backends_key).await?.is_some();
+        if !backends_exist && function.backends.len() > BACKENDS_PER_CHUNK {
+            // Chunks are the backends znode's children, so it has to exist before they're written.
+            let mut multi = zk.new_multi_writer();
+            if !function_exists {
+                multi.add_create(
+                    &function_key,
+                    &definition,
+                    &zookeeper_client::CreateMode::Persistent
+                        .with_acls(zookeeper_client::Acls::anyone_all()),
+                )?;
+            }
+            multi.add_create(
+                &backends_key,
+                &pack_backends(&[]),
+                &zookeeper_client::CreateMode::Persistent
+                    .with_acls(zookeeper_client::Acls::anyone_all()),
+            )?;
+            multi
+                .commit()
+                .await
+                .with_context(|| format!("Error importing function {}", function_id))?;
+            (function_exists, backends_exist) = (true, true);
+        }
+        let replaced = if backends_exist {
+            Some(read_backends(zk, function_id).await?.1)
+        } else {
+            None
+        };
+        let prepared = prepare_backends(zk, function_id, &function.backends).await?;
+
+        let mut multi = zk.new_multi_writer();
+        if function_exists {
+            multi.add_set_data(&function_key, &definition, None)?;
+        } else {
+            multi.add_create(
+                &function_key,
+                &definition,
+                &zookeeper_client::CreateMode::Persistent
+                    .with_acls(zookeeper_client::Acls::anyone_all()),
+            )?;
+        }
+        match replaced {
+            Some(replaced) => {
+                multi.add_set_data(&backends_key, &prepared.data, Some(replaced.version))?
+            }
+            None => multi.add_create(
+                &backends_key,
+                &prepared.data,
+                &zookeeper_client::CreateMode::Persistent
+                    .with_acls(zookeeper_client::Acls::anyone_all()),
+            )?,
+        }
+        if let Some(metadata) = &function.metadata {
+            let metadata = serde_json::to_vec(metadata)?;
+            if zk.check_stat(&metadata_key).await?.is_some() {
+                multi.add_set_data(&metadata_key, &metadata, None)?;
+            } else {
+                multi.add_create(
+                    &metadata_key,
+                    &metadata,
+                    &zookeeper_client::CreateMode::Persistent
+                        .with_acls(zookeeper_client::Acls::anyone_all()),
+                )?;
+            }
+        }
+        if let Some(config) = &function.config {
+            let config = serde_json::to_vec(config)?;
+            if zk.check_stat(&config_key).await?.is_some() {
+                multi.add_set_data(&config_key, &config, None)?;
+            } else {
+                multi.add_create(
+                    &config_key,
+                    &config,
+                    &zookeeper_client::CreateMode::Persistent
+                        .with_acls(zookeeper_client::Acls::anyone_all()),
+                )?;
+            }
+        }
+        match multi.commit().await {
+            Ok(_) => {
+                if let Some(replaced) = replaced {
+                    prepared.finish(zk, replaced).await;
+                }
+            }
+            Err(e) => {
+                prepared.abandon(zk).await;
+                return Err(e).with_context(|| format!("Error importing function {}", function_id));
+            }
+        }
+    }
+
+    Ok(())
+}
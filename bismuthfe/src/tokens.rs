@@ -0,0 +1,243 @@
+//! Tenant API tokens, minted and revoked through the admin API and stored hashed in ZooKeeper
+//! (`/token/{id}`). Every frontend keeps them all in memory, reloaded on change, and with
+//! `--require-tenant-token` rejects invocations without a token (in `TOKEN_HEADER`) that's
+//! scoped to the function.
+
+use axum::extract::State;
+use axum::http::{HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse as _, Response};
+use base64::Engine as _;
+use hyper::Body;
+use opentelemetry::metrics::Counter;
+use opentelemetry::KeyValue;
+use std::collections::HashMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use bismuth_common::{Priority, TenantToken};
+
+use crate::context::Principal;
+use crate::priority::TokenPriority;
+use crate::BackendMonitor;
+
+pub const TOKEN_HEADER: &str = "x-bismuth-token";
+pub const TOKENS_PATH: &str = "/token";
+/// Prefix of every secret, so leaked ones are easy to scan for.
+const SECRET_PREFIX: &str = "bt_";
+
+/// The tenant an invocation's token belongs to, as a request extension.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tenant(pub String);
+
+/// The token an invocation was authorized with, as a request extension: not proxied to backends,
+/// but sent on to a peer frontend the invocation fails over to, which checks it again.
+#[derive(Clone)]
+pub struct ForwardedToken(pub HeaderValue);
+
+/// What a token that may invoke a function grants.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Authorized {
+    pub token_id: Uuid,
+    pub tenant: Tenant,
+    pub priority: Option<Priority>,
+}
+
+/// A new random secret, returned to the caller once and never stored.
+pub fn generate_secret() -> Result<String, ring::error::Unspecified> {
+    let bytes: [u8; 32] = ring::rand::generate(&ring::rand::SystemRandom::new())?.expose();
+    Ok(format!(
+        "{}{}",
+        SECRET_PREFIX,
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+    ))
+}
+
+/// What's stored as `TenantToken::secret_hash`. Unsalted, since secrets are random rather than
+/// chosen by people.
+pub fn hash_secret(secret: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, secret.as_bytes());
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(digest.as_ref())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rejection {
+    Missing,
+    Unknown,
+    OutOfScope,
+}
+
+impl Rejection {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Rejection::Missing => "missing",
+            Rejection::Unknown => "unknown",
+            Rejection::OutOfScope => "out_of_scope",
+        }
+    }
+}
+
+/// Every token, by secret hash.
+pub struct TokenCache {
+    tokens: std::sync::RwLock<HashMap<String, (Uuid, TenantToken)>>,
+    rejected: Counter<u64>,
+}
+
+impl TokenCache {
+    pub fn new() -> Self {
+        Self {
+            tokens: std::sync::RwLock::new(HashMap::new()),
+            rejected: opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+                .u64_counter("tenant_token_rejections")
+                .with_description("Invocations rejected for their tenant token, by reason")
+                .init(),
+        }
+    }
+
+    pub fn replace(&self, tokens: impl IntoIterator<Item = (Uuid, TenantToken)>) {
+        *self.tokens.write().unwrap() = tokens
+            .into_iter()
+            .map(|(id, token)| (token.secret_hash.clone(), (id, token)))
+            .collect();
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The token `secret` is, if it may invoke `function_id`.
+    pub fn authorize(
+        &self,
+        secret: Option<&str>,
+        function_id: &Uuid,
+    ) -> Result<Authorized, Rejection> {
+        let secret = secret.ok_or(Rejection::Missing)?;
+        let tokens = self.tokens.read().unwrap();
+        let (id, token) = tokens.get(&hash_secret(secret)).ok_or(Rejection::Unknown)?;
+        if !token.scope.allows(function_id) {
+            return Err(Rejection::OutOfScope);
+        }
+        Ok(Authorized {
+            token_id: *id,
+            tenant: Tenant(token.tenant.clone()),
+            priority: token.priority,
+        })
+    }
+}
+
+impl Default for TokenCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The function an invocation route's path is for.
+pub fn function_id(path: &str) -> Option<Uuid> {
+    let rest = path.strip_prefix("/invoke/")?;
+    Uuid::parse_str(rest.split('/').next()?).ok()
+}
+
+/// Route layer for the invocation routes. The token is removed before the request is proxied
+/// (kept as a `ForwardedToken` for peers), and the tenant added as a `Tenant` extension (with its
+/// priority as a `TokenPriority` one, and the token as the `Principal`).
+pub async fn middleware(
+    State(monitor): State<Arc<BackendMonitor>>,
+    mut req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let Some(function_id) = function_id(req.uri().path()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let header = req.headers_mut().remove(TOKEN_HEADER);
+    let secret = header.as_ref().and_then(|v| v.to_str().ok());
+    match monitor.tokens.authorize(secret, &function_id) {
+        Ok(authorized) => {
+            if let Some(header) = header {
+                req.extensions_mut().insert(ForwardedToken(header));
+            }
+            req.extensions_mut()
+                .insert(Principal(format!("token:{}", authorized.token_id)));
+            req.extensions_mut().insert(authorized.tenant);
+            if let Some(priority) = authorized.priority {
+                req.extensions_mut().insert(TokenPriority(priority));
+            }
+            next.run(req).await
+        }
+        Err(rejection) => {
+            monitor
+                .tokens
+                .rejected
+                .add(1, &[KeyValue::new("reason", rejection.as_str())]);
+            match rejection {
+                Rejection::OutOfScope => {
+                    (StatusCode::FORBIDDEN, "Token may not invoke this function").into_response()
+                }
+                _ => (StatusCode::UNAUTHORIZED, "Missing or invalid tenant token").into_response(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bismuth_common::TokenScope;
+
+    #[test]
+    fn test_function_id() {
+        let id = Uuid::new_v4();
+        assert_eq!(function_id(&format!("/invoke/{}", id)), Some(id));
+        assert_eq!(function_id(&format!("/invoke/{}/a/b", id)), Some(id));
+        assert_eq!(function_id("/invoke/nope/a"), None);
+        assert_eq!(function_id("/healthz"), None);
+    }
+
+    #[test]
+    fn test_authorize() {
+        let allowed = Uuid::new_v4();
+        let secret = generate_secret().unwrap();
+        assert!(secret.starts_with(SECRET_PREFIX));
+        let cache = TokenCache::new();
+        let token_id = Uuid::new_v4();
+        cache.replace([(
+            token_id,
+            TenantToken {
+                tenant: "acme".to_string(),
+                secret_hash: hash_secret(&secret),
+                scope: TokenScope::Functions(vec![allowed]),
+                created_at: 0,
+                rotated_at: None,
+                priority: Some(Priority::High),
+            },
+        )]);
+
+        assert_eq!(
+            cache.authorize(Some(&secret), &allowed),
+            Ok(Authorized {
+                token_id,
+                tenant: Tenant("acme".to_string()),
+                priority: Some(Priority::High),
+            })
+        );
+        assert_eq!(
+            cache.authorize(Some(&secret), &Uuid::new_v4()),
+            Err(Rejection::OutOfScope)
+        );
+        assert_eq!(
+            cache.authorize(Some("bt_guess"), &allowed),
+            Err(Rejection::Unknown)
+        );
+        assert_eq!(cache.authorize(None, &allowed), Err(Rejection::Missing));
+
+        // Revoked.
+        cache.replace([]);
+        assert_eq!(
+            cache.authorize(Some(&secret), &allowed),
+            Err(Rejection::Unknown)
+        );
+    }
+}
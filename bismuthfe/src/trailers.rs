@@ -0,0 +1,173 @@
+//! Trailers through the proxy, in both directions. hyper passes on the trailers of bodies it's
+//! given as they are, but the frontend's body wrappers (minimum rates, decompression, upload and
+//! response limits, deadlines, draining) are streams of data, which have none. So bodies that may
+//! have trailers have them taken out before they're wrapped and put back on the wrapped body once
+//! its data is done: requests announcing them in `Trailer`, and responses announcing them or to
+//! requests accepting them (`TE: trailers`, as gRPC's do, without it being announced).
+//!
+//! Buffered responses (`response_mode: buffer`) drop their trailers.
+
+use axum::http::{header, HeaderMap, Request};
+use hyper::body::{Body, HttpBody as _};
+use std::sync::{Arc, Mutex};
+
+/// A body's trailers, once its data is done.
+#[derive(Clone, Debug, Default)]
+pub struct Trailers(Arc<Mutex<Option<HeaderMap>>>);
+
+impl Trailers {
+    fn take(&self) -> Option<HeaderMap> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+/// Whether `headers` announce trailers.
+pub fn announced(headers: &HeaderMap) -> bool {
+    headers.contains_key(header::TRAILER)
+}
+
+/// Whether a request's `headers` accept trailers on its response.
+pub fn accepted(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|te| {
+            te.split(';')
+                .next()
+                .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("trailers"))
+        })
+}
+
+/// `body` without its trailers, which go to the returned `Trailers` once its data is done.
+pub fn split(body: Body) -> (Body, Trailers) {
+    let trailers = Trailers::default();
+    let trailers_ = trailers.clone();
+    let body = Body::wrap_stream(futures::stream::unfold(Some(body), move |state| {
+        let trailers = trailers_.clone();
+        async move {
+            let mut body = state?;
+            match body.data().await {
+                Some(chunk) => Some((chunk, Some(body))),
+                None => match body.trailers().await {
+                    Ok(received) => {
+                        *trailers.0.lock().unwrap() = received;
+                        None
+                    }
+                    Err(e) => Some((Err(e), None)),
+                },
+            }
+        }
+    }));
+    (body, trailers)
+}
+
+/// `body` with `trailers` after its data.
+pub fn join(mut body: Body, trailers: Trailers) -> Body {
+    let (mut sender, joined) = Body::channel();
+    tokio::spawn(async move {
+        while let Some(chunk) = body.data().await {
+            let sent = match chunk {
+                Ok(chunk) => sender.send_data(chunk).await.is_ok(),
+                Err(_) => false,
+            };
+            if !sent {
+                // Fails the joined body, if it's still wanted.
+                sender.abort();
+                return;
+            }
+        }
+        if let Some(trailers) = trailers.take() {
+            let _ = sender.send_trailers(trailers).await;
+        }
+    });
+    joined
+}
+
+/// Take `req`'s trailers out of its body if it announces any, for `restore` to put back.
+pub fn take(req: &mut Request<Body>) {
+    if !announced(req.headers()) || req.extensions().get::<Trailers>().is_some() {
+        return;
+    }
+    let (body, trailers) = split(std::mem::take(req.body_mut()));
+    *req.body_mut() = body;
+    req.extensions_mut().insert(trailers);
+}
+
+/// Put back the trailers `take` took out of `req`'s body.
+pub fn restore(req: &mut Request<Body>) {
+    if let Some(trailers) = req.extensions_mut().remove::<Trailers>() {
+        let body = std::mem::take(req.body_mut());
+        *req.body_mut() = join(body, trailers);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+    use futures::StreamExt as _;
+
+    fn with_trailers() -> Body {
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            sender.send_data("hello ".into()).await.unwrap();
+            sender.send_data("world".into()).await.unwrap();
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+            sender.send_trailers(trailers).await.unwrap();
+        });
+        body
+    }
+
+    #[tokio::test]
+    async fn test_through_wrappers() {
+        let (body, trailers) = split(with_trailers());
+        // A wrapper like the others, which only passes on data.
+        let wrapped =
+            Body::wrap_stream(body.map(|chunk| chunk.map(|data| data.to_ascii_uppercase())));
+        let mut joined = join(wrapped, trailers);
+        let mut data = Vec::new();
+        while let Some(chunk) = joined.data().await {
+            data.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(data, b"HELLO WORLD");
+        let trailers = joined.trailers().await.unwrap().unwrap();
+        assert_eq!(trailers["grpc-status"], "0");
+    }
+
+    #[tokio::test]
+    async fn test_requests() {
+        let mut req = Request::post("/")
+            .header(header::TRAILER, "x-checksum")
+            .body(with_trailers())
+            .unwrap();
+        take(&mut req);
+        assert!(req.extensions().get::<Trailers>().is_some());
+        restore(&mut req);
+        assert!(req.extensions().get::<Trailers>().is_none());
+        let mut body = req.into_body();
+        while body.data().await.is_some() {}
+        assert!(body.trailers().await.unwrap().is_some());
+
+        // Without `Trailer`, the body is left alone.
+        let mut req = Request::post("/").body(Body::from("hi")).unwrap();
+        take(&mut req);
+        assert!(req.extensions().get::<Trailers>().is_none());
+    }
+
+    #[test]
+    fn test_accepted() {
+        let headers = |te: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::TE, HeaderValue::from_static(te));
+            headers
+        };
+        assert!(accepted(&headers("trailers")));
+        assert!(accepted(&headers("gzip, Trailers")));
+        assert!(accepted(&headers("trailers;q=1")));
+        assert!(!accepted(&headers("gzip")));
+        assert!(!accepted(&HeaderMap::new()));
+    }
+}
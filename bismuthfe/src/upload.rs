@@ -0,0 +1,86 @@
+//! Upload ceilings. Request bodies are streamed through to the backend as they arrive, so the
+//! client only sends as fast as the backend reads; these just stop a function from being sent
+//! more than it's configured to accept.
+
+use axum::http::{Request, StatusCode};
+use futures::StreamExt as _;
+use hyper::body::{Body, Bytes};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Set once a streamed body goes over its ceiling, which fails the request to the backend.
+/// Stored in the request's extensions so the error can be reported as a 413.
+#[derive(Clone, Debug, Default)]
+pub struct Exceeded(Arc<AtomicBool>);
+
+impl Exceeded {
+    pub fn get(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Enforce a ceiling of `max` bytes on `req`'s body. A `Content-Length` over it is rejected
+/// straight away, before a client sending `Expect: 100-continue` has sent anything; otherwise the
+/// body is counted as it streams and cut off at the ceiling.
+pub fn limit(req: &mut Request<Body>, max: u64) -> Result<(), StatusCode> {
+    if let Some(len) = req
+        .headers()
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<u64>().ok())
+    {
+        // hyper won't read past the declared length, so there's nothing else to check.
+        return if len > max {
+            Err(StatusCode::PAYLOAD_TOO_LARGE)
+        } else {
+            Ok(())
+        };
+    }
+
+    let exceeded = Exceeded::default();
+    let flag = exceeded.0.clone();
+    let mut seen = 0u64;
+    let body = std::mem::take(req.body_mut()).map(
+        move |chunk| -> Result<Bytes, Box<dyn std::error::Error + Send + Sync>> {
+            let chunk = chunk?;
+            seen += chunk.len() as u64;
+            if seen > max {
+                flag.store(true, Ordering::Relaxed);
+                return Err(format!("Upload exceeds {} bytes", max).into());
+            }
+            Ok(chunk)
+        },
+    );
+    *req.body_mut() = Body::wrap_stream(body);
+    req.extensions_mut().insert(exceeded);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_limit() {
+        let mut req = Request::post("/")
+            .header(hyper::header::CONTENT_LENGTH, "11")
+            .body(Body::from("hello world"))
+            .unwrap();
+        assert_eq!(limit(&mut req, 5), Err(StatusCode::PAYLOAD_TOO_LARGE));
+
+        let chunks: Vec<Result<_, std::io::Error>> = vec![Ok("hello"), Ok(" world")];
+        let mut req = Request::post("/")
+            .body(Body::wrap_stream(futures::stream::iter(chunks)))
+            .unwrap();
+        limit(&mut req, 8).unwrap();
+        let exceeded = req.extensions().get::<Exceeded>().unwrap().clone();
+        assert!(hyper::body::to_bytes(req.into_body()).await.is_err());
+        assert!(exceeded.get());
+
+        let mut req = Request::post("/").body(Body::from("hello")).unwrap();
+        limit(&mut req, 8).unwrap();
+        assert_eq!(
+            hyper::body::to_bytes(req.into_body()).await.unwrap(),
+            "hello"
+        );
+    }
+}
@@ -0,0 +1,188 @@
+//! Connections to backends and peers, counted as the client's connector makes them: how many are
+//! open (hyper's pool, idle and in use), how many were opened and failed to open, and how long
+//! they lasted. Compared with the invocation count, connections opened give the pool's reuse rate.
+
+use futures::future::BoxFuture;
+use hyper::client::connect::{Connected, Connection as _};
+use hyper::client::HttpConnector;
+use hyper::Uri;
+use opentelemetry::metrics::{Counter, Histogram};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tower::Service;
+
+pub type Client = hyper::Client<Connector, hyper::Body>;
+
+struct Metrics {
+    open: AtomicUsize,
+    opened: Counter<u64>,
+    connect_errors: Counter<u64>,
+    duration: Histogram<f64>,
+}
+
+/// An `HttpConnector` that counts its connections.
+#[derive(Clone)]
+pub struct Connector {
+    inner: HttpConnector,
+    metrics: Arc<Metrics>,
+}
+
+impl Connector {
+    pub fn new(inner: HttpConnector) -> anyhow::Result<Self> {
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+        let metrics = Arc::new(Metrics {
+            open: AtomicUsize::new(0),
+            opened: meter
+                .u64_counter("backend_connections_opened")
+                .with_description("Connections opened to backends and peers")
+                .init(),
+            connect_errors: meter
+                .u64_counter("backend_connect_errors")
+                .with_description("Connections to backends and peers that failed or timed out")
+                .init(),
+            duration: meter
+                .f64_histogram("backend_connection_duration")
+                .with_description("How long connections to backends and peers were open")
+                .with_unit(opentelemetry::metrics::Unit::new("s"))
+                .init(),
+        });
+        let open_gauge = meter
+            .u64_observable_gauge("backend_connections_open")
+            .with_description("Connections to backends and peers open, idle or in use")
+            .init();
+        let metrics_ = metrics.clone();
+        meter.register_callback(&[open_gauge.as_any()], move |observer| {
+            observer.observe_u64(
+                &open_gauge,
+                metrics_.open.load(Ordering::Relaxed) as u64,
+                &[],
+            );
+        })?;
+        Ok(Self { inner, metrics })
+    }
+}
+
+impl Service<Uri> for Connector {
+    type Response = Connection;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = BoxFuture<'static, Result<Connection, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connecting = self.inner.call(uri);
+        let metrics = self.metrics.clone();
+        Box::pin(async move {
+            match connecting.await {
+                Ok(stream) => {
+                    metrics.opened.add(1, &[]);
+                    metrics.open.fetch_add(1, Ordering::Relaxed);
+                    Ok(Connection {
+                        inner: stream,
+                        metrics,
+                        opened: Instant::now(),
+                    })
+                }
+                Err(e) => {
+                    metrics.connect_errors.add(1, &[]);
+                    Err(e.into())
+                }
+            }
+        })
+    }
+}
+
+pub struct Connection {
+    inner: TcpStream,
+    metrics: Arc<Metrics>,
+    opened: Instant,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.metrics.open.fetch_sub(1, Ordering::Relaxed);
+        self.metrics
+            .duration
+            .record(self.opened.elapsed().as_secs_f64(), &[]);
+    }
+}
+
+impl hyper::client::connect::Connection for Connection {
+    fn connected(&self) -> Connected {
+        self.inner.connected()
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_counts_connections() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut connector = Connector::new(HttpConnector::new()).unwrap();
+        let uri: Uri = format!("http://{}", addr).parse().unwrap();
+
+        // HttpConnector is always ready.
+        let conn = connector.call(uri.clone()).await.unwrap();
+        assert_eq!(connector.metrics.open.load(Ordering::Relaxed), 1);
+        // hyper only writes body chunks alongside their headers, without copying them into its
+        // buffer, over connections that say they're vectored.
+        assert!(conn.is_write_vectored());
+        drop(conn);
+        assert_eq!(connector.metrics.open.load(Ordering::Relaxed), 0);
+
+        drop(listener);
+        assert!(connector.call(uri).await.is_err());
+        assert_eq!(connector.metrics.open.load(Ordering::Relaxed), 0);
+    }
+}
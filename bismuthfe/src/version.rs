@@ -0,0 +1,52 @@
+//! What build this is, served at `/version` and attached to traces and metrics as resource
+//! attributes, to line behavior changes up with deployments.
+
+use opentelemetry::KeyValue;
+use serde::Serialize;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// `unknown` if built outside a git checkout without `GIT_SHA` set.
+pub const GIT_SHA: &str = env!("BISMUTHFE_GIT_SHA");
+const BUILD_TIMESTAMP: &str = env!("BISMUTHFE_BUILD_TIMESTAMP");
+const FEATURES: &str = env!("BISMUTHFE_FEATURES");
+
+#[derive(Debug, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_sha: &'static str,
+    /// Unix seconds.
+    pub build_timestamp: u64,
+    /// Cargo features enabled in this build.
+    pub features: Vec<&'static str>,
+}
+
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: VERSION,
+        git_sha: GIT_SHA,
+        build_timestamp: BUILD_TIMESTAMP.parse().unwrap_or_default(),
+        features: FEATURES.split(',').filter(|f| !f.is_empty()).collect(),
+    }
+}
+
+pub fn resource_attributes() -> Vec<KeyValue> {
+    vec![
+        KeyValue::new("service.version", VERSION),
+        KeyValue::new("build.git_sha", GIT_SHA),
+        KeyValue::new("build.timestamp", BUILD_TIMESTAMP),
+        KeyValue::new("build.features", FEATURES),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_info() {
+        let info = build_info();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert!(!info.git_sha.is_empty());
+        assert!(info.build_timestamp > 0);
+    }
+}
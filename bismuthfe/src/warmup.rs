@@ -0,0 +1,138 @@
+//! Warm-up probes for backends newly added to a function with `warmup` in its config: they're
+//! kept out of the ring until they've answered the warm-up requests, so no real invocation is the
+//! one that hits a cold container.
+
+use anyhow::{anyhow, Result};
+use axum::http::Request;
+use hyper::Body;
+use std::collections::HashMap;
+use std::time::Duration;
+use uuid::Uuid;
+
+use bismuth_common::{Backend, Warmup};
+
+/// Set on warm-up requests, so functions can tell them apart from invocations.
+pub const WARMUP_HEADER: &str = "x-bismuth-warmup";
+/// Between rounds of probes of a backend that isn't ready yet.
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Warming {
+    Probing,
+    /// Admitted to the ring as of the function's next load.
+    Passed,
+}
+
+pub type WarmingBackends = HashMap<(Uuid, Backend), Warming>;
+
+/// Which of the function's `listed` backends go in the ring: the ones it already routed to and
+/// the ones that have passed their probes. Also returns the backends to start probing, which are
+/// marked as being probed. Backends that are no longer listed are forgotten.
+pub fn admit(
+    function_id: Uuid,
+    listed: Vec<Backend>,
+    previous: &[Backend],
+    warming: &mut WarmingBackends,
+) -> (Vec<Backend>, Vec<Backend>) {
+    warming.retain(|(function, backend), _| *function != function_id || listed.contains(backend));
+    let mut new = Vec::new();
+    let admitted = listed
+        .into_iter()
+        .filter(|backend| {
+            if previous.contains(backend) {
+                return true;
+            }
+            let key = (function_id, backend.clone());
+            match warming.get(&key) {
+                Some(Warming::Passed) => {
+                    warming.remove(&key);
+                    true
+                }
+                Some(Warming::Probing) => false,
+                None => {
+                    warming.insert(key, Warming::Probing);
+                    new.push(backend.clone());
+                    false
+                }
+            }
+        })
+        .collect();
+    (admitted, new)
+}
+
+/// Send `backend` the warm-up requests one after another, failing on the first that doesn't get
+/// a 2xx in time.
+pub async fn probe(
+    http_client: &hyper::client::Client<hyper::client::HttpConnector, Body>,
+    backend: &Backend,
+    warmup: &Warmup,
+) -> Result<()> {
+    let timeout = Duration::from_millis(warmup.timeout_ms);
+    for _ in 0..warmup.requests {
+        let req = Request::get(crate::backend_uri(
+            backend,
+            warmup.path.trim_start_matches('/'),
+        )?)
+        .header(WARMUP_HEADER, "1")
+        .body(Body::empty())?;
+        let resp = tokio::time::timeout(timeout, async {
+            let resp = http_client.request(req).await?;
+            let status = resp.status();
+            hyper::body::to_bytes(resp.into_body()).await?;
+            Ok::<_, hyper::Error>(status)
+        })
+        .await
+        .map_err(|_| anyhow!("Timed out"))??;
+        if !resp.is_success() {
+            return Err(anyhow!("Got {}", resp));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backend(n: u8) -> Backend {
+        Backend {
+            ip: [10, 0, 0, n].into(),
+            container_id: Uuid::from_u128(n as u128),
+        }
+    }
+
+    #[test]
+    fn test_admit() {
+        let function_id = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        let mut warming = WarmingBackends::new();
+        warming.insert((other, backend(9)), Warming::Probing);
+
+        let (admitted, new) = admit(
+            function_id,
+            vec![backend(1), backend(2), backend(3)],
+            &[backend(1)],
+            &mut warming,
+        );
+        assert_eq!(admitted, vec![backend(1)]);
+        assert_eq!(new, vec![backend(2), backend(3)]);
+
+        // Reloaded before either passed: not probed again.
+        warming.insert((function_id, backend(2)), Warming::Passed);
+        let (admitted, new) = admit(
+            function_id,
+            vec![backend(1), backend(2), backend(3)],
+            &admitted,
+            &mut warming,
+        );
+        assert_eq!(admitted, vec![backend(1), backend(2)]);
+        assert!(new.is_empty());
+
+        // Removed while still warming.
+        let (admitted, new) = admit(function_id, vec![backend(1)], &admitted, &mut warming);
+        assert_eq!(admitted, vec![backend(1)]);
+        assert!(new.is_empty());
+        assert_eq!(warming.len(), 1);
+        assert!(warming.contains_key(&(other, backend(9))));
+    }
+}
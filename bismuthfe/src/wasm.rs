@@ -0,0 +1,343 @@
+//! Request/response filters compiled to WebAssembly, loaded at startup with
+//! `--wasm-filter NAME=PATH` and enabled per function with `filters` in its config.
+//!
+//! A filter module exports `memory`, `alloc(len: i32) -> i32`, and `on_request` and/or
+//! `on_response`, both `(ptr: i32, len: i32) -> i64`. The frontend writes a JSON view of the
+//! request (`{"method", "path", "headers"}`) or response (`{"status", "headers"}`) into memory
+//! from `alloc`, and the filter returns the location of a JSON `Action` as `ptr << 32 | len`.
+//! Every call gets a fresh instance with a fuel budget, so filters can't keep state between
+//! requests or stall the proxy.
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use hyper::Body;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{event, Level};
+use wasmtime::{Engine, Linker, Module, Store};
+
+use crate::middleware::{Invocation, ProxyMiddleware};
+
+/// Longest action a filter may return: far more than any set of headers and response body it
+/// should need.
+const MAX_ACTION_LEN: usize = 1 << 20;
+
+/// Where in a `memory_size` byte memory the action a hook returned as `ptr << 32 | len` is, if
+/// it's all inside it and not too long.
+fn action_range(ret: u64, memory_size: usize) -> Result<std::ops::Range<usize>> {
+    let (ptr, len) = ((ret >> 32) as usize, (ret & 0xffff_ffff) as usize);
+    if len > MAX_ACTION_LEN {
+        return Err(anyhow!(
+            "Action of {} bytes is over {}",
+            len,
+            MAX_ACTION_LEN
+        ));
+    }
+    match ptr.checked_add(len) {
+        Some(end) if end <= memory_size => Ok(ptr..end),
+        _ => Err(anyhow!("Action at {}+{} is outside memory", ptr, len)),
+    }
+}
+
+#[derive(Serialize)]
+struct RequestView<'a> {
+    method: &'a str,
+    path: &'a str,
+    headers: BTreeMap<&'a str, &'a str>,
+}
+
+#[derive(Serialize)]
+struct ResponseView<'a> {
+    status: u16,
+    headers: BTreeMap<&'a str, &'a str>,
+}
+
+fn header_view(headers: &HeaderMap) -> BTreeMap<&str, &str> {
+    headers
+        .iter()
+        .filter_map(|(k, v)| Some((k.as_str(), v.to_str().ok()?)))
+        .collect()
+}
+
+/// What a filter wants done with the request or response.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    /// Pass it on, with headers changed.
+    Continue {
+        #[serde(default)]
+        set_headers: BTreeMap<String, String>,
+        #[serde(default)]
+        remove_headers: Vec<String>,
+    },
+    /// Answer with this response instead (e.g. a 401 from an auth filter).
+    Respond {
+        status: u16,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+        #[serde(default)]
+        body: String,
+    },
+}
+
+fn apply_headers(
+    headers: &mut HeaderMap,
+    set_headers: &BTreeMap<String, String>,
+    remove_headers: &[String],
+) -> Result<()> {
+    for name in remove_headers {
+        headers.remove(name.as_str());
+    }
+    for (name, value) in set_headers {
+        headers.insert(
+            HeaderName::try_from(name.as_str())?,
+            HeaderValue::try_from(value.as_str())?,
+        );
+    }
+    Ok(())
+}
+
+fn respond(
+    status: u16,
+    headers: &BTreeMap<String, String>,
+    body: String,
+) -> Result<axum::response::Response<Body>> {
+    let mut resp = axum::response::Response::new(Body::from(body));
+    *resp.status_mut() = StatusCode::from_u16(status)?;
+    apply_headers(resp.headers_mut(), headers, &[])?;
+    Ok(resp)
+}
+
+pub struct Filters {
+    engine: Engine,
+    modules: HashMap<String, Module>,
+    fuel: u64,
+}
+
+impl Filters {
+    /// Compile every filter up front, so a broken module fails startup rather than requests.
+    pub fn load(filters: &[(String, PathBuf)], fuel: u64) -> Result<Self> {
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config)?;
+        let modules = filters
+            .iter()
+            .map(|(name, path)| {
+                let module = Module::from_file(&engine, path)
+                    .with_context(|| format!("Error loading WASM filter {}", path.display()))?;
+                Ok((name.clone(), module))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            engine,
+            modules,
+            fuel,
+        })
+    }
+
+    /// Run `export` of filter `name` on `input`. `None` if the filter doesn't have that hook.
+    fn call(&self, name: &str, export: &str, input: &[u8]) -> Result<Option<Action>> {
+        let module = self
+            .modules
+            .get(name)
+            .ok_or_else(|| anyhow!("Unknown WASM filter {}", name))?;
+        if module.get_export(export).is_none() {
+            return Ok(None);
+        }
+
+        let mut store = Store::new(&self.engine, ());
+        store.set_fuel(self.fuel)?;
+        let instance = Linker::new(&self.engine).instantiate(&mut store, module)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| anyhow!("WASM filter {} doesn't export memory", name))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let hook = instance.get_typed_func::<(i32, i32), i64>(&mut store, export)?;
+
+        let len = i32::try_from(input.len())?;
+        let ptr = alloc.call(&mut store, len)?;
+        memory.write(&mut store, ptr as usize, input)?;
+        let ret = hook.call(&mut store, (ptr, len))? as u64;
+        // Checked before copying, since the filter could claim any length.
+        let range = action_range(ret, memory.data_size(&store))
+            .with_context(|| format!("Invalid action from WASM filter {}", name))?;
+        let output = &memory.data(&store)[range];
+        Ok(Some(serde_json::from_slice(output).with_context(|| {
+            format!("Invalid action from WASM filter {}", name)
+        })?))
+    }
+
+    /// `call`, off the async runtime.
+    async fn call_blocking(
+        self: &Arc<Self>,
+        name: &str,
+        export: &'static str,
+        input: Vec<u8>,
+    ) -> Result<Option<Action>> {
+        let this = self.clone();
+        let name = name.to_string();
+        tokio::task::spawn_blocking(move || this.call(&name, export, &input)).await?
+    }
+
+    /// Run the `on_request` hooks of `names` in order, each seeing the previous ones' changes.
+    /// Returns a response if one of them answers the request itself.
+    pub async fn on_request(
+        self: &Arc<Self>,
+        names: &[String],
+        parts: &mut axum::http::request::Parts,
+        path: &str,
+    ) -> Result<Option<axum::response::Response<Body>>> {
+        for name in names {
+            let input = serde_json::to_vec(&RequestView {
+                method: parts.method.as_str(),
+                path,
+                headers: header_view(&parts.headers),
+            })?;
+            let Some(action) = self.call_blocking(name, "on_request", input).await? else {
+                continue;
+            };
+            match action {
+                Action::Continue {
+                    set_headers,
+                    remove_headers,
+                } => apply_headers(&mut parts.headers, &set_headers, &remove_headers)?,
+                Action::Respond {
+                    status,
+                    headers,
+                    body,
+                } => {
+                    event!(Level::DEBUG, filter = %name, status, "WASM filter answered request");
+                    return Ok(Some(respond(status, &headers, body)?));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Run the `on_response` hooks of `names` in order, like `on_request`. Returns a replacement
+    /// response if one of them asks for it.
+    pub async fn on_response(
+        self: &Arc<Self>,
+        names: &[String],
+        parts: &mut axum::http::response::Parts,
+    ) -> Result<Option<axum::response::Response<Body>>> {
+        for name in names {
+            let input = serde_json::to_vec(&ResponseView {
+                status: parts.status.as_u16(),
+                headers: header_view(&parts.headers),
+            })?;
+            let Some(action) = self.call_blocking(name, "on_response", input).await? else {
+                continue;
+            };
+            match action {
+                Action::Continue {
+                    set_headers,
+                    remove_headers,
+                } => apply_headers(&mut parts.headers, &set_headers, &remove_headers)?,
+                Action::Respond {
+                    status,
+                    headers,
+                    body,
+                } => {
+                    event!(Level::DEBUG, filter = %name, status, "WASM filter replaced response");
+                    return Ok(Some(respond(status, &headers, body)?));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Runs the filters named in each function's config. Always installed, so a function whose
+/// filters aren't loaded fails closed.
+pub struct WasmMiddleware(pub Arc<Filters>);
+
+#[async_trait]
+impl ProxyMiddleware for WasmMiddleware {
+    async fn on_request(
+        &self,
+        invocation: &Invocation<'_>,
+        req: &mut axum::http::request::Parts,
+    ) -> Result<Option<axum::response::Response<Body>>> {
+        match invocation.config {
+            Some(config) if !config.filters.is_empty() => {
+                self.0
+                    .on_request(&config.filters, req, invocation.path)
+                    .await
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn on_response(
+        &self,
+        invocation: &Invocation<'_>,
+        resp: &mut axum::http::response::Parts,
+    ) -> Result<Option<axum::response::Response<Body>>> {
+        match invocation.config {
+            Some(config) if !config.filters.is_empty() => {
+                self.0.on_response(&config.filters, resp).await
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Parse `NAME=PATH` command line arguments.
+pub fn parse_filter(s: &str) -> Result<(String, PathBuf), String> {
+    let (name, path) = crate::geo::parse_key_val(s)?;
+    Ok((name, PathBuf::from(path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_actions() {
+        assert_eq!(
+            serde_json::from_str::<Action>(
+                r#"{"action": "continue", "set_headers": {"x-user": "alice"}}"#
+            )
+            .unwrap(),
+            Action::Continue {
+                set_headers: [("x-user".to_string(), "alice".to_string())].into(),
+                remove_headers: vec![],
+            }
+        );
+        assert_eq!(
+            serde_json::from_str::<Action>(r#"{"action": "respond", "status": 401}"#).unwrap(),
+            Action::Respond {
+                status: 401,
+                headers: BTreeMap::new(),
+                body: String::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_action_range() {
+        assert_eq!(action_range((16 << 32) | 4, 64).unwrap(), 16..20);
+        assert_eq!(action_range((60 << 32) | 4, 64).unwrap(), 60..64);
+        assert!(action_range((61 << 32) | 4, 64).is_err());
+        assert!(action_range(0xffff_ffff, 1 << 32).is_err());
+        assert!(action_range(u64::MAX, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn test_apply_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", HeaderValue::from_static("Bearer x"));
+        apply_headers(
+            &mut headers,
+            &[("x-user".to_string(), "alice".to_string())].into(),
+            &["authorization".to_string()],
+        )
+        .unwrap();
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers["x-user"], "alice");
+    }
+}
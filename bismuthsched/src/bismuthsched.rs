@@ -0,0 +1,321 @@
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use std::net::Ipv4Addr;
+use std::str::FromStr as _;
+use tokio::time::sleep;
+use tracing::{event, instrument, Level};
+use tracing_subscriber::{layer::SubscriberExt as _, util::SubscriberInitExt as _};
+use uuid::Uuid;
+
+use bismuth_common::{
+    add_write_draining, backends_path, init_metrics, init_sentry, init_tracer, prepare_backends,
+    read_backends, read_draining, Backend, BackendsVersion, ContainerState, DrainingBackend,
+    FunctionDefinition, NodeCapacity,
+};
+
+pub mod drain;
+pub mod placement;
+
+/// bismuthsched
+#[derive(Debug, Parser)]
+#[clap(name = "bismuthsched", version)]
+struct Cli {
+    /// ZooKeeper IP:port
+    #[clap(long, global = true, default_value = "127.0.0.1:2181")]
+    zookeeper: String,
+
+    /// ZooKeeper environment name (e.g. "dev", "test", "default")
+    #[clap(long, global = true, default_value = "default")]
+    zookeeper_env: String,
+
+    /// Seconds between reconciliations, in addition to reconciling whenever a function changes
+    #[clap(long, default_value = "10")]
+    interval: u64,
+
+    /// Log the changes that would be made without writing them
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Seconds backends stay listed, drained of invocations by frontends, before they're removed
+    /// (0 to remove them straight away)
+    #[clap(long, default_value = "30")]
+    drain_secs: u64,
+}
+
+/// A function's backends as read, to replace them with.
+struct Listed {
+    /// Including draining ones.
+    backends: Vec<Backend>,
+    version: BackendsVersion,
+    draining: Vec<DrainingBackend>,
+    /// Of the draining znode, if it exists.
+    draining_version: Option<i32>,
+}
+
+async fn connect(zk_cluster: &str, zk_env: &str) -> Result<zookeeper_client::Client> {
+    let zk = zookeeper_client::Client::connect(zk_cluster)
+        .await
+        .context("Error connecting to ZooKeeper")?;
+    zk.chroot(format!("/{}", zk_env))
+        .map_err(|_| anyhow!("Failed to chroot to env {}", zk_env))
+}
+
+async fn read_nodes(zk: &zookeeper_client::Client) -> Result<Vec<placement::Node>> {
+    let mut nodes = vec![];
+    for ip in zk
+        .list_children("/node")
+        .await
+        .context("Error listing nodes")?
+    {
+        let node_key = format!("/node/{}", ip);
+        let (data, _) = match zk.get_data(&node_key).await {
+            Ok(data) => data,
+            Err(zookeeper_client::Error::NoNode) => continue,
+            Err(e) => return Err(e).context("Error getting node data"),
+        };
+        let capacity = match zk.get_data(&format!("{}/capacity", node_key)).await {
+            Ok((capacity, _)) => Some(
+                serde_json::from_slice::<NodeCapacity>(&capacity)
+                    .with_context(|| format!("Invalid capacity for node {}", ip))?,
+            ),
+            Err(zookeeper_client::Error::NoNode) => None,
+            Err(e) => return Err(e).context("Error getting node capacity"),
+        };
+        nodes.push(placement::Node {
+            ip: Ipv4Addr::from_str(&ip)?,
+            enabled: data == vec![1u8],
+            capacity,
+        });
+    }
+    Ok(nodes)
+}
+
+/// Returns each function, with only its backends that aren't draining, along with all of them.
+async fn read_functions(
+    zk: &zookeeper_client::Client,
+) -> Result<Vec<(placement::Function, Listed)>> {
+    let mut functions = vec![];
+    for function_id in zk
+        .list_children("/function")
+        .await
+        .context("Error listing functions")?
+    {
+        let function_id = Uuid::parse_str(&function_id)?;
+        let function_key = format!("/function/{}", function_id);
+        let (definition, (backends, version), draining) = match tokio::try_join!(
+            async {
+                zk.get_data(&function_key)
+                    .await
+                    .context("Error getting function")
+            },
+            read_backends(zk, &function_id),
+            read_draining(zk, &function_id)
+        ) {
+            Ok(((definition, _), backends, draining)) => (definition, backends, draining),
+            // Deleted since listing.
+            Err(e)
+                if matches!(
+                    e.downcast_ref::<zookeeper_client::Error>(),
+                    Some(zookeeper_client::Error::NoNode)
+                ) =>
+            {
+                continue
+            }
+            Err(e) => return Err(e),
+        };
+        let (draining, draining_version) = draining;
+        let definition: FunctionDefinition = serde_json::from_slice(&definition)
+            .with_context(|| format!("Invalid definition for function {}", function_id))?;
+        let replicas = match zk.get_data(&format!("{}/replicas", function_key)).await {
+            Ok((replicas, _)) => serde_json::from_slice::<u32>(&replicas)
+                .with_context(|| format!("Invalid replicas for function {}", function_id))?,
+            Err(zookeeper_client::Error::NoNode) => 1,
+            Err(e) => return Err(e).context("Error getting function replicas"),
+        };
+        functions.push((
+            placement::Function {
+                id: function_id,
+                cpu: definition.cpu,
+                memory: definition.memory,
+                replicas: replicas.min(definition.max_instances),
+                backends: backends
+                    .iter()
+                    .filter(|b| !draining.iter().any(|d| d.container_id == b.container_id))
+                    .cloned()
+                    .collect(),
+            },
+            Listed {
+                backends,
+                version,
+                draining,
+                draining_version,
+            },
+        ));
+    }
+    Ok(functions)
+}
+
+/// Bring every function's backends in line with its desired replica count.
+#[instrument(skip(zk))]
+async fn reconcile(zk: &zookeeper_client::Client, dry_run: bool, drain_secs: u64) -> Result<()> {
+    let nodes = read_nodes(zk).await?;
+    let functions = read_functions(zk).await?;
+    let plan = placement::plan(
+        &nodes,
+        &functions.iter().map(|(f, _)| f.clone()).collect::<Vec<_>>(),
+    );
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    for (function, listed) in &functions {
+        let planned = plan.get(&function.id).unwrap_or(&function.backends);
+        let drain::Changes {
+            backends,
+            draining,
+            removed,
+        } = drain::changes(&listed.backends, &listed.draining, planned, drain_secs, now);
+        if backends == listed.backends && draining == listed.draining {
+            continue;
+        }
+        let added: Vec<_> = backends
+            .iter()
+            .filter(|b| !listed.backends.contains(b))
+            .collect();
+        event!(
+            Level::INFO,
+            function = %function.id,
+            ?added,
+            ?removed,
+            draining = ?draining.iter().map(|d| d.container_id).collect::<Vec<_>>(),
+            dry_run,
+            "Rescheduling function"
+        );
+        if dry_run {
+            continue;
+        }
+
+        let prepared = match prepare_backends(zk, &function.id, &backends).await {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                event!(Level::WARN, function = %function.id, error = ?e, "Error updating function backends");
+                continue;
+            }
+        };
+        let mut multi = zk.new_multi_writer();
+        // Fails the whole update if someone else changed the backends since we read them.
+        multi.add_set_data(
+            &backends_path(&function.id),
+            &prepared.data,
+            Some(listed.version.version),
+        )?;
+        if draining != listed.draining {
+            add_write_draining(&mut multi, &function.id, &draining, listed.draining_version)?;
+        }
+        for backend in &removed {
+            // Nothing to clean up if the node itself is gone.
+            if !nodes.iter().any(|n| n.ip == backend.ip) {
+                continue;
+            }
+            multi.add_delete(
+                &format!(
+                    "/node/{}/container/{}/status",
+                    backend.ip, backend.container_id
+                ),
+                None,
+            )?;
+            multi.add_delete(
+                &format!("/node/{}/container/{}", backend.ip, backend.container_id),
+                None,
+            )?;
+        }
+        for backend in added {
+            multi.add_create(
+                &format!("/node/{}/container/{}", backend.ip, backend.container_id),
+                function.id.as_bytes(),
+                &zookeeper_client::CreateMode::Persistent
+                    .with_acls(zookeeper_client::Acls::anyone_all()),
+            )?;
+            multi.add_create(
+                &format!(
+                    "/node/{}/container/{}/status",
+                    backend.ip, backend.container_id
+                ),
+                &[ContainerState::Starting as u8],
+                &zookeeper_client::CreateMode::Persistent
+                    .with_acls(zookeeper_client::Acls::anyone_all()),
+            )?;
+        }
+        match multi.commit().await {
+            Ok(_) => prepared.finish(zk, listed.version).await,
+            Err(e) => {
+                prepared.abandon(zk).await;
+                // Picked up again on the next pass.
+                event!(Level::WARN, function = %function.id, error = ?e, "Error updating function backends");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn run(args: &Cli) -> Result<()> {
+    let zk = connect(&args.zookeeper, &args.zookeeper_env).await?;
+    let mut watcher = zk
+        .watch(
+            "/function",
+            zookeeper_client::AddWatchMode::PersistentRecursive,
+        )
+        .await?;
+
+    loop {
+        if let Err(e) = reconcile(&zk, args.dry_run, args.drain_secs).await {
+            event!(Level::ERROR, error = ?e, "Error reconciling");
+        }
+
+        tokio::select! {
+            event = watcher.changed() => {
+                if event.event_type == zookeeper_client::EventType::Session
+                    && (event.session_state == zookeeper_client::SessionState::Disconnected
+                        || event.session_state == zookeeper_client::SessionState::Expired
+                        || event.session_state == zookeeper_client::SessionState::Closed)
+                {
+                    return Err(anyhow!("ZooKeeper session disconnected or terminal"));
+                }
+            }
+            _ = sleep(std::time::Duration::from_secs(args.interval)) => {}
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    bismuth_common::init_scrubber()?;
+    let _sentry_guard = init_sentry();
+    let tracer = init_tracer(env!("CARGO_PKG_NAME"))?;
+    init_metrics(&[opentelemetry::KeyValue::new(
+        "service.name",
+        env!("CARGO_PKG_NAME"),
+    )]);
+
+    let args = Cli::parse();
+
+    tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .with(tracing_subscriber::EnvFilter::from_default_env())
+        .with(
+            tracing_subscriber::fmt::layer().with_writer(bismuth_common::Scrubbed(std::io::stdout)),
+        )
+        .with(bismuth_common::file_log_layer()?)
+        .with(bismuth_common::system_log_layers(env!("CARGO_PKG_NAME"))?)
+        .init();
+
+    loop {
+        if let Err(e) = run(&args).await {
+            event!(Level::ERROR, error = %e, "Error in scheduler loop");
+        }
+        sleep(std::time::Duration::from_secs(1)).await;
+    }
+}
@@ -0,0 +1,94 @@
+//! Draining backends before removing them: with `--drain-secs`, backends the plan drops stay
+//! listed but marked draining, so frontends stop sending them new invocations while the ones in
+//! flight finish, and are only removed (and their containers stopped) once they've been draining
+//! that long. Draining backends are left out of placement, so replacements are started for them
+//! straight away.
+
+use bismuth_common::{Backend, DrainingBackend};
+
+#[derive(Debug, PartialEq)]
+pub struct Changes {
+    /// The function's backends to list: the planned ones, then those still draining.
+    pub backends: Vec<Backend>,
+    pub draining: Vec<DrainingBackend>,
+    /// Backends to take out of the list and stop.
+    pub removed: Vec<Backend>,
+}
+
+/// Work out a function's backends, given those `listed`, those of them already `draining`, and
+/// the `planned` backends for the rest. `now` is a Unix timestamp in seconds.
+pub fn changes(
+    listed: &[Backend],
+    draining: &[DrainingBackend],
+    planned: &[Backend],
+    drain_secs: u64,
+    now: u64,
+) -> Changes {
+    let mut changes = Changes {
+        backends: planned.to_vec(),
+        draining: Vec::new(),
+        removed: Vec::new(),
+    };
+    for backend in listed.iter().filter(|b| !planned.contains(b)) {
+        let since = draining
+            .iter()
+            .find(|d| d.container_id == backend.container_id)
+            .map_or(now, |d| d.since);
+        if now.saturating_sub(since) >= drain_secs {
+            changes.removed.push(backend.clone());
+        } else {
+            changes.backends.push(backend.clone());
+            changes.draining.push(DrainingBackend {
+                container_id: backend.container_id,
+                since,
+            });
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn backend(n: u8) -> Backend {
+        Backend {
+            ip: [10, 0, 0, n].into(),
+            container_id: Uuid::from_u128(n.into()),
+        }
+    }
+
+    #[test]
+    fn test_drain_then_remove() {
+        let listed = vec![backend(1), backend(2)];
+        let planned = vec![backend(2), backend(3)];
+        let changes = changes(&listed, &[], &planned, 30, 1000);
+        assert_eq!(changes.backends, [backend(2), backend(3), backend(1)]);
+        assert_eq!(
+            changes.draining,
+            [DrainingBackend {
+                container_id: backend(1).container_id,
+                since: 1000
+            }]
+        );
+        assert!(changes.removed.is_empty());
+
+        // The next passes keep it until it's been draining long enough.
+        let listed = changes.backends;
+        let same = super::changes(&listed, &changes.draining, &planned, 30, 1029);
+        assert_eq!(same.backends, listed);
+        assert_eq!(same.draining, changes.draining);
+        let done = super::changes(&listed, &changes.draining, &planned, 30, 1030);
+        assert_eq!(done.backends, planned);
+        assert!(done.draining.is_empty());
+        assert_eq!(done.removed, [backend(1)]);
+    }
+
+    #[test]
+    fn test_no_drain() {
+        let changes = changes(&[backend(1)], &[], &[backend(2)], 0, 1000);
+        assert_eq!(changes.backends, [backend(2)]);
+        assert_eq!(changes.removed, [backend(1)]);
+    }
+}
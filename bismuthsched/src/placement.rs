@@ -0,0 +1,241 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::Ipv4Addr;
+use tracing::{event, Level};
+use uuid::Uuid;
+
+use bismuth_common::{Backend, NodeCapacity};
+
+#[derive(Clone, Debug)]
+pub struct Node {
+    pub ip: Ipv4Addr,
+    pub enabled: bool,
+    pub capacity: Option<NodeCapacity>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Function {
+    pub id: Uuid,
+    pub cpu: f32,
+    pub memory: u64,
+    /// Desired number of backends, already capped at the definition's `max_instances`.
+    pub replicas: u32,
+    pub backends: Vec<Backend>,
+}
+
+#[derive(Default)]
+struct Usage {
+    cpu: f64,
+    memory: u64,
+    containers: usize,
+}
+
+impl Usage {
+    /// Fraction of the node's CPU or memory in use, whichever is higher.
+    fn load(&self, capacity: Option<&NodeCapacity>) -> f64 {
+        match capacity {
+            Some(capacity) => {
+                (self.cpu / capacity.cpu as f64).max(self.memory as f64 / capacity.memory as f64)
+            }
+            None => 0.0,
+        }
+    }
+
+    fn fits(&self, capacity: Option<&NodeCapacity>, function: &Function) -> bool {
+        match capacity {
+            Some(capacity) => {
+                self.cpu + function.cpu as f64 <= capacity.cpu as f64
+                    && self.memory + function.memory <= capacity.memory
+            }
+            None => true,
+        }
+    }
+
+    fn add(&mut self, function: &Function) {
+        self.cpu += function.cpu as f64;
+        self.memory += function.memory;
+        self.containers += 1;
+    }
+
+    fn remove(&mut self, function: &Function) {
+        self.cpu -= function.cpu as f64;
+        self.memory = self.memory.saturating_sub(function.memory);
+        self.containers = self.containers.saturating_sub(1);
+    }
+}
+
+/// Work out new backend lists so every function has its desired number of backends, all on
+/// enabled nodes with room for them.
+/// Backends on missing or disabled nodes are dropped, and new ones are spread across nodes
+/// (fewest backends of the same function first, then least loaded).
+/// Only functions whose backends change are returned.
+pub fn plan(nodes: &[Node], functions: &[Function]) -> BTreeMap<Uuid, Vec<Backend>> {
+    let nodes: HashMap<Ipv4Addr, &Node> = nodes.iter().map(|n| (n.ip, n)).collect();
+    let mut usage: HashMap<Ipv4Addr, Usage> = HashMap::new();
+    for function in functions {
+        for backend in &function.backends {
+            if nodes.contains_key(&backend.ip) {
+                usage.entry(backend.ip).or_default().add(function);
+            }
+        }
+    }
+
+    let mut functions: Vec<&Function> = functions.iter().collect();
+    functions.sort_by_key(|f| f.id);
+
+    let mut changes = BTreeMap::new();
+    for function in functions {
+        let mut backends = Vec::with_capacity(function.replicas as usize);
+        for backend in &function.backends {
+            match nodes.get(&backend.ip) {
+                Some(node) if node.enabled => backends.push(backend.clone()),
+                Some(_) => usage.entry(backend.ip).or_default().remove(function),
+                None => {}
+            }
+        }
+
+        while backends.len() > function.replicas as usize {
+            // Scale down on the most loaded node.
+            let (i, _) = backends
+                .iter()
+                .enumerate()
+                .max_by(|(_, a), (_, b)| {
+                    let load = |b: &Backend| {
+                        usage
+                            .get(&b.ip)
+                            .map(|u| u.load(nodes[&b.ip].capacity.as_ref()))
+                            .unwrap_or(0.0)
+                    };
+                    load(a).total_cmp(&load(b))
+                })
+                .unwrap();
+            let removed = backends.remove(i);
+            usage.entry(removed.ip).or_default().remove(function);
+        }
+
+        while backends.len() < function.replicas as usize {
+            let candidate = nodes
+                .values()
+                .filter(|n| n.enabled)
+                .filter(|n| {
+                    usage
+                        .get(&n.ip)
+                        .map(|u| u.fits(n.capacity.as_ref(), function))
+                        .unwrap_or_else(|| Usage::default().fits(n.capacity.as_ref(), function))
+                })
+                .min_by(|a, b| {
+                    let key = |n: &Node| {
+                        let same = backends.iter().filter(|b| b.ip == n.ip).count();
+                        let usage = usage.get(&n.ip);
+                        (
+                            same,
+                            usage.map(|u| u.load(n.capacity.as_ref())).unwrap_or(0.0),
+                            usage.map(|u| u.containers).unwrap_or(0),
+                            n.ip,
+                        )
+                    };
+                    let (a, b) = (key(a), key(b));
+                    a.0.cmp(&b.0)
+                        .then(a.1.total_cmp(&b.1))
+                        .then(a.2.cmp(&b.2))
+                        .then(a.3.cmp(&b.3))
+                });
+            let Some(node) = candidate else {
+                event!(
+                    Level::WARN,
+                    function = %function.id,
+                    have = backends.len(),
+                    want = function.replicas,
+                    "No node has capacity for another backend"
+                );
+                break;
+            };
+            usage.entry(node.ip).or_default().add(function);
+            backends.push(Backend {
+                ip: node.ip,
+                container_id: Uuid::new_v4(),
+            });
+        }
+
+        if backends != function.backends {
+            changes.insert(function.id, backends);
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(last: u8, capacity: Option<(f32, u64)>) -> Node {
+        Node {
+            ip: Ipv4Addr::new(10, 0, 0, last),
+            enabled: true,
+            capacity: capacity.map(|(cpu, memory)| NodeCapacity { cpu, memory }),
+        }
+    }
+
+    fn function(replicas: u32, backends: Vec<Backend>) -> Function {
+        Function {
+            id: Uuid::new_v4(),
+            cpu: 1.0,
+            memory: 1024,
+            replicas,
+            backends,
+        }
+    }
+
+    #[test]
+    fn test_plan_spreads_new_backends() {
+        let nodes = vec![node(1, None), node(2, None), node(3, None)];
+        let functions = vec![function(3, vec![])];
+        let changes = plan(&nodes, &functions);
+        let mut ips: Vec<_> = changes[&functions[0].id].iter().map(|b| b.ip).collect();
+        ips.sort();
+        assert_eq!(ips, nodes.iter().map(|n| n.ip).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_plan_respects_capacity() {
+        let nodes = vec![node(1, Some((1.5, 1 << 20))), node(2, Some((0.5, 1 << 20)))];
+        let functions = vec![function(2, vec![])];
+        let changes = plan(&nodes, &functions);
+        let backends = &changes[&functions[0].id];
+        // Only one fits on node 1, and none on node 2.
+        assert_eq!(backends.len(), 1);
+        assert_eq!(backends[0].ip, nodes[0].ip);
+    }
+
+    #[test]
+    fn test_plan_replaces_backends_on_disabled_nodes() {
+        let mut nodes = vec![node(1, None), node(2, None)];
+        nodes[0].enabled = false;
+        let stale = Backend {
+            ip: nodes[0].ip,
+            container_id: Uuid::new_v4(),
+        };
+        let functions = vec![function(1, vec![stale])];
+        let changes = plan(&nodes, &functions);
+        let backends = &changes[&functions[0].id];
+        assert_eq!(backends.len(), 1);
+        assert_eq!(backends[0].ip, nodes[1].ip);
+    }
+
+    #[test]
+    fn test_plan_scales_down_and_leaves_satisfied_functions_alone() {
+        let nodes = vec![node(1, None), node(2, None)];
+        let backends = || {
+            nodes
+                .iter()
+                .map(|n| Backend {
+                    ip: n.ip,
+                    container_id: Uuid::new_v4(),
+                })
+                .collect::<Vec<_>>()
+        };
+        let functions = vec![function(1, backends()), function(2, backends())];
+        let changes = plan(&nodes, &functions);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[&functions[0].id].len(), 1);
+    }
+}
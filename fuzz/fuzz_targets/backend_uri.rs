@@ -0,0 +1,23 @@
+#![no_main]
+
+use bismuth_common::{Backend, BACKEND_PORT};
+use libfuzzer_sys::fuzz_target;
+use std::net::Ipv4Addr;
+use uuid::Uuid;
+
+// The request path is client controlled, and must never be able to redirect the request to
+// anywhere other than the chosen backend.
+fuzz_target!(|input: (u32, [u8; 16], &str)| {
+    let (ip, container_id, reqpath) = input;
+    let backend = Backend {
+        ip: Ipv4Addr::from(ip),
+        container_id: Uuid::from_bytes(container_id),
+    };
+    if let Ok(uri) = bismuthfe::backend_uri(&backend, reqpath) {
+        assert_eq!(uri.host(), Some(backend.ip.to_string().as_str()));
+        assert_eq!(uri.port_u16(), Some(BACKEND_PORT));
+        assert!(uri
+            .path()
+            .starts_with(&format!("/invoke/{}/", backend.container_id)));
+    }
+});
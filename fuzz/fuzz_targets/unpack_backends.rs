@@ -0,0 +1,11 @@
+#![no_main]
+
+use bismuth_common::{pack_backends, unpack_backends};
+use libfuzzer_sys::fuzz_target;
+
+// Backend lists come from ZooKeeper, so a corrupt or malicious znode must never crash a frontend.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(backends) = unpack_backends(data) {
+        assert_eq!(pack_backends(&backends), data);
+    }
+});
@@ -0,0 +1,26 @@
+//! Writes seed inputs for the `unpack_backends` target, generated with `pack_backends`.
+//! Run with `cargo run --manifest-path fuzz/Cargo.toml --bin seed_corpus`.
+
+use bismuth_common::{pack_backends, Backend};
+use std::net::Ipv4Addr;
+use uuid::Uuid;
+
+fn main() -> std::io::Result<()> {
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("corpus/unpack_backends");
+    std::fs::create_dir_all(&dir)?;
+
+    for count in [0u8, 1, 2, 16] {
+        let backends: Vec<Backend> = (0..count)
+            .map(|i| Backend {
+                ip: Ipv4Addr::new(10, 0, 0, i),
+                container_id: Uuid::from_u128(i as u128),
+            })
+            .collect();
+        std::fs::write(
+            dir.join(format!("seed-{}", count)),
+            pack_backends(&backends),
+        )?;
+    }
+
+    Ok(())
+}
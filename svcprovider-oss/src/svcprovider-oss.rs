@@ -379,6 +379,7 @@ fn app() -> axum::Router<Arc<SVCProviderState>> {
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    bismuth_common::init_scrubber()?;
     let tracer = init_tracer("svcprovider")?;
 
     let args = Cli::parse();
@@ -389,7 +390,11 @@ async fn main() -> Result<()> {
             tracing_subscriber::EnvFilter::try_from_default_env()
                 .unwrap_or_else(|_| "svcprovider=debug".into()),
         )
-        .with(tracing_subscriber::fmt::layer())
+        .with(
+            tracing_subscriber::fmt::layer().with_writer(bismuth_common::Scrubbed(std::io::stdout)),
+        )
+        .with(bismuth_common::file_log_layer()?)
+        .with(bismuth_common::system_log_layers("svcprovider")?)
         .init();
 
     let config = aws_config::load_from_env().await;